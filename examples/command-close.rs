@@ -31,13 +31,23 @@ fn main() {
 
     unsafe {
         cmd.pre_exec(move || {
-            // On macOS/iOS, just set them as close-on-exec
+            // On macOS/iOS/tvOS/watchOS, just set them as close-on-exec
             // Some sources indicate libdispatch may crash if the file descriptors are *actually*
             // closed
 
-            #[cfg(any(target_os = "macos", target_os = "ios"))]
+            #[cfg(any(
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "tvos",
+                target_os = "watchos"
+            ))]
             close_fds::set_fds_cloexec(3, &keep_fds);
-            #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+            #[cfg(not(any(
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "tvos",
+                target_os = "watchos"
+            )))]
             close_fds::close_open_fds(3, &keep_fds);
 
             Ok(())
@@ -0,0 +1,275 @@
+use core::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, OwnedFd};
+use std::vec::Vec;
+
+use super::{CloseFdsBuilder, FdAction};
+
+/// The first file descriptor used for socket activation (`SD_LISTEN_FDS_START`).
+pub const LISTEN_FDS_START: libc::c_int = 3;
+
+/// The `LISTEN_PID` entry passed to the child, with a placeholder that is overwritten with the
+/// child's PID after the `fork()`. The placeholder is long enough to hold any PID.
+const LISTEN_PID_ENTRY: &[u8] = b"LISTEN_PID=0000000000\0";
+
+/// Spawn a new process that runs the program at `path`, passing it the file descriptors in `fds`
+/// the way systemd does for socket activation.
+///
+/// In the child process, `fds[0]` becomes file descriptor 3 ([`LISTEN_FDS_START`]), `fds[1]`
+/// becomes file descriptor 4, and so on. Every other file descriptor starting at 3 (except the
+/// ones passed to [`CloseFdsBuilder::keep_fds()`]) is closed. `LISTEN_FDS` is set to the number
+/// of file descriptors and `LISTEN_PID` is set to the PID of the child process; any existing
+/// `LISTEN_FDS`, `LISTEN_PID`, and `LISTEN_FDNAMES` entries are removed from the environment
+/// first (so stale values from the current process can't be passed on).
+///
+/// `path`, `argv`, and `envp` are as described for
+/// [`spawn_with_closed_fds()`](crate::spawn_with_closed_fds), and the same guarantees apply: all
+/// memory allocation is done before the `fork()`, and errors in the child process (including
+/// from `execve()`) are returned from this function.
+///
+/// This can't be done with `std::process::Command`: `LISTEN_PID` can only be set after the
+/// `fork()`, but `pre_exec()` closures run before the child's environment is installed, so there
+/// is no async-signal-safe way for them to modify it.
+///
+/// An error is returned if any of the file descriptors in `fds` are negative. File descriptors
+/// may appear in `fds` more than once.
+///
+/// See [`receive_listen_fds()`] for the receiving side.
+///
+/// This is not available on tvOS or watchOS, where `fork()` and `exec()` are prohibited.
+///
+/// # Example
+///
+/// ```
+/// # use std::ffi::CStr;
+/// # use std::os::unix::io::AsRawFd;
+/// # use close_fds::{spawn_with_listen_fds, CloseFdsBuilder};
+/// let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+///
+/// let cstr = |s: &'static [u8]| CStr::from_bytes_with_nul(s).unwrap();
+/// let argv = [
+///     cstr(b"sh\0"),
+///     cstr(b"-c\0"),
+///     cstr(b"[ \"$LISTEN_FDS\" = 1 ] && [ \"$LISTEN_PID\" = $$ ]\0"),
+/// ];
+///
+/// let pid = spawn_with_listen_fds(
+///     cstr(b"/bin/sh\0"),
+///     &argv,
+///     None,
+///     &[listener.as_raw_fd()],
+///     &CloseFdsBuilder::new(),
+/// )
+/// .unwrap();
+///
+/// let mut status = 0;
+/// assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+/// assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+/// ```
+pub fn spawn_with_listen_fds(
+    path: &CStr,
+    argv: &[&CStr],
+    envp: Option<&[&CStr]>,
+    fds: &[libc::c_int],
+    builder: &CloseFdsBuilder,
+) -> io::Result<libc::pid_t> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("spawn_with_listen_fds", nfds = fds.len()).entered();
+
+    let nfds = libc::c_int::try_from(fds.len())
+        .ok()
+        .filter(|&n| n <= libc::c_int::MAX - LISTEN_FDS_START)
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::EMFILE))?;
+    if fds.iter().any(|&fd| fd < 0) {
+        return Err(io::Error::from_raw_os_error(libc::EBADF));
+    }
+
+    let is_listen_var = |entry: &CStr| {
+        [&b"LISTEN_FDS="[..], b"LISTEN_PID=", b"LISTEN_FDNAMES="]
+            .iter()
+            .any(|prefix| entry.to_bytes().starts_with(prefix))
+    };
+
+    let owned_env: Vec<CString>;
+    let mut env: Vec<&CStr> = match envp {
+        Some(envp) => envp
+            .iter()
+            .copied()
+            .filter(|entry| !is_listen_var(entry))
+            .collect(),
+        None => {
+            owned_env = std::env::vars_os()
+                .map(|(key, val)| {
+                    let mut entry = key.as_bytes().to_vec();
+                    entry.push(b'=');
+                    entry.extend_from_slice(val.as_bytes());
+                    CString::new(entry).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+                })
+                .collect::<io::Result<_>>()?;
+            owned_env
+                .iter()
+                .map(|entry| entry.as_c_str())
+                .filter(|entry| !is_listen_var(entry))
+                .collect()
+        }
+    };
+
+    let listen_fds = CString::new(std::format!("LISTEN_FDS={}", nfds)).unwrap();
+    env.push(&listen_fds);
+
+    // The child overwrites the placeholder through this pointer (in its own copy of the memory)
+    let mut listen_pid = LISTEN_PID_ENTRY.to_vec();
+    let listen_pid_ptr = listen_pid.as_mut_ptr();
+    // Safety: LISTEN_PID_ENTRY is NUL-terminated
+    env.push(unsafe { CStr::from_ptr(listen_pid_ptr as *const libc::c_char) });
+
+    let mut spawn = super::spawn::PreparedSpawn::new(path, argv, Some(&env), builder)?;
+    for i in 0..nfds {
+        spawn.keep_fd(LISTEN_FDS_START + i);
+    }
+    // Make sure that moving the file descriptors into place can't clobber the pipe used to report
+    // errors
+    spawn.move_pipe_above(LISTEN_FDS_START + nfds)?;
+
+    // The file descriptors are first duplicated to temporary file descriptors above everything
+    // that we care about, so moving one into place can't clobber another one that hasn't been
+    // moved yet. (Anything that was open at one of the temporary file descriptors would have been
+    // closed anyway.)
+    let tmpbase = fds
+        .iter()
+        .copied()
+        .fold(spawn.max_keep_fd(), core::cmp::max)
+        .checked_add(1)
+        .filter(|tmpbase| tmpbase.checked_add(nfds).is_some())
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::EMFILE))?;
+
+    let setup = || unsafe {
+        let prefix_len = b"LISTEN_PID=".len();
+        let pid_buf = core::slice::from_raw_parts_mut(
+            listen_pid_ptr.add(prefix_len),
+            LISTEN_PID_ENTRY.len() - prefix_len - 1,
+        );
+        let len = crate::util::format_int(libc::getpid(), pid_buf);
+        if len < pid_buf.len() {
+            pid_buf[len] = 0;
+        }
+
+        for (i, &fd) in fds.iter().enumerate() {
+            if libc::dup2(fd, tmpbase + i as libc::c_int) < 0 {
+                return Err(crate::util::errno());
+            }
+        }
+
+        // dup2() clears the close-on-exec flag
+        for i in 0..nfds {
+            if libc::dup2(tmpbase + i, LISTEN_FDS_START + i) < 0 {
+                return Err(crate::util::errno());
+            }
+            libc::close(tmpbase + i);
+        }
+
+        Ok(())
+    };
+
+    // Safety: setup() only performs async-signal-safe operations
+    let res = unsafe { spawn.spawn_with(LISTEN_FDS_START, setup) };
+    drop(listen_pid);
+    res
+}
+
+/// Receive the file descriptors passed to the current process with socket activation (for
+/// example, by systemd or [`spawn_with_listen_fds()`]), and then close (or set the close-on-exec
+/// flag on) all other file descriptors starting at 3.
+///
+/// This works like systemd's `sd_listen_fds()`:
+///
+/// 1. If `LISTEN_PID` is set to the PID of the current process, `LISTEN_FDS` file descriptors
+///    starting at 3 ([`LISTEN_FDS_START`]) were passed to it. Otherwise (for example, if the
+///    variables were inherited from a parent process that was socket-activated), no file
+///    descriptors were passed.
+/// 2. `LISTEN_PID`, `LISTEN_FDS`, and `LISTEN_FDNAMES` are removed from the environment, so they
+///    won't be inherited by child processes.
+/// 3. The close-on-exec flag is set on the passed file descriptors.
+///
+/// Then `action` is applied to all other file descriptors starting at 3 (except the ones passed
+/// to [`CloseFdsBuilder::keep_fds()`] on `builder`), as with [`FdAction::apply()`]. This happens
+/// even if no file descriptors were passed, so the process always ends up with a known file
+/// descriptor table.
+///
+/// On success, the passed file descriptors are returned in order (so the first one is file
+/// descriptor 3). An error is returned if `LISTEN_PID` or `LISTEN_FDS` is malformed, or if one of
+/// the passed file descriptors is not open; in that case, the environment variables are still
+/// removed, but no file descriptors are closed (and the close-on-exec flag may not have been set
+/// on all of the passed file descriptors). An error is also returned (without closing anything) if
+/// [`CloseFdsBuilder::also_keep()`] has already been called 4 times on `builder`, since there's no
+/// room to add the passed file descriptors.
+///
+/// This is not available on tvOS or watchOS, where `exec()` is prohibited.
+///
+/// # Safety
+///
+/// If `action` is [`FdAction::Close`], this closes file descriptors, so all of the warnings on
+/// [`CloseFdsBuilder::closefrom()`] apply. In either case, this modifies the environment, so it
+/// should be called at startup, before any other threads are started.
+pub unsafe fn receive_listen_fds(
+    builder: &CloseFdsBuilder,
+    action: FdAction,
+) -> io::Result<Vec<OwnedFd>> {
+    let parse = |name: &str| -> io::Result<Option<libc::c_int>> {
+        match std::env::var_os(name) {
+            Some(val) => val
+                .to_str()
+                .and_then(|val| val.parse().ok())
+                .filter(|&val: &libc::c_int| val >= 0)
+                .map(Some)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        std::format!("invalid value for {}", name),
+                    )
+                }),
+            None => Ok(None),
+        }
+    };
+
+    let res = match (parse("LISTEN_PID"), parse("LISTEN_FDS")) {
+        (Ok(Some(pid)), Ok(Some(nfds))) if pid == libc::getpid() => {
+            if nfds <= libc::c_int::MAX - LISTEN_FDS_START {
+                Ok(nfds)
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid value for LISTEN_FDS",
+                ))
+            }
+        }
+        (Ok(_), Ok(_)) => Ok(0),
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    };
+
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"].iter() {
+        std::env::remove_var(name);
+    }
+
+    let nfds = res?;
+    let fds: Vec<libc::c_int> = (LISTEN_FDS_START..LISTEN_FDS_START + nfds).collect();
+
+    for &fd in fds.iter() {
+        if !crate::util::is_fd_valid(fd) || crate::util::set_cloexec(fd).is_err() {
+            return Err(io::Error::from_raw_os_error(libc::EBADF));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    tracing::debug!(nfds, "received socket activation file descriptors");
+
+    let mut builder = builder.clone();
+    builder
+        .also_keep(&fds)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    action.apply(&builder, LISTEN_FDS_START);
+
+    Ok(fds.iter().map(|&fd| OwnedFd::from_raw_fd(fd)).collect())
+}
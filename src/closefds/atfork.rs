@@ -0,0 +1,310 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::{KeepFds, KeepFdsBuf};
+
+/// The maximum number of file descriptors that can be passed to [`install_atfork_closer()`].
+pub const MAX_ATFORK_KEEP_FDS: usize = 64;
+
+/// The maximum number of file descriptors that can be marked with [`set_clofork()`] at once.
+pub const MAX_CLOFORK_FDS: usize = 64;
+
+struct AtforkConfig {
+    enabled: bool,
+    minfd: libc::c_int,
+    keep_fds: KeepFdsBuf<MAX_ATFORK_KEEP_FDS>,
+    // The file descriptors marked with set_clofork()
+    clofork: KeepFdsBuf<MAX_CLOFORK_FDS>,
+}
+
+struct AtforkState {
+    // Held while the configuration is being changed, and across fork() (from the "prepare" handler
+    // until the "parent"/"child" handlers). This ensures that the child never sees a partially
+    // updated configuration.
+    lock: AtomicBool,
+    config: UnsafeCell<AtforkConfig>,
+}
+
+// Safety: `config` is only accessed with `lock` held
+unsafe impl Sync for AtforkState {}
+
+static STATE: AtforkState = AtforkState {
+    lock: AtomicBool::new(false),
+    config: UnsafeCell::new(AtforkConfig {
+        enabled: false,
+        minfd: 0,
+        keep_fds: KeepFdsBuf::new(),
+        clofork: KeepFdsBuf::new(),
+    }),
+};
+
+/// Set once the handlers have been registered (they can never be unregistered)
+static REGISTERED: AtomicBool = AtomicBool::new(false);
+
+fn lock() {
+    while STATE
+        .lock
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+}
+
+fn unlock() {
+    STATE.lock.store(false, Ordering::Release);
+}
+
+extern "C" fn atfork_prepare() {
+    lock();
+}
+
+extern "C" fn atfork_parent() {
+    unlock();
+}
+
+extern "C" fn atfork_child() {
+    // Safety: The lock was acquired in atfork_prepare() (and there's only one thread now)
+    let config = unsafe { &mut *STATE.config.get() };
+
+    for &fd in config.clofork.iter() {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+    // They're closed now, so the numbers may be reused for other files in this process
+    config.clofork.clear();
+
+    if config.enabled {
+        super::cloexec::set_fds_cloexec(
+            config.minfd,
+            // Safety: KeepFdsBuf keeps itself sorted
+            unsafe { KeepFds::new_sorted(config.keep_fds.as_slice()) },
+            crate::FdIterBuilder::new(),
+            false,
+            super::observe::Observer::default(),
+            super::backend::Backend::default(),
+        );
+    }
+
+    unlock();
+}
+
+/// Register the `pthread_atfork()` handlers if they haven't been registered yet. On failure, the
+/// error code is returned.
+fn register_handlers() -> Result<(), libc::c_int> {
+    if REGISTERED.load(Ordering::Acquire) {
+        return Ok(());
+    }
+
+    lock();
+    // Check again now that we have the lock, in case another thread got here first
+    let res = if REGISTERED.load(Ordering::Relaxed) {
+        0
+    } else {
+        unsafe {
+            libc::pthread_atfork(
+                Some(atfork_prepare),
+                Some(atfork_parent),
+                Some(atfork_child),
+            )
+        }
+    };
+    if res == 0 {
+        REGISTERED.store(true, Ordering::Release);
+    }
+    unlock();
+
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(res)
+    }
+}
+
+/// Arrange for the close-on-exec flag to be set on all file descriptors starting at `minfd`
+/// (except the ones in `keep_fds`) in the child process after every `fork()`.
+///
+/// This uses `pthread_atfork()`, so it also covers `fork()`s made by code that the application
+/// doesn't control (such as third-party C libraries). Note that it does *not* cover `vfork()`,
+/// `posix_spawn()`, or `clone()` called directly, which don't run `pthread_atfork()` handlers.
+///
+/// The file descriptors are marked close-on-exec rather than closed, because many callers of
+/// `fork()` (including `std::process::Command`) rely on file descriptors that were open before the
+/// `fork()` remaining usable until `exec()`. The end result is the same once the child calls
+/// `exec()`; the main consequence is that file descriptors that are not in `keep_fds` will not be
+/// inherited by the executed program.
+///
+/// Calling this again replaces the previous configuration. The `pthread_atfork()` handlers can't
+/// be removed once they're registered, but they can be disabled with
+/// [`uninstall_atfork_closer()`].
+///
+/// `keep_fds` is copied into a fixed-size buffer (so the handlers don't need to allocate memory);
+/// it may contain at most [`MAX_ATFORK_KEEP_FDS`] distinct file descriptors.
+pub fn install_atfork_closer(
+    minfd: libc::c_int,
+    keep_fds: &[libc::c_int],
+) -> Result<(), AtforkCloserError> {
+    let mut buf = KeepFdsBuf::new();
+    for &fd in keep_fds {
+        buf.push(fd)
+            .map_err(|e| AtforkCloserError::TooManyKeepFds(e.0))?;
+    }
+
+    register_handlers().map_err(AtforkCloserError::Register)?;
+
+    lock();
+    // Safety: We hold the lock
+    unsafe {
+        let config = &mut *STATE.config.get();
+        config.enabled = true;
+        config.minfd = core::cmp::max(minfd, 0);
+        config.keep_fds = buf;
+    }
+    unlock();
+
+    Ok(())
+}
+
+/// Disable the handlers installed by [`install_atfork_closer()`].
+///
+/// This does nothing if they were never installed.
+pub fn uninstall_atfork_closer() {
+    lock();
+    // Safety: We hold the lock
+    unsafe {
+        (*STATE.config.get()).enabled = false;
+    }
+    unlock();
+}
+
+/// Mark `fd` as "close-on-fork": it will be closed in the child process after every `fork()`.
+///
+/// This emulates the `FD_CLOFORK` flag on platforms that don't support it. The file descriptor is
+/// added to a fixed-size registry, and a `pthread_atfork()` child handler (the same one used by
+/// [`install_atfork_closer()`], which is registered the first time either function is called)
+/// closes every registered file descriptor in the child. The registry is then cleared in the
+/// child (so the numbers can be reused there); the parent's registry is unaffected.
+///
+/// Like the handler used by [`install_atfork_closer()`], this does not cover `vfork()`,
+/// `posix_spawn()`, or `clone()` called directly. [`run_clofork()`] can be called in such
+/// children to close the registered file descriptors explicitly.
+///
+/// The registry only records file descriptor *numbers*, so a file descriptor should be removed
+/// with [`unset_clofork()`] before it is closed. Otherwise, another file that is later opened with
+/// the same number will also be closed in child processes.
+///
+/// At most [`MAX_CLOFORK_FDS`] file descriptors may be registered at once. Marking a file
+/// descriptor that is already registered does nothing.
+pub fn set_clofork(fd: libc::c_int) -> Result<(), CloforkError> {
+    if fd < 0 {
+        return Err(CloforkError::BadFd(fd));
+    }
+
+    register_handlers().map_err(CloforkError::Register)?;
+
+    lock();
+    // Safety: We hold the lock
+    let res = unsafe { (*STATE.config.get()).clofork.push(fd) };
+    unlock();
+
+    res.map_err(|e| CloforkError::Full(e.0))
+}
+
+/// Remove `fd` from the registry of file descriptors to close after `fork()` (see
+/// [`set_clofork()`]), returning whether it was registered.
+pub fn unset_clofork(fd: libc::c_int) -> bool {
+    lock();
+    // Safety: We hold the lock
+    let res = unsafe { (*STATE.config.get()).clofork.remove(fd) };
+    unlock();
+    res
+}
+
+/// Check whether `fd` is registered to be closed after `fork()` (see [`set_clofork()`]).
+pub fn is_clofork(fd: libc::c_int) -> bool {
+    lock();
+    // Safety: We hold the lock
+    let res = unsafe { (*STATE.config.get()).clofork.contains(&fd) };
+    unlock();
+    res
+}
+
+/// Close all of the file descriptors registered with [`set_clofork()`].
+///
+/// This is intended to be called in child processes that were created without running the
+/// `pthread_atfork()` handlers (for example, with `vfork()` or `clone()`). It does not allocate
+/// memory and is async-signal-safe. Unlike the `pthread_atfork()` handler, it leaves the registry
+/// unchanged, since a `vfork()` child shares the parent's memory.
+///
+/// # Safety
+///
+/// This closes file descriptors that may still be in use in the current process, so it must only
+/// be called in a child process (before `exec()`). It must not be called if the process was
+/// created (e.g. with the raw `fork` system call) while another thread was modifying the
+/// registry, since the registry's lock would never be released.
+pub unsafe fn run_clofork() {
+    lock();
+    for &fd in (*STATE.config.get()).clofork.iter() {
+        libc::close(fd);
+    }
+    unlock();
+}
+
+/// The error returned by [`set_clofork()`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum CloforkError {
+    /// The given file descriptor is negative.
+    BadFd(libc::c_int),
+    /// The registry is full (see [`MAX_CLOFORK_FDS`]). This contains the file descriptor that
+    /// didn't fit.
+    Full(libc::c_int),
+    /// `pthread_atfork()` failed with the given error code.
+    Register(libc::c_int),
+}
+
+impl fmt::Display for CloforkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::BadFd(fd) => write!(f, "Invalid file descriptor {}", fd),
+            Self::Full(fd) => write!(
+                f,
+                "Too many close-on-fork file descriptors (no room for {}; the maximum is {})",
+                fd, MAX_CLOFORK_FDS
+            ),
+            Self::Register(eno) => write!(f, "pthread_atfork() failed with error code {}", eno),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CloforkError {}
+
+/// The error returned by [`install_atfork_closer()`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum AtforkCloserError {
+    /// Too many file descriptors were passed in `keep_fds` (see [`MAX_ATFORK_KEEP_FDS`]). This
+    /// contains the first file descriptor that didn't fit.
+    TooManyKeepFds(libc::c_int),
+    /// `pthread_atfork()` failed with the given error code.
+    Register(libc::c_int),
+}
+
+impl fmt::Display for AtforkCloserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::TooManyKeepFds(fd) => write!(
+                f,
+                "Too many file descriptors to keep (no room for {}; the maximum is {})",
+                fd, MAX_ATFORK_KEEP_FDS
+            ),
+            Self::Register(eno) => write!(f, "pthread_atfork() failed with error code {}", eno),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AtforkCloserError {}
@@ -0,0 +1,215 @@
+use super::observe::Observer;
+use super::{CloexecStats, KeepFds};
+
+/// A custom backend for finding open file descriptors, closing them, and setting them as
+/// close-on-exec. See [`CloseFdsBuilder::closer()`].
+///
+/// This is intended for embedders on platforms that this crate doesn't know about, or that have
+/// their own kernel interfaces for these operations. The builder still handles the keep list:
+/// ranges of file descriptors to close are planned around the kept file descriptors, and
+/// enumerated file descriptors are checked against the keep list before being closed.
+///
+/// Every method has a default implementation that fails with `ENOSYS`, meaning "not supported". In
+/// that case, this crate falls back on its own methods (for enumeration) or on acting on each file
+/// descriptor individually with `close()` or `fcntl()` (for closing and setting the close-on-exec
+/// flag). So a backend only needs to implement the operations it can actually speed up.
+///
+/// # Safety requirements for implementors
+///
+/// The methods may be called in a child process after a `fork()` (for example, by the process
+/// spawning helpers or by `pre_exec()` closures), so they **must** be async-signal-safe, and they
+/// must not allocate memory. They must not open or close any file descriptors except the ones
+/// that [`Self::close_range()`] is asked to close (and temporary file descriptors that are closed
+/// again before returning).
+///
+/// [`CloseFdsBuilder::closer()`]: ./struct.CloseFdsBuilder.html#method.closer
+pub trait FdCloser: Sync {
+    /// Call `f` with every open file descriptor starting at `minfd`, in ascending order, until it
+    /// returns `false`.
+    ///
+    /// Some file descriptors that aren't open may be passed as well (for example, if they were
+    /// closed while the listing was in progress). Returns an error code if the open file
+    /// descriptors can't be listed this way (before calling `f`).
+    #[allow(unused_variables)]
+    fn for_each_fd(
+        &self,
+        minfd: libc::c_int,
+        f: &mut dyn FnMut(libc::c_int) -> bool,
+    ) -> Result<(), libc::c_int> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Close all of the file descriptors from `low` to `high` (inclusive). Not all of them are
+    /// necessarily open, and `high` is often `c_int::MAX`.
+    ///
+    /// Returns an error code if this fails or isn't supported (in which case nothing should have
+    /// been closed).
+    ///
+    /// # Safety
+    ///
+    /// See [`CloseFdsBuilder::closefrom()`](./struct.CloseFdsBuilder.html#method.closefrom).
+    #[allow(unused_variables)]
+    unsafe fn close_range(&self, low: libc::c_int, high: libc::c_int) -> Result<(), libc::c_int> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Set the close-on-exec flag on all of the file descriptors from `low` to `high` (inclusive).
+    /// Not all of them are necessarily open, and `high` is often `c_int::MAX`.
+    ///
+    /// Returns an error code if this fails or isn't supported (in which case no flags should have
+    /// been changed).
+    #[allow(unused_variables)]
+    fn cloexec_range(&self, low: libc::c_int, high: libc::c_int) -> Result<(), libc::c_int> {
+        Err(libc::ENOSYS)
+    }
+}
+
+/// The (optional) custom backend that the closing and close-on-exec code uses.
+#[derive(Copy, Clone, Default)]
+pub(crate) struct Backend(pub Option<&'static dyn FdCloser>);
+
+impl core::fmt::Debug for Backend {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("Some(FdCloser)"),
+            None => f.write_str("None"),
+        }
+    }
+}
+
+/// Close all open file descriptors starting at `minfd` (except the ones in `keep_fds`) using
+/// `closer`, falling back on `itbuilder` to find them if `closer` can't list them.
+pub(crate) unsafe fn close_fds(
+    closer: &dyn FdCloser,
+    mut minfd: libc::c_int,
+    mut keep_fds: KeepFds,
+    mut itbuilder: crate::FdIterBuilder,
+    obs: Observer,
+) {
+    keep_fds.simplify(&mut minfd);
+    let mut gaps = keep_fds.gaps(minfd);
+
+    let close_gap = |low: libc::c_int, high: libc::c_int| {
+        closer.close_range(low, high).map_err(drop)?;
+        obs.closed_range(low, high);
+        Ok(())
+    };
+
+    // If the ranges around the kept file descriptors are known, try to close them in bulk (if
+    // this fails partway through, going over everything below is still correct)
+    if plan_ranges(minfd, &keep_fds, gaps.as_ref(), close_gap).is_ok() {
+        return;
+    }
+
+    let max_keep_fd = keep_fds.max;
+    let mut tried_rest = false;
+
+    let mut visit = |fd: libc::c_int| {
+        if fd > max_keep_fd && !tried_rest {
+            // None of the file descriptors from here onward can be in keep_fds
+            if close_gap(fd, libc::c_int::MAX).is_ok() {
+                return false;
+            }
+            tried_rest = true;
+        }
+
+        if match gaps {
+            Some(ref mut gaps) => gaps.should_keep(fd),
+            None => keep_fds.should_keep(fd),
+        } {
+            obs.kept(fd);
+        } else {
+            obs.close(fd);
+        }
+        true
+    };
+
+    if closer.for_each_fd(minfd, &mut visit).is_err() {
+        itbuilder.set_possible(true);
+
+        for fd in itbuilder.iter_from(minfd) {
+            if !visit(fd) {
+                break;
+            }
+        }
+    }
+}
+
+/// Set the close-on-exec flag on all open file descriptors starting at `minfd` (except the ones
+/// in `keep_fds`) using `closer`, falling back on `itbuilder` to find them if `closer` can't list
+/// them.
+pub(crate) fn set_fds_cloexec(
+    closer: &dyn FdCloser,
+    mut minfd: libc::c_int,
+    mut keep_fds: KeepFds,
+    mut itbuilder: crate::FdIterBuilder,
+    blind: bool,
+    stats: &mut CloexecStats,
+    obs: Observer,
+) {
+    keep_fds.simplify(&mut minfd);
+    let mut gaps = keep_fds.gaps(minfd);
+
+    let cloexec_gap = |low: libc::c_int, high: libc::c_int| {
+        closer.cloexec_range(low, high).map_err(drop)?;
+        obs.notify(super::FdDecision::CloexecRange { low, high });
+        Ok(())
+    };
+
+    if plan_ranges(minfd, &keep_fds, gaps.as_ref(), cloexec_gap).is_ok() {
+        stats.bulk = true;
+        return;
+    }
+
+    let max_keep_fd = keep_fds.max;
+    let mut tried_rest = false;
+
+    let mut visit = |fd: libc::c_int| {
+        if fd > max_keep_fd && !tried_rest {
+            if cloexec_gap(fd, libc::c_int::MAX).is_ok() {
+                stats.bulk = true;
+                return false;
+            }
+            tried_rest = true;
+        }
+
+        if match gaps {
+            Some(ref mut gaps) => gaps.should_keep(fd),
+            None => keep_fds.should_keep(fd),
+        } {
+            obs.kept(fd);
+        } else {
+            super::cloexec::record_cloexec(fd, blind, stats, obs);
+        }
+        true
+    };
+
+    if closer.for_each_fd(minfd, &mut visit).is_err() {
+        itbuilder.set_possible(true);
+
+        for fd in itbuilder.iter_from(minfd) {
+            if !visit(fd) {
+                break;
+            }
+        }
+    }
+}
+
+/// Apply `func` to every range of file descriptors starting at `minfd` that isn't in `keep_fds`,
+/// if those ranges can be determined without listing the open file descriptors.
+fn plan_ranges<F: FnMut(libc::c_int, libc::c_int) -> Result<(), ()>>(
+    minfd: libc::c_int,
+    keep_fds: &KeepFds,
+    gaps: Option<&super::gaps::KeepGaps>,
+    mut func: F,
+) -> Result<(), ()> {
+    if keep_fds.max < minfd {
+        func(minfd, libc::c_int::MAX)
+    } else if let Some(gaps) = gaps {
+        gaps.apply(func)
+    } else if keep_fds.sorted {
+        crate::util::apply_range(minfd, keep_fds.iter_sorted(), func)
+    } else {
+        Err(())
+    }
+}
@@ -1,16 +1,37 @@
+use super::backend::Backend;
+use super::observe::Observer;
 use crate::util;
 
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "android"))]
 use core::sync::atomic::{AtomicBool, Ordering};
 
-#[cfg(target_os = "linux")]
-static MAY_HAVE_CLOSE_RANGE_CLOEXEC: AtomicBool = AtomicBool::new(true);
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(super) static MAY_HAVE_CLOSE_RANGE_CLOEXEC: AtomicBool = AtomicBool::new(true);
 
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "android"))]
 #[inline]
-fn set_cloexec_range(minfd: libc::c_uint, maxfd: libc::c_uint) -> Result<(), ()> {
+pub(super) fn may_have_close_range_cloexec() -> bool {
+    if cfg!(any(
+        feature = "deterministic-syscalls",
+        feature = "libc-wrappers-only"
+    )) {
+        return false;
+    }
+
+    #[cfg(target_os = "android")]
+    if !util::android_close_range_allowed() {
+        return false;
+    }
+
+    MAY_HAVE_CLOSE_RANGE_CLOEXEC.load(Ordering::Relaxed)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[inline]
+fn set_cloexec_range(minfd: libc::c_uint, maxfd: libc::c_uint, obs: Observer) -> Result<(), ()> {
     debug_assert!(minfd <= maxfd, "{} > {}", minfd, maxfd);
 
+    obs.counter.close_range();
     if unsafe {
         libc::syscall(
             libc::SYS_close_range,
@@ -27,22 +48,28 @@ fn set_cloexec_range(minfd: libc::c_uint, maxfd: libc::c_uint) -> Result<(), ()>
     }
 }
 
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "android"))]
 #[inline]
 fn set_cloexec_shortcut(
     minfd: libc::c_int,
-    keep_fds: &[libc::c_int],
-    max_keep_fd: libc::c_int,
-    fds_sorted: bool,
+    keep_fds: &super::KeepFds,
+    gaps: Option<&super::gaps::KeepGaps>,
+    obs: Observer,
 ) -> Result<(), ()> {
-    if !MAY_HAVE_CLOSE_RANGE_CLOEXEC.load(Ordering::Relaxed) {
+    let cloexec_gap = |low: libc::c_int, high: libc::c_int| {
+        set_cloexec_range(low as libc::c_uint, high as libc::c_uint, obs)?;
+        obs.cloexec_range(low, high);
+        Ok(())
+    };
+
+    if !may_have_close_range_cloexec() {
         Err(())
-    } else if max_keep_fd < minfd {
-        set_cloexec_range(minfd as libc::c_uint, libc::c_uint::MAX)
-    } else if fds_sorted {
-        util::apply_range(minfd, keep_fds, |low, high| {
-            set_cloexec_range(low as libc::c_uint, high as libc::c_uint)
-        })
+    } else if keep_fds.max < minfd {
+        cloexec_gap(minfd, libc::c_int::MAX)
+    } else if let Some(gaps) = gaps {
+        gaps.apply(cloexec_gap)
+    } else if keep_fds.sorted {
+        util::apply_range(minfd, keep_fds.iter_sorted(), cloexec_gap)
     } else {
         Err(())
     }
@@ -50,23 +77,36 @@ fn set_cloexec_shortcut(
 
 pub(crate) fn set_fds_cloexec(
     mut minfd: libc::c_int,
-    keep_fds: super::KeepFds,
+    mut keep_fds: super::KeepFds,
     mut itbuilder: crate::FdIterBuilder,
-) {
-    let super::KeepFds {
-        max: max_keep_fd,
-        fds: mut keep_fds,
-        sorted: fds_sorted,
-    } = keep_fds;
+    blind: bool,
+    obs: Observer,
+    backend: Backend,
+) -> super::CloexecStats {
+    let mut stats = super::CloexecStats {
+        passes: 1,
+        ..Default::default()
+    };
 
-    keep_fds = util::simplify_keep_fds(keep_fds, fds_sorted, &mut minfd);
+    if let Some(closer) = backend.0 {
+        super::backend::set_fds_cloexec(closer, minfd, keep_fds, itbuilder, blind, &mut stats, obs);
+        return stats;
+    }
 
-    #[cfg(target_os = "linux")]
-    if set_cloexec_shortcut(minfd, keep_fds, max_keep_fd, fds_sorted).is_ok() {
-        return;
+    keep_fds.simplify(&mut minfd);
+
+    // If the keep list is sorted and small, precompute the ranges to set as close-on-exec once
+    let mut gaps = keep_fds.gaps(minfd);
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if set_cloexec_shortcut(minfd, &keep_fds, gaps.as_ref(), obs).is_ok() {
+        stats.bulk = true;
+        return stats;
     }
 
-    itbuilder.possible(true);
+    let max_keep_fd = keep_fds.max;
+
+    itbuilder.set_possible(true);
 
     let mut fditer = itbuilder.iter_from(minfd);
 
@@ -74,29 +114,74 @@ pub(crate) fn set_fds_cloexec(
         if fd > max_keep_fd {
             // We know that none of the file descriptors we encounter from here onward can be in
             // keep_fds.
-            set_cloexec_rest(fd, fditer);
-            return;
-        } else if !util::check_should_keep(&mut keep_fds, fd, fds_sorted) {
+            set_cloexec_rest(fd, fditer, blind, &mut stats, obs);
+            break;
+        } else if match gaps {
+            Some(ref mut gaps) => gaps.should_keep(fd),
+            None => keep_fds.should_keep(fd),
+        } {
+            obs.kept(fd);
+        } else {
             // It's not in keep_fds
-            util::set_cloexec(fd);
+            record_cloexec(fd, blind, &mut stats, obs);
         }
     }
+
+    stats
 }
 
-fn set_cloexec_rest(fd: libc::c_int, fditer: crate::FdIter) {
+fn set_cloexec_rest(
+    fd: libc::c_int,
+    fditer: crate::FdIter,
+    blind: bool,
+    stats: &mut super::CloexecStats,
+    obs: Observer,
+) {
     // On Linux, we may be able to use close_range() with the CLOSE_RANGE_CLOEXEC flag to set them
     // as close-on-exec directly
-    #[cfg(target_os = "linux")]
-    if MAY_HAVE_CLOSE_RANGE_CLOEXEC.load(Ordering::Relaxed)
-        && set_cloexec_range(fd as libc::c_uint, libc::c_uint::MAX).is_ok()
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if may_have_close_range_cloexec()
+        && set_cloexec_range(fd as libc::c_uint, libc::c_uint::MAX, obs).is_ok()
     {
+        stats.bulk = true;
+        obs.cloexec_range(fd, libc::c_int::MAX);
         return;
     }
 
     // Fall back on looping through and closing manually
-    util::set_cloexec(fd);
-    for fd in fditer {
-        util::set_cloexec(fd);
+    record_cloexec(fd, blind, stats, obs);
+    fditer.for_each(|fd| record_cloexec(fd, blind, stats, obs));
+}
+
+#[inline]
+pub(super) fn record_cloexec(
+    fd: libc::c_int,
+    blind: bool,
+    stats: &mut super::CloexecStats,
+    obs: Observer,
+) {
+    let res = set_cloexec(fd, blind, obs);
+    if res.is_ok() {
+        obs.cloexec(fd);
+    }
+    stats.record(res);
+}
+
+/// Set the close-on-exec flag on `fd`, without checking its current flags first if `blind` is
+/// true (see `CloseFdsBuilder::blind_cloexec()`).
+#[inline]
+pub(super) fn set_cloexec(fd: libc::c_int, blind: bool, obs: Observer) -> Result<bool, ()> {
+    // Note: Reading the flags from /proc/self/fdinfo on Linux would not save anything here; each
+    // file descriptor has its own fdinfo file, so that's three syscalls (open/read/close) per file
+    // descriptor instead of one fcntl(F_GETFD). Use blind mode to skip the F_GETFD instead.
+    if blind {
+        obs.counter.fcntl(1);
+        util::set_cloexec_blind(fd)
+    } else {
+        let res = util::set_cloexec(fd);
+        // F_GETFD, plus F_SETFD if the flag wasn't already set
+        obs.counter.fcntl(if res == Ok(false) { 1 } else { 2 });
+        res
     }
 }
 
@@ -106,6 +191,19 @@ pub(crate) fn probe() {
     // "invalid flags" or "invalid file descriptor range". So we have to make a call like this
     // (which *should* do nothing; it shouldn't be possible to open and use file descriptors in
     // the vicinity of 2^32).
-    #[cfg(target_os = "linux")]
-    let _ = set_cloexec_range(libc::c_uint::MAX, libc::c_uint::MAX);
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        if may_have_close_range_cloexec() {
+            let _ = set_cloexec_range(libc::c_uint::MAX, libc::c_uint::MAX, Observer::default());
+        }
+
+        log_debug!(
+            "close_range(CLOSE_RANGE_CLOEXEC) is {}",
+            if may_have_close_range_cloexec() {
+                "available"
+            } else {
+                "not available"
+            }
+        );
+    }
 }
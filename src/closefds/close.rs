@@ -1,28 +1,63 @@
-#[cfg(target_os = "linux")]
+use super::backend::Backend;
+use super::observe::Observer;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
 use core::sync::atomic::{AtomicBool, Ordering};
 #[cfg(target_os = "freebsd")]
 use core::sync::atomic::{AtomicU8, Ordering};
 
+#[allow(unused_variables)]
 pub(crate) unsafe fn close_fds(
     mut minfd: libc::c_int,
-    keep_fds: super::KeepFds,
+    mut keep_fds: super::KeepFds,
     mut itbuilder: crate::FdIterBuilder,
+    juggle: bool,
+    descending: bool,
+    obs: Observer,
+    backend: Backend,
 ) {
-    let super::KeepFds {
-        max: max_keep_fd,
-        fds: mut keep_fds,
-        sorted: fds_sorted,
-    } = keep_fds;
+    if let Some(closer) = backend.0 {
+        super::backend::close_fds(closer, minfd, keep_fds, itbuilder, obs);
+        return;
+    }
+
+    // By far the most common case is keeping just the standard file descriptors (or nothing) and
+    // closing everything from 3 up; go straight to closefrom()/close_range() if we can.
+    if !descending && keep_fds.is_stdio_only(minfd) && close_all_from(3, obs).is_ok() {
+        obs.closed_range(3, libc::c_int::MAX);
+        return;
+    }
+
+    keep_fds.simplify(&mut minfd);
 
-    keep_fds = crate::util::simplify_keep_fds(keep_fds, fds_sorted, &mut minfd);
+    // If the keep list is sorted and small, precompute the ranges to close once
+    let mut gaps = keep_fds.gaps(minfd);
+
+    if descending {
+        close_descending(minfd, &keep_fds, itbuilder.clone(), obs);
+        // Now fall through and close everything again the normal way, in case anything was
+        // reopened while we were closing
+    }
 
     // Some OSes have (or may have) a closefrom() or close_range() syscall that we can use to
     // improve performance if certain conditions are true.
-    if close_fds_shortcut(minfd, keep_fds, max_keep_fd, fds_sorted).is_ok() {
+    if close_fds_shortcut(minfd, &keep_fds, gaps.as_ref(), obs).is_ok() {
         return;
     }
 
-    itbuilder.possible(true);
+    #[cfg(any(
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+    ))]
+    if juggle && close_fds_juggle(minfd, &keep_fds, obs).is_ok() {
+        return;
+    }
+
+    let max_keep_fd = keep_fds.max;
+
+    itbuilder.set_possible(true);
 
     // On systems with closefrom(), skip the "nfds" method when determining maxfd -- these systems
     // have a working closefrom(), so we can just call that once we pass the end of keep_fds.
@@ -41,16 +76,302 @@ pub(crate) unsafe fn close_fds(
         if fd > max_keep_fd {
             // If fd > max_keep_fd, we know that none of the file descriptors we encounter from
             // here onward can be in keep_fds.
-            close_rest(fd, fditer);
+            close_rest(fd, fditer, obs);
             return;
-        } else if !crate::util::check_should_keep(&mut keep_fds, fd, fds_sorted) {
+        } else if match gaps {
+            Some(ref mut gaps) => gaps.should_keep(fd),
+            None => keep_fds.should_keep(fd),
+        } {
+            obs.kept(fd);
+        } else {
             // Close it if it's not in keep_fds
-            libc::close(fd);
+            obs.close(fd);
+        }
+    }
+
+    // If the iterator was looping up to a maximum file descriptor derived from the file
+    // descriptor limit, there may be open file descriptors above that (the limit is clamped, and
+    // it may have been lowered after they were opened). Try to close them too.
+    if let Some(maxfd) = fditer.known_max_fd() {
+        // Close the directory file descriptor (if one is being used) first
+        #[allow(clippy::drop_non_drop)]
+        drop(fditer);
+        close_above(maxfd, &keep_fds, obs);
+    }
+}
+
+/// Close all open file descriptors starting at `minfd` (except the ones in `keep_fds`), starting
+/// with the highest one and working down. See `CloseFdsBuilder::close_descending()`.
+unsafe fn close_descending(
+    minfd: libc::c_int,
+    keep_fds: &super::KeepFds,
+    mut itbuilder: crate::FdIterBuilder,
+    obs: Observer,
+) {
+    itbuilder.set_possible(false);
+
+    // Find the highest open file descriptor first (this also closes the directory file
+    // descriptor, if one is used)
+    let maxfd = match itbuilder.iter_from(minfd).last() {
+        Some(fd) => fd,
+        None => return,
+    };
+
+    // should_keep() requires ascending order if the lists are sorted, so we have to check every
+    // list in full
+    for fd in (minfd..=maxfd).rev() {
+        if keep_fds.iter_all().any(|&keep_fd| keep_fd == fd) {
+            obs.kept(fd);
+        } else {
+            obs.close(fd);
+        }
+    }
+}
+
+/// The maximum number of file descriptors that `close_fds_juggle()` will move around
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+))]
+const MAX_JUGGLE_FDS: usize = 64;
+
+/// The maximum number of other file descriptor numbers that `close_fds_juggle()` will close one
+/// at a time below the temporary copies (if there aren't enough free slots near `minfd`, the copies
+/// end up far above it, and closing everything below them would take one syscall per number)
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+))]
+const MAX_JUGGLE_GAP: libc::c_int = 64;
+
+/// Close all file descriptors starting at `minfd` (except the ones in `keep_fds`) by moving the
+/// kept file descriptors down to the lowest free slots, calling `closefrom()` above them, and then
+/// moving them back. See `CloseFdsBuilder::juggle_keep_fds()`.
+///
+/// If this fails (including if the copies would end up too far above `minfd`; see
+/// `MAX_JUGGLE_GAP`), nothing has been closed.
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+))]
+unsafe fn close_fds_juggle(
+    minfd: libc::c_int,
+    keep_fds: &super::KeepFds,
+    obs: Observer,
+) -> Result<(), ()> {
+    let mut keep = super::KeepFdsBuf::<MAX_JUGGLE_FDS>::new();
+    for &fd in keep_fds.iter_all() {
+        if fd >= minfd {
+            keep.push(fd).map_err(drop)?;
+        }
+    }
+
+    // dup2() fails if the new file descriptor is at or above the limit, so we wouldn't be able to
+    // move any such file descriptors back
+    let mut rlim = core::mem::MaybeUninit::<libc::rlimit>::uninit();
+    if libc::getrlimit(libc::RLIMIT_NOFILE, rlim.as_mut_ptr()) != 0 {
+        return Err(());
+    }
+    let fdlimit = rlim.assume_init().rlim_cur;
+    if let Some(&maxfd) = keep.last() {
+        if fdlimit != libc::RLIM_INFINITY && maxfd as libc::rlim_t >= fdlimit {
+            return Err(());
+        }
+    }
+
+    // (original, temporary copy, original flags) for each open file descriptor that's being kept
+    let mut moved = [(0, 0, 0); MAX_JUGGLE_FDS];
+    let mut nmoved = 0;
+
+    // Find out which ones are open *before* making any copies (since a copy may end up with the
+    // number of a file descriptor in the list that isn't open)
+    for &fd in keep.iter() {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        obs.counter.fcntl(1);
+        if flags >= 0 {
+            moved[nmoved] = (fd, -1, flags);
+            nmoved += 1;
+        }
+    }
+
+    let moved = &mut moved[..nmoved];
+
+    for i in 0..moved.len() {
+        // This picks the lowest free file descriptor, so the copies will be in ascending order
+        let tmpfd = libc::fcntl(moved[i].0, libc::F_DUPFD, minfd);
+        obs.counter.fcntl(1);
+        // The numbers below this copy (apart from the other copies) will have to be closed one at
+        // a time, so give up if there are too many of them
+        if tmpfd < 0 || tmpfd - minfd - i as libc::c_int > MAX_JUGGLE_GAP {
+            if tmpfd >= 0 {
+                libc::close(tmpfd);
+                obs.counter.close();
+            }
+            for &(_, tmpfd, _) in moved[..i].iter() {
+                libc::close(tmpfd);
+                obs.counter.close();
+            }
+            return Err(());
+        }
+
+        moved[i].1 = tmpfd;
+    }
+
+    // Close everything above the copies in one go...
+    let closefrom_fd = match moved.last() {
+        Some(&(_, tmpfd, _)) => tmpfd + 1,
+        None => minfd,
+    };
+    let _ = crate::sys::closefrom(closefrom_fd);
+    obs.counter.closefrom();
+    obs.closed_range(closefrom_fd, libc::c_int::MAX);
+
+    // ...and everything below them (except the copies themselves)
+    let mut tmpfds = moved.iter().map(|&(_, tmpfd, _)| tmpfd).peekable();
+    for fd in minfd..closefrom_fd {
+        if tmpfds.peek() == Some(&fd) {
+            tmpfds.next();
+        } else {
+            obs.close(fd);
+        }
+    }
+
+    // Now move them back
+    for &(fd, tmpfd, flags) in moved.iter() {
+        if libc::dup2(tmpfd, fd) >= 0 && flags != 0 {
+            // dup2() clears FD_CLOEXEC
+            libc::fcntl(fd, libc::F_SETFD, flags);
+            obs.counter.fcntl(1);
+        }
+        libc::close(tmpfd);
+        obs.counter.close();
+        obs.kept(fd);
+    }
+
+    Ok(())
+}
+
+/// Close all file descriptors above `maxfd` (except the ones in `keep_fds`).
+///
+/// If `closefrom()` or `close_range()` can't be used, this falls back on looping up to the size of
+/// the file descriptor table (unclamped). File descriptors above a limit that was lowered after
+/// they were opened can only be closed with `closefrom()` or `close_range()`.
+unsafe fn close_above(maxfd: libc::c_int, keep_fds: &super::KeepFds, obs: Observer) {
+    let minfd = match maxfd.checked_add(1) {
+        Some(minfd) => core::cmp::max(minfd, 0),
+        None => return,
+    };
+
+    if close_above_fast(minfd, keep_fds, obs).is_ok() {
+        return;
+    }
+
+    let end = crate::fd_table_size();
+    if end == libc::c_int::MAX {
+        // The limit is unknown (or unlimited), so we'd have to check every possible number
+        return;
+    }
+
+    let mut keep_fds = keep_fds.clone();
+    for fd in minfd..end {
+        if keep_fds.should_keep(fd) {
+            obs.kept(fd);
+        } else {
+            obs.close(fd);
+        }
+    }
+}
+
+/// Try to close all file descriptors starting at `minfd` (except the ones in `keep_fds`) with
+/// `closefrom()` or `close_range()`.
+#[allow(unused_variables)]
+unsafe fn close_above_fast(
+    minfd: libc::c_int,
+    keep_fds: &super::KeepFds,
+    obs: Observer,
+) -> Result<(), ()> {
+    if keep_fds.max < minfd {
+        // Nothing at or above minfd needs to be kept
+        #[cfg(any(
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "openbsd",
+            target_os = "dragonfly",
+        ))]
+        {
+            let _ = crate::sys::closefrom(minfd);
+            obs.counter.closefrom();
+            obs.closed_range(minfd, libc::c_int::MAX);
+            return Ok(());
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if may_have_close_range() {
+            try_close_range(minfd as libc::c_uint, libc::c_uint::MAX, obs)?;
+            obs.closed_range(minfd, libc::c_int::MAX);
+            return Ok(());
+        }
+    } else {
+        // Some of the file descriptors that we need to keep are at or above minfd, so we can only
+        // close the gaps around them (which requires close_range())
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+        return close_gaps_from(minfd, keep_fds, obs);
+    }
+
+    #[allow(unreachable_code)]
+    Err(())
+}
+
+/// Use `close_range()` to close all file descriptors starting at `minfd`, except the ones in
+/// `keep_fds` (which does not have to be sorted).
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+unsafe fn close_gaps_from(
+    mut minfd: libc::c_int,
+    keep_fds: &super::KeepFds,
+    obs: Observer,
+) -> Result<(), ()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if !may_have_close_range() {
+        return Err(());
+    }
+
+    #[cfg(target_os = "freebsd")]
+    check_has_close_range()?;
+
+    loop {
+        // The keep list may not be sorted, so look for the next file descriptor to keep each time
+        // (there shouldn't be many in the range we're dealing with)
+        let next_keep = keep_fds.iter_all().copied().filter(|&fd| fd >= minfd).min();
+
+        match next_keep {
+            Some(fd) => {
+                if fd > minfd {
+                    try_close_range(minfd as libc::c_uint, (fd - 1) as libc::c_uint, obs)?;
+                    obs.closed_range(minfd, fd - 1);
+                }
+
+                minfd = match fd.checked_add(1) {
+                    Some(minfd) => minfd,
+                    None => return Ok(()),
+                };
+            }
+
+            None => {
+                try_close_range(minfd as libc::c_uint, libc::c_uint::MAX, obs)?;
+                obs.closed_range(minfd, libc::c_int::MAX);
+                return Ok(());
+            }
         }
     }
 }
 
-unsafe fn close_rest(fd: libc::c_int, fditer: crate::FdIter) {
+unsafe fn close_rest(fd: libc::c_int, fditer: crate::FdIter, obs: Observer) {
     cfg_if::cfg_if! {
         if #[cfg(any(
             target_os = "freebsd",
@@ -61,42 +382,73 @@ unsafe fn close_rest(fd: libc::c_int, fditer: crate::FdIter) {
             // On the BSDs we can use closefrom() to close the rest
             // Close the directory file descriptor (if one is being used) first
             drop(fditer);
-            crate::sys::closefrom(fd);
+            let _ = crate::sys::closefrom(fd);
+            obs.counter.closefrom();
+            obs.closed_range(fd, libc::c_int::MAX);
         } else {
             // On Linux we can do the same thing with close_range() if it's available
-            #[cfg(target_os = "linux")]
-            if MAY_HAVE_CLOSE_RANGE.load(Ordering::Relaxed)
-                && try_close_range(fd as libc::c_uint, libc::c_uint::MAX).is_ok()
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            if may_have_close_range()
+                && try_close_range(fd as libc::c_uint, libc::c_uint::MAX, obs).is_ok()
             {
                 // We can't close the directory file descriptor *first*, because close_range()
                 // might not be available. So there's a slight race condition here where the call
                 // to close() might accidentally close another file descriptor.
                 // Then again, this is documented as being unsafe if other threads are interacting
                 // with file descriptors.
+                #[allow(clippy::drop_non_drop)]
                 drop(fditer);
+                obs.closed_range(fd, libc::c_int::MAX);
                 return;
             }
 
             // No closefrom() or close_range(); fall back on looping through and closing manually
-            libc::close(fd);
-            for fd in fditer {
-                libc::close(fd);
+            let maxfd = fditer.known_max_fd();
+            obs.close(fd);
+            fditer.for_each(|fd| obs.close(fd));
+
+            // And then keep going past the end of the iterator's range (see close_above())
+            if let Some(maxfd) = maxfd {
+                close_above(maxfd, &super::KeepFds::new(&[]), obs);
             }
         }
     }
 }
 
-#[cfg(target_os = "linux")]
-static MAY_HAVE_CLOSE_RANGE: AtomicBool = AtomicBool::new(true);
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(super) static MAY_HAVE_CLOSE_RANGE: AtomicBool = AtomicBool::new(true);
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[inline]
+pub(super) fn may_have_close_range() -> bool {
+    if cfg!(any(
+        feature = "deterministic-syscalls",
+        feature = "libc-wrappers-only"
+    )) {
+        return false;
+    }
+
+    #[cfg(target_os = "android")]
+    if !crate::util::android_close_range_allowed() {
+        return false;
+    }
 
-#[cfg(target_os = "linux")]
-unsafe fn try_close_range(minfd: libc::c_uint, maxfd: libc::c_uint) -> Result<(), ()> {
+    MAY_HAVE_CLOSE_RANGE.load(Ordering::Relaxed)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn try_close_range(
+    minfd: libc::c_uint,
+    maxfd: libc::c_uint,
+    obs: Observer,
+) -> Result<(), ()> {
     // Sanity check
     // This shouldn't happen -- code that calls this function is usually careful to validate the
     // arguments -- but we want to make sure it doesn't happen because it could cause close_range()
     // to fail and make the code incorrectly assume that it isn't available.
     debug_assert!(minfd <= maxfd, "{} > {}", minfd, maxfd);
 
+    obs.counter.close_range();
     #[allow(clippy::unnecessary_cast)]
     if libc::syscall(
         libc::SYS_close_range,
@@ -113,12 +465,19 @@ unsafe fn try_close_range(minfd: libc::c_uint, maxfd: libc::c_uint) -> Result<()
 }
 
 #[cfg(target_os = "freebsd")]
-fn check_has_close_range() -> Result<(), ()> {
+pub(super) fn check_has_close_range() -> Result<(), ()> {
     // On FreeBSD, trying to make a syscall that the kernel doesn't recognize will result in the
     // process being killed with SIGSYS. So before we try making a syscall(), we have to check if
     // the kernel is new enough. (We also have to cache the presence/absence differently because of
     // this).
 
+    if cfg!(any(
+        feature = "deterministic-syscalls",
+        feature = "libc-wrappers-only"
+    )) {
+        return Err(());
+    }
+
     // 1=present, 0=absent, other values=uninitialized
     static HAS_CLOSE_RANGE: AtomicU8 = AtomicU8::new(2);
 
@@ -160,12 +519,17 @@ fn check_has_close_range() -> Result<(), ()> {
 }
 
 #[cfg(target_os = "freebsd")]
-unsafe fn try_close_range(minfd: libc::c_uint, maxfd: libc::c_uint) -> Result<(), ()> {
+unsafe fn try_close_range(
+    minfd: libc::c_uint,
+    maxfd: libc::c_uint,
+    obs: Observer,
+) -> Result<(), ()> {
     debug_assert!(minfd <= maxfd, "{} > {}", minfd, maxfd);
 
     // This should have been checked previously
     debug_assert!(check_has_close_range().is_ok());
 
+    obs.counter.close_range();
     if libc::syscall(
         crate::sys::SYS_CLOSE_RANGE,
         minfd as libc::c_uint,
@@ -183,70 +547,123 @@ unsafe fn try_close_range(minfd: libc::c_uint, maxfd: libc::c_uint) -> Result<()
 #[inline]
 unsafe fn close_fds_shortcut(
     minfd: libc::c_int,
-    keep_fds: &[libc::c_int],
-    max_keep_fd: libc::c_int,
-    fds_sorted: bool,
+    keep_fds: &super::KeepFds,
+    gaps: Option<&super::gaps::KeepGaps>,
+    obs: Observer,
 ) -> Result<(), ()> {
-    #[cfg(any(
-        target_os = "freebsd",
-        target_os = "netbsd",
-        target_os = "openbsd",
-        target_os = "dragonfly"
-    ))]
-    if max_keep_fd < minfd {
-        // On the BSDs, if all the file descriptors in keep_fds are less than
-        // minfd (or if keep_fds is empty), we can just call closefrom()
+    let max_keep_fd = keep_fds.max;
 
-        crate::sys::closefrom(minfd);
+    if max_keep_fd < minfd {
+        // If all the file descriptors in keep_fds are less than minfd (or if keep_fds is empty),
+        // we can just close everything starting at minfd
+        close_all_from(minfd, obs)?;
+        obs.closed_range(minfd, libc::c_int::MAX);
         return Ok(());
     }
 
-    #[cfg(target_os = "linux")]
-    if !MAY_HAVE_CLOSE_RANGE.load(Ordering::Relaxed) {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if !may_have_close_range() {
         // If we know that close_range() definitely isn't available, there's nothing we can do.
         return Err(());
-    } else if max_keep_fd < minfd {
-        // Same case as closefrom() on the BSDs
-        return try_close_range(minfd as libc::c_uint, libc::c_uint::MAX);
     }
 
-    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
-    if fds_sorted {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    if keep_fds.sorted {
         // If the list of file descriptors is sorted, we can use close_range() to close the "gaps"
         // between file descriptors.
 
-        debug_assert!(!keep_fds.is_empty());
+        debug_assert!(max_keep_fd >= minfd);
 
         #[cfg(target_os = "freebsd")]
         check_has_close_range()?;
 
-        return crate::util::apply_range(minfd, keep_fds, |low, high| {
-            try_close_range(low as libc::c_uint, high as libc::c_uint)
-        });
+        let close_gap = |low: libc::c_int, high: libc::c_int| {
+            try_close_range(low as libc::c_uint, high as libc::c_uint, obs)?;
+            obs.closed_range(low, high);
+            Ok(())
+        };
+
+        return match gaps {
+            Some(gaps) => gaps.apply(close_gap),
+            None => crate::util::apply_range(minfd, keep_fds.iter_sorted(), close_gap),
+        };
     }
 
     // We can't do any optimizations without calling iter_possible_fds()
     Err(())
 }
 
+/// Close all file descriptors starting at `minfd` with a single call to `closefrom()` or
+/// `close_range()`, if either is available.
+#[allow(unused_variables)]
+#[inline]
+unsafe fn close_all_from(minfd: libc::c_int, obs: Observer) -> Result<(), ()> {
+    cfg_if::cfg_if! {
+        if #[cfg(any(
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "openbsd",
+            target_os = "dragonfly"
+        ))] {
+            let _ = crate::sys::closefrom(minfd);
+            obs.counter.closefrom();
+            Ok(())
+        } else if #[cfg(any(target_os = "linux", target_os = "android"))] {
+            if may_have_close_range() {
+                try_close_range(minfd as libc::c_uint, libc::c_uint::MAX, obs)
+            } else {
+                Err(())
+            }
+        } else {
+            Err(())
+        }
+    }
+}
+
 #[inline]
 pub(crate) fn probe() {
-    #[cfg(target_os = "linux")]
-    unsafe {
-        // This call *should* fail with EINVAL (because first > last). If it succeeds (!), or
-        // if it fails with a different error, something's wrong.
-        if libc::syscall(
-            libc::SYS_close_range,
-            libc::c_uint::MAX,
-            libc::c_uint::MAX - 1,
-            0,
-        ) == 0
-            || *libc::__errno_location() != libc::EINVAL
-        {
-            MAY_HAVE_CLOSE_RANGE.store(false, Ordering::Relaxed);
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        if may_have_close_range() {
+            // This call *should* fail with EINVAL (because first > last). If it succeeds (!), or
+            // if it fails with a different error, something's wrong.
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_close_range,
+                    libc::c_uint::MAX,
+                    libc::c_uint::MAX - 1,
+                    0,
+                )
+            };
+            let eno = crate::util::errno();
+
+            if ret == 0 || eno != libc::EINVAL {
+                MAY_HAVE_CLOSE_RANGE.store(false, Ordering::Relaxed);
+
+                if ret != 0 && eno != libc::ENOSYS {
+                    // Probably a seccomp filter
+                    log_warn!("close_range() failed unexpectedly (error code {})", eno);
+                }
+            }
         }
+
+        log_debug!(
+            "close_range() is {}",
+            if may_have_close_range() {
+                "available"
+            } else {
+                "not available"
+            }
+        );
     }
 
     #[cfg(target_os = "freebsd")]
-    let _ = check_has_close_range();
+    log_debug!(
+        "close_range() is {}",
+        if check_has_close_range().is_ok() {
+            "available"
+        } else {
+            "not available"
+        }
+    );
 }
@@ -0,0 +1,121 @@
+use core::convert::TryFrom;
+use core::fmt;
+
+/// Move the file descriptors in `fds` to the lowest numbers starting at `minfd` (so `fds[0]`
+/// becomes `minfd`, `fds[1]` becomes `minfd + 1`, and so on), then close every other file
+/// descriptor starting at `minfd`.
+///
+/// This is useful for implementing "pass these file descriptors to the child" semantics, where the
+/// executed program should see a predictable, dense layout (for example, `fds[0]` as file
+/// descriptor 3). The close-on-exec flag is cleared on the new file descriptors. File descriptors
+/// below `minfd` are left alone, and they may also appear in `fds` (for example, `0` can be passed
+/// to make a copy of stdin at `minfd`).
+///
+/// On success, an iterator over the `(old, new)` file descriptor pairs is returned, in the same
+/// order as `fds`. (Since the new file descriptor numbers are determined by the order of `fds`,
+/// this doesn't require allocating memory.)
+///
+/// An error is returned if any of the entries in `fds` are negative, duplicated, or not open
+/// (before anything is changed), or if duplicating a file descriptor fails (e.g. because the new
+/// file descriptor number would be above the file descriptor limit). In the latter case, some of
+/// the file descriptors may have already been moved, but nothing has been closed.
+///
+/// Like the other functions in this crate, this does not allocate memory and is
+/// async-signal-safe, so it can be used in the child after a `fork()`.
+///
+/// # Safety
+///
+/// This closes file descriptors, so all of the warnings on
+/// [`CloseFdsBuilder::closefrom()`](./struct.CloseFdsBuilder.html#method.closefrom) apply. In
+/// addition, file descriptors in the range `minfd..(minfd + fds.len())` are replaced even if they
+/// aren't in `fds`.
+pub unsafe fn compact_fds(
+    minfd: libc::c_int,
+    fds: &[libc::c_int],
+) -> Result<impl Iterator<Item = (libc::c_int, libc::c_int)> + Clone + '_, CompactFdsError> {
+    let minfd = core::cmp::max(minfd, 0);
+
+    let count = match libc::c_int::try_from(fds.len())
+        .ok()
+        .and_then(|n| minfd.checked_add(n))
+    {
+        Some(end) => end - minfd,
+        None => return Err(CompactFdsError::Os(libc::EMFILE)),
+    };
+
+    // The highest file descriptor number that we need to worry about clobbering
+    let mut maxfd = minfd + count - 1;
+
+    for (i, &fd) in fds.iter().enumerate() {
+        if fd < 0 || !crate::util::is_fd_valid(fd) {
+            return Err(CompactFdsError::BadFd(fd));
+        } else if fds[..i].contains(&fd) {
+            return Err(CompactFdsError::Duplicate(fd));
+        }
+
+        maxfd = maxfd.max(fd);
+    }
+
+    // First, duplicate each file descriptor to a temporary file descriptor above all of the ones
+    // we care about, so moving one into place can't clobber another one that hasn't been moved
+    // yet. (Anything that was open at one of the temporary file descriptors would have been
+    // closed anyway.)
+    let tmpbase = match maxfd.checked_add(1) {
+        Some(tmpbase) if tmpbase.checked_add(count).is_some() => tmpbase,
+        _ => return Err(CompactFdsError::Os(libc::EMFILE)),
+    };
+
+    for (i, &fd) in fds.iter().enumerate() {
+        if libc::dup2(fd, tmpbase + i as libc::c_int) < 0 {
+            return Err(CompactFdsError::Os(crate::util::errno()));
+        }
+    }
+
+    // Now move them into place (dup2() clears the close-on-exec flag)
+    for i in 0..count {
+        if libc::dup2(tmpbase + i, minfd + i) < 0 {
+            return Err(CompactFdsError::Os(crate::util::errno()));
+        }
+    }
+
+    // This closes the temporary file descriptors too
+    super::close::close_fds(
+        minfd + count,
+        super::KeepFds::empty(),
+        crate::FdIterBuilder::new(),
+        false,
+        false,
+        super::observe::Observer::default(),
+        super::backend::Backend::default(),
+    );
+
+    Ok(fds
+        .iter()
+        .enumerate()
+        .map(move |(i, &fd)| (fd, minfd + i as libc::c_int)))
+}
+
+/// The error returned by [`compact_fds()`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum CompactFdsError {
+    /// The given file descriptor is negative or not open.
+    BadFd(libc::c_int),
+    /// The given file descriptor was listed more than once.
+    Duplicate(libc::c_int),
+    /// Moving the file descriptors failed with the given error code.
+    Os(libc::c_int),
+}
+
+impl fmt::Display for CompactFdsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::BadFd(fd) => write!(f, "File descriptor {} is not open", fd),
+            Self::Duplicate(fd) => write!(f, "File descriptor {} was listed more than once", fd),
+            Self::Os(eno) => write!(f, "Moving file descriptors failed with error code {}", eno),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CompactFdsError {}
@@ -0,0 +1,163 @@
+use std::io;
+use std::os::unix::prelude::*;
+use std::vec::Vec;
+
+fn open_dev_null() -> io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::open(
+            "/dev/null\0".as_ptr() as *const libc::c_char,
+            libc::O_RDONLY | libc::O_CLOEXEC,
+        )
+    };
+
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
+/// Open `n` new file descriptors (each one open to `/dev/null`, with the close-on-exec flag set).
+///
+/// This is intended for tests that need a table with some extra file descriptors in it. They're
+/// closed when the returned `OwnedFd`s are dropped.
+///
+/// This is only available with the `test-util` and `std` features.
+pub fn open_dummy_fds(n: usize) -> io::Result<Vec<OwnedFd>> {
+    (0..n).map(|_| open_dev_null()).collect()
+}
+
+/// Open a new file descriptor (to `/dev/null`, with the close-on-exec flag set) at exactly the
+/// number `fd`.
+///
+/// This is intended for tests that need a file descriptor at a predictable number (for example,
+/// to check that it's closed or kept by a [`CloseFdsBuilder`](crate::CloseFdsBuilder)). Pick a
+/// high number that other parts of the test program are unlikely to use.
+///
+/// An error with the kind `AlreadyExists` is returned if `fd` is already open (it is *not*
+/// replaced). Note that this check is racy if other threads may be opening files.
+///
+/// This is only available with the `test-util` and `std` features.
+pub fn open_fd_at(fd: libc::c_int) -> io::Result<OwnedFd> {
+    if fd < 0 {
+        return Err(io::Error::from_raw_os_error(libc::EBADF));
+    } else if crate::util::is_fd_valid(fd) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "file descriptor is already open",
+        ));
+    }
+
+    let tmp = open_dev_null()?;
+
+    if tmp.as_raw_fd() == fd {
+        return Ok(tmp);
+    } else if unsafe { libc::dup2(tmp.as_raw_fd(), fd) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // dup2() clears the close-on-exec flag
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(owned)
+}
+
+/// Run `check` in a child process created with `fork()`, wait for it to exit, and panic if it
+/// exits with a non-zero status (or is killed by a signal).
+///
+/// The child exits with the value returned by `check` (or 101 if `check` panics). This lets tests
+/// close file descriptors (or otherwise mess with the file descriptor table) without affecting the
+/// test runner or other tests.
+///
+/// This is only available with the `test-util` and `std` features.
+///
+/// # Safety
+///
+/// The test runner is multithreaded, so `check` runs in a child process that was forked from a
+/// multithreaded process. It must only do things that are safe in that situation; strictly
+/// speaking, that means only calling async-signal-safe functions (see the crate-level
+/// documentation on [async-signal-safety](./index.html#async-signal-safety)). In particular, it
+/// should avoid allocating memory, and it shouldn't rely on `panic!()` messages being printed.
+pub unsafe fn run_in_child<F: FnOnce() -> libc::c_int>(check: F) {
+    match libc::fork() {
+        0 => {
+            let code = std::panic::catch_unwind(std::panic::AssertUnwindSafe(check)).unwrap_or(101);
+            libc::_exit(code)
+        }
+
+        ret if ret < 0 => panic!("Error fork()ing: {}", io::Error::last_os_error()),
+
+        pid => {
+            let mut stat = 0;
+
+            while libc::waitpid(pid, &mut stat, 0) < 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() != Some(libc::EINTR) {
+                    panic!("Error wait()ing for child: {}", err);
+                }
+            }
+
+            assert!(libc::WIFEXITED(stat), "Child process did not exit normally");
+            assert_eq!(
+                libc::WEXITSTATUS(stat),
+                0,
+                "Child process exited with non-zero value"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_dummy_fds() {
+        let fds = open_dummy_fds(3).unwrap();
+        assert_eq!(fds.len(), 3);
+        for fd in fds.iter() {
+            assert!(crate::util::is_fd_valid(fd.as_raw_fd()));
+        }
+    }
+
+    #[test]
+    fn test_open_fd_at() {
+        let fd = open_fd_at(977).unwrap();
+        assert_eq!(fd.as_raw_fd(), 977);
+        let flags = unsafe { libc::fcntl(977, libc::F_GETFD) };
+        assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+
+        assert_eq!(
+            open_fd_at(977).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        drop(fd);
+        assert!(!crate::util::is_fd_valid(977));
+
+        assert_eq!(
+            open_fd_at(-1).unwrap_err().raw_os_error(),
+            Some(libc::EBADF)
+        );
+    }
+
+    #[test]
+    fn test_run_in_child() {
+        unsafe {
+            run_in_child(|| {
+                crate::close_open_fds(3, &[]);
+                0
+            });
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "non-zero")]
+    fn test_run_in_child_failure() {
+        unsafe {
+            run_in_child(|| 2);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+/// The maximum number of "gaps" that `KeepGaps` can hold. A sorted keep list with `n` distinct
+/// file descriptors at or above `minfd` has at most `n + 1` gaps.
+const MAX_KEEP_GAPS: usize = 32;
+
+/// The ranges of file descriptors that are *not* being kept (the "gaps" between the file
+/// descriptors in a sorted keep list), precomputed into a fixed-size array.
+///
+/// Walking this is cheaper than calling `KeepFds::should_keep()` for every file descriptor, which
+/// has to rescan (and merge) all of the keep lists each time. It's only built if the lists are
+/// sorted and small enough to fit.
+#[derive(Clone, Debug)]
+pub(crate) struct KeepGaps {
+    // (low, high) pairs (inclusive), in ascending order
+    gaps: [(libc::c_int, libc::c_int); MAX_KEEP_GAPS],
+    len: usize,
+    // The index of the first gap that may contain file descriptors passed to should_keep()
+    cur: usize,
+}
+
+impl KeepGaps {
+    /// Compute the gaps starting at `minfd`. Returns `None` if the lists aren't sorted or there
+    /// are too many gaps to fit.
+    pub fn new(minfd: libc::c_int, keep_fds: &super::KeepFds) -> Option<Self> {
+        if !keep_fds.sorted {
+            return None;
+        }
+
+        let mut gaps = Self {
+            gaps: [(0, 0); MAX_KEEP_GAPS],
+            len: 0,
+            cur: 0,
+        };
+
+        crate::util::apply_range(minfd, keep_fds.iter_sorted(), |low, high| {
+            let gap = gaps.gaps.get_mut(gaps.len).ok_or(())?;
+            *gap = (low, high);
+            gaps.len += 1;
+            Ok(())
+        })
+        .ok()?;
+
+        Some(gaps)
+    }
+
+    /// Call `func` with the bounds of each gap, in ascending order, stopping at the first error.
+    #[inline]
+    pub fn apply<F: FnMut(libc::c_int, libc::c_int) -> Result<(), ()>>(
+        &self,
+        mut func: F,
+    ) -> Result<(), ()> {
+        self.gaps[..self.len]
+            .iter()
+            .try_for_each(|&(low, high)| func(low, high))
+    }
+
+    /// Check whether the given file descriptor should be kept (i.e. it isn't in any of the gaps).
+    ///
+    /// This must be called with file descriptors in ascending order.
+    #[inline]
+    pub fn should_keep(&mut self, fd: libc::c_int) -> bool {
+        while let Some(&(low, high)) = self.gaps[..self.len].get(self.cur) {
+            if fd > high {
+                self.cur += 1;
+            } else {
+                return fd < low;
+            }
+        }
+
+        // Past the last gap (which only happens if the last kept file descriptor is
+        // c_int::MAX)
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::KeepFds;
+    use super::*;
+
+    #[test]
+    fn test_keep_gaps() {
+        let mut keep_fds = KeepFds::new(&[3, 4, 7]);
+        keep_fds.push_extra(&[7, 9]).unwrap();
+
+        let mut gaps = KeepGaps::new(3, &keep_fds).unwrap();
+        assert_eq!(
+            gaps.gaps[..gaps.len],
+            [(5, 6), (8, 8), (10, libc::c_int::MAX)]
+        );
+        for fd in 3..20 {
+            assert_eq!(gaps.should_keep(fd), [3, 4, 7, 9].contains(&fd));
+        }
+
+        let mut gaps = KeepGaps::new(0, &KeepFds::new(&[libc::c_int::MAX])).unwrap();
+        assert_eq!(gaps.gaps[..gaps.len], [(0, libc::c_int::MAX - 1)]);
+        assert!(!gaps.should_keep(0));
+        assert!(gaps.should_keep(libc::c_int::MAX));
+
+        let gaps = KeepGaps::new(5, &KeepFds::empty()).unwrap();
+        assert_eq!(gaps.gaps[..gaps.len], [(5, libc::c_int::MAX)]);
+
+        // Not sorted
+        assert!(KeepGaps::new(0, &KeepFds::new(&[4, 3])).is_none());
+
+        // Too many
+        let mut fds = [0; MAX_KEEP_GAPS];
+        for (i, fd) in fds.iter_mut().enumerate() {
+            *fd = i as libc::c_int * 2 + 1;
+        }
+        assert!(KeepGaps::new(0, &KeepFds::new(&fds)).is_none());
+        assert!(KeepGaps::new(0, &KeepFds::new(&fds[1..])).is_some());
+    }
+}
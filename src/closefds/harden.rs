@@ -0,0 +1,129 @@
+use std::io;
+use std::vec::Vec;
+
+use super::CloseFdsBuilder;
+use crate::{FdAuditPolicy, FdAuditViolation};
+
+/// Sanitize the file descriptor table at the start of a setuid/setgid program (or any other
+/// program that may be started by an untrusted parent).
+///
+/// This performs the following steps, in this order:
+///
+/// 1. The open file descriptors are audited (see [`FdAuditPolicy`]), recording every file
+///    descriptor other than 0, 1, 2, and the ones in `keep_fds`. This happens first so the report
+///    reflects what was inherited, and so the memory allocation and file access that it requires
+///    happen before anything is changed.
+/// 2. All file descriptors starting at 3 (except the ones in `keep_fds`) are closed, as with
+///    [`CloseFdsBuilder::closefrom()`].
+/// 3. If any of the standard file descriptors (0, 1, and 2) are closed, they are opened to
+///    `/dev/null`. This prevents files that the program opens later from being mistaken for
+///    stdin/stdout/stderr. It happens after step 2 so that opening `/dev/null` can't fail because
+///    the file descriptor table is full.
+/// 4. If `clear_cloexec` is `true`, the close-on-exec flag is cleared on the file descriptors in
+///    `keep_fds` (so they will be inherited if the program executes another program).
+///
+/// File descriptors in `keep_fds` that aren't open are ignored.
+///
+/// If opening `/dev/null` or clearing the close-on-exec flag fails, an error is returned; any
+/// earlier steps will have already been performed.
+///
+/// This doesn't re-execute the program; see [`sanitize_and_reexec()`](./fn.sanitize_and_reexec.html)
+/// for that.
+///
+/// # Safety
+///
+/// This closes file descriptors, so all of the warnings on [`CloseFdsBuilder::closefrom()`]
+/// apply. It should be called before any other threads are started and before any files are
+/// opened.
+pub unsafe fn harden_setuid_startup(
+    keep_fds: &[libc::c_int],
+    clear_cloexec: bool,
+) -> io::Result<StartupHardeningReport> {
+    let closed_fds = FdAuditPolicy::new()
+        .allow_fds(&[0, 1, 2])
+        .allow_fds(keep_fds)
+        .audit();
+
+    let mut kept_fds: Vec<libc::c_int> = keep_fds
+        .iter()
+        .copied()
+        .filter(|&fd| fd >= 0 && crate::util::is_fd_valid(fd))
+        .collect();
+    kept_fds.sort_unstable();
+    kept_fds.dedup();
+
+    CloseFdsBuilder::new().keep_fds(keep_fds).closefrom(3);
+
+    let reopened_std_fds = open_std_fds()?;
+
+    if clear_cloexec {
+        for &fd in kept_fds.iter() {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags < 0
+                || (flags & libc::FD_CLOEXEC != 0
+                    && libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) < 0)
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+
+    Ok(StartupHardeningReport {
+        reopened_std_fds,
+        closed_fds,
+        kept_fds,
+    })
+}
+
+/// A summary of the changes made by [`harden_setuid_startup()`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct StartupHardeningReport {
+    /// The standard file descriptors (0, 1, and/or 2) that were closed and have been opened to
+    /// `/dev/null`.
+    pub reopened_std_fds: Vec<libc::c_int>,
+    /// The file descriptors that were closed, as reported by [`FdAuditPolicy::audit()`] before
+    /// closing them.
+    pub closed_fds: Vec<FdAuditViolation>,
+    /// The file descriptors in `keep_fds` that were open (sorted and deduplicated).
+    pub kept_fds: Vec<libc::c_int>,
+}
+
+/// Make sure that file descriptors 0, 1, and 2 are open (opening any closed ones to `/dev/null`).
+///
+/// Returns the file descriptors that were opened.
+pub(super) fn open_std_fds() -> io::Result<Vec<libc::c_int>> {
+    let mut opened = Vec::new();
+
+    for fd in 0..=2 {
+        if crate::util::is_fd_valid(fd) {
+            continue;
+        }
+
+        let nullfd = unsafe {
+            libc::open(
+                b"/dev/null\0".as_ptr() as *const libc::c_char,
+                libc::O_RDWR | libc::O_NOCTTY,
+            )
+        };
+        if nullfd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // open() returns the lowest available file descriptor, which should be `fd` (since all of
+        // the file descriptors below it are open). Check just in case.
+        if nullfd != fd {
+            let res = unsafe { libc::dup2(nullfd, fd) };
+            unsafe {
+                libc::close(nullfd);
+            }
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        opened.push(fd);
+    }
+
+    Ok(opened)
+}
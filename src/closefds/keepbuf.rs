@@ -0,0 +1,584 @@
+use core::ffi::CStr;
+use core::fmt;
+
+/// A fixed-capacity list of file descriptors that keeps itself sorted and deduplicated.
+///
+/// This is intended for building lists of file descriptors to keep open incrementally (for
+/// example, before a `fork()`), without allocating memory and without having to sort the list
+/// afterward. It can be passed directly to [`CloseFdsBuilder::keep_fds_buf()`], or used as a
+/// slice (it implements `Deref<Target = [libc::c_int]>`).
+///
+/// # Example
+///
+/// ```
+/// # use close_fds::{CloseFdsBuilder, KeepFdsBuf};
+/// let mut keep_fds = KeepFdsBuf::<4>::new();
+/// keep_fds.push(5).unwrap();
+/// keep_fds.push(3).unwrap();
+/// keep_fds.push(5).unwrap();
+/// assert_eq!(&keep_fds[..], [3, 5]);
+///
+/// let mut builder = CloseFdsBuilder::new();
+/// builder.keep_fds_buf(&keep_fds);
+/// ```
+///
+/// With the `serde` feature enabled, a `KeepFdsBuf` can be serialized and deserialized as a
+/// sequence of file descriptors (for example, to load a list of file descriptors to keep from a
+/// configuration file). Deserialization fails if there are more than `N` distinct file
+/// descriptors.
+///
+/// [`CloseFdsBuilder::keep_fds_buf()`]: ./struct.CloseFdsBuilder.html#method.keep_fds_buf
+#[derive(Copy, Clone)]
+pub struct KeepFdsBuf<const N: usize> {
+    fds: [libc::c_int; N],
+    len: usize,
+}
+
+impl<const N: usize> KeepFdsBuf<N> {
+    /// Create a new, empty buffer.
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            fds: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Add a file descriptor to the buffer.
+    ///
+    /// If the file descriptor is already present, nothing is done. An error is returned if the
+    /// file descriptor is not present and the buffer is full.
+    pub fn push(&mut self, fd: libc::c_int) -> Result<(), KeepFdsBufFull> {
+        let i = match self.fds[..self.len].binary_search(&fd) {
+            Ok(_) => return Ok(()),
+            Err(i) => i,
+        };
+
+        if self.len == N {
+            return Err(KeepFdsBufFull(fd));
+        }
+
+        // Shift everything after the insertion point up by one
+        self.fds.copy_within(i..self.len, i + 1);
+        self.fds[i] = fd;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove a file descriptor from the buffer, returning whether it was present.
+    pub fn remove(&mut self, fd: libc::c_int) -> bool {
+        match self.fds[..self.len].binary_search(&fd) {
+            Ok(i) => {
+                self.fds.copy_within(i + 1..self.len, i);
+                self.len -= 1;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Remove all file descriptors from the buffer.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Get the maximum number of file descriptors that this buffer can hold.
+    #[inline]
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Get the file descriptors in the buffer, in ascending order.
+    #[inline]
+    pub fn as_slice(&self) -> &[libc::c_int] {
+        &self.fds[..self.len]
+    }
+
+    /// Parse a list of file descriptors and add them to the buffer.
+    ///
+    /// The list consists of comma-separated entries, each of which is either a single file
+    /// descriptor (e.g. `3`) or an inclusive range (e.g. `7-9`). Spaces around entries are
+    /// ignored, and an empty (or all-whitespace) list is valid. For example, `3,7-9` adds file
+    /// descriptors 3, 7, 8, and 9.
+    ///
+    /// This does not allocate memory, so it is async-signal-safe. If an error is returned, some
+    /// of the file descriptors (the ones before the problematic entry) may have been added.
+    pub fn extend_from_spec(&mut self, spec: &[u8]) -> Result<(), KeepFdsSpecError> {
+        if spec.iter().all(|&ch| ch == b' ') {
+            return Ok(());
+        }
+
+        for entry in spec.split(|&ch| ch == b',') {
+            let (low, high) = match entry.iter().position(|&ch| ch == b'-') {
+                Some(i) => (parse_fd(&entry[..i])?, parse_fd(&entry[i + 1..])?),
+                None => {
+                    let fd = parse_fd(entry)?;
+                    (fd, fd)
+                }
+            };
+
+            if low > high {
+                return Err(KeepFdsSpecError::Invalid);
+            }
+
+            for fd in low..=high {
+                self.push(fd).map_err(|e| KeepFdsSpecError::Full(e.0))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read a list of file descriptors from the environment variable `name` (in the format
+    /// accepted by [`Self::extend_from_spec()`]) and add them to the buffer.
+    ///
+    /// This allows wrapper scripts and test harnesses to specify extra file descriptors that a
+    /// program should keep open, without any changes to the program itself. [`KEEP_FDS_ENV`]
+    /// (`CLOSE_FDS_KEEP`) is the conventional name for the variable; for example:
+    ///
+    /// ```
+    /// # use close_fds::{CloseFdsBuilder, KeepFdsBuf, KEEP_FDS_ENV};
+    /// let mut keep_fds = KeepFdsBuf::<16>::new();
+    /// keep_fds.extend_from_env(KEEP_FDS_ENV).unwrap();
+    ///
+    /// let mut builder = CloseFdsBuilder::new();
+    /// builder.keep_fds_buf(&keep_fds);
+    /// ```
+    ///
+    /// Returns `Ok(false)` (and does nothing) if the variable is not set.
+    ///
+    /// This uses `getenv()`, so it does not allocate memory. However, it is not safe to call if
+    /// another thread might be modifying the environment at the same time.
+    pub fn extend_from_env(&mut self, name: &CStr) -> Result<bool, KeepFdsSpecError> {
+        let value = unsafe { libc::getenv(name.as_ptr()) };
+        if value.is_null() {
+            return Ok(false);
+        }
+
+        let value = unsafe { CStr::from_ptr(value) };
+        self.extend_from_spec(value.to_bytes())?;
+        Ok(true)
+    }
+
+    /// Write the file descriptors in the buffer to `buf` in the format accepted by
+    /// [`Self::extend_from_spec()`], returning the written portion of `buf`.
+    ///
+    /// Consecutive file descriptors are written as ranges, so the result is as short as possible
+    /// (for example, `[3, 7, 8, 9]` is written as `3,7-9`). `None` is returned if `buf` is too
+    /// small, or if the buffer contains negative file descriptors (which can't be represented).
+    ///
+    /// This does not allocate memory, so it is async-signal-safe.
+    pub fn format_spec<'b>(&self, buf: &'b mut [u8]) -> Option<&'b mut [u8]> {
+        // The longest possible entry is ",2147483647-2147483647"
+        let mut tmp = [0u8; 24];
+        let mut len = 0;
+
+        let mut fds = self.as_slice().iter().copied().peekable();
+        while let Some(low) = fds.next() {
+            if low < 0 {
+                return None;
+            }
+
+            let mut high = low;
+            while high < libc::c_int::MAX && fds.peek() == Some(&(high + 1)) {
+                high += 1;
+                fds.next();
+            }
+
+            let mut n = 0;
+            if len != 0 {
+                tmp[0] = b',';
+                n += 1;
+            }
+            n += crate::util::format_int(low, &mut tmp[n..]);
+            if high != low {
+                tmp[n] = b'-';
+                n += 1;
+                n += crate::util::format_int(high, &mut tmp[n..]);
+            }
+
+            buf.get_mut(len..len + n)?.copy_from_slice(&tmp[..n]);
+            len += n;
+        }
+
+        Some(&mut buf[..len])
+    }
+
+    /// Write an environment variable entry of the form `NAME=SPEC` (where `SPEC` is as described
+    /// for [`Self::format_spec()`]) to `buf`, followed by a NUL byte.
+    ///
+    /// This is intended for multi-stage launchers (for example, wrapper -> sandboxer -> target)
+    /// that need to carry the set of file descriptors to keep across `exec()`: the entry can be
+    /// placed in the `envp` array passed to `execve()` (possibly after a `fork()`, since this does
+    /// not allocate memory and is async-signal-safe), and the executed program can then call
+    /// [`close_fds_from_env()`] at startup to close everything else.
+    ///
+    /// `None` is returned if `buf` is too small, if `name` is empty or contains `=`, or if the
+    /// buffer contains negative file descriptors.
+    pub fn format_env<'b>(&self, name: &CStr, buf: &'b mut [u8]) -> Option<&'b CStr> {
+        let name = name.to_bytes();
+        if name.is_empty() || name.contains(&b'=') {
+            return None;
+        }
+
+        buf.get_mut(..name.len())?.copy_from_slice(name);
+        *buf.get_mut(name.len())? = b'=';
+        let start = name.len() + 1;
+
+        let end = start + self.format_spec(buf.get_mut(start..)?)?.len();
+        *buf.get_mut(end)? = 0;
+
+        // Safety: The name and the spec can't contain NUL bytes
+        Some(unsafe { CStr::from_bytes_with_nul_unchecked(&buf[..end + 1]) })
+    }
+}
+
+/// The conventional name of the environment variable read by [`KeepFdsBuf::extend_from_env()`].
+pub const KEEP_FDS_ENV: &CStr = unsafe { CStr::from_bytes_with_nul_unchecked(b"CLOSE_FDS_KEEP\0") };
+
+/// The maximum number of file descriptors that [`close_fds_from_env()`] can keep open.
+pub const MAX_ENV_KEEP_FDS: usize = 256;
+
+/// Close all file descriptors starting at `minfd`, except the ones listed in the environment
+/// variable `name` (in the format accepted by [`KeepFdsBuf::extend_from_spec()`]).
+///
+/// This is the counterpart to [`KeepFdsBuf::format_env()`]: a program that is executed by a
+/// launcher which passed the file descriptors it should receive in `name` can call this at
+/// startup to re-assert that policy, closing anything else that leaked through (for example,
+/// because an intermediate stage didn't set the close-on-exec flag on its own file descriptors).
+/// For example:
+///
+/// ```no_run
+/// # use close_fds::{close_fds_from_env, KEEP_FDS_ENV};
+/// unsafe {
+///     close_fds_from_env(KEEP_FDS_ENV, 3).unwrap();
+/// }
+/// ```
+///
+/// Returns `Ok(true)` if the variable was set (and the file descriptors were closed), or
+/// `Ok(false)` if it was not set, in which case nothing is closed. If the variable is malformed
+/// or lists more than [`MAX_ENV_KEEP_FDS`] file descriptors, an error is returned and nothing is
+/// closed.
+///
+/// Like [`KeepFdsBuf::extend_from_env()`], this does not allocate memory, but it is not safe to
+/// call if another thread might be modifying the environment at the same time.
+///
+/// # Safety
+///
+/// This closes file descriptors, so all of the warnings on
+/// [`CloseFdsBuilder::closefrom()`](./struct.CloseFdsBuilder.html#method.closefrom) apply.
+pub unsafe fn close_fds_from_env(
+    name: &CStr,
+    minfd: libc::c_int,
+) -> Result<bool, KeepFdsSpecError> {
+    let mut keep_fds = KeepFdsBuf::<MAX_ENV_KEEP_FDS>::new();
+    if !keep_fds.extend_from_env(name)? {
+        return Ok(false);
+    }
+
+    super::CloseFdsBuilder::new()
+        .keep_fds_buf(&keep_fds)
+        .closefrom(minfd);
+    Ok(true)
+}
+
+fn parse_fd(s: &[u8]) -> Result<libc::c_int, KeepFdsSpecError> {
+    let start = s.iter().position(|&ch| ch != b' ').unwrap_or(s.len());
+    let end = s
+        .iter()
+        .rposition(|&ch| ch != b' ')
+        .map_or(start, |i| i + 1);
+    let s = &s[start..end];
+
+    if s.is_empty() {
+        return Err(KeepFdsSpecError::Invalid);
+    }
+
+    s.iter().try_fold(0 as libc::c_int, |fd, &ch| {
+        if !ch.is_ascii_digit() {
+            return Err(KeepFdsSpecError::Invalid);
+        }
+
+        fd.checked_mul(10)
+            .and_then(|fd| fd.checked_add((ch - b'0') as libc::c_int))
+            .ok_or(KeepFdsSpecError::Invalid)
+    })
+}
+
+impl<const N: usize> Default for KeepFdsBuf<N> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> core::ops::Deref for KeepFdsBuf<N> {
+    type Target = [libc::c_int];
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        self.as_slice()
+    }
+}
+
+impl<const N: usize> fmt::Debug for KeepFdsBuf<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.as_slice()).finish()
+    }
+}
+
+impl<const N: usize> PartialEq for KeepFdsBuf<N> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl<const N: usize> Eq for KeepFdsBuf<N> {}
+
+/// The error returned by [`KeepFdsBuf::push()`] if the buffer is full. It contains the file
+/// descriptor that could not be added.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct KeepFdsBufFull(pub libc::c_int);
+
+impl fmt::Display for KeepFdsBufFull {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "No room to add file descriptor {}", self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for KeepFdsBufFull {}
+
+/// The error returned by [`KeepFdsBuf::extend_from_spec()`] and
+/// [`KeepFdsBuf::extend_from_env()`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum KeepFdsSpecError {
+    /// The list of file descriptors is malformed.
+    Invalid,
+    /// The buffer is full. This contains the first file descriptor that didn't fit.
+    Full(libc::c_int),
+}
+
+impl fmt::Display for KeepFdsSpecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Invalid => f.write_str("Invalid list of file descriptors"),
+            Self::Full(fd) => write!(f, "No room to add file descriptor {}", fd),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for KeepFdsSpecError {}
+
+#[cfg(feature = "serde")]
+impl<const N: usize> serde::Serialize for KeepFdsBuf<N> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.as_slice())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, const N: usize> serde::Deserialize<'de> for KeepFdsBuf<N> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor<const N: usize>;
+
+        impl<'de, const N: usize> serde::de::Visitor<'de> for Visitor<N> {
+            type Value = KeepFdsBuf<N>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a sequence of at most {} file descriptors", N)
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<Self::Value, A::Error> {
+                let mut buf = KeepFdsBuf::new();
+                while let Some(fd) = seq.next_element()? {
+                    buf.push(fd).map_err(serde::de::Error::custom)?;
+                }
+                Ok(buf)
+            }
+        }
+
+        deserializer.deserialize_seq(Visitor::<N>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keep_fds_buf() {
+        let mut buf = KeepFdsBuf::<4>::new();
+        assert!(buf.is_empty());
+        assert_eq!(buf.capacity(), 4);
+
+        for &fd in [7, 3, 7, 9, 0, 3].iter() {
+            buf.push(fd).unwrap();
+        }
+        assert_eq!(buf.as_slice(), [0, 3, 7, 9]);
+
+        // Duplicates can still be "added" when the buffer is full
+        buf.push(3).unwrap();
+        assert_eq!(buf.push(5), Err(KeepFdsBufFull(5)));
+        assert_eq!(buf.as_slice(), [0, 3, 7, 9]);
+
+        assert!(buf.remove(3));
+        assert!(!buf.remove(3));
+        assert_eq!(buf.as_slice(), [0, 7, 9]);
+        buf.push(8).unwrap();
+        assert_eq!(buf.as_slice(), [0, 7, 8, 9]);
+
+        buf.clear();
+        assert_eq!(buf.as_slice(), []);
+
+        let mut buf = KeepFdsBuf::<0>::new();
+        assert_eq!(buf.push(1), Err(KeepFdsBufFull(1)));
+        assert!(!buf.remove(1));
+    }
+
+    #[test]
+    fn test_extend_from_spec() {
+        let mut buf = KeepFdsBuf::<8>::new();
+        buf.extend_from_spec(b"").unwrap();
+        buf.extend_from_spec(b"  ").unwrap();
+        assert_eq!(buf.as_slice(), []);
+
+        buf.extend_from_spec(b"3,7-9").unwrap();
+        assert_eq!(buf.as_slice(), [3, 7, 8, 9]);
+        buf.extend_from_spec(b" 8 - 10 , 1,2-2").unwrap();
+        assert_eq!(buf.as_slice(), [1, 2, 3, 7, 8, 9, 10]);
+
+        assert_eq!(
+            buf.extend_from_spec(b"11-12"),
+            Err(KeepFdsSpecError::Full(12))
+        );
+        assert_eq!(buf.as_slice(), [1, 2, 3, 7, 8, 9, 10, 11]);
+
+        let mut buf = KeepFdsBuf::<8>::new();
+        for &spec in [
+            &b","[..],
+            b"1,",
+            b"1,,2",
+            b"-1",
+            b"1-",
+            b"2-1",
+            b"1-2-3",
+            b"1 2",
+            b"x",
+            b"+1",
+            b"2147483648",
+        ]
+        .iter()
+        {
+            assert_eq!(buf.extend_from_spec(spec), Err(KeepFdsSpecError::Invalid));
+        }
+        // Entries before the invalid one were added
+        assert_eq!(buf.as_slice(), [1]);
+
+        let mut buf = KeepFdsBuf::<8>::new();
+        buf.extend_from_spec(b"2147483647").unwrap();
+        assert_eq!(buf.as_slice(), [libc::c_int::MAX]);
+    }
+
+    #[test]
+    fn test_format_spec() {
+        let mut out = [0; 64];
+
+        let mut buf = KeepFdsBuf::<8>::new();
+        assert_eq!(buf.format_spec(&mut out).unwrap(), b"");
+
+        buf.extend_from_spec(b"0,3,7-9,11-12,2147483647").unwrap();
+        assert_eq!(
+            buf.format_spec(&mut out).unwrap(),
+            b"0,3,7-9,11-12,2147483647"
+        );
+
+        // Round trip
+        let mut buf2 = KeepFdsBuf::<8>::new();
+        buf2.extend_from_spec(buf.format_spec(&mut out).unwrap())
+            .unwrap();
+        assert_eq!(buf, buf2);
+
+        // Too small
+        assert_eq!(buf.format_spec(&mut out[..23]), None);
+        assert_eq!(
+            buf.format_spec(&mut out[..24]).unwrap(),
+            b"0,3,7-9,11-12,2147483647"
+        );
+
+        buf.clear();
+        buf.push(-1).unwrap();
+        assert_eq!(buf.format_spec(&mut out), None);
+    }
+
+    #[test]
+    fn test_format_env() {
+        let mut out = [0; 64];
+
+        let mut buf = KeepFdsBuf::<8>::new();
+        assert_eq!(
+            buf.format_env(KEEP_FDS_ENV, &mut out).unwrap().to_bytes(),
+            b"CLOSE_FDS_KEEP="
+        );
+
+        buf.extend_from_spec(b"3,7-9").unwrap();
+        assert_eq!(
+            buf.format_env(KEEP_FDS_ENV, &mut out)
+                .unwrap()
+                .to_bytes_with_nul(),
+            b"CLOSE_FDS_KEEP=3,7-9\0"
+        );
+        assert_eq!(buf.format_env(KEEP_FDS_ENV, &mut out[..20]), None);
+        assert!(buf.format_env(KEEP_FDS_ENV, &mut out[..21]).is_some());
+
+        for &name in [&b"\0"[..], b"A=B\0"].iter() {
+            let name = CStr::from_bytes_with_nul(name).unwrap();
+            assert_eq!(buf.format_env(name, &mut out), None);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_keep_fds_buf_serde() {
+        use serde_test::{assert_de_tokens, assert_de_tokens_error, assert_tokens, Token};
+
+        let mut buf = KeepFdsBuf::<3>::new();
+        assert_tokens(&buf, &[Token::Seq { len: Some(0) }, Token::SeqEnd]);
+
+        buf.push(7).unwrap();
+        buf.push(3).unwrap();
+        assert_tokens(
+            &buf,
+            &[
+                Token::Seq { len: Some(2) },
+                Token::I32(3),
+                Token::I32(7),
+                Token::SeqEnd,
+            ],
+        );
+
+        // Unsorted lists and duplicates are accepted
+        assert_de_tokens(
+            &buf,
+            &[
+                Token::Seq { len: None },
+                Token::I32(7),
+                Token::I32(3),
+                Token::I32(7),
+                Token::SeqEnd,
+            ],
+        );
+
+        assert_de_tokens_error::<KeepFdsBuf<1>>(
+            &[Token::Seq { len: Some(2) }, Token::I32(3), Token::I32(7)],
+            "No room to add file descriptor 7",
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use super::{CloexecStats, CloseFdsBuilder};
+
+/// The number of file descriptors that a [`MockFdTable`] can simulate (file descriptors
+/// `0..MAX_MOCK_FDS`).
+pub const MAX_MOCK_FDS: libc::c_int = 1024;
+
+const WORDS: usize = MAX_MOCK_FDS as usize / 64;
+
+/// A fixed-size set of file descriptors in the range `0..MAX_MOCK_FDS`.
+#[derive(Copy, Clone, Default, Eq, PartialEq, Hash)]
+struct FdSet([u64; WORDS]);
+
+impl FdSet {
+    #[inline]
+    fn index(fd: libc::c_int) -> Option<(usize, u64)> {
+        if (0..MAX_MOCK_FDS).contains(&fd) {
+            Some((fd as usize / 64, 1 << (fd as usize % 64)))
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    fn contains(&self, fd: libc::c_int) -> bool {
+        match Self::index(fd) {
+            Some((i, bit)) => self.0[i] & bit != 0,
+            None => false,
+        }
+    }
+
+    #[inline]
+    fn set(&mut self, fd: libc::c_int, present: bool) {
+        let (i, bit) = Self::index(fd).expect("file descriptor out of range for MockFdTable");
+        if present {
+            self.0[i] |= bit;
+        } else {
+            self.0[i] &= !bit;
+        }
+    }
+
+    fn iter_from(&self, minfd: libc::c_int) -> impl Iterator<Item = libc::c_int> + '_ {
+        (core::cmp::max(minfd, 0)..MAX_MOCK_FDS).filter(move |&fd| self.contains(fd))
+    }
+}
+
+/// A simulated file descriptor table, for unit-testing code that configures a
+/// [`CloseFdsBuilder`] without forking or touching any real file descriptors.
+///
+/// Populate the table with [`Self::open()`], then run a builder against it with
+/// [`Self::closefrom()`] or [`Self::cloexecfrom()`]. These apply the builder's keep list exactly
+/// like [`CloseFdsBuilder::closefrom()`] and [`CloseFdsBuilder::cloexecfrom()`] would, but only
+/// update the table and record which file descriptors were affected (see
+/// [`Self::closed_fds()`] and [`Self::cloexec_fds()`]). Since nothing is actually closed, this
+/// also won't trip the runtime I/O safety checks in the standard library (which abort the process
+/// if a file descriptor owned by e.g. a `File` is closed out from under it).
+///
+/// Only file descriptors below [`MAX_MOCK_FDS`] can be simulated. Settings that only affect how
+/// file descriptors are found (such as [`CloseFdsBuilder::threadsafe()`]) are ignored.
+///
+/// This is only available with the `test-util` feature.
+///
+/// # Example
+///
+/// ```
+/// # use close_fds::{CloseFdsBuilder, MockFdTable};
+/// let mut table = MockFdTable::new();
+/// for fd in 0..6 {
+///     table.open(fd, false);
+/// }
+///
+/// table.closefrom(CloseFdsBuilder::new().keep_fds(&[4]), 3);
+/// assert!(table.closed_fds().eq([3, 5]));
+/// assert!(table.iter_open_fds(0).eq([0, 1, 2, 4]));
+/// ```
+#[derive(Clone, Default, Eq, PartialEq, Hash)]
+pub struct MockFdTable {
+    open: FdSet,
+    cloexec: FdSet,
+    closed: FdSet,
+    cloexec_set: FdSet,
+}
+
+impl MockFdTable {
+    /// Create a new, empty table (no file descriptors are open).
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mark `fd` as open, with the close-on-exec flag set or cleared according to `cloexec`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `fd` is negative or not below [`MAX_MOCK_FDS`].
+    #[inline]
+    pub fn open(&mut self, fd: libc::c_int, cloexec: bool) -> &mut Self {
+        self.open.set(fd, true);
+        self.cloexec.set(fd, cloexec);
+        self
+    }
+
+    /// Check whether `fd` is currently open in the table.
+    #[inline]
+    pub fn is_open(&self, fd: libc::c_int) -> bool {
+        self.open.contains(fd)
+    }
+
+    /// Get whether the close-on-exec flag is set on `fd`, or `None` if it isn't open.
+    #[inline]
+    pub fn is_cloexec(&self, fd: libc::c_int) -> Option<bool> {
+        if self.is_open(fd) {
+            Some(self.cloexec.contains(fd))
+        } else {
+            None
+        }
+    }
+
+    /// Iterate over the file descriptors that are open in the table, starting at `minfd`, in
+    /// ascending order.
+    #[inline]
+    pub fn iter_open_fds(&self, minfd: libc::c_int) -> impl Iterator<Item = libc::c_int> + '_ {
+        self.open.iter_from(minfd)
+    }
+
+    /// Iterate over the file descriptors that have been closed by [`Self::closefrom()`] (since
+    /// the table was created or [`Self::clear_history()`] was called), in ascending order.
+    #[inline]
+    pub fn closed_fds(&self) -> impl Iterator<Item = libc::c_int> + '_ {
+        self.closed.iter_from(0)
+    }
+
+    /// Iterate over the file descriptors that the close-on-exec flag has been set on by
+    /// [`Self::cloexecfrom()`] (since the table was created or [`Self::clear_history()`] was
+    /// called), in ascending order.
+    #[inline]
+    pub fn cloexec_fds(&self) -> impl Iterator<Item = libc::c_int> + '_ {
+        self.cloexec_set.iter_from(0)
+    }
+
+    /// Forget which file descriptors have been closed or had the close-on-exec flag set (the
+    /// table itself is not changed).
+    #[inline]
+    pub fn clear_history(&mut self) {
+        self.closed = FdSet::default();
+        self.cloexec_set = FdSet::default();
+    }
+
+    /// Simulate [`CloseFdsBuilder::closefrom()`] on this table.
+    pub fn closefrom(&mut self, builder: &CloseFdsBuilder, minfd: libc::c_int) {
+        let mut minfd = core::cmp::max(minfd, 0);
+        let mut keep_fds = builder.keep_fds.clone();
+        keep_fds.simplify(&mut minfd);
+
+        for fd in minfd..MAX_MOCK_FDS {
+            if self.open.contains(fd) && !keep_fds.should_keep(fd) {
+                self.open.set(fd, false);
+                self.cloexec.set(fd, false);
+                self.closed.set(fd, true);
+            }
+        }
+    }
+
+    /// Simulate [`CloseFdsBuilder::cloexecfrom()`] on this table.
+    ///
+    /// [`CloseFdsBuilder::blind_cloexec()`] is respected: if it's set, file descriptors that
+    /// already had the close-on-exec flag set are counted as changed (and recorded).
+    pub fn cloexecfrom(&mut self, builder: &CloseFdsBuilder, minfd: libc::c_int) -> CloexecStats {
+        let mut stats = CloexecStats {
+            passes: 1,
+            ..Default::default()
+        };
+
+        let mut minfd = core::cmp::max(minfd, 0);
+        let mut keep_fds = builder.keep_fds.clone();
+        keep_fds.simplify(&mut minfd);
+
+        for fd in minfd..MAX_MOCK_FDS {
+            if self.open.contains(fd) && !keep_fds.should_keep(fd) {
+                let changed = builder.blind_cloexec || !self.cloexec.contains(fd);
+                self.cloexec.set(fd, true);
+                if changed {
+                    self.cloexec_set.set(fd, true);
+                }
+                stats.record(Ok(changed));
+            }
+        }
+
+        stats
+    }
+}
+
+impl core::fmt::Debug for MockFdTable {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        struct Fds<'a>(&'a FdSet);
+
+        impl core::fmt::Debug for Fds<'_> {
+            fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                f.debug_list().entries(self.0.iter_from(0)).finish()
+            }
+        }
+
+        f.debug_struct("MockFdTable")
+            .field("open", &Fds(&self.open))
+            .field("cloexec", &Fds(&self.cloexec))
+            .field("closed", &Fds(&self.closed))
+            .field("cloexec_set", &Fds(&self.cloexec_set))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_closefrom() {
+        let mut table = MockFdTable::new();
+        for fd in [0, 1, 2, 3, 5, 8, 100, MAX_MOCK_FDS - 1] {
+            table.open(fd, fd % 2 == 0);
+        }
+
+        table.closefrom(CloseFdsBuilder::new().keep_fds(&[8, 3, 7]), 3);
+        assert!(table.closed_fds().eq([5, 100, MAX_MOCK_FDS - 1]));
+        assert!(table.iter_open_fds(0).eq([0, 1, 2, 3, 8]));
+        assert_eq!(table.is_cloexec(8), Some(true));
+        assert_eq!(table.is_cloexec(3), Some(false));
+        assert_eq!(table.is_cloexec(5), None);
+
+        table.clear_history();
+        assert!(table.closed_fds().eq([]));
+
+        table.closefrom(CloseFdsBuilder::new().also_keep(&[1]).unwrap(), -1);
+        assert!(table.closed_fds().eq([0, 2, 3, 8]));
+        assert!(table.iter_open_fds(0).eq([1]));
+        assert!(table.cloexec_fds().eq([]));
+    }
+
+    #[test]
+    fn test_mock_cloexecfrom() {
+        let mut table = MockFdTable::new();
+        for fd in 0..8 {
+            table.open(fd, fd == 5);
+        }
+
+        let stats = table.cloexecfrom(CloseFdsBuilder::new().keep_fds(&[4, 6]), 3);
+        assert_eq!(stats.changed, 2);
+        assert!(!stats.bulk);
+        assert!(table.cloexec_fds().eq([3, 7]));
+        assert!(table.closed_fds().eq([]));
+        for fd in 0..8 {
+            assert_eq!(table.is_cloexec(fd), Some(matches!(fd, 3 | 5 | 7)));
+        }
+
+        table.clear_history();
+        let stats = table.cloexecfrom(CloseFdsBuilder::new().blind_cloexec(true), 5);
+        assert_eq!(stats.changed, 3);
+        assert!(table.cloexec_fds().eq([5, 6, 7]));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_mock_out_of_range() {
+        MockFdTable::new().open(MAX_MOCK_FDS, false);
+    }
+}
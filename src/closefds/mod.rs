@@ -1,13 +1,89 @@
-use crate::FdIterBuilder;
+use crate::{FdIterBuilder, SyscallCounter};
 
+#[cfg(all(feature = "std", not(any(target_os = "tvos", target_os = "watchos"))))]
+mod activation;
+mod atfork;
+mod backend;
 mod cloexec;
 mod close;
+mod compact;
+#[cfg(all(feature = "test-util", feature = "std"))]
+mod fixtures;
+mod gaps;
+#[cfg(feature = "std")]
+mod harden;
+mod keepbuf;
+#[cfg(feature = "test-util")]
+mod mock;
+mod observe;
+#[cfg(all(feature = "std", not(any(target_os = "tvos", target_os = "watchos"))))]
+mod posix_spawn;
+#[cfg(feature = "std")]
+mod pre_exec;
+mod range;
+#[cfg(all(feature = "std", not(any(target_os = "tvos", target_os = "watchos"))))]
+mod reexec;
+#[cfg(feature = "std")]
+mod scm;
+#[cfg(not(any(target_os = "tvos", target_os = "watchos")))]
+mod selftest;
+mod shared;
+#[cfg(all(feature = "std", not(any(target_os = "tvos", target_os = "watchos"))))]
+mod spawn;
+mod stdio;
+mod token;
+
+#[cfg(all(feature = "std", not(any(target_os = "tvos", target_os = "watchos"))))]
+pub use activation::{receive_listen_fds, spawn_with_listen_fds, LISTEN_FDS_START};
+pub use atfork::{
+    install_atfork_closer, is_clofork, run_clofork, set_clofork, uninstall_atfork_closer,
+    unset_clofork, AtforkCloserError, CloforkError, MAX_ATFORK_KEEP_FDS, MAX_CLOFORK_FDS,
+};
+pub use backend::FdCloser;
+pub use compact::{compact_fds, CompactFdsError};
+#[cfg(all(feature = "test-util", feature = "std"))]
+pub use fixtures::{open_dummy_fds, open_fd_at, run_in_child};
+#[cfg(feature = "std")]
+pub use harden::{harden_setuid_startup, StartupHardeningReport};
+pub use keepbuf::{
+    close_fds_from_env, KeepFdsBuf, KeepFdsBufFull, KeepFdsSpecError, KEEP_FDS_ENV,
+    MAX_ENV_KEEP_FDS,
+};
+#[cfg(feature = "test-util")]
+pub use mock::{MockFdTable, MAX_MOCK_FDS};
+pub use observe::FdDecision;
+#[cfg(all(feature = "std", not(any(target_os = "tvos", target_os = "watchos"))))]
+pub use posix_spawn::posix_spawn_with_closed_fds;
+#[cfg(feature = "tokio")]
+pub use pre_exec::TokioCommandExt;
+#[cfg(feature = "std")]
+pub use pre_exec::{FdMapping, FdMappingCollision, PreExecPipeline};
+pub use range::{close_range, CloseRangeError, CloseRangeFlag};
+#[cfg(all(feature = "std", not(any(target_os = "tvos", target_os = "watchos"))))]
+pub use reexec::sanitize_and_reexec;
+#[cfg(feature = "std")]
+pub use scm::drain_scm_rights;
+#[cfg(not(any(target_os = "tvos", target_os = "watchos")))]
+pub use selftest::{selftest, SelftestOutcome, SelftestReport};
+pub use shared::{is_fd_table_shared, SharedFdTablePolicy};
+#[cfg(all(feature = "std", not(any(target_os = "tvos", target_os = "watchos"))))]
+pub use spawn::{spawn_daemon_with_closed_fds, spawn_with_closed_fds, spawn_with_closed_fds_vfork};
+pub use stdio::{redirect_stdio, StdioSource};
+pub use token::PreExecToken;
 
 /// A "builder" for either closing all open file descriptors or setting them as close-on-exec.
 #[derive(Clone, Debug)]
 pub struct CloseFdsBuilder<'a> {
     keep_fds: KeepFds<'a>,
     it: FdIterBuilder,
+    blind_cloexec: bool,
+    juggle_keep_fds: bool,
+    close_descending: bool,
+    stabilize_passes: usize,
+    observer: observe::Observer,
+    make_kept_inheritable: bool,
+    shared_fd_table: SharedFdTablePolicy,
+    closer: backend::Backend,
 }
 
 impl<'a> CloseFdsBuilder<'a> {
@@ -17,6 +93,14 @@ impl<'a> CloseFdsBuilder<'a> {
         Self {
             keep_fds: KeepFds::empty(),
             it: FdIterBuilder::new(),
+            blind_cloexec: false,
+            juggle_keep_fds: false,
+            close_descending: false,
+            stabilize_passes: 1,
+            observer: observe::Observer::default(),
+            make_kept_inheritable: false,
+            shared_fd_table: SharedFdTablePolicy::Ignore,
+            closer: backend::Backend(None),
         }
     }
 
@@ -24,8 +108,9 @@ impl<'a> CloseFdsBuilder<'a> {
     /// close-on-exec flag on them.
     ///
     /// Calling this method multiple times will *replace* the list of file descriptors to be left
-    /// alone, not extend it. Additionally, it's not recommended to call this method multiple
-    /// times, since it pre-scans the list to collect information for later use.
+    /// alone, not extend it (this also discards any lists added with [`Self::also_keep()`]).
+    /// Additionally, it's not recommended to call this method multiple times, since it pre-scans
+    /// the list to collect information for later use.
     ///
     /// # Efficiency
     ///
@@ -35,6 +120,9 @@ impl<'a> CloseFdsBuilder<'a> {
     ///
     /// `close_fds` can't just copy the slice and sort it for you because allocating memory is not
     /// async-signal-safe (see ["Async-signal-safety"](./index.html#async-signal-safety)).
+    ///
+    /// Duplicate entries are allowed, and don't affect performance (a sorted list with duplicates
+    /// is still considered sorted).
     #[inline]
     pub fn keep_fds(&mut self, keep_fds: &'a [libc::c_int]) -> &mut Self {
         self.keep_fds = KeepFds::new(keep_fds);
@@ -53,6 +141,38 @@ impl<'a> CloseFdsBuilder<'a> {
         self
     }
 
+    /// Identical to [`Self::keep_fds()`], but takes the file descriptors from a [`KeepFdsBuf`].
+    ///
+    /// Since a `KeepFdsBuf` is always sorted, this is as efficient as [`Self::keep_fds_sorted()`].
+    #[inline]
+    pub fn keep_fds_buf<const N: usize>(&mut self, keep_fds: &'a KeepFdsBuf<N>) -> &mut Self {
+        // Safety: KeepFdsBuf keeps itself sorted
+        unsafe { self.keep_fds_sorted(keep_fds.as_slice()) }
+    }
+
+    /// Leave the file descriptors listed in `keep_fds` alone, in addition to the ones passed to
+    /// [`Self::keep_fds()`] and any previous calls to this method.
+    ///
+    /// This is useful when several different parts of a program each have their own list of file
+    /// descriptors that need to be kept open; it avoids having to concatenate them into a new
+    /// (allocated) list. The lists are not copied; instead, each of them is checked when deciding
+    /// whether to leave a file descriptor alone.
+    ///
+    /// As with [`Self::keep_fds()`], performance will be significantly better if every list is
+    /// sorted.
+    ///
+    /// Up to 4 additional lists may be added this way. If this method is called more times than
+    /// that (without an intervening call to [`Self::keep_fds()`] or [`Self::keep_fds_sorted()`]),
+    /// [`TooManyKeepLists`] is returned and the list is not added.
+    #[inline]
+    pub fn also_keep(
+        &mut self,
+        keep_fds: &'a [libc::c_int],
+    ) -> Result<&mut Self, TooManyKeepLists> {
+        self.keep_fds.push_extra(keep_fds)?;
+        Ok(self)
+    }
+
     /// Set whether [`Self::cloexecfrom()`] needs to behave reliably in multithreaded programs
     /// (default is `false`).
     ///
@@ -67,6 +187,18 @@ impl<'a> CloseFdsBuilder<'a> {
         self
     }
 
+    /// Make [`Self::cloexecfrom()`] behave reliably in multithreaded programs only if the current
+    /// process actually has more than one thread.
+    ///
+    /// See
+    /// [`FdIterBuilder::threadsafe_auto()`](./struct.FdIterBuilder.html#method.threadsafe_auto)
+    /// for more information.
+    #[inline]
+    pub fn threadsafe_auto(&mut self) -> &mut Self {
+        self.it.threadsafe_auto();
+        self
+    }
+
     /// Set whether this crate is allowed to look at special files for speedups when closing the
     /// specified file descriptors (default is `true`).
     ///
@@ -79,17 +211,292 @@ impl<'a> CloseFdsBuilder<'a> {
         self
     }
 
+    /// Set the lowest file descriptor number that may be used for any directory file descriptor
+    /// opened internally (default is `0`).
+    ///
+    /// See [`FdIterBuilder::dirfd_minfd()`](./struct.FdIterBuilder.html#method.dirfd_minfd) for
+    /// more information.
+    #[inline]
+    pub fn dirfd_minfd(&mut self, dirfd_minfd: libc::c_int) -> &mut Self {
+        self.it.dirfd_minfd(dirfd_minfd);
+        self
+    }
+
+    /// Set whether [`Self::cloexecfrom()`] should set the close-on-exec flag on file descriptors
+    /// without checking their current flags first (default is `false`).
+    ///
+    /// Normally, each file descriptor's flags are read with `fcntl(F_GETFD)` and only updated
+    /// (with `fcntl(F_SETFD)`) if necessary. If this is set to `true`, `fcntl(F_SETFD, FD_CLOEXEC)`
+    /// is called directly, which halves the number of syscalls when there are many file
+    /// descriptors to go through. The tradeoffs are:
+    ///
+    /// - Any *other* file descriptor flags are cleared. (`FD_CLOEXEC` is the only file descriptor
+    ///   flag on most platforms, but this may not always be true.)
+    /// - [`CloexecStats::changed`] will also count file descriptors that already had the flag
+    ///   set.
+    ///
+    /// This has no effect when the flag can be set in bulk (e.g. with
+    /// `close_range(CLOSE_RANGE_CLOEXEC)` on Linux 5.11+).
+    ///
+    /// (On Linux, the flags are also visible in `/proc/self/fdinfo/<fd>`, but that isn't used to
+    /// avoid the `fcntl(F_GETFD)` calls: there's no way to read more than one file descriptor's
+    /// entry at a time, so each lookup would take an `open()`, a `read()`, and a `close()`
+    /// instead of a single `fcntl()`. This option is the way to reduce the number of syscalls.)
+    #[inline]
+    pub fn blind_cloexec(&mut self, blind: bool) -> &mut Self {
+        self.blind_cloexec = blind;
+        self
+    }
+
+    /// Set whether [`Self::closefrom()`] should close file descriptors by temporarily moving the
+    /// ones that need to be kept out of the way (default is `false`).
+    ///
+    /// If this is set to `true`, the file descriptors passed to [`Self::keep_fds()`] are
+    /// duplicated down to the lowest available numbers (starting at `minfd`), `closefrom()` is
+    /// called once to close everything above the copies, any other file descriptors below that
+    /// point are closed, and then the copies are moved back to their original numbers (restoring
+    /// their close-on-exec flags). When there are free slots near `minfd`, this replaces a loop over
+    /// every possible file descriptor with a number of syscalls proportional to the number of kept
+    /// file descriptors plus the number of other file descriptor numbers below the copies.
+    ///
+    /// Some things to be aware of:
+    ///
+    /// - POSIX record locks (`fcntl(F_SETLK)`) held on the files that the kept file descriptors
+    ///   refer to are released, since closing *any* file descriptor for a file releases them.
+    /// - If there are more than 64 file descriptors to keep, any of them are at or above the
+    ///   current file descriptor limit, or a temporary copy can't be made (e.g. because the limit
+    ///   has been reached), the normal method is used instead. The same goes if there aren't
+    ///   enough free slots near `minfd` (so that more than 64 other numbers below the copies would
+    ///   have to be closed one at a time).
+    ///
+    /// Currently, this will only have an effect on FreeBSD, NetBSD, OpenBSD, and DragonFly BSD
+    /// (which have `closefrom()`). On Linux, `close_range()` can be used to the same effect
+    /// without moving file descriptors around.
+    #[inline]
+    pub fn juggle_keep_fds(&mut self, juggle: bool) -> &mut Self {
+        self.juggle_keep_fds = juggle;
+        self
+    }
+
+    /// Set whether [`Self::closefrom()`] should close file descriptors starting with the highest
+    /// one and working down (default is `false`).
+    ///
+    /// Some (buggy) libraries react to one of their file descriptors being closed by opening new
+    /// ones, for example from a "teardown" hook. If file descriptors are closed in ascending
+    /// order, those new file descriptors may take the numbers that were just closed (and be
+    /// missed) or clobber ones that haven't been closed yet. If this is set to `true`, the highest
+    /// open file descriptor is found first, and every file descriptor from there down to `minfd`
+    /// is closed in descending order; then everything is closed again the normal way (e.g. with
+    /// `closefrom()` or `close_range()`) to catch anything that was reopened in the meantime.
+    ///
+    /// This makes at least one `close()` call for every number between `minfd` and the highest
+    /// open file descriptor, so it can be much slower than the normal method if there are large
+    /// gaps between file descriptors.
+    #[inline]
+    pub fn close_descending(&mut self, descending: bool) -> &mut Self {
+        self.close_descending = descending;
+        self
+    }
+
+    /// Make [`Self::cloexecfrom()`] (and [`Self::cloexecfrom_cached()`]) repeat its sweep over
+    /// the file descriptor table until a pass
+    /// doesn't find any file descriptors without the close-on-exec flag set, or until `max_passes`
+    /// passes have been made (default is `1`, i.e. no repetition).
+    ///
+    /// In a multithreaded program, another thread may open a file descriptor without the
+    /// close-on-exec flag (for example, on macOS, where some APIs can't set it atomically) after
+    /// the sweep has passed its number. Repeating the sweep until it's stable shrinks the window
+    /// in which such a file descriptor can escape. It can't close the window entirely; only
+    /// ensuring that every file descriptor is opened with the close-on-exec flag can do that.
+    ///
+    /// Passes after the first always check each file descriptor's flags (even if
+    /// [`Self::blind_cloexec()`] is set), since that's how it's determined whether anything was
+    /// missed. If the flag is set in bulk (e.g. with `close_range(CLOSE_RANGE_CLOEXEC)` on Linux
+    /// 5.11+), which is atomic with respect to the file descriptor table, no more passes are made.
+    /// [`CloexecStats::passes`] reports the number of passes that were made.
+    ///
+    /// A value of `0` is treated as `1`.
+    #[inline]
+    pub fn stabilize(&mut self, max_passes: usize) -> &mut Self {
+        self.stabilize_passes = core::cmp::max(max_passes, 1);
+        self
+    }
+
+    /// Set whether [`Self::closefrom()`] and [`Self::cloexecfrom()`] should also clear the
+    /// close-on-exec flag on the file descriptors that are being kept (default is `false`).
+    ///
+    /// Preparing to `exec()` a program usually requires both getting rid of the file descriptors
+    /// that it shouldn't inherit and making sure that the ones it *should* inherit don't have the
+    /// close-on-exec flag set. If this is set to `true`, the flag is cleared on every open file
+    /// descriptor passed to [`Self::keep_fds()`] or [`Self::also_keep()`] that is at or above
+    /// `minfd` (file descriptors below `minfd` are left alone, as usual). This is done by walking
+    /// the keep lists after the sweep, so the file descriptor table isn't scanned twice.
+    ///
+    /// This also applies to the process spawning helpers and `pre_exec()` functions created from
+    /// this builder. It has no effect on [`Self::closefrom_and_cloexec_kept()`], which always sets
+    /// the flag on the kept file descriptors.
+    #[inline]
+    pub fn make_kept_inheritable(&mut self, inheritable: bool) -> &mut Self {
+        self.make_kept_inheritable = inheritable;
+        self
+    }
+
+    /// Set a function to be called with every decision that is made while closing file
+    /// descriptors or setting them as close-on-exec (default is `None`).
+    ///
+    /// This is intended for auditing and instrumentation: it makes it possible to record exactly
+    /// what a sweep did. Every file descriptor that the sweep examines individually is reported as
+    /// [`FdDecision::Kept`], [`FdDecision::Closed`] (only if it was actually open), or
+    /// [`FdDecision::Cloexec`]. File descriptors that are handled in bulk (e.g. with
+    /// `closefrom()` or `close_range()`) are reported once per call as
+    /// [`FdDecision::ClosedRange`] or [`FdDecision::CloexecRange`]; kept file descriptors that
+    /// are skipped over this way are not reported. Temporary file descriptors used internally
+    /// (such as a directory file descriptor for `/proc/self/fd`) are never reported.
+    ///
+    /// The observer is used by [`Self::closefrom()`], [`Self::cloexecfrom()`], and the other
+    /// methods that call them, as well as by the process spawning helpers that take a builder.
+    ///
+    /// The observer is called synchronously in the middle of the sweep, which may be in a child
+    /// process after a `fork()`. It **must** be async-signal-safe, it must not allocate memory,
+    /// and it must not open or close file descriptors. Incrementing atomic counters, or
+    /// formatting a message into a stack buffer and `write()`ing it to a file descriptor that is
+    /// being kept, is fine. (`spawn_with_closed_fds_vfork()` imposes additional restrictions;
+    /// see its documentation.)
+    #[inline]
+    pub fn observer(&mut self, observer: Option<fn(FdDecision)>) -> &mut Self {
+        self.observer.func = observer;
+        self
+    }
+
+    /// Count the system calls made while closing file descriptors or setting them as
+    /// close-on-exec in `counter` (default is `None`).
+    ///
+    /// This covers the `close()`, `fcntl()`, `close_range()`, and `closefrom()` calls made by
+    /// [`Self::closefrom()`], [`Self::cloexecfrom()`], and the other methods that call them (as
+    /// well as the process spawning helpers that take a builder), and the calls made to list the
+    /// open file descriptors (see [`FdIterBuilder::syscall_counter()`]). Calls made by a custom
+    /// backend (see [`Self::closer()`]), by [`Self::shared_fd_table()`], and by
+    /// [`Self::make_kept_inheritable()`] are not counted. See [`SyscallCounter`] for more
+    /// information.
+    #[inline]
+    pub fn syscall_counter(&mut self, counter: Option<&'static SyscallCounter>) -> &mut Self {
+        self.observer.counter = crate::counter::Counter(counter);
+        self.it.syscall_counter(counter);
+        self
+    }
+
+    /// Use a custom backend to find open file descriptors, close them, and set them as
+    /// close-on-exec (default is `None`, which uses this crate's own methods).
+    ///
+    /// The builder still takes care of the keep list: it asks `closer` to act on the ranges of
+    /// file descriptors around the kept ones, and checks the file descriptors that `closer` lists
+    /// against the keep list. Operations that `closer` doesn't support fall back on this crate's
+    /// own methods (see [`FdCloser`]). Shortcuts like `closefrom()` and `close_range()` are *not*
+    /// used when a custom backend is set, so the backend has full control.
+    ///
+    /// The backend is used by [`Self::closefrom()`], [`Self::cloexecfrom()`], the methods that
+    /// call them, the process spawning helpers, and `pre_exec()` functions. (With
+    /// [`Self::cloexecfrom_cached()`], the cached directory is only used if `closer` can't list the
+    /// open file descriptors itself.)
+    #[inline]
+    pub fn closer(&mut self, closer: Option<&'static dyn FdCloser>) -> &mut Self {
+        self.closer = backend::Backend(closer);
+        self
+    }
+
+    /// Set what [`Self::closefrom()`] should do if the current process's file descriptor table
+    /// is shared with another process (default is [`SharedFdTablePolicy::Ignore`]).
+    ///
+    /// If another process was created with `clone(CLONE_FILES)` (for example, by a library called
+    /// through FFI), closing file descriptors would close them in that process too. With a policy
+    /// other than `Ignore`, [`Self::closefrom()`] (and [`Self::closefrom_and_cloexec_kept()`])
+    /// first checks for this with [`is_fd_table_shared()`], and then either closes nothing
+    /// ([`SharedFdTablePolicy::Refuse`]), calls a function and closes the file descriptors
+    /// anyway ([`SharedFdTablePolicy::Warn`]), or stops sharing the file descriptor table before
+    /// closing them ([`SharedFdTablePolicy::Unshare`]). If it can't be determined whether the file
+    /// descriptor table is shared (including on platforms other than Linux), the file descriptors
+    /// are closed as usual.
+    ///
+    /// The check reads `/proc` and calls `kcmp()` for every process on the system, so it's
+    /// relatively expensive; with [`SharedFdTablePolicy::Unshare`], it's skipped unless
+    /// `unshare(CLONE_FILES)` fails. It isn't performed by the process spawning helpers or `pre_exec()`
+    /// functions, since a child process created with `fork()` always gets its own copy of the file
+    /// descriptor table.
+    #[inline]
+    pub fn shared_fd_table(&mut self, policy: SharedFdTablePolicy) -> &mut Self {
+        self.shared_fd_table = policy;
+        self
+    }
+
     /// Identical to [`Self::closefrom()`], but sets the `FD_CLOEXEC` flag on the file descriptors
     /// instead of closing them.
     ///
     /// On some platforms (most notably, some of the BSDs), this is significantly less efficient than
     /// [`Self::closefrom()`], and use of that function should be preferred when possible.
+    ///
+    /// See [`Self::cloexecfrom_stats()`] for a version that reports which file descriptors were
+    /// affected.
+    #[inline]
     pub fn cloexecfrom(&self, minfd: libc::c_int) {
-        cloexec::set_fds_cloexec(
-            core::cmp::max(minfd, 0),
+        self.cloexecfrom_stats(minfd);
+    }
+
+    /// Identical to [`Self::cloexecfrom()`], but returns a [`CloexecStats`] describing which file
+    /// descriptors were affected.
+    pub fn cloexecfrom_stats(&self, minfd: libc::c_int) -> CloexecStats {
+        self.cloexecfrom_with(minfd, &self.it)
+    }
+
+    /// Identical to [`Self::cloexecfrom_stats()`], but uses the directory file descriptor cached in
+    /// `cache` (opening it if necessary) to find the open file descriptors, instead of opening a
+    /// new one each time.
+    ///
+    /// This is intended for programs that set the close-on-exec flag on their file descriptors
+    /// periodically. See [`FdDirCache`](crate::FdDirCache) for more information.
+    pub fn cloexecfrom_cached(
+        &self,
+        cache: &mut crate::FdDirCache,
+        minfd: libc::c_int,
+    ) -> CloexecStats {
+        self.cloexecfrom_with(minfd, &self.it.with_cache(cache))
+    }
+
+    /// Set the close-on-exec flag as described for [`Self::cloexecfrom_stats()`] (including the
+    /// extra passes requested with [`Self::stabilize()`]), finding the open file descriptors with
+    /// `it`.
+    fn cloexecfrom_with(&self, minfd: libc::c_int, it: &crate::FdIterBuilder) -> CloexecStats {
+        let minfd = core::cmp::max(minfd, 0);
+
+        let mut stats = cloexec::set_fds_cloexec(
+            minfd,
             self.keep_fds.clone(),
-            self.it.clone(),
+            it.clone(),
+            self.blind_cloexec,
+            self.observer,
+            self.closer,
         );
+
+        let mut last = stats;
+        while stats.passes < self.stabilize_passes && !last.bulk && last.changed != 0 {
+            last = cloexec::set_fds_cloexec(
+                minfd,
+                self.keep_fds.clone(),
+                it.clone(),
+                false,
+                self.observer,
+                self.closer,
+            );
+            stats.changed += last.changed;
+            stats.failed += last.failed;
+            stats.bulk |= last.bulk;
+            stats.passes += 1;
+        }
+
+        if self.make_kept_inheritable {
+            self.keep_fds.clear_cloexec(minfd);
+        }
+
+        stats
     }
 
     /// Close all of the file descriptors starting at `minfd` and not excluded by
@@ -115,15 +522,213 @@ impl<'a> CloseFdsBuilder<'a> {
     /// (Note: The above warnings, by definition, make it unsafe to call this function concurrently
     /// from multiple threads. As a result, this function may perform other non-thread-safe
     /// operations.)
+    ///
+    /// See [`Self::closefrom_with()`] for a safe alternative that takes a [`PreExecToken`].
     pub unsafe fn closefrom(&self, minfd: libc::c_int) {
+        let minfd = core::cmp::max(minfd, 0);
+
+        if !self.shared_fd_table.check() {
+            return;
+        }
+
         close::close_fds(
-            core::cmp::max(minfd, 0),
+            minfd,
             self.keep_fds.clone(),
             self.it.clone(),
+            self.juggle_keep_fds,
+            self.close_descending,
+            self.observer,
+            self.closer,
         );
+
+        if self.make_kept_inheritable {
+            self.keep_fds.clear_cloexec(minfd);
+        }
+    }
+
+    /// Identical to [`Self::closefrom()`], but safe to call, since `token` proves that the caller
+    /// has already checked that closing file descriptors is safe (see [`PreExecToken`]).
+    #[inline]
+    pub fn closefrom_with(&self, _token: PreExecToken, minfd: libc::c_int) {
+        // Safety: Guaranteed by the creator of `token`
+        unsafe { self.closefrom(minfd) }
+    }
+
+    /// Close file descriptors as with [`Self::closefrom()`], and also set the close-on-exec flag on
+    /// all of the file descriptors starting at `minfd` that were kept open (i.e. the ones passed
+    /// to [`Self::keep_fds()`] and [`Self::also_keep()`]).
+    ///
+    /// This is for callers that need to keep some file descriptors open for now (e.g. to pass them
+    /// to a child process with `dup2()`) but don't want the originals to leak into programs that
+    /// are executed later. It's equivalent to calling `closefrom()` and then `cloexecfrom()` with
+    /// no file descriptors kept, but the file descriptor table is only scanned once (the kept file
+    /// descriptors are found by walking the keep list instead).
+    ///
+    /// [`Self::blind_cloexec()`] is respected when setting the close-on-exec flag.
+    ///
+    /// # Safety
+    ///
+    /// See [`Self::closefrom()`].
+    pub unsafe fn closefrom_and_cloexec_kept(&self, minfd: libc::c_int) {
+        let minfd = core::cmp::max(minfd, 0);
+
+        if !self.shared_fd_table.check() {
+            return;
+        }
+
+        close::close_fds(
+            minfd,
+            self.keep_fds.clone(),
+            self.it.clone(),
+            self.juggle_keep_fds,
+            self.close_descending,
+            self.observer,
+            self.closer,
+        );
+
+        for &fd in self.keep_fds.iter_all() {
+            if fd >= minfd {
+                // Errors here just mean the file descriptor isn't open
+                if cloexec::set_cloexec(fd, self.blind_cloexec, self.observer).is_ok() {
+                    self.observer.cloexec(fd);
+                }
+            }
+        }
+    }
+
+    /// Close file descriptors as with [`Self::closefrom()`], then lower the `RLIMIT_NOFILE` limit
+    /// so that only `headroom` new file descriptors can be opened at or above `minfd`.
+    ///
+    /// This is a hardening measure: after the file descriptor table has been cleaned up, any code
+    /// that is later compromised can't quietly open hundreds of new file descriptors. The soft limit
+    /// is set to `minfd + headroom` (it's never raised). If `lower_hard` is `true`, the hard limit is
+    /// lowered to match; otherwise, the process can raise the soft limit again.
+    ///
+    /// Note that file descriptors at or above the new limit (for example, ones passed to
+    /// [`Self::keep_fds()`]) remain open and usable; the limit only affects newly opened file
+    /// descriptors. Kept file descriptors below the new limit count against `headroom`. Also, note
+    /// that file descriptors above the limit may be missed by [`FdIter`](crate::FdIter)s that have
+    /// to fall back on checking every possible file descriptor (see
+    /// [`fallback_max_fd()`](crate::fallback_max_fd)).
+    ///
+    /// An error is returned if getting or setting the limit fails. (The file descriptors are
+    /// closed in either case.) Lowering the hard limit is irreversible for unprivileged processes.
+    ///
+    /// # Safety
+    ///
+    /// See [`Self::closefrom()`].
+    pub unsafe fn closefrom_and_limit(
+        &self,
+        minfd: libc::c_int,
+        headroom: libc::c_int,
+        lower_hard: bool,
+    ) -> Result<(), crate::FdLimitError> {
+        let minfd = core::cmp::max(minfd, 0);
+        self.closefrom(minfd);
+
+        let limit = minfd as u64 + core::cmp::max(headroom, 0) as u64;
+        crate::iterfds::lower_fd_limit(limit, lower_hard)
+    }
+
+    /// Close all of the file descriptors starting at `minfd` that have been opened since
+    /// `snapshot` was taken, except the ones excluded by [`Self::keep_fds()`].
+    ///
+    /// This is intended for plugin hosts and similar programs that need to undo file descriptor
+    /// leaks caused by a module (for example, after unloading it) without disturbing their own
+    /// long-lived file descriptors: take a [`FdSnapshot`](crate::FdSnapshot) before loading the
+    /// module, and call this after unloading it. File descriptors that were open when the snapshot
+    /// was taken are left alone. (Since a snapshot only records file descriptor numbers, this
+    /// includes file descriptors that were closed and then reused for something else.)
+    ///
+    /// The snapshot is used as an additional list of file descriptors to keep, as with
+    /// [`Self::also_keep()`], so this does not allocate memory and the file descriptor table is
+    /// only scanned once.
+    ///
+    /// If [`Self::also_keep()`] has already been called 4 times, there's no room to add the
+    /// snapshot, so nothing is closed and [`TooManyKeepLists`] is returned.
+    ///
+    /// # Safety
+    ///
+    /// See [`Self::closefrom()`]. In particular, in a multithreaded program, any file descriptors
+    /// that other threads have opened since the snapshot was taken will be closed too.
+    #[cfg(feature = "std")]
+    pub unsafe fn close_new_fds_since(
+        &self,
+        snapshot: &crate::FdSnapshot,
+        minfd: libc::c_int,
+    ) -> Result<(), TooManyKeepLists> {
+        let mut builder = self.clone();
+        builder.also_keep(snapshot.fds())?.closefrom(minfd);
+        Ok(())
+    }
+
+    /// Check the file descriptors passed to [`Self::keep_fds()`] (and [`Self::also_keep()`]) for
+    /// likely configuration mistakes, as if [`Self::closefrom()`] or [`Self::cloexecfrom()`] was
+    /// about to be called with the given `minfd`.
+    ///
+    /// None of these problems cause errors when closing file descriptors (the entries in question
+    /// are simply ignored), so this is primarily intended for catching bugs during development
+    /// (e.g. in a `debug_assert!()`).
+    ///
+    /// If any problems are found, the first one is returned (checking each list in order). See
+    /// [`KeepFdsProblem`] for the problems that are detected.
+    pub fn check_keep_fds(&self, minfd: libc::c_int) -> Result<(), KeepFdsProblem> {
+        let minfd = core::cmp::max(minfd, 0);
+
+        for &fd in self.keep_fds.iter_all() {
+            if fd < 0 {
+                return Err(KeepFdsProblem::Negative(fd));
+            } else if fd < minfd {
+                return Err(KeepFdsProblem::BelowMinfd(fd));
+            } else if !crate::util::is_fd_valid(fd) {
+                return Err(KeepFdsProblem::NotOpen(fd));
+            }
+        }
+
+        Ok(())
     }
 }
 
+/// A problem with a list of file descriptors to keep, as reported by
+/// [`CloseFdsBuilder::check_keep_fds()`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum KeepFdsProblem {
+    /// The given (negative) file descriptor can never be valid.
+    Negative(libc::c_int),
+    /// The given file descriptor is below `minfd`, so it would have been left alone anyway.
+    BelowMinfd(libc::c_int),
+    /// The given file descriptor is not open.
+    NotOpen(libc::c_int),
+}
+
+impl KeepFdsProblem {
+    /// Get the file descriptor that this problem concerns.
+    #[inline]
+    pub fn fd(&self) -> libc::c_int {
+        match *self {
+            Self::Negative(fd) | Self::BelowMinfd(fd) | Self::NotOpen(fd) => fd,
+        }
+    }
+}
+
+impl core::fmt::Display for KeepFdsProblem {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            Self::Negative(fd) => write!(f, "Negative file descriptor {} in keep list", fd),
+            Self::BelowMinfd(fd) => write!(
+                f,
+                "File descriptor {} in keep list is below minfd (it will be kept anyway)",
+                fd
+            ),
+            Self::NotOpen(fd) => write!(f, "File descriptor {} in keep list is not open", fd),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for KeepFdsProblem {}
+
 impl<'a> Default for CloseFdsBuilder<'a> {
     #[inline]
     fn default() -> Self {
@@ -131,11 +736,123 @@ impl<'a> Default for CloseFdsBuilder<'a> {
     }
 }
 
+/// Information on the file descriptors affected by [`CloseFdsBuilder::cloexecfrom_stats()`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub struct CloexecStats {
+    /// The number of file descriptors that the close-on-exec flag was set on (not counting file
+    /// descriptors that already had it set, unless [`CloseFdsBuilder::blind_cloexec()`] was
+    /// used).
+    pub changed: usize,
+    /// The number of open file descriptors that the close-on-exec flag could not be set on.
+    pub failed: usize,
+    /// Whether some (or all) of the file descriptors were handled in bulk (e.g. with
+    /// `close_range(CLOSE_RANGE_CLOEXEC)` on Linux 5.11+), in which case the number of file
+    /// descriptors that were changed is not known and is not included in `changed`.
+    pub bulk: bool,
+    /// The number of passes that were made over the file descriptor table. This is always 1
+    /// unless [`CloseFdsBuilder::stabilize()`] was used.
+    pub passes: usize,
+}
+
+impl CloexecStats {
+    #[inline]
+    fn record(&mut self, res: Result<bool, ()>) {
+        match res {
+            Ok(true) => self.changed += 1,
+            Ok(false) => (),
+            Err(()) => self.failed += 1,
+        }
+    }
+}
+
+/// What to do with the file descriptors that a child process shouldn't inherit (see
+/// [`recommended_action()`]).
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum FdAction {
+    /// Close them (with [`CloseFdsBuilder::closefrom()`]).
+    Close,
+    /// Set the close-on-exec flag on them (with [`CloseFdsBuilder::cloexecfrom()`]).
+    Cloexec,
+}
+
+impl FdAction {
+    /// Perform this action on the file descriptors starting at `minfd` (except the ones excluded
+    /// by `builder`).
+    ///
+    /// # Safety
+    ///
+    /// If this is [`FdAction::Close`], this closes file descriptors, so all of the warnings on
+    /// [`CloseFdsBuilder::closefrom()`] apply.
+    #[inline]
+    pub unsafe fn apply(self, builder: &CloseFdsBuilder, minfd: libc::c_int) {
+        match self {
+            Self::Close => builder.closefrom(minfd),
+            Self::Cloexec => {
+                builder.cloexecfrom(minfd);
+            }
+        }
+    }
+}
+
+/// Get the recommended way to keep file descriptors from being inherited by a child process on
+/// the current platform, when running in the child just before `exec()` (for example, in
+/// `CommandExt::pre_exec()`).
+///
+/// On macOS, iOS, tvOS, and watchOS, this is [`FdAction::Cloexec`] (some sources indicate that
+/// closing file descriptors directly in the child may cause problems there, since system
+/// libraries may be using them). On all other platforms, it's [`FdAction::Close`].
+///
+/// This allows writing platform-independent code like this:
+///
+/// ```
+/// # use close_fds::{recommended_action, CloseFdsBuilder};
+/// # let keep_fds = [];
+/// # unsafe {
+/// recommended_action().apply(CloseFdsBuilder::new().keep_fds(&keep_fds), 3);
+/// # }
+/// ```
+#[inline]
+pub const fn recommended_action() -> FdAction {
+    if cfg!(target_vendor = "apple") {
+        FdAction::Cloexec
+    } else {
+        FdAction::Close
+    }
+}
+
+/// The maximum number of additional lists that can be added with
+/// [`CloseFdsBuilder::also_keep()`].
+const MAX_EXTRA_KEEP_FDS: usize = 4;
+
+/// The error returned by [`CloseFdsBuilder::also_keep()`] if the maximum number of additional
+/// lists (4) have already been added.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct TooManyKeepLists;
+
+impl core::fmt::Display for TooManyKeepLists {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "Too many lists passed to also_keep() (the maximum is {})",
+            MAX_EXTRA_KEEP_FDS
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TooManyKeepLists {}
+
 #[derive(Clone, Debug)]
 pub(crate) struct KeepFds<'a> {
     fds: &'a [libc::c_int],
-    max: libc::c_int,
-    sorted: bool,
+    extra: [&'a [libc::c_int]; MAX_EXTRA_KEEP_FDS],
+    nextra: usize,
+    // The maximum file descriptor in *all* of the lists
+    pub max: libc::c_int,
+    // Whether *all* of the lists are sorted
+    pub sorted: bool,
 }
 
 impl<'a> KeepFds<'a> {
@@ -143,6 +860,8 @@ impl<'a> KeepFds<'a> {
     pub fn empty() -> Self {
         Self {
             fds: &[],
+            extra: [&[]; MAX_EXTRA_KEEP_FDS],
+            nextra: 0,
             max: -1,
             sorted: true,
         }
@@ -151,7 +870,12 @@ impl<'a> KeepFds<'a> {
     #[inline]
     pub fn new(fds: &'a [libc::c_int]) -> Self {
         let (max, sorted) = crate::util::inspect_keep_fds(fds);
-        Self { fds, max, sorted }
+        Self {
+            fds,
+            max,
+            sorted,
+            ..Self::empty()
+        }
     }
 
     #[inline]
@@ -160,8 +884,129 @@ impl<'a> KeepFds<'a> {
             fds,
             max: fds.last().copied().unwrap_or(-1),
             sorted: true,
+            ..Self::empty()
+        }
+    }
+
+    pub fn push_extra(&mut self, fds: &'a [libc::c_int]) -> Result<(), TooManyKeepLists> {
+        if self.nextra >= MAX_EXTRA_KEEP_FDS {
+            return Err(TooManyKeepLists);
+        }
+
+        let (max, sorted) = crate::util::inspect_keep_fds(fds);
+        self.extra[self.nextra] = fds;
+        self.nextra += 1;
+        self.max = core::cmp::max(self.max, max);
+        self.sorted &= sorted;
+        Ok(())
+    }
+
+    /// Check whether keeping these file descriptors open and closing everything else starting at
+    /// `minfd` is the same as closing everything starting at 3 (i.e. the list is exactly
+    /// `[0, 1, 2]`, or `minfd` is 3 and nothing at or above it is kept).
+    ///
+    /// This is cheap enough to check before doing anything else.
+    #[inline]
+    pub fn is_stdio_only(&self, minfd: libc::c_int) -> bool {
+        match minfd {
+            3 => self.max < 3,
+            0..=2 => self.nextra == 0 && self.fds == [0, 1, 2],
+            _ => false,
+        }
+    }
+
+    /// Iterate over the file descriptors in all of the lists (in no particular order).
+    #[inline]
+    pub fn iter_all(&self) -> impl Iterator<Item = &'a libc::c_int> {
+        let extra: [&'a [libc::c_int]; MAX_EXTRA_KEEP_FDS] = self.extra;
+        self.fds
+            .iter()
+            .chain(IntoIterator::into_iter(extra).take(self.nextra).flatten())
+    }
+
+    /// Clear the close-on-exec flag on all of the file descriptors in the lists starting at
+    /// `minfd` (see `CloseFdsBuilder::make_kept_inheritable()`).
+    #[inline]
+    pub fn clear_cloexec(&self, minfd: libc::c_int) {
+        for &fd in self.iter_all() {
+            if fd >= minfd {
+                // Errors here just mean the file descriptor isn't open
+                let _ = crate::util::clear_cloexec(fd);
+            }
         }
     }
+
+    /// Try to simplify the keep list by adjusting `minfd` (see `util::simplify_keep_fds()`).
+    #[inline]
+    pub fn simplify(&mut self, minfd: &mut libc::c_int) {
+        self.fds = crate::util::simplify_keep_fds(self.fds, self.sorted, minfd);
+    }
+
+    /// Precompute the "gaps" between the file descriptors to keep, starting at `minfd`, if the
+    /// lists are sorted and small enough (see `gaps::KeepGaps`).
+    #[inline]
+    pub fn gaps(&self, minfd: libc::c_int) -> Option<gaps::KeepGaps> {
+        gaps::KeepGaps::new(minfd, self)
+    }
+
+    /// Check whether the given file descriptor should be kept.
+    ///
+    /// If the lists are sorted, this must be called with file descriptors in ascending order.
+    #[inline]
+    pub fn should_keep(&mut self, fd: libc::c_int) -> bool {
+        let sorted = self.sorted;
+
+        crate::util::check_should_keep(&mut self.fds, fd, sorted)
+            || self.extra[..self.nextra]
+                .iter_mut()
+                .any(|fds| crate::util::check_should_keep(fds, fd, sorted))
+    }
+
+    /// Iterate over the file descriptors in all of the lists in ascending order. This is only
+    /// meaningful if all of the lists are sorted.
+    #[inline]
+    pub fn iter_sorted(&self) -> SortedKeepFdsIter<'a> {
+        debug_assert!(self.sorted);
+
+        let mut lists = [&[][..]; MAX_EXTRA_KEEP_FDS + 1];
+        lists[0] = self.fds;
+        lists[1..].copy_from_slice(&self.extra);
+        SortedKeepFdsIter { lists }
+    }
+
+    /// Collect the file descriptors from all of the lists into a sorted, deduplicated `Vec`.
+    #[cfg(feature = "std")]
+    pub fn to_sorted_vec(&self) -> std::vec::Vec<libc::c_int> {
+        let mut fds = self.fds.to_vec();
+        for extra in self.extra[..self.nextra].iter() {
+            fds.extend_from_slice(extra);
+        }
+        fds.sort_unstable();
+        fds.dedup();
+        fds
+    }
+}
+
+/// Merges several sorted lists of file descriptors (without allocating memory).
+pub(crate) struct SortedKeepFdsIter<'a> {
+    lists: [&'a [libc::c_int]; MAX_EXTRA_KEEP_FDS + 1],
+}
+
+impl Iterator for SortedKeepFdsIter<'_> {
+    type Item = libc::c_int;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Find the list with the lowest first element and pop it off
+        let list = self
+            .lists
+            .iter_mut()
+            .filter(|list| !list.is_empty())
+            .min_by_key(|list| list[0])?;
+
+        let (&fd, rest) = list.split_first()?;
+        *list = rest;
+        Some(fd)
+    }
 }
 
 /// Identical to [`close_open_fds()`], but sets the `FD_CLOEXEC` flag on the file descriptors instead
@@ -175,6 +1020,17 @@ pub fn set_fds_cloexec(minfd: libc::c_int, keep_fds: &[libc::c_int]) {
     CloseFdsBuilder::new().keep_fds(keep_fds).cloexecfrom(minfd)
 }
 
+/// Identical to [`set_fds_cloexec()`], but returns a [`CloexecStats`] describing which file
+/// descriptors were affected.
+///
+/// This is equivalent to `CloseFdsBuilder::new().keep_fds(keep_fds).cloexecfrom_stats(minfd)`.
+#[inline]
+pub fn set_fds_cloexec_stats(minfd: libc::c_int, keep_fds: &[libc::c_int]) -> CloexecStats {
+    CloseFdsBuilder::new()
+        .keep_fds(keep_fds)
+        .cloexecfrom_stats(minfd)
+}
+
 /// Equivalent to `set_fds_cloexec()`, but behaves more reliably in multithreaded programs (at the
 /// cost of decreased performance on some platforms).
 ///
@@ -190,6 +1046,22 @@ pub fn set_fds_cloexec_threadsafe(minfd: libc::c_int, keep_fds: &[libc::c_int])
         .cloexecfrom(minfd)
 }
 
+/// Identical to [`set_fds_cloexec_threadsafe()`], but returns a [`CloexecStats`] describing which
+/// file descriptors were affected.
+///
+/// This is equivalent to
+/// `CloseFdsBuilder::new().keep_fds(keep_fds).threadsafe(true).cloexecfrom_stats(minfd)`.
+#[inline]
+pub fn set_fds_cloexec_threadsafe_stats(
+    minfd: libc::c_int,
+    keep_fds: &[libc::c_int],
+) -> CloexecStats {
+    CloseFdsBuilder::new()
+        .keep_fds(keep_fds)
+        .threadsafe(true)
+        .cloexecfrom_stats(minfd)
+}
+
 /// Close all open file descriptors starting at `minfd`, except for the file descriptors in
 /// `keep_fds`.
 ///
@@ -204,8 +1076,183 @@ pub unsafe fn close_open_fds(minfd: libc::c_int, keep_fds: &[libc::c_int]) {
     CloseFdsBuilder::new().keep_fds(keep_fds).closefrom(minfd)
 }
 
+/// Close all open file descriptors starting at `minfd`.
+///
+/// This uses the native `closefrom()` on FreeBSD, NetBSD, OpenBSD, and DragonFly BSD, and the
+/// `close_range()` system call on Linux 5.9+ and Android (where allowed; see the README). If
+/// neither is available, it falls back on finding the open file descriptors with the fastest
+/// method available (see [`FdIterBuilder`]) and closing them one by one.
+///
+/// Like the other functions in this crate, this is async-signal-safe (see the crate-level
+/// documentation), so it can be called in a child process between `fork()` and `exec()`.
+///
+/// This is equivalent to `close_open_fds(minfd, &[])` and `CloseFdsBuilder::new().closefrom(minfd)`.
+///
+/// # Safety
+///
+/// See [`CloseFdsBuilder::closefrom()`].
+#[inline]
+pub unsafe fn closefrom(minfd: libc::c_int) {
+    CloseFdsBuilder::new().closefrom(minfd)
+}
+
+/// Make sure that the open file descriptors starting at `minfd` (except for the ones in
+/// `keep_fds`) won't be inherited by a program executed by this process, using the
+/// platform-recommended method (see [`recommended_action()`]).
+///
+/// This closes them on most platforms (like [`close_open_fds()`]), but only sets the
+/// close-on-exec flag on them on macOS, iOS, tvOS, and watchOS (like [`set_fds_cloexec()`]).
+///
+/// This is equivalent to
+/// `recommended_action().apply(CloseFdsBuilder::new().keep_fds(keep_fds), minfd)`.
+///
+/// # Safety
+///
+/// See [`CloseFdsBuilder::closefrom()`]. (This is intended to be called in the child just before
+/// `exec()`, where those warnings are not a concern.)
+#[inline]
+pub unsafe fn close_or_cloexec_open_fds(minfd: libc::c_int, keep_fds: &[libc::c_int]) {
+    recommended_action().apply(CloseFdsBuilder::new().keep_fds(keep_fds), minfd)
+}
+
+/// Collect the file descriptors of the given objects (for example, `File`s or `TcpListener`s) into
+/// `buf`, and return the part of `buf` that was filled, sorted and deduplicated.
+///
+/// The result is suitable for passing to [`CloseFdsBuilder::keep_fds()`] (or
+/// [`CloseFdsBuilder::keep_fds_sorted()`]). Since it's written into a buffer provided by the
+/// caller, this doesn't allocate memory.
+///
+/// Returns `None` if `buf` is too small to hold all of the file descriptors.
+///
+/// # Example
+///
+/// ```
+/// # use close_fds::{collect_keep_fds, CloseFdsBuilder};
+/// let f1 = std::fs::File::open("/").unwrap();
+/// let f2 = std::fs::File::open("/").unwrap();
+///
+/// let mut buf = [0; 2];
+/// let keep_fds = collect_keep_fds(&[f1, f2], &mut buf).unwrap();
+///
+/// let mut builder = CloseFdsBuilder::new();
+/// // Safety: collect_keep_fds() sorts the file descriptors
+/// unsafe {
+///     builder.keep_fds_sorted(keep_fds);
+/// }
+/// ```
+#[cfg(feature = "std")]
+pub fn collect_keep_fds<'b, T, I>(objs: I, buf: &mut [libc::c_int]) -> Option<&[libc::c_int]>
+where
+    T: std::os::unix::io::AsRawFd + ?Sized + 'b,
+    I: IntoIterator<Item = &'b T>,
+{
+    let mut len = 0;
+    for obj in objs {
+        *buf.get_mut(len)? = obj.as_raw_fd();
+        len += 1;
+    }
+
+    let fds = &mut buf[..len];
+    fds.sort_unstable();
+
+    // Deduplicate in place
+    let mut n = 0;
+    for i in 0..fds.len() {
+        if i == 0 || fds[i] != fds[n - 1] {
+            fds[n] = fds[i];
+            n += 1;
+        }
+    }
+
+    Some(&buf[..n])
+}
+
 #[inline]
 pub(crate) fn probe() {
     close::probe();
     cloexec::probe();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keep_fds_extra() {
+        let mut keep_fds = KeepFds::new(&[3, 7]);
+        keep_fds.push_extra(&[]).unwrap();
+        keep_fds.push_extra(&[4, 7, 10]).unwrap();
+        keep_fds.push_extra(&[1]).unwrap();
+        assert_eq!(keep_fds.max, 10);
+        assert!(keep_fds.sorted);
+
+        let mut it = keep_fds.iter_sorted();
+        for &fd in [1, 3, 4, 7, 7, 10].iter() {
+            assert_eq!(it.next(), Some(fd));
+        }
+        assert_eq!(it.next(), None);
+
+        // should_keep() advances through the lists, so use a copy
+        let mut keep_fds2 = keep_fds.clone();
+        for fd in 0..12 {
+            assert_eq!(keep_fds2.should_keep(fd), [1, 3, 4, 7, 10].contains(&fd));
+        }
+
+        keep_fds.push_extra(&[9, 8]).unwrap();
+        assert_eq!(keep_fds.max, 10);
+        assert!(!keep_fds.sorted);
+
+        for fd in 0..12 {
+            assert_eq!(
+                keep_fds.should_keep(fd),
+                [1, 3, 4, 7, 8, 9, 10].contains(&fd)
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_keep_fds() {
+        // Use a file descriptor that's very unlikely to be open
+        let closed = libc::c_int::MAX - 1;
+
+        assert_eq!(CloseFdsBuilder::new().check_keep_fds(3), Ok(()));
+        assert_eq!(
+            CloseFdsBuilder::new().keep_fds(&[0]).check_keep_fds(0),
+            Ok(())
+        );
+        assert_eq!(
+            CloseFdsBuilder::new().keep_fds(&[0]).check_keep_fds(-5),
+            Ok(())
+        );
+
+        assert_eq!(
+            CloseFdsBuilder::new().keep_fds(&[-1]).check_keep_fds(0),
+            Err(KeepFdsProblem::Negative(-1))
+        );
+        assert_eq!(
+            CloseFdsBuilder::new().keep_fds(&[0, 1]).check_keep_fds(1),
+            Err(KeepFdsProblem::BelowMinfd(0))
+        );
+        assert_eq!(
+            CloseFdsBuilder::new()
+                .keep_fds(&[0])
+                .also_keep(&[])
+                .unwrap()
+                .also_keep(&[closed])
+                .unwrap()
+                .check_keep_fds(0),
+            Err(KeepFdsProblem::NotOpen(closed))
+        );
+        assert_eq!(KeepFdsProblem::NotOpen(closed).fd(), closed);
+    }
+
+    #[test]
+    fn test_keep_fds_extra_too_many() {
+        let mut keep_fds = KeepFds::empty();
+        for _ in 0..MAX_EXTRA_KEEP_FDS {
+            keep_fds.push_extra(&[]).unwrap();
+        }
+        assert_eq!(keep_fds.push_extra(&[1]), Err(TooManyKeepLists));
+        assert_eq!(keep_fds.max, -1);
+    }
+}
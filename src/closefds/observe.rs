@@ -0,0 +1,74 @@
+/// A decision made about a file descriptor (or a range of file descriptors) while closing file
+/// descriptors or setting them as close-on-exec. See [`CloseFdsBuilder::observer()`].
+///
+/// [`CloseFdsBuilder::observer()`]: ./struct.CloseFdsBuilder.html#method.observer
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum FdDecision {
+    /// The given file descriptor was found, but it was left alone because it's in the keep list.
+    Kept(libc::c_int),
+    /// The given file descriptor was closed.
+    Closed(libc::c_int),
+    /// The close-on-exec flag was set on the given file descriptor (or it was already set).
+    Cloexec(libc::c_int),
+    /// All of the file descriptors from `low` to `high` (inclusive) were closed with a single call
+    /// (e.g. `closefrom()` or `close_range()`). Not all of them were necessarily open; `high` is
+    /// often `c_int::MAX`.
+    ClosedRange { low: libc::c_int, high: libc::c_int },
+    /// The close-on-exec flag was set on all of the file descriptors from `low` to `high`
+    /// (inclusive) with a single call (e.g. `close_range(CLOSE_RANGE_CLOEXEC)`). Not all of them
+    /// were necessarily open; `high` is often `c_int::MAX`.
+    CloexecRange { low: libc::c_int, high: libc::c_int },
+}
+
+/// The (optional) observer that the closing and close-on-exec code reports its decisions to, and
+/// the (optional) counter that it reports its system calls to.
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct Observer {
+    pub func: Option<fn(FdDecision)>,
+    pub counter: crate::counter::Counter,
+}
+
+impl Observer {
+    #[inline]
+    pub fn notify(self, decision: FdDecision) {
+        if let Some(func) = self.func {
+            func(decision);
+        }
+    }
+
+    #[inline]
+    pub fn kept(self, fd: libc::c_int) {
+        self.notify(FdDecision::Kept(fd));
+    }
+
+    #[inline]
+    pub fn cloexec(self, fd: libc::c_int) {
+        self.notify(FdDecision::Cloexec(fd));
+    }
+
+    #[inline]
+    pub fn closed_range(self, low: libc::c_int, high: libc::c_int) {
+        self.notify(FdDecision::ClosedRange { low, high });
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[inline]
+    pub fn cloexec_range(self, low: libc::c_int, high: libc::c_int) {
+        self.notify(FdDecision::CloexecRange { low, high });
+    }
+
+    /// Close the given file descriptor, and report it unless it wasn't open.
+    #[inline]
+    pub unsafe fn close(self, fd: libc::c_int) {
+        let ret = libc::close(fd);
+        self.counter.close();
+
+        if let Some(func) = self.func {
+            // Other errors (like EINTR) still leave the file descriptor closed
+            if ret == 0 || crate::util::errno() != libc::EBADF {
+                func(FdDecision::Closed(fd));
+            }
+        }
+    }
+}
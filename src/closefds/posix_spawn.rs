@@ -0,0 +1,312 @@
+use std::ffi::CStr;
+use std::io;
+
+#[cfg(not(target_os = "android"))]
+use super::spawn::ExecArgs;
+use super::CloseFdsBuilder;
+
+/// Identical to [`spawn_with_closed_fds()`](./fn.spawn_with_closed_fds.html), but uses
+/// `posix_spawn()` instead of `fork()` and `execve()`.
+///
+/// The file descriptors are closed using "file actions" that are generated ahead of time. How this
+/// is done depends on the platform:
+///
+/// - On macOS and iOS, the `POSIX_SPAWN_CLOEXEC_DEFAULT` flag is used, and the file descriptors
+///   that should be kept are explicitly inherited. This is reliable even if other threads are
+///   opening file descriptors concurrently.
+/// - Elsewhere, the file descriptors that are currently open and should be closed are found (in
+///   the parent), and a "close" action is added for each of them. If
+///   `posix_spawn_file_actions_addclosefrom_np()` is available (glibc 2.34+, FreeBSD 13.1+,
+///   Solaris, Illumos), it is used to close all file descriptors above the highest kept file
+///   descriptor.
+///
+///   Note that with this approach, file descriptors opened by other threads after they are found
+///   (and below the point where `posix_spawn_file_actions_addclosefrom_np()` takes over, if it's
+///   available) will not be closed.
+///
+/// As with [`spawn_with_closed_fds()`](./fn.spawn_with_closed_fds.html), the child's signal mask
+/// and the disposition of `SIGPIPE` are reset to the defaults.
+///
+/// On Android (where `posix_spawn()` is only available in newer versions), this is currently
+/// equivalent to [`spawn_with_closed_fds()`](./fn.spawn_with_closed_fds.html).
+pub fn posix_spawn_with_closed_fds(
+    path: &CStr,
+    argv: &[&CStr],
+    envp: Option<&[&CStr]>,
+    minfd: libc::c_int,
+    builder: &CloseFdsBuilder,
+) -> io::Result<libc::pid_t> {
+    #[cfg(target_os = "android")]
+    return super::spawn_with_closed_fds(path, argv, envp, minfd, builder);
+
+    #[cfg(not(target_os = "android"))]
+    return {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("posix_spawn_with_closed_fds", minfd).entered();
+
+        let args = ExecArgs::new(path, argv, envp)?;
+        let minfd = core::cmp::max(minfd, 0);
+
+        let mut actions = FileActions::new()?;
+        let mut attr = SpawnAttr::new()?;
+
+        attr.setup(
+            libc::POSIX_SPAWN_SETSIGMASK as libc::c_short
+                | libc::POSIX_SPAWN_SETSIGDEF as libc::c_short,
+        )?;
+        add_close_actions(&mut actions, &mut attr, minfd, builder)?;
+
+        let mut pid = 0;
+        match unsafe {
+            libc::posix_spawn(
+                &mut pid,
+                args.path.as_ptr(),
+                &actions.0,
+                &attr.0,
+                args.argv.as_ptr() as *const *mut libc::c_char,
+                args.envp.as_ptr() as *const *mut libc::c_char,
+            )
+        } {
+            0 => Ok(pid),
+            eno => Err(io::Error::from_raw_os_error(eno)),
+        }
+    };
+}
+
+#[cfg(not(target_os = "android"))]
+fn check(eno: libc::c_int) -> io::Result<()> {
+    if eno == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(eno))
+    }
+}
+
+#[cfg(not(target_os = "android"))]
+struct FileActions(libc::posix_spawn_file_actions_t);
+
+#[cfg(not(target_os = "android"))]
+impl FileActions {
+    fn new() -> io::Result<Self> {
+        let mut actions = core::mem::MaybeUninit::uninit();
+        check(unsafe { libc::posix_spawn_file_actions_init(actions.as_mut_ptr()) })?;
+        Ok(Self(unsafe { actions.assume_init() }))
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    fn add_close(&mut self, fd: libc::c_int) -> io::Result<()> {
+        check(unsafe { libc::posix_spawn_file_actions_addclose(&mut self.0, fd) })
+    }
+}
+
+#[cfg(not(target_os = "android"))]
+impl Drop for FileActions {
+    fn drop(&mut self) {
+        unsafe {
+            libc::posix_spawn_file_actions_destroy(&mut self.0);
+        }
+    }
+}
+
+#[cfg(not(target_os = "android"))]
+struct SpawnAttr(libc::posix_spawnattr_t);
+
+#[cfg(not(target_os = "android"))]
+impl SpawnAttr {
+    fn new() -> io::Result<Self> {
+        let mut attr = core::mem::MaybeUninit::uninit();
+        check(unsafe { libc::posix_spawnattr_init(attr.as_mut_ptr()) })?;
+        Ok(Self(unsafe { attr.assume_init() }))
+    }
+
+    /// Set the given flags, an empty signal mask, and `SIGPIPE` as the only signal to reset.
+    fn setup(&mut self, flags: libc::c_short) -> io::Result<()> {
+        unsafe {
+            let mut sigset = core::mem::MaybeUninit::uninit();
+            libc::sigemptyset(sigset.as_mut_ptr());
+            check(libc::posix_spawnattr_setsigmask(
+                &mut self.0,
+                sigset.as_ptr(),
+            ))?;
+
+            libc::sigaddset(sigset.as_mut_ptr(), libc::SIGPIPE);
+            check(libc::posix_spawnattr_setsigdefault(
+                &mut self.0,
+                sigset.as_ptr(),
+            ))?;
+
+            check(libc::posix_spawnattr_setflags(&mut self.0, flags))
+        }
+    }
+}
+
+#[cfg(not(target_os = "android"))]
+impl Drop for SpawnAttr {
+    fn drop(&mut self) {
+        unsafe {
+            libc::posix_spawnattr_destroy(&mut self.0);
+        }
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn add_close_actions(
+    actions: &mut FileActions,
+    attr: &mut SpawnAttr,
+    minfd: libc::c_int,
+    builder: &CloseFdsBuilder,
+) -> io::Result<()> {
+    let mut flags = 0;
+    check(unsafe { libc::posix_spawnattr_getflags(&attr.0, &mut flags) })?;
+    check(unsafe {
+        libc::posix_spawnattr_setflags(
+            &mut attr.0,
+            flags | libc::POSIX_SPAWN_CLOEXEC_DEFAULT as libc::c_short,
+        )
+    })?;
+
+    // Everything that isn't explicitly inherited will be closed, so inherit the file descriptors
+    // below minfd and the ones that should be kept (if they're open).
+    let keep_fds = builder.keep_fds.to_sorted_vec();
+
+    let mut it = builder.it.clone();
+    it.threadsafe_auto();
+
+    #[cfg(feature = "tracing")]
+    let (start, mut scanned, mut inherited) = (std::time::Instant::now(), 0usize, 0usize);
+
+    for fd in it.iter_from(0) {
+        #[cfg(feature = "tracing")]
+        {
+            scanned += 1;
+        }
+
+        if fd < minfd || keep_fds.binary_search(&fd).is_ok() {
+            check(unsafe {
+                crate::ffi::posix_spawn_file_actions_addinherit_np(&mut actions.0, fd)
+            })?;
+
+            #[cfg(feature = "tracing")]
+            {
+                inherited += 1;
+            }
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        strategy = "cloexec_default",
+        scanned,
+        inherited,
+        elapsed_us = start.elapsed().as_micros() as u64,
+        "generated file actions"
+    );
+
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "android",)))]
+fn add_close_actions(
+    actions: &mut FileActions,
+    _attr: &mut SpawnAttr,
+    minfd: libc::c_int,
+    builder: &CloseFdsBuilder,
+) -> io::Result<()> {
+    let keep_fds = builder.keep_fds.to_sorted_vec();
+
+    let mut it = builder.it.clone();
+    it.threadsafe_auto();
+
+    let addclosefrom = find_addclosefrom();
+
+    // If posix_spawn_file_actions_addclosefrom_np() is available, we only have to add individual
+    // "close" actions up to the highest file descriptor that we're keeping
+    let closefrom_fd = match (addclosefrom, keep_fds.last()) {
+        (Some(_), Some(&maxfd)) if maxfd >= minfd => maxfd.checked_add(1),
+        (Some(_), _) => Some(minfd),
+        (None, _) => None,
+    };
+
+    #[cfg(feature = "tracing")]
+    let (start, mut scanned, mut closed) = (std::time::Instant::now(), 0usize, 0usize);
+
+    for fd in it.iter_from(minfd) {
+        match closefrom_fd {
+            Some(closefrom_fd) if fd >= closefrom_fd => break,
+            _ => (),
+        }
+
+        #[cfg(feature = "tracing")]
+        {
+            scanned += 1;
+        }
+
+        if keep_fds.binary_search(&fd).is_err() {
+            actions.add_close(fd)?;
+
+            #[cfg(feature = "tracing")]
+            {
+                closed += 1;
+            }
+        }
+    }
+
+    if let (Some(addclosefrom), Some(closefrom_fd)) = (addclosefrom, closefrom_fd) {
+        check(unsafe { addclosefrom(&mut actions.0, closefrom_fd) })?;
+    }
+
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        strategy = if addclosefrom.is_some() {
+            "addclosefrom"
+        } else {
+            "close_actions"
+        },
+        closefrom_fd,
+        scanned,
+        closed,
+        elapsed_us = start.elapsed().as_micros() as u64,
+        "generated file actions"
+    );
+
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "android",)))]
+type AddCloseFromFn =
+    unsafe extern "C" fn(*mut libc::posix_spawn_file_actions_t, libc::c_int) -> libc::c_int;
+
+#[cfg(any(target_os = "solaris", target_os = "illumos"))]
+fn find_addclosefrom() -> Option<AddCloseFromFn> {
+    Some(libc::posix_spawn_file_actions_addclosefrom_np)
+}
+
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+fn find_addclosefrom() -> Option<AddCloseFromFn> {
+    // This was only added in glibc 2.34 and FreeBSD 13.1, so we have to look it up at runtime
+    let ptr = unsafe {
+        libc::dlsym(
+            libc::RTLD_DEFAULT,
+            b"posix_spawn_file_actions_addclosefrom_np\0".as_ptr() as *const libc::c_char,
+        )
+    };
+
+    if ptr.is_null() {
+        None
+    } else {
+        Some(unsafe { core::mem::transmute::<*mut libc::c_void, AddCloseFromFn>(ptr) })
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "freebsd",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "android",
+)))]
+fn find_addclosefrom() -> Option<AddCloseFromFn> {
+    None
+}
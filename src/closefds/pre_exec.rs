@@ -0,0 +1,349 @@
+use std::boxed::Box;
+use std::fmt;
+use std::io;
+use std::vec::Vec;
+
+use super::{cloexec, CloseFdsBuilder, KeepFds};
+
+impl<'a> CloseFdsBuilder<'a> {
+    /// Create a closure, suitable for passing to `pre_exec()`, that will set the close-on-exec
+    /// flag on all file descriptors starting at `minfd` (except the ones passed to
+    /// [`Self::keep_fds()`]).
+    ///
+    /// The returned closure satisfies the `FnMut() -> io::Result<()> + Send + Sync + 'static`
+    /// bounds required by both `std::os::unix::process::CommandExt::pre_exec()` and
+    /// `tokio::process::Command::pre_exec()`. The list of file descriptors to keep is copied when
+    /// this method is called (in the parent), so the closure itself does not allocate memory and
+    /// is async-signal-safe.
+    ///
+    /// Note that the closure uses [`Self::cloexecfrom()`], not [`Self::closefrom()`]. The standard
+    /// library reports errors from `exec()` back to the parent using a close-on-exec pipe; closing
+    /// that pipe in `pre_exec()` would cause those errors to be silently lost. Setting the
+    /// close-on-exec flag has the same end result once `exec()` succeeds.
+    pub fn pre_exec_fn(
+        &self,
+        minfd: libc::c_int,
+    ) -> impl FnMut() -> io::Result<()> + Send + Sync + 'static {
+        let keep_fds = self.keep_fds.to_sorted_vec().into_boxed_slice();
+        let it = self.it.clone();
+        let blind = self.blind_cloexec;
+        let observer = self.observer;
+        let closer = self.closer;
+        let inheritable = self.make_kept_inheritable;
+
+        move || {
+            let minfd = core::cmp::max(minfd, 0);
+            // Safety: to_sorted_vec() sorts the list
+            let keep_fds = unsafe { KeepFds::new_sorted(&keep_fds) };
+
+            cloexec::set_fds_cloexec(minfd, keep_fds.clone(), it.clone(), blind, observer, closer);
+            if inheritable {
+                keep_fds.clear_cloexec(minfd);
+            }
+            Ok(())
+        }
+    }
+
+    /// Like [`Self::pre_exec_fn()`], but the returned closure first moves file descriptors into
+    /// place in the child as described by `mappings`.
+    ///
+    /// For each [`FdMapping`], `parent_fd` is duplicated onto `child_fd` (with the close-on-exec
+    /// flag cleared), so that the executed program will see it as `child_fd`. Mappings are applied
+    /// "simultaneously": a mapping's `child_fd` may be the same as another mapping's `parent_fd`.
+    ///
+    /// The `child_fd`s are then left alone (in addition to any file descriptors passed to
+    /// [`Self::keep_fds()`]), and the close-on-exec flag is set on every other file descriptor
+    /// starting at `minfd`.
+    ///
+    /// An error is returned if two mappings specify the same `child_fd`, or if any of the file
+    /// descriptors are negative.
+    pub fn pre_exec_fn_with_mappings(
+        &self,
+        minfd: libc::c_int,
+        mappings: &[FdMapping],
+    ) -> Result<impl FnMut() -> io::Result<()> + Send + Sync + 'static, FdMappingCollision> {
+        let mut keep_fds = self.keep_fds.to_sorted_vec();
+
+        // The highest file descriptor number that we need to worry about clobbering
+        let mut maxfd = self.keep_fds.max;
+
+        for (i, mapping) in mappings.iter().enumerate() {
+            if mapping.parent_fd < 0
+                || mapping.child_fd < 0
+                || mappings[..i].iter().any(|m| m.child_fd == mapping.child_fd)
+            {
+                return Err(FdMappingCollision(mapping.child_fd));
+            }
+
+            keep_fds.push(mapping.child_fd);
+            maxfd = maxfd.max(mapping.parent_fd).max(mapping.child_fd);
+        }
+
+        keep_fds.sort_unstable();
+        keep_fds.dedup();
+        let keep_fds = keep_fds.into_boxed_slice();
+
+        let mappings: Box<[FdMapping]> = mappings.into();
+        // Space to record the temporary file descriptors in (allocated here so the closure doesn't
+        // have to allocate memory)
+        let mut tmpfds: Box<[libc::c_int]> = mappings.iter().map(|_| -1).collect();
+        let it = self.it.clone();
+        let blind = self.blind_cloexec;
+        let observer = self.observer;
+        let closer = self.closer;
+        let inheritable = self.make_kept_inheritable;
+
+        Ok(move || {
+            // First, duplicate every parent_fd to a temporary file descriptor above all of the
+            // file descriptors we care about. This way, a mapping's child_fd can't clobber another
+            // mapping's parent_fd.
+            //
+            // F_DUPFD_CLOEXEC picks the lowest *free* file descriptor, so nothing that's already
+            // open gets clobbered (in particular, the close-on-exec pipe that the standard library
+            // uses to report exec() errors). File descriptors below minfd are implicitly kept, so
+            // we have to stay above those too.
+            let tmpbase = core::cmp::max(maxfd + 1, minfd);
+            for i in 0..mappings.len() {
+                let tmpfd =
+                    unsafe { libc::fcntl(mappings[i].parent_fd, libc::F_DUPFD_CLOEXEC, tmpbase) };
+                if tmpfd < 0 {
+                    let err = io::Error::last_os_error();
+                    for &fd in tmpfds[..i].iter() {
+                        unsafe {
+                            libc::close(fd);
+                        }
+                    }
+                    return Err(err);
+                }
+                tmpfds[i] = tmpfd;
+            }
+
+            // Now move them into place (dup2() clears the close-on-exec flag) and close the
+            // temporary file descriptors
+            for (&tmpfd, mapping) in tmpfds.iter().zip(mappings.iter()) {
+                if unsafe { libc::dup2(tmpfd, mapping.child_fd) } < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                unsafe {
+                    libc::close(tmpfd);
+                }
+            }
+
+            let minfd = core::cmp::max(minfd, 0);
+            // Safety: keep_fds was sorted above
+            let keep_fds = unsafe { KeepFds::new_sorted(&keep_fds) };
+
+            cloexec::set_fds_cloexec(minfd, keep_fds.clone(), it.clone(), blind, observer, closer);
+            if inheritable {
+                keep_fds.clear_cloexec(minfd);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// The type of a single step in a [`PreExecPipeline`].
+type PreExecStep = Box<dyn FnMut() -> io::Result<()> + Send + Sync>;
+
+/// Combines several `pre_exec()` steps into a single closure, making sure that closing file
+/// descriptors (or setting the close-on-exec flag on them) always happens *last*.
+///
+/// `pre_exec()` closures that need file descriptors (for example, to `dup2()` them into place or
+/// to `ioctl()` a terminal) must run before any closing step, or they may fail in confusing ways.
+/// A `PreExecPipeline` runs the steps added with [`Self::step()`] in the order they were added,
+/// and then runs the closing step set with [`Self::close_fds()`] (if any), no matter what order
+/// the methods were called in. If a step fails, the remaining steps are not run.
+///
+/// # Example
+///
+/// ```
+/// # use std::os::unix::process::CommandExt;
+/// # use close_fds::{CloseFdsBuilder, PreExecPipeline};
+/// let mut cmd = std::process::Command::new("true");
+///
+/// let f = PreExecPipeline::new()
+///     .close_fds(3, &CloseFdsBuilder::new())
+///     .step(|| {
+///         // Runs before the file descriptors are closed
+///         Ok(())
+///     })
+///     .build();
+///
+/// unsafe {
+///     cmd.pre_exec(f);
+/// }
+/// assert!(cmd.status().unwrap().success());
+/// ```
+#[derive(Default)]
+pub struct PreExecPipeline {
+    steps: Vec<PreExecStep>,
+    close: Option<PreExecStep>,
+}
+
+impl PreExecPipeline {
+    /// Create a new, empty pipeline.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a step to the pipeline. It will run after all of the steps that were added before it,
+    /// but before the closing step.
+    ///
+    /// As with any `pre_exec()` closure, `step` should only perform async-signal-safe operations.
+    pub fn step<F>(mut self, step: F) -> Self
+    where
+        F: FnMut() -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.steps.push(Box::new(step));
+        self
+    }
+
+    /// Add a step that replaces the standard file descriptors as specified by `stdin`, `stdout`,
+    /// and `stderr` (see [`redirect_stdio()`](crate::redirect_stdio)). Like any other step, it
+    /// runs before the closing step.
+    pub fn redirect_stdio(
+        self,
+        stdin: super::StdioSource,
+        stdout: super::StdioSource,
+        stderr: super::StdioSource,
+    ) -> Self {
+        self.step(move || {
+            // Safety: This only runs in the child
+            unsafe { super::redirect_stdio(stdin, stdout, stderr) }
+                .map_err(io::Error::from_raw_os_error)
+        })
+    }
+
+    /// Set the closing step, which will set the close-on-exec flag on all file descriptors
+    /// starting at `minfd` (except the ones passed to [`CloseFdsBuilder::keep_fds()`]) after all
+    /// other steps have run.
+    ///
+    /// This uses [`CloseFdsBuilder::pre_exec_fn()`]. Calling this again replaces the previous
+    /// closing step.
+    pub fn close_fds(mut self, minfd: libc::c_int, builder: &CloseFdsBuilder) -> Self {
+        self.close = Some(Box::new(builder.pre_exec_fn(minfd)));
+        self
+    }
+
+    /// Like [`Self::close_fds()`], but the closing step first moves file descriptors into place as
+    /// described by `mappings` (see [`CloseFdsBuilder::pre_exec_fn_with_mappings()`]).
+    pub fn close_fds_with_mappings(
+        mut self,
+        minfd: libc::c_int,
+        builder: &CloseFdsBuilder,
+        mappings: &[FdMapping],
+    ) -> Result<Self, FdMappingCollision> {
+        self.close = Some(Box::new(
+            builder.pre_exec_fn_with_mappings(minfd, mappings)?,
+        ));
+        Ok(self)
+    }
+
+    /// Produce a single closure, suitable for passing to `pre_exec()`, that runs all of the steps.
+    ///
+    /// Running the pipeline does not allocate memory, so the closure is async-signal-safe as long
+    /// as all of the steps are.
+    pub fn build(self) -> impl FnMut() -> io::Result<()> + Send + Sync + 'static {
+        let Self {
+            mut steps,
+            mut close,
+        } = self;
+
+        move || {
+            for step in steps.iter_mut() {
+                step()?;
+            }
+
+            if let Some(close) = close.as_mut() {
+                close()?;
+            }
+
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Debug for PreExecPipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PreExecPipeline")
+            .field("steps", &self.steps.len())
+            .field("close", &self.close.is_some())
+            .finish()
+    }
+}
+
+/// A mapping from a file descriptor in the parent to a file descriptor in the child.
+///
+/// See [`CloseFdsBuilder::pre_exec_fn_with_mappings()`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct FdMapping {
+    /// The file descriptor in the parent process.
+    ///
+    /// This must remain open until the child process has been spawned.
+    pub parent_fd: libc::c_int,
+    /// The number that the file descriptor should have in the child process.
+    pub child_fd: libc::c_int,
+}
+
+/// The error returned by [`CloseFdsBuilder::pre_exec_fn_with_mappings()`] if two mappings
+/// specify the same `child_fd` (or if a mapping contains a negative file descriptor).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FdMappingCollision(pub libc::c_int);
+
+impl fmt::Display for FdMappingCollision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid or duplicate child file descriptor {}", self.0)
+    }
+}
+
+impl std::error::Error for FdMappingCollision {}
+
+/// An extension trait for `tokio::process::Command` (requires the `tokio` feature).
+#[cfg(feature = "tokio")]
+pub trait TokioCommandExt {
+    /// Set the close-on-exec flag on all file descriptors starting at `minfd` (except the ones
+    /// passed to [`CloseFdsBuilder::keep_fds()`]) in the child process before it is executed.
+    ///
+    /// This is equivalent to `cmd.pre_exec(builder.pre_exec_fn(minfd))`. See
+    /// [`CloseFdsBuilder::pre_exec_fn()`] for more information.
+    fn close_fds_on_exec(&mut self, minfd: libc::c_int, builder: &CloseFdsBuilder) -> &mut Self;
+}
+
+#[cfg(feature = "tokio")]
+impl TokioCommandExt for tokio::process::Command {
+    #[inline]
+    fn close_fds_on_exec(&mut self, minfd: libc::c_int, builder: &CloseFdsBuilder) -> &mut Self {
+        // Safety: The closure returned by pre_exec_fn() only performs async-signal-safe
+        // operations, and it only sets the close-on-exec flag (so it can't interfere with any file
+        // descriptors that the child needs before calling exec()).
+        unsafe { self.pre_exec(builder.pre_exec_fn(minfd)) }
+    }
+}
+
+#[cfg(feature = "async-process")]
+impl CloseFdsBuilder<'_> {
+    /// Convert `cmd` into an `async_process::Command` (which is also used by `smol` as
+    /// `smol::process::Command`) that will set the close-on-exec flag on all file descriptors
+    /// starting at `minfd` (except the ones passed to [`Self::keep_fds()`]) in the child process
+    /// before it is executed. Requires the `async-process` feature.
+    ///
+    /// `async_process::Command` doesn't expose `pre_exec()`, so this registers
+    /// [`Self::pre_exec_fn()`] on the `std::process::Command` first and then converts it (which
+    /// preserves the closure). Any other configuration should also be done on `cmd` beforehand,
+    /// or on the returned command.
+    pub fn async_process_command(
+        &self,
+        mut cmd: std::process::Command,
+        minfd: libc::c_int,
+    ) -> async_process::Command {
+        use std::os::unix::process::CommandExt;
+
+        // Safety: See TokioCommandExt::close_fds_on_exec()
+        unsafe {
+            cmd.pre_exec(self.pre_exec_fn(minfd));
+        }
+
+        cmd.into()
+    }
+}
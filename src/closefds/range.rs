@@ -0,0 +1,157 @@
+use core::fmt;
+
+/// A flag for [`close_range()`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum CloseRangeFlag {
+    /// Set the close-on-exec flag on the file descriptors instead of closing them
+    /// (`CLOSE_RANGE_CLOEXEC`; Linux 5.11+ and FreeBSD 13.1+).
+    Cloexec,
+    /// Unshare the file descriptor table before closing the file descriptors, if it's shared with
+    /// another process (`CLOSE_RANGE_UNSHARE`; Linux only). See
+    /// [`SharedFdTablePolicy::Unshare`](crate::SharedFdTablePolicy::Unshare).
+    Unshare,
+}
+
+/// The error returned by [`close_range()`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum CloseRangeError {
+    /// `close_range()` (or one of the requested flags) isn't supported on this platform or by the
+    /// running kernel, or this crate has been configured not to use it. Nothing was done.
+    Unsupported,
+    /// `close_range()` failed with the given error code.
+    Os(libc::c_int),
+}
+
+impl fmt::Display for CloseRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Unsupported => f.write_str("close_range() is not supported"),
+            Self::Os(eno) => write!(f, "close_range() failed with error code {}", eno),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CloseRangeError {}
+
+/// Close all of the file descriptors from `low` to `high` (inclusive) with the `close_range()`
+/// system call, or apply `flags` to them.
+///
+/// This uses the same detection as the rest of this crate: once `close_range()` (or
+/// `CLOSE_RANGE_CLOEXEC`) has been found to be unavailable, later calls fail with
+/// [`CloseRangeError::Unsupported`] without making the system call again. It's also unsupported
+/// if the `deterministic-syscalls` or `libc-wrappers-only` feature is enabled, on Android before
+/// API level 34 (see the OS-specific notes in the README), and on platforms other than Linux,
+/// Android, and FreeBSD.
+///
+/// Not all of the file descriptors in the range have to be open; pass `c_uint::MAX` as `high` to
+/// close everything starting at `low`. If `low > high`, `EINVAL` is returned.
+///
+/// This is async-signal-safe.
+///
+/// # Safety
+///
+/// Unless [`CloseRangeFlag::Cloexec`] is passed, this closes file descriptors, so all of the
+/// warnings on [`CloseFdsBuilder::closefrom()`](crate::CloseFdsBuilder::closefrom) apply.
+pub unsafe fn close_range(
+    low: libc::c_uint,
+    high: libc::c_uint,
+    flags: &[CloseRangeFlag],
+) -> Result<(), CloseRangeError> {
+    if low > high {
+        return Err(CloseRangeError::Os(libc::EINVAL));
+    }
+
+    sys_close_range(
+        low,
+        high,
+        flags.contains(&CloseRangeFlag::Cloexec),
+        flags.contains(&CloseRangeFlag::Unshare),
+    )
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn sys_close_range(
+    low: libc::c_uint,
+    high: libc::c_uint,
+    cloexec: bool,
+    unshare: bool,
+) -> Result<(), CloseRangeError> {
+    use core::sync::atomic::Ordering;
+
+    if !super::close::may_have_close_range()
+        || (cloexec && !super::cloexec::may_have_close_range_cloexec())
+    {
+        return Err(CloseRangeError::Unsupported);
+    }
+
+    let mut raw_flags = 0;
+    if cloexec {
+        raw_flags |= crate::sys::CLOSE_RANGE_CLOEXEC;
+    }
+    if unshare {
+        raw_flags |= crate::sys::CLOSE_RANGE_UNSHARE;
+    }
+
+    if libc::syscall(libc::SYS_close_range, low, high, raw_flags) == 0 {
+        return Ok(());
+    }
+
+    match crate::util::errno() {
+        // Not supported by the kernel, or blocked by a seccomp filter
+        libc::ENOSYS | libc::EPERM => {
+            super::close::MAY_HAVE_CLOSE_RANGE.store(false, Ordering::Relaxed);
+            super::cloexec::MAY_HAVE_CLOSE_RANGE_CLOEXEC.store(false, Ordering::Relaxed);
+            Err(CloseRangeError::Unsupported)
+        }
+        // We've already checked the range, so the kernel doesn't recognize CLOSE_RANGE_CLOEXEC
+        libc::EINVAL if cloexec => {
+            super::cloexec::MAY_HAVE_CLOSE_RANGE_CLOEXEC.store(false, Ordering::Relaxed);
+            Err(CloseRangeError::Unsupported)
+        }
+        eno => Err(CloseRangeError::Os(eno)),
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+unsafe fn sys_close_range(
+    low: libc::c_uint,
+    high: libc::c_uint,
+    cloexec: bool,
+    unshare: bool,
+) -> Result<(), CloseRangeError> {
+    if unshare || super::close::check_has_close_range().is_err() {
+        return Err(CloseRangeError::Unsupported);
+    }
+
+    let raw_flags = if cloexec {
+        crate::sys::CLOSE_RANGE_CLOEXEC
+    } else {
+        0
+    };
+
+    if libc::syscall(crate::sys::SYS_CLOSE_RANGE, low, high, raw_flags) == 0 {
+        return Ok(());
+    }
+
+    match crate::util::errno() {
+        // We've already checked the range, so the kernel doesn't recognize CLOSE_RANGE_CLOEXEC
+        // (it was added in FreeBSD 13.1)
+        libc::EINVAL if cloexec => Err(CloseRangeError::Unsupported),
+        eno => Err(CloseRangeError::Os(eno)),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+#[allow(unused_variables)]
+#[inline]
+unsafe fn sys_close_range(
+    low: libc::c_uint,
+    high: libc::c_uint,
+    cloexec: bool,
+    unshare: bool,
+) -> Result<(), CloseRangeError> {
+    Err(CloseRangeError::Unsupported)
+}
@@ -0,0 +1,124 @@
+use std::ffi::{CStr, CString, OsStr};
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::vec::Vec;
+
+use super::spawn::ExecArgs;
+use super::CloseFdsBuilder;
+
+/// Give the current process a clean file descriptor table by closing unexpected file descriptors,
+/// and then re-execute the current program so it also starts with fresh address-space state.
+///
+/// This is intended to be called at the very beginning of `main()` in setuid/setgid programs (or
+/// other programs that may be started by untrusted parents). It works like this:
+///
+/// 1. All file descriptors starting at 3 (except the ones passed to
+///    [`CloseFdsBuilder::keep_fds()`]) are closed, as with [`CloseFdsBuilder::closefrom()`].
+/// 2. If any of the standard file descriptors (0, 1, and 2) are closed, they are opened to
+///    `/dev/null`. This prevents files that the program opens later from being mistaken for
+///    stdin/stdout/stderr.
+/// 3. If the environment variable named `marker` is set, this process has already been
+///    re-executed; the variable is removed from the environment and `Ok(())` is returned.
+/// 4. Otherwise, the current program is re-executed with the same arguments and environment, plus
+///    `marker` set to `1`. (On Linux and Android, `/proc/self/exe` is executed if possible, to
+///    avoid races with the program being replaced on disk.)
+///
+/// As a result, this function only returns `Ok(())` in the re-executed process. If re-executing
+/// fails, an error is returned.
+///
+/// Note that the marker is *not* a security boundary: an attacker can set it to skip the
+/// re-execution. However, steps 1 and 2 are performed in either case, so the file descriptor
+/// table is always sanitized.
+///
+/// This is not available on tvOS or watchOS, where `exec()` is prohibited.
+///
+/// # Safety
+///
+/// This closes file descriptors, so all of the warnings on [`CloseFdsBuilder::closefrom()`]
+/// apply. It should be called before any other threads are started and before any files are
+/// opened.
+pub unsafe fn sanitize_and_reexec(marker: &OsStr, builder: &CloseFdsBuilder) -> io::Result<()> {
+    let reexecuted = std::env::var_os(marker).is_some();
+
+    // Prepare everything that requires memory allocation (or opening files) *before* closing any
+    // file descriptors
+    let args = if reexecuted {
+        None
+    } else {
+        Some(ReexecArgs::new(marker)?)
+    };
+
+    builder.closefrom(3);
+    super::harden::open_std_fds()?;
+
+    match args {
+        Some(args) => Err(args.exec()),
+        None => {
+            std::env::remove_var(marker);
+            Ok(())
+        }
+    }
+}
+
+struct ReexecArgs {
+    exe: CString,
+    argv: Vec<CString>,
+    env: Vec<CString>,
+}
+
+impl ReexecArgs {
+    fn new(marker: &OsStr) -> io::Result<Self> {
+        let cstring = |bytes: Vec<u8>| {
+            CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+        };
+
+        let exe = cstring(std::env::current_exe()?.into_os_string().into_vec())?;
+
+        let argv = std::env::args_os()
+            .map(|arg| cstring(arg.into_vec()))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut env = std::env::vars_os()
+            .filter(|(key, _)| key != marker)
+            .map(|(key, val)| {
+                let mut entry = key.into_vec();
+                entry.push(b'=');
+                entry.extend_from_slice(val.as_bytes());
+                cstring(entry)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut entry = marker.as_bytes().to_vec();
+        entry.extend_from_slice(b"=1");
+        env.push(cstring(entry)?);
+
+        Ok(Self { exe, argv, env })
+    }
+
+    /// Execute the program. This only returns if that fails.
+    fn exec(&self) -> io::Error {
+        let argv: Vec<&CStr> = self.argv.iter().map(|s| s.as_c_str()).collect();
+        let env: Vec<&CStr> = self.env.iter().map(|s| s.as_c_str()).collect();
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Ok(args) = ExecArgs::new(
+            CStr::from_bytes_with_nul(b"/proc/self/exe\0").unwrap(),
+            &argv,
+            Some(&env),
+        ) {
+            unsafe {
+                libc::execve(args.path.as_ptr(), args.argv.as_ptr(), args.envp.as_ptr());
+            }
+        }
+
+        match ExecArgs::new(&self.exe, &argv, Some(&env)) {
+            Ok(args) => {
+                unsafe {
+                    libc::execve(args.path.as_ptr(), args.argv.as_ptr(), args.envp.as_ptr());
+                }
+                io::Error::last_os_error()
+            }
+            Err(e) => e,
+        }
+    }
+}
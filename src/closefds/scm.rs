@@ -0,0 +1,127 @@
+use std::io;
+
+/// Receive buffer for the control messages. This is large enough for several hundred file
+/// descriptors per message; if a message carries more than that, the kernel discards (and closes)
+/// the ones that don't fit.
+#[repr(C, align(8))]
+struct CmsgBuf([u8; 2048]);
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "illumos",
+))]
+const RECV_FLAGS: libc::c_int = libc::MSG_DONTWAIT | libc::MSG_CMSG_CLOEXEC;
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "illumos",
+)))]
+const RECV_FLAGS: libc::c_int = libc::MSG_DONTWAIT;
+
+/// Drain all pending messages from the Unix socket `sockfd`, closing any file descriptors that
+/// were passed over it with `SCM_RIGHTS`.
+///
+/// File descriptors that have been sent over a socket but not yet received are "in flight": they
+/// aren't in this process's file descriptor table, so closing file descriptors (e.g. with
+/// [`CloseFdsBuilder::closefrom()`](./struct.CloseFdsBuilder.html#method.closefrom)) won't catch
+/// them. If the socket itself is inherited by a program that this process executes, that program
+/// can receive them later. Calling this on each inherited socket before executing the program
+/// prevents that.
+///
+/// Messages are received with `MSG_DONTWAIT` until none are left (and with `MSG_CMSG_CLOEXEC`
+/// where it's supported, so the received file descriptors are never inheritable, even briefly).
+/// **All pending data on the socket is discarded**, not just the ancillary data, so this should
+/// only be used on sockets whose pending contents are no longer needed.
+///
+/// Returns the number of file descriptors that were received and closed. If `sockfd` isn't a socket,
+/// or if receiving fails with an error other than `EAGAIN`/`EWOULDBLOCK` or `EINTR`, an error is returned (any file
+/// descriptors received before that will have already been closed).
+///
+/// This does not allocate memory and is async-signal-safe, so it can be used in the child after a
+/// `fork()` (such as in [`CloseFdsBuilder::pre_exec_fn()`](./struct.CloseFdsBuilder.html)).
+pub fn drain_scm_rights(sockfd: libc::c_int) -> io::Result<usize> {
+    let mut nclosed = 0;
+
+    // Datagram sockets can have empty messages queued, but on other sockets, receiving 0 bytes
+    // with no control messages means EOF
+    let mut sotype: libc::c_int = 0;
+    let mut optlen = core::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    if unsafe {
+        libc::getsockopt(
+            sockfd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut sotype as *mut libc::c_int as *mut libc::c_void,
+            &mut optlen,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    let is_dgram = sotype == libc::SOCK_DGRAM;
+
+    let mut databuf = [0u8; 256];
+    let mut cmsgbuf = CmsgBuf([0; 2048]);
+
+    loop {
+        let mut iov = libc::iovec {
+            iov_base: databuf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: databuf.len(),
+        };
+
+        let mut msg: libc::msghdr = unsafe { core::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsgbuf.0.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = cmsgbuf.0.len() as _;
+
+        let n = unsafe { libc::recvmsg(sockfd, &mut msg, RECV_FLAGS) };
+        if n < 0 {
+            let eno = crate::util::errno();
+            if eno == libc::EINTR {
+                continue;
+            } else if eno == libc::EAGAIN || eno == libc::EWOULDBLOCK {
+                return Ok(nclosed);
+            } else {
+                return Err(io::Error::from_raw_os_error(eno));
+            }
+        }
+
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        let mut got_cmsg = false;
+
+        while !cmsg.is_null() {
+            got_cmsg = true;
+
+            let hdr = unsafe { &*cmsg };
+            if hdr.cmsg_level == libc::SOL_SOCKET && hdr.cmsg_type == libc::SCM_RIGHTS {
+                let data = unsafe { libc::CMSG_DATA(cmsg) } as *const libc::c_int;
+                let len =
+                    (hdr.cmsg_len as usize).saturating_sub(unsafe { libc::CMSG_LEN(0) } as usize);
+
+                for i in 0..(len / core::mem::size_of::<libc::c_int>()) {
+                    unsafe {
+                        libc::close(core::ptr::read_unaligned(data.add(i)));
+                    }
+                    nclosed += 1;
+                }
+            }
+
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+
+        if n == 0 && !got_cmsg && !is_dgram {
+            // EOF on a stream/seqpacket socket (receiving again would just return 0 forever)
+            return Ok(nclosed);
+        }
+    }
+}
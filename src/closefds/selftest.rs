@@ -0,0 +1,345 @@
+use crate::{close_range, CloseFdsBuilder, CloseRangeError, CloseRangeFlag, FdIterBuilder};
+
+/// The result of one of the checks made by [`selftest()`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum SelftestOutcome {
+    /// The strategy was used, and it did what it was supposed to.
+    Works,
+    /// The strategy was used, but it failed or gave the wrong results (or the test process was
+    /// killed while it was being used, e.g. by a seccomp filter).
+    Broken,
+    /// The strategy isn't available on this platform or in this environment (for example, because
+    /// `/proc` isn't mounted, or because the kernel is too old), so it wasn't tested. This crate
+    /// doesn't use strategies that are unavailable, so this isn't a problem by itself.
+    Unavailable,
+    /// The check wasn't run, because the test process died during an earlier check.
+    Untested,
+}
+
+/// A report on which strategies for finding and closing file descriptors work in the current
+/// environment, returned by [`selftest()`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub struct SelftestReport {
+    /// Listing the open file descriptors by reading a directory like `/proc/self/fd` or `/dev/fd`
+    /// (see [`FdIterBuilder::allow_filesystem()`]).
+    pub dirfd_iter: SelftestOutcome,
+    /// Listing the open file descriptors by checking each possible file descriptor.
+    pub maxfd_iter: SelftestOutcome,
+    /// Closing a range of file descriptors with `close_range()` (Linux 5.9+ and FreeBSD 12.2+).
+    pub close_range: SelftestOutcome,
+    /// Setting the close-on-exec flag on a range of file descriptors with
+    /// `close_range(CLOSE_RANGE_CLOEXEC)` (Linux 5.11+ and FreeBSD 13.1+).
+    pub close_range_cloexec: SelftestOutcome,
+    /// Closing all file descriptors above a certain number with the native `closefrom()` (on the
+    /// BSDs).
+    pub closefrom: SelftestOutcome,
+    /// Setting the close-on-exec flag on file descriptors (except the kept ones) with
+    /// [`CloseFdsBuilder::cloexecfrom()`], using whichever strategies are available.
+    pub cloexec_fds: SelftestOutcome,
+    /// Closing file descriptors (except the kept ones) with [`CloseFdsBuilder::closefrom()`],
+    /// using whichever strategies are available.
+    pub close_fds: SelftestOutcome,
+}
+
+impl SelftestReport {
+    /// Check whether file descriptors can be reliably closed and set as close-on-exec in this
+    /// environment: [`Self::close_fds`] and [`Self::cloexec_fds`] work, and none of the individual
+    /// strategies are broken.
+    pub fn is_ok(&self) -> bool {
+        self.outcomes()
+            .iter()
+            .all(|&o| o == SelftestOutcome::Works || o == SelftestOutcome::Unavailable)
+            && self.close_fds == SelftestOutcome::Works
+            && self.cloexec_fds == SelftestOutcome::Works
+    }
+
+    fn outcomes(&self) -> [SelftestOutcome; NCHECKS] {
+        [
+            self.dirfd_iter,
+            self.maxfd_iter,
+            self.close_range,
+            self.close_range_cloexec,
+            self.closefrom,
+            self.cloexec_fds,
+            self.close_fds,
+        ]
+    }
+
+    fn from_outcomes(outcomes: [SelftestOutcome; NCHECKS]) -> Self {
+        Self {
+            dirfd_iter: outcomes[0],
+            maxfd_iter: outcomes[1],
+            close_range: outcomes[2],
+            close_range_cloexec: outcomes[3],
+            closefrom: outcomes[4],
+            cloexec_fds: outcomes[5],
+            close_fds: outcomes[6],
+        }
+    }
+}
+
+/// The number of checks (and the order they're run in; see `SelftestReport::outcomes()`)
+const NCHECKS: usize = 7;
+
+/// Check which of this crate's strategies for finding, closing, and setting the close-on-exec flag
+/// on file descriptors work in the current environment.
+///
+/// This is intended for deployments that need to verify that file descriptor hygiene will
+/// actually work before relying on it (for example, in a setuid program, or in a container or
+/// sandbox where `/proc` may not be mounted and system calls may be blocked by a seccomp filter).
+///
+/// The checks are run in a child process created with `fork()`, so the current process's file
+/// descriptors aren't affected. For each strategy, the child opens some file descriptors, uses the
+/// strategy to list, close, or set the close-on-exec flag on them, and then verifies the result.
+/// If the child is killed partway through (for example, because a seccomp filter killed it for
+/// making a forbidden system call), the check that was running is reported as
+/// [`SelftestOutcome::Broken`], and the remaining ones as [`SelftestOutcome::Untested`].
+///
+/// Only async-signal-safe functions are called in the child, so this is safe to call in
+/// multithreaded programs. It doesn't allocate memory.
+///
+/// Returns an error code if the pipe used to communicate with the child can't be created, or if
+/// `fork()` fails.
+pub fn selftest() -> Result<SelftestReport, libc::c_int> {
+    let mut fds = [-1; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(crate::util::errno());
+    }
+    let [rfd, wfd] = fds;
+    // Don't leak the pipe into programs executed by other threads
+    let _ = crate::util::set_cloexec(rfd);
+    let _ = crate::util::set_cloexec(wfd);
+
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        let eno = crate::util::errno();
+        unsafe {
+            libc::close(rfd);
+            libc::close(wfd);
+        }
+        return Err(eno);
+    } else if pid == 0 {
+        unsafe {
+            libc::close(rfd);
+            run_checks(wfd);
+            libc::_exit(0);
+        }
+    }
+
+    unsafe {
+        libc::close(wfd);
+    }
+
+    // The child writes one byte per check as it finishes it
+    let mut buf = [0u8; NCHECKS];
+    let mut nread = 0;
+    while nread < buf.len() {
+        let n = unsafe {
+            libc::read(
+                rfd,
+                buf[nread..].as_mut_ptr() as *mut libc::c_void,
+                buf.len() - nread,
+            )
+        };
+        if n > 0 {
+            nread += n as usize;
+        } else if n == 0 || crate::util::errno() != libc::EINTR {
+            break;
+        }
+    }
+
+    unsafe {
+        libc::close(rfd);
+        while libc::waitpid(pid, core::ptr::null_mut(), 0) < 0
+            && crate::util::errno() == libc::EINTR
+        {}
+    }
+
+    let mut outcomes = [SelftestOutcome::Untested; NCHECKS];
+    for (i, outcome) in outcomes.iter_mut().enumerate() {
+        *outcome = if i < nread {
+            decode_outcome(buf[i])
+        } else if i == nread {
+            // The child died while running this check
+            SelftestOutcome::Broken
+        } else {
+            SelftestOutcome::Untested
+        };
+    }
+
+    Ok(SelftestReport::from_outcomes(outcomes))
+}
+
+#[inline]
+fn encode_outcome(outcome: SelftestOutcome) -> u8 {
+    match outcome {
+        SelftestOutcome::Works => 1,
+        SelftestOutcome::Broken => 2,
+        SelftestOutcome::Unavailable => 3,
+        SelftestOutcome::Untested => 4,
+    }
+}
+
+#[inline]
+fn decode_outcome(byte: u8) -> SelftestOutcome {
+    match byte {
+        1 => SelftestOutcome::Works,
+        3 => SelftestOutcome::Unavailable,
+        4 => SelftestOutcome::Untested,
+        _ => SelftestOutcome::Broken,
+    }
+}
+
+/// Run the checks (in the child), writing the outcome of each one to `wfd` in the same order as
+/// `SelftestReport::outcomes()`.
+unsafe fn run_checks(wfd: libc::c_int) {
+    // The test file descriptors are placed right above the pipe
+    let base = match wfd.checked_add(1) {
+        Some(base) if base <= libc::c_int::MAX - 2 => base,
+        _ => libc::_exit(1),
+    };
+
+    let checks: [unsafe fn(libc::c_int, libc::c_int) -> SelftestOutcome; NCHECKS] = [
+        |wfd, base| check_iter(wfd, base, true),
+        |wfd, base| check_iter(wfd, base, false),
+        check_close_range,
+        check_close_range_cloexec,
+        check_closefrom,
+        check_cloexec_fds,
+        check_close_fds,
+    ];
+
+    for check in checks.iter() {
+        let byte = encode_outcome(check(wfd, base));
+        if libc::write(wfd, &byte as *const u8 as *const libc::c_void, 1) != 1 {
+            libc::_exit(1);
+        }
+    }
+}
+
+/// Open file descriptors `base`, `base + 1`, and `base + 2` (without the close-on-exec flag) as
+/// copies of `wfd`.
+unsafe fn open_test_fds(wfd: libc::c_int, base: libc::c_int) -> Result<(), ()> {
+    for fd in base..=base + 2 {
+        if libc::dup2(wfd, fd) != fd {
+            return Err(());
+        }
+    }
+    Ok(())
+}
+
+/// Check which of the test file descriptors are open (`Some(cloexec)`) or closed (`None`).
+fn test_fd_states(base: libc::c_int) -> [Option<bool>; 3] {
+    let mut states = [None; 3];
+    for (state, fd) in states.iter_mut().zip(base..) {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags >= 0 {
+            *state = Some(flags & libc::FD_CLOEXEC != 0);
+        }
+    }
+    states
+}
+
+#[inline]
+fn outcome_if(ok: bool) -> SelftestOutcome {
+    if ok {
+        SelftestOutcome::Works
+    } else {
+        SelftestOutcome::Broken
+    }
+}
+
+unsafe fn check_iter(wfd: libc::c_int, base: libc::c_int, fs: bool) -> SelftestOutcome {
+    if open_test_fds(wfd, base).is_err() {
+        return SelftestOutcome::Broken;
+    }
+
+    let mut fditer = FdIterBuilder::new().allow_filesystem(fs).iter_from(base);
+    if fs && fditer.backend_name() != "dirfd" {
+        return SelftestOutcome::Unavailable;
+    }
+
+    let mut found = [-1; 3];
+    let n = fditer.collect_into_slice(&mut found);
+    outcome_if(n == 3 && found == [base, base + 1, base + 2])
+}
+
+unsafe fn check_close_range(wfd: libc::c_int, base: libc::c_int) -> SelftestOutcome {
+    if open_test_fds(wfd, base).is_err() {
+        return SelftestOutcome::Broken;
+    }
+
+    match close_range(base as libc::c_uint, (base + 1) as libc::c_uint, &[]) {
+        Ok(()) => outcome_if(test_fd_states(base) == [None, None, Some(false)]),
+        Err(CloseRangeError::Unsupported) => SelftestOutcome::Unavailable,
+        Err(_) => SelftestOutcome::Broken,
+    }
+}
+
+unsafe fn check_close_range_cloexec(wfd: libc::c_int, base: libc::c_int) -> SelftestOutcome {
+    if open_test_fds(wfd, base).is_err() {
+        return SelftestOutcome::Broken;
+    }
+
+    match close_range(
+        base as libc::c_uint,
+        (base + 1) as libc::c_uint,
+        &[CloseRangeFlag::Cloexec],
+    ) {
+        Ok(()) => outcome_if(test_fd_states(base) == [Some(true), Some(true), Some(false)]),
+        Err(CloseRangeError::Unsupported) => SelftestOutcome::Unavailable,
+        Err(_) => SelftestOutcome::Broken,
+    }
+}
+
+#[allow(unused_variables)]
+unsafe fn check_closefrom(wfd: libc::c_int, base: libc::c_int) -> SelftestOutcome {
+    cfg_if::cfg_if! {
+        if #[cfg(any(
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "openbsd",
+            target_os = "dragonfly"
+        ))] {
+            if open_test_fds(wfd, base).is_err() {
+                return SelftestOutcome::Broken;
+            }
+
+            // This also closes anything else the child inherited above the test file descriptors
+            if crate::sys::closefrom(base + 1).is_err() {
+                return SelftestOutcome::Broken;
+            }
+            outcome_if(test_fd_states(base) == [Some(false), None, None])
+        } else {
+            SelftestOutcome::Unavailable
+        }
+    }
+}
+
+unsafe fn check_cloexec_fds(wfd: libc::c_int, base: libc::c_int) -> SelftestOutcome {
+    if open_test_fds(wfd, base).is_err() {
+        return SelftestOutcome::Broken;
+    }
+
+    CloseFdsBuilder::new()
+        .keep_fds(&[base + 1])
+        .cloexecfrom(base);
+    outcome_if(test_fd_states(base) == [Some(true), Some(false), Some(true)])
+}
+
+unsafe fn check_close_fds(wfd: libc::c_int, base: libc::c_int) -> SelftestOutcome {
+    if open_test_fds(wfd, base).is_err() {
+        return SelftestOutcome::Broken;
+    }
+
+    // Keep the pipe open so the outcome can be reported
+    CloseFdsBuilder::new()
+        .keep_fds(&[wfd, base + 1])
+        .closefrom(3);
+    outcome_if(
+        test_fd_states(base) == [None, Some(false), None]
+            && crate::iter_open_fds(3).eq([wfd, base + 1].iter().copied()),
+    )
+}
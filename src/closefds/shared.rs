@@ -0,0 +1,187 @@
+/// What [`CloseFdsBuilder::closefrom()`](./struct.CloseFdsBuilder.html#method.closefrom) should do
+/// if the file descriptor table is shared with another process. See
+/// [`CloseFdsBuilder::shared_fd_table()`](./struct.CloseFdsBuilder.html#method.shared_fd_table).
+#[derive(Copy, Clone, Debug)]
+#[non_exhaustive]
+pub enum SharedFdTablePolicy {
+    /// Don't check whether the file descriptor table is shared (the default).
+    Ignore,
+    /// Don't close any file descriptors if the file descriptor table is shared.
+    Refuse,
+    /// Call the given function if the file descriptor table is shared, and then close the file
+    /// descriptors anyway.
+    Warn(fn()),
+    /// Stop sharing the file descriptor table by calling `unshare(CLONE_FILES)` (which is what
+    /// `close_range(CLOSE_RANGE_UNSHARE)` does), and then close the file descriptors in the
+    /// private copy.
+    ///
+    /// The kernel only copies the table if it's actually shared, so this doesn't look for other
+    /// processes first (which makes it much cheaper than `Refuse` or `Warn`). If
+    /// `unshare(CLONE_FILES)` fails, the table is checked as for `Refuse`.
+    ///
+    /// Note that the calling thread also stops sharing its file descriptor table with the other
+    /// threads in the current process (if there are any).
+    Unshare,
+}
+
+impl Default for SharedFdTablePolicy {
+    #[inline]
+    fn default() -> Self {
+        Self::Ignore
+    }
+}
+
+impl SharedFdTablePolicy {
+    /// Check the file descriptor table as specified by this policy, and return whether it's OK to
+    /// go ahead and close file descriptors.
+    pub(crate) fn check(self) -> bool {
+        if matches!(self, Self::Ignore) {
+            return true;
+        }
+
+        // unshare(CLONE_FILES) is cheap, so try it before looking for other processes
+        if matches!(self, Self::Unshare) && unshare_fd_table() {
+            return true;
+        }
+
+        if is_fd_table_shared() != Some(true) {
+            return true;
+        }
+
+        match self {
+            Self::Ignore => true,
+            // For Unshare, unshare(CLONE_FILES) already failed
+            Self::Refuse | Self::Unshare => false,
+            Self::Warn(func) => {
+                func();
+                true
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[inline]
+fn unshare_fd_table() -> bool {
+    unsafe { libc::unshare(libc::CLONE_FILES) == 0 }
+}
+
+#[cfg(not(target_os = "linux"))]
+#[inline]
+fn unshare_fd_table() -> bool {
+    false
+}
+
+/// Check whether the current process's file descriptor table is shared with another process (for
+/// example, because a library created a process with `clone(CLONE_FILES)`).
+///
+/// If it is, closing file descriptors (e.g. with
+/// [`CloseFdsBuilder::closefrom()`](./struct.CloseFdsBuilder.html#method.closefrom)) would also
+/// close them in the other process, which is almost never what was intended. (Threads in the
+/// current process always share its file descriptor table; that doesn't count.)
+///
+/// On Linux, this checks every process listed in `/proc` with `kcmp(KCMP_FILES)`, so it makes one
+/// system call per process on the system. Avoid calling it frequently.
+///
+/// Returns `None` if this can't be determined: on other platforms, if the `kcmp()` system call is
+/// unavailable (it requires `CONFIG_CHECKPOINT_RESTORE`) or blocked, if `/proc` can't be read, if
+/// any process couldn't be compared (for example, because it belongs to another user, or because
+/// `/proc` is mounted with `hidepid`), or if the `no-fs-backends` or `libc-wrappers-only` feature
+/// is enabled. A process that shares the file descriptor table is found even if some other
+/// processes can't be compared.
+pub fn is_fd_table_shared() -> Option<bool> {
+    cfg_if::cfg_if! {
+        if #[cfg(all(
+            target_os = "linux",
+            not(any(feature = "no-fs-backends", feature = "libc-wrappers-only"))
+        ))] {
+            unsafe { proc_fd_table_shared() }
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    not(any(feature = "no-fs-backends", feature = "libc-wrappers-only"))
+))]
+unsafe fn proc_fd_table_shared() -> Option<bool> {
+    #[repr(align(8))]
+    struct DirentBuf([u8; 2048]);
+
+    let pid = libc::getpid();
+    let kcmp_files = |other: libc::pid_t| {
+        libc::syscall(
+            libc::SYS_kcmp,
+            pid,
+            other,
+            crate::ffi::KCMP_FILES,
+            0 as libc::c_ulong,
+            0 as libc::c_ulong,
+        )
+    };
+
+    // Make sure kcmp() actually works first
+    if kcmp_files(pid) != 0 {
+        return None;
+    }
+
+    let dirfd = libc::open(
+        "/proc\0".as_ptr() as *const libc::c_char,
+        libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+    );
+    if dirfd < 0 {
+        return None;
+    }
+
+    let mut buf = DirentBuf([0; 2048]);
+    let mut shared = Some(false);
+
+    'outer: loop {
+        let nbytes = libc::syscall(
+            libc::SYS_getdents64,
+            dirfd as libc::c_uint,
+            buf.0.as_mut_ptr(),
+            buf.0.len(),
+        );
+        if nbytes < 0 {
+            shared = None;
+            break;
+        } else if nbytes == 0 {
+            break;
+        }
+
+        let mut offset = 0;
+        while offset < nbytes as usize {
+            #[allow(clippy::cast_ptr_alignment)] // We trust the kernel not to make us segfault
+            let entry = &*(buf.0.as_ptr().add(offset) as *const libc::dirent64);
+            offset += entry.d_reclen as usize;
+
+            let name = entry
+                .d_name
+                .iter()
+                .take_while(|c| **c != 0)
+                .map(|c| *c as u8);
+            if let Some(other) = crate::iterfds::dirfd::parse_int_bytes(name) {
+                if other == pid {
+                    continue;
+                }
+
+                match kcmp_files(other) {
+                    0 => {
+                        shared = Some(true);
+                        break 'outer;
+                    }
+                    // ESRCH just means the process has exited. Anything else (e.g. EPERM) means
+                    // we can't rule it out, but keep looking in case another process matches.
+                    -1 if crate::util::errno() != libc::ESRCH => shared = None,
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    libc::close(dirfd);
+    shared
+}
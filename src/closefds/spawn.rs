@@ -0,0 +1,651 @@
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::vec::Vec;
+
+use super::{close, CloseFdsBuilder, KeepFds};
+
+/// Spawn a new process that runs the program at `path`, with all file descriptors starting at
+/// `minfd` (except the ones passed to [`CloseFdsBuilder::keep_fds()`]) closed.
+///
+/// This performs the entire `fork()`/`exec()` sequence: the child process closes the file
+/// descriptors (as with [`CloseFdsBuilder::closefrom()`]), resets its signal mask and the
+/// disposition of `SIGPIPE` to the defaults, and then calls `execve()`. If `execve()` fails, the
+/// error is sent back to the parent over a close-on-exec pipe and returned from this function.
+///
+/// `argv` is the argument list for the new program (including `argv[0]`). If `envp` is `None`,
+/// the new program inherits the current process's environment; otherwise, `envp` should contain
+/// strings of the form `KEY=VALUE`. Note that `path` is not searched for in `PATH`.
+///
+/// On success, the PID of the child process is returned. The caller is responsible for waiting
+/// for it (e.g. with `waitpid()`).
+///
+/// All memory allocation is done before the `fork()`, and the child process only performs
+/// async-signal-safe operations, so this is safe to use in multithreaded programs.
+///
+/// This is not available on tvOS or watchOS, where `fork()` and `exec()` are prohibited.
+///
+/// # Example
+///
+/// ```
+/// # use std::ffi::CStr;
+/// # use close_fds::{spawn_with_closed_fds, CloseFdsBuilder};
+/// let cstr = |s: &'static [u8]| CStr::from_bytes_with_nul(s).unwrap();
+/// let argv = [cstr(b"sh\0"), cstr(b"-c\0"), cstr(b"exit 0\0")];
+///
+/// let pid = spawn_with_closed_fds(cstr(b"/bin/sh\0"), &argv, None, 3, &CloseFdsBuilder::new())
+///     .unwrap();
+///
+/// let mut status = 0;
+/// assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+/// assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+/// ```
+pub fn spawn_with_closed_fds(
+    path: &CStr,
+    argv: &[&CStr],
+    envp: Option<&[&CStr]>,
+    minfd: libc::c_int,
+    builder: &CloseFdsBuilder,
+) -> io::Result<libc::pid_t> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("spawn_with_closed_fds", minfd, method = "fork").entered();
+
+    let spawn = PreparedSpawn::new(path, argv, envp, builder)?;
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => unsafe { spawn.exec_child(minfd) },
+        pid => spawn.finish(pid),
+    }
+}
+
+/// Identical to [`spawn_with_closed_fds()`], but uses a `vfork()`-style fast path on platforms
+/// where that is supported.
+///
+/// On Linux and Android, the child process is created with `clone(CLONE_VM | CLONE_VFORK)`: it
+/// runs on a separate stack but shares the parent's memory (instead of copying the parent's page
+/// tables, as `fork()` does), and the calling thread is suspended until the child calls `execve()`
+/// or exits. This can be much faster than `fork()` for processes with large address spaces.
+///
+/// This is only possible because the child process doesn't allocate memory or otherwise modify
+/// state that the parent relies on. To keep signal handlers from running in the child (where they
+/// could corrupt the parent's memory), all signals are blocked in the calling thread while the
+/// child is running, and the child resets every caught signal to its default disposition before
+/// unblocking them.
+///
+/// Any callbacks set on `builder` (an [`observer`](CloseFdsBuilder::observer()) or a custom
+/// [`closer`](CloseFdsBuilder::closer())) also run in the child, so on Linux and Android they are
+/// subject to the same restrictions, in addition to the usual ones for code that runs after
+/// `fork()`. In particular, they must not write to any memory that the parent uses (including
+/// thread-local variables, since the child shares the calling thread's TLS), and they must not
+/// use much stack space, since the child's stack is a fixed 128 KiB buffer with no guard page. If
+/// that can't be guaranteed, use [`spawn_with_closed_fds()`] instead.
+///
+/// On other platforms, this is currently equivalent to [`spawn_with_closed_fds()`].
+pub fn spawn_with_closed_fds_vfork(
+    path: &CStr,
+    argv: &[&CStr],
+    envp: Option<&[&CStr]>,
+    minfd: libc::c_int,
+    builder: &CloseFdsBuilder,
+) -> io::Result<libc::pid_t> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return {
+        #[cfg(feature = "tracing")]
+        let _span =
+            tracing::debug_span!("spawn_with_closed_fds", minfd, method = "clone_vfork").entered();
+
+        let spawn = PreparedSpawn::new(path, argv, envp, builder)?;
+        let pid = unsafe { clone_vfork(&spawn, minfd) }?;
+        spawn.finish(pid)
+    };
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    spawn_with_closed_fds(path, argv, envp, minfd, builder)
+}
+
+/// Spawn a new process that runs the program at `path` as a daemon (detached from the current
+/// process and its session), with all file descriptors starting at `minfd` (except the ones passed
+/// to [`CloseFdsBuilder::keep_fds()`]) closed.
+///
+/// This performs the classic "double fork" sequence:
+///
+/// 1. The current process `fork()`s an intermediate child.
+/// 2. The intermediate child resets its signal mask and the disposition of `SIGPIPE`, closes the
+///    file descriptors (as with [`CloseFdsBuilder::closefrom()`]), and calls `setsid()` to start
+///    a new session (which detaches it from the controlling terminal).
+/// 3. The intermediate child `fork()`s again and exits. Its child (the daemon) is not a session
+///    leader, so it can never reacquire a controlling terminal, and it is reparented to `init` (or
+///    the nearest subreaper).
+/// 4. The daemon calls `execve()`.
+/// 5. The current process reaps the intermediate child.
+///
+/// `argv` and `envp` are as described for [`spawn_with_closed_fds()`]. Nothing else about the
+/// environment of the daemon is changed; in particular, its working directory and umask are
+/// inherited, and so are file descriptors 0-2 unless `minfd` is below 3. (If they should be
+/// redirected to `/dev/null`, the executed program must do that itself.)
+///
+/// On success, the PID of the daemon is returned. Since it is not a child of the current process,
+/// it can't be waited for. If any step fails (including `execve()`), the error is sent back to the
+/// current process over a close-on-exec pipe and returned from this function.
+///
+/// All memory allocation is done before the first `fork()`, and the child processes only perform
+/// async-signal-safe operations, so this is safe to use in multithreaded programs.
+///
+/// This is not available on tvOS or watchOS, where `fork()` and `exec()` are prohibited.
+pub fn spawn_daemon_with_closed_fds(
+    path: &CStr,
+    argv: &[&CStr],
+    envp: Option<&[&CStr]>,
+    minfd: libc::c_int,
+    builder: &CloseFdsBuilder,
+) -> io::Result<libc::pid_t> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("spawn_daemon_with_closed_fds", minfd).entered();
+
+    let spawn = PreparedSpawn::new(path, argv, envp, builder)?;
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => unsafe { spawn.daemon_child(minfd) },
+        pid => spawn.finish_daemon(pid),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const VFORK_STACK_SIZE: usize = 128 * 1024;
+
+/// The highest signal number.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const MAX_SIGNAL: libc::c_int = if cfg!(any(target_arch = "mips", target_arch = "mips64")) {
+    127
+} else {
+    64
+};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+struct VforkArgs<'a, 'b> {
+    spawn: &'a PreparedSpawn<'b>,
+    minfd: libc::c_int,
+}
+
+// The child shares the caller's memory (including its TLS block, since clone() doesn't set up a
+// new thread pointer without CLONE_SETTLS), so everything it runs -- including the builder's
+// observer and closer -- must stick to the rules documented on spawn_with_closed_fds_vfork().
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn clone_vfork(spawn: &PreparedSpawn, minfd: libc::c_int) -> io::Result<libc::pid_t> {
+    extern "C" fn child(arg: *mut libc::c_void) -> libc::c_int {
+        unsafe {
+            let args = &*(arg as *const VforkArgs);
+
+            for sig in 1..=MAX_SIGNAL {
+                let mut act = core::mem::MaybeUninit::<libc::sigaction>::zeroed();
+                if libc::sigaction(sig, core::ptr::null(), act.as_mut_ptr()) == 0 {
+                    let handler = act.assume_init().sa_sigaction;
+                    if handler != libc::SIG_DFL && handler != libc::SIG_IGN {
+                        libc::signal(sig, libc::SIG_DFL);
+                    }
+                }
+            }
+
+            args.spawn.exec_child(args.minfd)
+        }
+    }
+
+    let mut stack = std::vec![0u8; VFORK_STACK_SIZE];
+    // The stack grows down on every architecture that Rust supports Linux on
+    let stack_top = (stack.as_mut_ptr() as usize + stack.len()) & !15;
+
+    let args = VforkArgs { spawn, minfd };
+
+    let mut allset = core::mem::MaybeUninit::uninit();
+    let mut oldset = core::mem::MaybeUninit::uninit();
+    libc::sigfillset(allset.as_mut_ptr());
+    libc::pthread_sigmask(libc::SIG_SETMASK, allset.as_ptr(), oldset.as_mut_ptr());
+
+    let pid = libc::clone(
+        child,
+        stack_top as *mut libc::c_void,
+        libc::CLONE_VM | libc::CLONE_VFORK | libc::SIGCHLD,
+        &args as *const VforkArgs as *mut libc::c_void,
+    );
+    let res = if pid < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(pid)
+    };
+
+    libc::pthread_sigmask(libc::SIG_SETMASK, oldset.as_ptr(), core::ptr::null_mut());
+
+    res
+}
+
+/// The arguments to `execve()`, converted ahead of time.
+pub(super) struct ExecArgs<'a> {
+    pub path: &'a CStr,
+    pub argv: Vec<*const libc::c_char>,
+    pub envp: Vec<*const libc::c_char>,
+    // Backing storage for `envp` if we had to build it
+    _env: Vec<CString>,
+}
+
+impl<'a> ExecArgs<'a> {
+    pub fn new(path: &'a CStr, argv: &[&CStr], envp: Option<&[&CStr]>) -> io::Result<Self> {
+        let env: Vec<CString> = match envp {
+            Some(_) => Vec::new(),
+            None => std::env::vars_os()
+                .map(|(key, val)| {
+                    let mut entry = key.as_bytes().to_vec();
+                    entry.push(b'=');
+                    entry.extend_from_slice(val.as_bytes());
+                    CString::new(entry).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+                })
+                .collect::<io::Result<_>>()?,
+        };
+
+        let envp: Vec<*const libc::c_char> = match envp {
+            Some(envp) => envp.iter().map(|s| s.as_ptr()).collect(),
+            None => env.iter().map(|s| s.as_ptr()).collect(),
+        };
+
+        Ok(Self {
+            path,
+            argv: null_terminated(argv.iter().map(|s| s.as_ptr()).collect()),
+            envp: null_terminated(envp),
+            _env: env,
+        })
+    }
+}
+
+/// Everything that the child process needs, allocated ahead of time.
+pub(super) struct PreparedSpawn<'a> {
+    args: ExecArgs<'a>,
+    // Sorted; includes `wfd`
+    keep_fds: Vec<libc::c_int>,
+    it: crate::FdIterBuilder,
+    juggle_keep_fds: bool,
+    close_descending: bool,
+    observer: super::observe::Observer,
+    closer: super::backend::Backend,
+    make_kept_inheritable: bool,
+    rfd: libc::c_int,
+    wfd: libc::c_int,
+}
+
+impl<'a> PreparedSpawn<'a> {
+    pub(super) fn new(
+        path: &'a CStr,
+        argv: &[&CStr],
+        envp: Option<&[&CStr]>,
+        builder: &CloseFdsBuilder,
+    ) -> io::Result<Self> {
+        let args = ExecArgs::new(path, argv, envp)?;
+
+        let (rfd, wfd) = pipe_cloexec()?;
+
+        let mut keep_fds = builder.keep_fds.to_sorted_vec();
+        if let Err(i) = keep_fds.binary_search(&wfd) {
+            keep_fds.insert(i, wfd);
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(keep_fds = keep_fds.len(), "prepared to spawn child process");
+
+        Ok(Self {
+            args,
+            keep_fds,
+            it: builder.it.clone(),
+            juggle_keep_fds: builder.juggle_keep_fds,
+            close_descending: builder.close_descending,
+            observer: builder.observer,
+            closer: builder.closer,
+            make_kept_inheritable: builder.make_kept_inheritable,
+            rfd,
+            wfd,
+        })
+    }
+
+    /// Close the file descriptors and execute the program. This must only be called in the child
+    /// process.
+    ///
+    /// # Safety
+    ///
+    /// See [`CloseFdsBuilder::closefrom()`].
+    unsafe fn exec_child(&self, minfd: libc::c_int) -> ! {
+        self.exec_child_with(minfd, || Ok(()))
+    }
+
+    /// Like [`Self::exec_child()`], but calls `setup` first (before anything else is done). If it
+    /// fails, the error code is reported to the parent as if `execve()` had failed.
+    ///
+    /// # Safety
+    ///
+    /// See [`CloseFdsBuilder::closefrom()`]. `setup` must only perform async-signal-safe
+    /// operations.
+    unsafe fn exec_child_with<F>(&self, minfd: libc::c_int, setup: F) -> !
+    where
+        F: Fn() -> Result<(), libc::c_int>,
+    {
+        let eno = match setup() {
+            Ok(()) => {
+                self.prepare_child(minfd);
+                self.exec_prepared()
+            }
+            Err(eno) => eno,
+        };
+
+        // exec() failed; report the error to the parent. The pipe's buffer is much larger than 4
+        // bytes, so this can't block or be split.
+        self.report(&eno.to_ne_bytes());
+        libc::_exit(127);
+    }
+
+    /// `fork()` and execute the program (as with [`spawn_with_closed_fds()`]), calling `setup` in
+    /// the child first (see [`Self::exec_child_with()`]).
+    ///
+    /// # Safety
+    ///
+    /// `setup` must only perform async-signal-safe operations.
+    pub(super) unsafe fn spawn_with<F>(
+        self,
+        minfd: libc::c_int,
+        setup: F,
+    ) -> io::Result<libc::pid_t>
+    where
+        F: Fn() -> Result<(), libc::c_int>,
+    {
+        match libc::fork() {
+            -1 => Err(io::Error::last_os_error()),
+            0 => self.exec_child_with(minfd, setup),
+            pid => self.finish(pid),
+        }
+    }
+
+    /// Also keep `fd` open in the child process.
+    pub(super) fn keep_fd(&mut self, fd: libc::c_int) {
+        if let Err(i) = self.keep_fds.binary_search(&fd) {
+            self.keep_fds.insert(i, fd);
+        }
+    }
+
+    /// Get the highest file descriptor that the child process needs to keep open (including the
+    /// write end of the pipe).
+    pub(super) fn max_keep_fd(&self) -> libc::c_int {
+        self.keep_fds.last().copied().unwrap_or(-1)
+    }
+
+    /// Move the write end of the pipe to a file descriptor at or above `minfd` (if it isn't
+    /// already), so that the child process can replace file descriptors below `minfd` without
+    /// clobbering it.
+    pub(super) fn move_pipe_above(&mut self, minfd: libc::c_int) -> io::Result<()> {
+        if self.wfd >= minfd {
+            return Ok(());
+        }
+
+        let newfd = unsafe { libc::fcntl(self.wfd, libc::F_DUPFD_CLOEXEC, minfd) };
+        if newfd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if let Ok(i) = self.keep_fds.binary_search(&self.wfd) {
+            self.keep_fds.remove(i);
+        }
+        unsafe {
+            libc::close(self.wfd);
+        }
+        self.wfd = newfd;
+        self.keep_fd(newfd);
+
+        Ok(())
+    }
+
+    /// Reset the signal mask and the disposition of `SIGPIPE`, and close the file descriptors.
+    /// This must only be called in the child process.
+    ///
+    /// # Safety
+    ///
+    /// See [`CloseFdsBuilder::closefrom()`].
+    unsafe fn prepare_child(&self, minfd: libc::c_int) {
+        let mut sigset = core::mem::MaybeUninit::uninit();
+        libc::sigemptyset(sigset.as_mut_ptr());
+        libc::pthread_sigmask(libc::SIG_SETMASK, sigset.as_ptr(), core::ptr::null_mut());
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+
+        let minfd = core::cmp::max(minfd, 0);
+        // Safety: keep_fds was sorted in new()
+        let keep_fds = KeepFds::new_sorted(&self.keep_fds);
+
+        close::close_fds(
+            minfd,
+            keep_fds.clone(),
+            self.it.clone(),
+            self.juggle_keep_fds,
+            self.close_descending,
+            self.observer,
+            self.closer,
+        );
+        if self.make_kept_inheritable {
+            keep_fds.clear_cloexec(minfd);
+            // The pipe is in `keep_fds` too, but it must stay close-on-exec so that the parent can
+            // tell when execve() succeeds
+            let _ = crate::util::set_cloexec(self.wfd);
+        }
+    }
+
+    /// Execute the program. This only returns (with the error code) if that fails.
+    unsafe fn exec_prepared(&self) -> libc::c_int {
+        libc::execve(
+            self.args.path.as_ptr(),
+            self.args.argv.as_ptr(),
+            self.args.envp.as_ptr(),
+        );
+        crate::util::errno()
+    }
+
+    /// Send a message to the parent over the pipe.
+    unsafe fn report(&self, msg: &[u8]) {
+        libc::write(self.wfd, msg.as_ptr() as *const libc::c_void, msg.len());
+    }
+
+    /// The body of the intermediate child process created by [`spawn_daemon_with_closed_fds()`].
+    ///
+    /// # Safety
+    ///
+    /// See [`CloseFdsBuilder::closefrom()`].
+    unsafe fn daemon_child(&self, minfd: libc::c_int) -> ! {
+        self.prepare_child(minfd);
+
+        if libc::setsid() < 0 {
+            self.report(&daemon_msg(DAEMON_MSG_ERROR, crate::util::errno()));
+            libc::_exit(127);
+        }
+
+        match libc::fork() {
+            -1 => {
+                self.report(&daemon_msg(DAEMON_MSG_ERROR, crate::util::errno()));
+                libc::_exit(127);
+            }
+            0 => {
+                let eno = self.exec_prepared();
+                self.report(&daemon_msg(DAEMON_MSG_ERROR, eno));
+                libc::_exit(127);
+            }
+            pid => {
+                self.report(&daemon_msg(DAEMON_MSG_PID, pid));
+                libc::_exit(0);
+            }
+        }
+    }
+
+    /// Read from the pipe until it is closed (which happens once every process holding the write
+    /// end has either executed the program or exited) or `buf` is full, returning the number of
+    /// bytes read.
+    fn read_reports(&mut self, buf: &mut [u8]) -> usize {
+        unsafe {
+            libc::close(self.wfd);
+        }
+        self.wfd = -1;
+
+        let mut len = 0;
+        while len < buf.len() {
+            let n = unsafe {
+                libc::read(
+                    self.rfd,
+                    buf[len..].as_mut_ptr() as *mut libc::c_void,
+                    buf.len() - len,
+                )
+            };
+
+            if n > 0 {
+                len += n as usize;
+            } else if n == 0 {
+                break;
+            } else if crate::util::errno() != libc::EINTR {
+                // We can't tell whether the exec() succeeded, so we have to assume it did
+                break;
+            }
+        }
+
+        len
+    }
+
+    /// Wait for the child process to either execute the program (in which case the pipe will be
+    /// closed) or report an error.
+    fn finish(mut self, pid: libc::pid_t) -> io::Result<libc::pid_t> {
+        #[cfg(feature = "tracing")]
+        let start = std::time::Instant::now();
+
+        let mut buf = [0u8; 4];
+        let len = self.read_reports(&mut buf);
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            pid,
+            exec_failed = len != 0,
+            wait_us = start.elapsed().as_micros() as u64,
+            "child process finished closing file descriptors"
+        );
+
+        if len == 0 {
+            return Ok(pid);
+        }
+
+        // The child exited without executing the program; reap it
+        reap(pid);
+
+        if len == buf.len() {
+            Err(io::Error::from_raw_os_error(libc::c_int::from_ne_bytes(
+                buf,
+            )))
+        } else {
+            Err(io::ErrorKind::UnexpectedEof.into())
+        }
+    }
+
+    /// Reap the intermediate child process created by [`spawn_daemon_with_closed_fds()`] and
+    /// return the PID of the daemon (or the error that occurred).
+    fn finish_daemon(mut self, pid: libc::pid_t) -> io::Result<libc::pid_t> {
+        // At most two messages: the daemon's PID from the intermediate child, and an error from
+        // the daemon if exec() failed
+        let mut buf = [0u8; 2 * DAEMON_MSG_LEN];
+        let len = self.read_reports(&mut buf);
+
+        reap(pid);
+
+        let mut daemon_pid = None;
+        for msg in buf[..len].chunks(DAEMON_MSG_LEN) {
+            if msg.len() != DAEMON_MSG_LEN {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            let value = libc::c_int::from_ne_bytes([msg[4], msg[5], msg[6], msg[7]]);
+            match libc::c_int::from_ne_bytes([msg[0], msg[1], msg[2], msg[3]]) {
+                DAEMON_MSG_PID => daemon_pid = Some(value),
+                _ => return Err(io::Error::from_raw_os_error(value)),
+            }
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(pid = daemon_pid, "spawned daemon process");
+
+        daemon_pid.ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+    }
+}
+
+const DAEMON_MSG_PID: libc::c_int = 0;
+const DAEMON_MSG_ERROR: libc::c_int = 1;
+const DAEMON_MSG_LEN: usize = 8;
+
+/// Encode a message from one of the processes created by [`spawn_daemon_with_closed_fds()`]. The
+/// pipe's buffer is much larger than this, so writing it can't block or be split.
+fn daemon_msg(kind: libc::c_int, value: libc::c_int) -> [u8; DAEMON_MSG_LEN] {
+    let mut msg = [0; DAEMON_MSG_LEN];
+    msg[..4].copy_from_slice(&kind.to_ne_bytes());
+    msg[4..].copy_from_slice(&value.to_ne_bytes());
+    msg
+}
+
+/// Wait for the given child process to exit, retrying on `EINTR`.
+fn reap(pid: libc::pid_t) {
+    while unsafe { libc::waitpid(pid, core::ptr::null_mut(), 0) } < 0
+        && crate::util::errno() == libc::EINTR
+    {}
+}
+
+impl Drop for PreparedSpawn<'_> {
+    fn drop(&mut self) {
+        for &fd in [self.rfd, self.wfd].iter() {
+            if fd >= 0 {
+                unsafe {
+                    libc::close(fd);
+                }
+            }
+        }
+    }
+}
+
+fn null_terminated(mut ptrs: Vec<*const libc::c_char>) -> Vec<*const libc::c_char> {
+    ptrs.push(core::ptr::null());
+    ptrs
+}
+
+/// Create a pipe with the close-on-exec flag set on both ends.
+fn pipe_cloexec() -> io::Result<(libc::c_int, libc::c_int)> {
+    let mut fds = [-1; 2];
+
+    cfg_if::cfg_if! {
+        if #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "openbsd",
+            target_os = "dragonfly",
+            target_os = "solaris",
+            target_os = "illumos",
+        ))] {
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        } else {
+            // There's a race condition here if another thread fork()s at the wrong time, but
+            // there's nothing we can do about that
+            if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            for &fd in fds.iter() {
+                if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                    let err = io::Error::last_os_error();
+                    unsafe {
+                        libc::close(fds[0]);
+                        libc::close(fds[1]);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    Ok((fds[0], fds[1]))
+}
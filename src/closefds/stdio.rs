@@ -0,0 +1,108 @@
+/// What to connect one of the standard file descriptors to (see [`redirect_stdio()`]).
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum StdioSource {
+    /// Leave the file descriptor alone.
+    Inherit,
+    /// Open `/dev/null` (for reading and writing).
+    Null,
+    /// Duplicate the given file descriptor. It may be one of the standard file descriptors (for
+    /// example, `StdioSource::Fd(1)` for stderr sends it wherever stdout *currently* goes).
+    Fd(libc::c_int),
+}
+
+/// Replace the standard file descriptors (0, 1, and 2) as specified by `stdin`, `stdout`, and
+/// `stderr`.
+///
+/// This is intended for use in a child process between `fork()` and `exec()`, before file
+/// descriptors are closed (see also
+/// [`PreExecPipeline::redirect_stdio()`](./struct.PreExecPipeline.html#method.redirect_stdio)).
+/// It does not allocate memory and is async-signal-safe.
+///
+/// The sources are all resolved before anything is replaced, so cases where a source is itself
+/// one of the standard file descriptors are handled correctly: for example, passing
+/// `StdioSource::Fd(2)` for `stdout` and `StdioSource::Fd(1)` for `stderr` swaps them, and
+/// passing `StdioSource::Fd(1)` for `stdout` leaves it in place. The close-on-exec flag is cleared
+/// on every file descriptor that is replaced (including in the latter case), so they will be
+/// inherited by the executed program.
+///
+/// On failure, the error code is returned. If opening `/dev/null` or duplicating one of the
+/// sources fails (e.g. because it isn't open), nothing is changed; if moving the file descriptors
+/// into place fails, some of them may have already been replaced.
+///
+/// # Safety
+///
+/// The standard file descriptors are replaced, which will affect anything else in the process
+/// that uses them (for example, `std::io::stdout()`). This should normally only be called in a
+/// child process before `exec()`.
+pub unsafe fn redirect_stdio(
+    stdin: StdioSource,
+    stdout: StdioSource,
+    stderr: StdioSource,
+) -> Result<(), libc::c_int> {
+    let sources = [stdin, stdout, stderr];
+
+    // First, duplicate every source to a temporary file descriptor above 2, so that replacing one
+    // of the standard file descriptors can't clobber another one's source
+    let mut tmpfds = [-1; 3];
+    let mut nullfd = -1;
+
+    let res = (|| {
+        // The file descriptor sources are duplicated before /dev/null is opened, in case it's
+        // opened at one of their numbers
+        for (tmpfd, &source) in tmpfds.iter_mut().zip(sources.iter()) {
+            if let StdioSource::Fd(fd) = source {
+                if fd < 0 {
+                    return Err(libc::EBADF);
+                }
+                *tmpfd = libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 3);
+                if *tmpfd < 0 {
+                    return Err(crate::util::errno());
+                }
+            }
+        }
+
+        for (tmpfd, &source) in tmpfds.iter_mut().zip(sources.iter()) {
+            if source == StdioSource::Null {
+                if nullfd < 0 {
+                    nullfd = libc::open(
+                        b"/dev/null\0".as_ptr() as *const libc::c_char,
+                        libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC,
+                    );
+                    if nullfd < 0 {
+                        return Err(crate::util::errno());
+                    }
+                }
+                *tmpfd = libc::fcntl(nullfd, libc::F_DUPFD_CLOEXEC, 3);
+                if *tmpfd < 0 {
+                    return Err(crate::util::errno());
+                }
+            }
+        }
+
+        Ok(())
+    })();
+
+    // If /dev/null was opened at one of the standard file descriptors (because it was closed),
+    // this also restores it to its original (closed) state
+    if nullfd >= 0 {
+        libc::close(nullfd);
+    }
+
+    // Now move them into place (dup2() clears the close-on-exec flag)
+    let res = res.and_then(|()| {
+        for (i, &tmpfd) in tmpfds.iter().enumerate() {
+            if tmpfd >= 0 && libc::dup2(tmpfd, i as libc::c_int) < 0 {
+                return Err(crate::util::errno());
+            }
+        }
+        Ok(())
+    });
+
+    for &tmpfd in tmpfds.iter() {
+        if tmpfd >= 0 {
+            libc::close(tmpfd);
+        }
+    }
+
+    res
+}
@@ -0,0 +1,66 @@
+use core::marker::PhantomData;
+
+/// Proof that the current process is in a state where closing file descriptors is safe.
+///
+/// [`CloseFdsBuilder::closefrom()`](./struct.CloseFdsBuilder.html#method.closefrom) is an `unsafe`
+/// function because closing file descriptors out from under other code can cause race conditions
+/// and security issues (see its documentation). A `PreExecToken` can only be created with one of
+/// its documented `unsafe` constructors, so the safety reasoning can be written down once, where
+/// the token is created, and the token can then be passed to safe methods like
+/// [`CloseFdsBuilder::closefrom_with()`](./struct.CloseFdsBuilder.html#method.closefrom_with).
+///
+/// Tokens are neither `Send` nor `Sync`, so they can't leave the thread they were created on.
+///
+/// # Example
+///
+/// ```no_run
+/// # use std::os::unix::process::CommandExt;
+/// use close_fds::{CloseFdsBuilder, PreExecToken};
+///
+/// let mut cmd = std::process::Command::new("true");
+/// unsafe {
+///     cmd.pre_exec(|| {
+///         // Safety: pre_exec() closures run in the child after fork(), just before exec()
+///         let token = PreExecToken::post_fork();
+///         CloseFdsBuilder::new().closefrom_with(token, 3);
+///         Ok(())
+///     });
+/// }
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct PreExecToken {
+    _marker: PhantomData<*const ()>,
+}
+
+impl PreExecToken {
+    /// Create a token asserting that the current process is the child of a `fork()` (or similar),
+    /// and that it will call `exec()` (or exit) without returning to other code that may be
+    /// relying on the file descriptors that are about to be closed.
+    ///
+    /// # Safety
+    ///
+    /// The above must be true, and the token must not be used after that point (for example, it
+    /// must not be stored somewhere and used after a failed `exec()` returns to normal code).
+    #[inline]
+    pub unsafe fn post_fork() -> Self {
+        Self {
+            _marker: PhantomData,
+        }
+    }
+
+    /// Create a token asserting that the current process is single-threaded and at the very
+    /// beginning of its startup (for example, at the top of `main()` in a setuid program), before
+    /// anything has opened file descriptors that it expects to stay open.
+    ///
+    /// # Safety
+    ///
+    /// The above must be true for as long as the token is used. In particular, no other threads
+    /// may have been started, and no objects that own file descriptors (such as `File`s) may be
+    /// alive, except for ones that will be excluded from closing.
+    #[inline]
+    pub unsafe fn single_threaded_startup() -> Self {
+        Self {
+            _marker: PhantomData,
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counts the system calls made while finding, closing, and setting the close-on-exec flag on
+/// file descriptors. See [`CloseFdsBuilder::syscall_counter()`] and
+/// [`FdIterBuilder::syscall_counter()`].
+///
+/// This is intended for tuning code that closes file descriptors in a hot path (for example,
+/// before spawning each child process) without having to run it under `strace`. A counter is
+/// usually a `static`, so that it can be shared with a child process after a `fork()` (the counts
+/// are then only updated in the child's copy):
+///
+/// ```
+/// use close_fds::{CloseFdsBuilder, SyscallCounter};
+///
+/// static COUNTER: SyscallCounter = SyscallCounter::new();
+///
+/// CloseFdsBuilder::new()
+///     .syscall_counter(Some(&COUNTER))
+///     .cloexecfrom(3);
+/// let counts = COUNTER.get();
+/// println!("{} fcntl() calls, {} close_range() calls", counts.fcntl, counts.close_range);
+/// ```
+///
+/// Updating the counter is async-signal-safe and doesn't allocate memory.
+///
+/// [`CloseFdsBuilder::syscall_counter()`]: ./struct.CloseFdsBuilder.html#method.syscall_counter
+/// [`FdIterBuilder::syscall_counter()`]: ./struct.FdIterBuilder.html#method.syscall_counter
+#[derive(Debug, Default)]
+pub struct SyscallCounter {
+    close: AtomicUsize,
+    fcntl: AtomicUsize,
+    getdents: AtomicUsize,
+    close_range: AtomicUsize,
+    closefrom: AtomicUsize,
+}
+
+impl SyscallCounter {
+    /// Create a new counter with all of the counts set to zero.
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            close: AtomicUsize::new(0),
+            fcntl: AtomicUsize::new(0),
+            getdents: AtomicUsize::new(0),
+            close_range: AtomicUsize::new(0),
+            closefrom: AtomicUsize::new(0),
+        }
+    }
+
+    /// Get the current counts.
+    pub fn get(&self) -> SyscallCounts {
+        SyscallCounts {
+            close: self.close.load(Ordering::Relaxed),
+            fcntl: self.fcntl.load(Ordering::Relaxed),
+            getdents: self.getdents.load(Ordering::Relaxed),
+            close_range: self.close_range.load(Ordering::Relaxed),
+            closefrom: self.closefrom.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reset all of the counts to zero, and return what they were.
+    pub fn reset(&self) -> SyscallCounts {
+        SyscallCounts {
+            close: self.close.swap(0, Ordering::Relaxed),
+            fcntl: self.fcntl.swap(0, Ordering::Relaxed),
+            getdents: self.getdents.swap(0, Ordering::Relaxed),
+            close_range: self.close_range.swap(0, Ordering::Relaxed),
+            closefrom: self.closefrom.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+/// A snapshot of the counts recorded by a [`SyscallCounter`].
+///
+/// Every call is counted, whether or not it succeeded (for example, a `close_range()` call that
+/// failed because the kernel is too old still counts).
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub struct SyscallCounts {
+    /// The number of `close()` calls (including closing the directory file descriptor used to
+    /// list the open file descriptors, like `/proc/self/fd`).
+    pub close: usize,
+    /// The number of `fcntl()` calls (e.g. to check whether a file descriptor is open, or to get
+    /// and set its flags).
+    pub fcntl: usize,
+    /// The number of calls that read directory entries while listing the open file descriptors
+    /// (`getdents64()` on Linux, or the equivalent on other platforms).
+    pub getdents: usize,
+    /// The number of `close_range()` calls (with or without `CLOSE_RANGE_CLOEXEC`).
+    pub close_range: usize,
+    /// The number of `closefrom()` calls (on the BSDs).
+    pub closefrom: usize,
+}
+
+impl SyscallCounts {
+    /// Get the total number of system calls counted.
+    #[inline]
+    pub fn total(&self) -> usize {
+        self.close + self.fcntl + self.getdents + self.close_range + self.closefrom
+    }
+}
+
+/// The (optional) counter that the closing, close-on-exec, and iteration code reports its system
+/// calls to.
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct Counter(pub Option<&'static SyscallCounter>);
+
+impl Counter {
+    #[inline]
+    fn add(self, field: fn(&SyscallCounter) -> &AtomicUsize, n: usize) {
+        if let Some(counter) = self.0 {
+            field(counter).fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub fn close(self) {
+        self.add(|c| &c.close, 1);
+    }
+
+    #[inline]
+    pub fn fcntl(self, n: usize) {
+        self.add(|c| &c.fcntl, n);
+    }
+
+    #[cfg(all(
+        not(feature = "no-fs-backends"),
+        any(
+            target_os = "linux",
+            target_os = "macos",
+            all(
+                any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                feature = "ios-raw-syscalls"
+            ),
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "solaris",
+            target_os = "illumos",
+        )
+    ))]
+    #[inline]
+    pub fn getdents(self) {
+        self.add(|c| &c.getdents, 1);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    #[inline]
+    pub fn close_range(self) {
+        self.add(|c| &c.close_range, 1);
+    }
+
+    #[cfg(any(
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+    ))]
+    #[inline]
+    pub fn closefrom(self) {
+        self.add(|c| &c.closefrom, 1);
+    }
+}
@@ -0,0 +1,122 @@
+// Declarations that `libc` doesn't provide (or doesn't provide consistently across versions).
+// The documented public wrappers around some of these live in `sys`.
+
+#[cfg(target_os = "freebsd")]
+extern "C" {
+    pub fn closefrom(lowfd: libc::c_int);
+}
+
+#[cfg(any(target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))]
+extern "C" {
+    pub fn closefrom(fd: libc::c_int) -> libc::c_int;
+    #[cfg(target_os = "openbsd")]
+    pub fn getdtablecount() -> libc::c_int;
+}
+
+// For openat2(); spelled out here because older versions of libc don't have them
+#[cfg(all(not(feature = "no-fs-backends"), target_os = "linux"))]
+#[repr(C)]
+pub struct open_how {
+    pub flags: u64,
+    pub mode: u64,
+    pub resolve: u64,
+}
+#[cfg(all(not(feature = "no-fs-backends"), target_os = "linux"))]
+pub const RESOLVE_NO_XDEV: u64 = 0x01;
+#[cfg(all(not(feature = "no-fs-backends"), target_os = "linux"))]
+pub const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+#[cfg(all(not(feature = "no-fs-backends"), target_os = "linux"))]
+pub const RESOLVE_NO_SYMLINKS: u64 = 0x04;
+#[cfg(all(not(feature = "no-fs-backends"), target_os = "linux"))]
+pub const PROC_SUPER_MAGIC: i64 = 0x9fa0;
+
+#[cfg(all(
+    target_os = "linux",
+    not(any(feature = "no-fs-backends", feature = "libc-wrappers-only"))
+))]
+pub const KCMP_FILES: libc::c_int = 2;
+
+#[cfg(all(
+    not(feature = "no-fs-backends"),
+    any(
+        target_os = "macos",
+        all(
+            any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+            feature = "ios-raw-syscalls"
+        )
+    )
+))]
+pub const SYS_GETDIRENTRIES64: libc::c_int = 344;
+
+#[cfg(all(not(feature = "no-fs-backends"), target_os = "freebsd"))]
+pub const SYS_GETDIRENTRIES: libc::c_int = 554;
+#[cfg(all(not(feature = "no-fs-backends"), target_os = "freebsd"))]
+pub const SYS_FREEBSD11_GETDIRENTRIES: libc::c_int = 196;
+
+// The `dirent` layout used on FreeBSD 12+ (after the switch to 64-bit inode numbers)
+//
+// This is spelled out explicitly (instead of using `libc::dirent`) because the layout of
+// `libc::dirent` depends on which FreeBSD version `libc` was configured for.
+#[cfg(all(not(feature = "no-fs-backends"), target_os = "freebsd"))]
+#[repr(C)]
+pub struct dirent {
+    pub d_fileno: u64,
+    pub d_off: i64,
+    pub d_reclen: u16,
+    pub d_type: u8,
+    d_pad0: u8,
+    pub d_namlen: u16,
+    d_pad1: u16,
+    pub d_name: [libc::c_char; 256],
+}
+
+// The `dirent` layout used before FreeBSD 12
+#[cfg(all(not(feature = "no-fs-backends"), target_os = "freebsd"))]
+#[repr(C)]
+pub struct freebsd11_dirent {
+    pub d_fileno: u32,
+    pub d_reclen: u16,
+    pub d_type: u8,
+    pub d_namlen: u8,
+    pub d_name: [libc::c_char; 256],
+}
+
+// libc only declares syscall() for macOS and iOS, but it's present on tvOS and watchOS too
+#[cfg(all(
+    not(feature = "no-fs-backends"),
+    any(target_os = "tvos", target_os = "watchos"),
+    feature = "ios-raw-syscalls"
+))]
+extern "C" {
+    pub fn syscall(num: libc::c_int, ...) -> libc::c_int;
+}
+
+#[cfg(all(feature = "std", any(target_os = "macos", target_os = "ios")))]
+extern "C" {
+    pub fn posix_spawn_file_actions_addinherit_np(
+        file_actions: *mut libc::posix_spawn_file_actions_t,
+        filedes: libc::c_int,
+    ) -> libc::c_int;
+}
+
+#[cfg(all(not(feature = "no-fs-backends"), target_os = "netbsd"))]
+extern "C" {
+    #[link_name = "__getdents30"]
+    pub fn getdents(
+        fildes: libc::c_int,
+        buf: *mut libc::c_char,
+        nbyte: libc::size_t,
+    ) -> libc::c_int;
+}
+
+#[cfg(all(
+    not(feature = "no-fs-backends"),
+    any(target_os = "solaris", target_os = "illumos")
+))]
+extern "C" {
+    pub fn getdents(
+        fildes: libc::c_int,
+        buf: *mut libc::dirent,
+        nbyte: libc::size_t,
+    ) -> libc::c_int;
+}
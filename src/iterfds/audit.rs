@@ -0,0 +1,211 @@
+use std::path::PathBuf;
+use std::vec::Vec;
+
+use super::FdInfoIter;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use super::SpecialFdKind;
+
+/// The kind of file that a file descriptor refers to, as reported by [`FdAuditPolicy::audit()`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum FdKind {
+    /// A regular file.
+    File,
+    /// A directory.
+    Dir,
+    /// A character device (such as a terminal or `/dev/null`).
+    CharDevice,
+    /// A block device.
+    BlockDevice,
+    /// A pipe or FIFO.
+    Fifo,
+    /// A socket.
+    Socket,
+    /// A symbolic link (opened with `O_PATH` or `O_SYMLINK`).
+    Symlink,
+    /// One of the special kinds of file descriptors provided by Linux.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    Special(SpecialFdKind),
+    /// The kind could not be determined.
+    Unknown,
+}
+
+impl FdKind {
+    pub(super) fn detect(it: &FdInfoIter, fd: libc::c_int) -> Self {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(kind) = it.read_special_kind(fd) {
+            return Self::Special(kind);
+        }
+
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let _ = it;
+
+        let mut st = core::mem::MaybeUninit::uninit();
+        if unsafe { libc::fstat(fd, st.as_mut_ptr()) } != 0 {
+            return Self::Unknown;
+        }
+
+        // st_mode isn't always a mode_t (e.g. on 32-bit Android)
+        #[allow(clippy::unnecessary_cast)]
+        match unsafe { st.assume_init() }.st_mode as libc::mode_t & libc::S_IFMT {
+            libc::S_IFREG => Self::File,
+            libc::S_IFDIR => Self::Dir,
+            libc::S_IFCHR => Self::CharDevice,
+            libc::S_IFBLK => Self::BlockDevice,
+            libc::S_IFIFO => Self::Fifo,
+            libc::S_IFSOCK => Self::Socket,
+            libc::S_IFLNK => Self::Symlink,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Get a short name for this kind (as used by [`dump_open_fds()`](./fn.dump_open_fds.html)).
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Self::File => "file",
+            Self::Dir => "dir",
+            Self::CharDevice => "chr",
+            Self::BlockDevice => "blk",
+            Self::Fifo => "fifo",
+            Self::Socket => "socket",
+            Self::Symlink => "link",
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Self::Special(kind) => match kind {
+                SpecialFdKind::Epoll => "epoll",
+                SpecialFdKind::EventFd => "eventfd",
+                SpecialFdKind::SignalFd => "signalfd",
+                SpecialFdKind::TimerFd => "timerfd",
+                SpecialFdKind::Inotify => "inotify",
+                SpecialFdKind::Memfd => "memfd",
+                SpecialFdKind::PidFd => "pidfd",
+                SpecialFdKind::IoUring => "io_uring",
+                SpecialFdKind::OtherAnonInode => "anon",
+            },
+            Self::Unknown => "?",
+        }
+    }
+}
+
+/// A policy describing which file descriptors a process expects to have open (for example, at
+/// startup).
+///
+/// A file descriptor is allowed if its number was passed to [`Self::allow_fds()`], *or* if the
+/// kind of file it refers to was passed to [`Self::allow_kinds()`]. [`Self::audit()`] reports
+/// every open file descriptor that isn't allowed.
+///
+/// Unlike [`assert_only_open_fds()`](./fn.assert_only_open_fds.html), this reports details about
+/// each unexpected file descriptor, which makes it suitable for a "report, then close" workflow:
+///
+/// ```no_run
+/// # use close_fds::FdAuditPolicy;
+/// let violations = FdAuditPolicy::new().allow_fds(&[0, 1, 2]).audit();
+///
+/// for violation in violations.iter() {
+///     eprintln!(
+///         "Closing unexpected file descriptor {} ({}, {:?})",
+///         violation.fd,
+///         violation.kind.name(),
+///         violation.path,
+///     );
+///     unsafe {
+///         libc::close(violation.fd);
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FdAuditPolicy {
+    allowed_fds: Vec<libc::c_int>,
+    allowed_kinds: Vec<FdKind>,
+}
+
+impl FdAuditPolicy {
+    /// Create a new policy that doesn't allow any file descriptors.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow the given file descriptors, in addition to any that were previously allowed.
+    pub fn allow_fds(&mut self, fds: &[libc::c_int]) -> &mut Self {
+        self.allowed_fds.extend_from_slice(fds);
+        self
+    }
+
+    /// Allow any file descriptors that refer to one of the given kinds of files, in addition to
+    /// any kinds that were previously allowed.
+    pub fn allow_kinds(&mut self, kinds: &[FdKind]) -> &mut Self {
+        self.allowed_kinds.extend_from_slice(kinds);
+        self
+    }
+
+    /// Check the current process's open file descriptors against this policy, returning the ones
+    /// that aren't allowed (in ascending order).
+    ///
+    /// See the warnings for [`FdIterBuilder`](./struct.FdIterBuilder.html) regarding
+    /// multithreaded programs.
+    pub fn audit(&self) -> Vec<FdAuditViolation> {
+        let mut violations = Vec::new();
+        let mut it = super::iter_open_fds_info(0);
+
+        while let Some(info) = it.next() {
+            if self.allowed_fds.contains(&info.fd) {
+                continue;
+            }
+
+            let kind = FdKind::detect(&it, info.fd);
+            if self.allowed_kinds.contains(&kind) {
+                continue;
+            }
+
+            violations.push(FdAuditViolation {
+                fd: info.fd,
+                kind,
+                cloexec: info.cloexec,
+                path: read_path(&it, info.fd),
+            });
+        }
+
+        violations
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "solaris",
+    target_os = "illumos",
+))]
+fn read_path(it: &FdInfoIter, fd: libc::c_int) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut buf = [0; libc::PATH_MAX as usize];
+    let path = it.read_path(fd, &mut buf)?;
+    Some(std::ffi::OsStr::from_bytes(path).into())
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "solaris",
+    target_os = "illumos",
+)))]
+fn read_path(_it: &FdInfoIter, _fd: libc::c_int) -> Option<PathBuf> {
+    None
+}
+
+/// A file descriptor that was not allowed by an [`FdAuditPolicy`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct FdAuditViolation {
+    /// The file descriptor number.
+    pub fd: libc::c_int,
+    /// The kind of file that it refers to.
+    pub kind: FdKind,
+    /// Whether the close-on-exec flag is set on it.
+    pub cloexec: bool,
+    /// The path that it refers to, if it could be determined.
+    ///
+    /// This is only available on Linux, Android, Solaris, and Illumos; see
+    /// [`FdInfoIter::read_path()`](./struct.FdInfoIter.html) for caveats.
+    pub path: Option<PathBuf>,
+}
@@ -0,0 +1,193 @@
+/// A cached handle to the directory that lists the current process's open file descriptors (such
+/// as `/proc/self/fd` or `/dev/fd`), for use with
+/// [`FdIterBuilder::iter_from_cached()`](./struct.FdIterBuilder.html#method.iter_from_cached) and
+/// [`CloseFdsBuilder::cloexecfrom_cached()`](./struct.CloseFdsBuilder.html#method.cloexecfrom_cached).
+///
+/// Normally, each [`FdIter`](./struct.FdIter.html) opens the directory and closes it when it's
+/// done. Programs that iterate over their file descriptors repeatedly (for example, servers that
+/// periodically make sure the close-on-exec flag is set on all of them) can use a cache to open
+/// the directory once and rewind it between uses instead.
+///
+/// The directory is opened the first time the cache is used, and closed when the cache is
+/// dropped (or [`Self::invalidate()`] is called). On platforms where file descriptors aren't
+/// found by reading a directory (or if the `no-fs-backends` feature is enabled), this does
+/// nothing.
+///
+/// # Caveats
+///
+/// - Iterators that use the cache skip the cached file descriptor, like they skip the directory
+///   file descriptor they open themselves.
+/// - The cached file descriptor must not be closed by other code. In particular, if
+///   [`CloseFdsBuilder::closefrom()`](./struct.CloseFdsBuilder.html#method.closefrom) may be
+///   called while the cache is alive, add [`Self::as_raw_fd()`] to the list of file descriptors to
+///   keep (or call `invalidate()` first).
+/// - After a `fork()`, the child's copy of the file descriptor shares its position with the
+///   parent's, so rewinding it in one process would interfere with the other. The cache notices if
+///   it's being used in a different process than the one it was opened in, and reopens the
+///   directory in that case; `invalidate()` can also be called explicitly in the child.
+#[derive(Debug)]
+pub struct FdDirCache {
+    dirfd: libc::c_int,
+    #[cfg(all(
+        not(feature = "no-fs-backends"),
+        any(
+            target_os = "linux",
+            target_os = "macos",
+            all(
+                any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                feature = "ios-raw-syscalls"
+            ),
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "solaris",
+            target_os = "illumos",
+        )
+    ))]
+    pid: libc::pid_t,
+}
+
+impl FdDirCache {
+    /// Create a new, empty cache.
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            dirfd: -1,
+            #[cfg(all(
+                not(feature = "no-fs-backends"),
+                any(
+                    target_os = "linux",
+                    target_os = "macos",
+                    all(
+                        any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                        feature = "ios-raw-syscalls"
+                    ),
+                    target_os = "freebsd",
+                    target_os = "netbsd",
+                    target_os = "solaris",
+                    target_os = "illumos",
+                )
+            ))]
+            pid: 0,
+        }
+    }
+
+    /// Get the cached directory file descriptor, if the directory has been opened.
+    #[inline]
+    pub fn as_raw_fd(&self) -> Option<libc::c_int> {
+        if self.dirfd >= 0 {
+            Some(self.dirfd)
+        } else {
+            None
+        }
+    }
+
+    /// Close the cached directory file descriptor (if it's open). The directory will be reopened
+    /// the next time the cache is used.
+    ///
+    /// This is async-signal-safe.
+    #[inline]
+    pub fn invalidate(&mut self) {
+        if self.dirfd >= 0 {
+            unsafe {
+                libc::close(self.dirfd);
+            }
+            self.dirfd = -1;
+        }
+    }
+
+    /// Get the directory file descriptor (opening it if necessary), or -1 if it can't be opened.
+    #[cfg(all(
+        not(feature = "no-fs-backends"),
+        any(
+            target_os = "linux",
+            target_os = "macos",
+            all(
+                any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                feature = "ios-raw-syscalls"
+            ),
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "solaris",
+            target_os = "illumos",
+        )
+    ))]
+    pub(crate) fn get(&mut self, dirfd_minfd: libc::c_int) -> libc::c_int {
+        let pid = unsafe { libc::getpid() };
+
+        if self.dirfd >= 0 && self.pid != pid {
+            // We've been fork()ed; see above
+            self.invalidate();
+        }
+
+        if self.dirfd < 0 {
+            if let Some(dirfd) = super::dirfd::open_dir(dirfd_minfd) {
+                self.dirfd = dirfd;
+                self.pid = pid;
+            }
+        }
+
+        self.dirfd
+    }
+}
+
+impl Default for FdDirCache {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for FdDirCache {
+    #[inline]
+    fn drop(&mut self) {
+        self.invalidate();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fd_dir_cache() {
+        let mut cache = FdDirCache::new();
+        assert_eq!(cache.as_raw_fd(), None);
+
+        // Should work everywhere, even if there's nothing to cache
+        assert!(crate::FdIterBuilder::new()
+            .iter_from_cached(&mut cache, 0)
+            .any(|fd| fd == 0));
+
+        let dirfd = cache.as_raw_fd();
+        assert_eq!(
+            dirfd.is_some(),
+            cfg!(all(
+                not(any(
+                    feature = "no-fs-backends",
+                    feature = "libc-wrappers-only"
+                )),
+                any(
+                    target_os = "linux",
+                    target_os = "macos",
+                    target_os = "freebsd",
+                    target_os = "netbsd",
+                    target_os = "solaris",
+                    target_os = "illumos",
+                )
+            )) && crate::iterfds::dirfd_backend_error().is_none()
+        );
+
+        if let Some(dirfd) = dirfd {
+            // The cached file descriptor is reused (after rewinding), and it's never yielded
+            for _ in 0..2 {
+                let mut it = crate::FdIterBuilder::new().iter_from_cached(&mut cache, 0);
+                assert!(!it.any(|fd| fd == dirfd));
+                drop(it);
+                assert_eq!(cache.as_raw_fd(), Some(dirfd));
+            }
+        }
+
+        cache.invalidate();
+        assert_eq!(cache.as_raw_fd(), None);
+    }
+}
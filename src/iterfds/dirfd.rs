@@ -1,3 +1,7 @@
+#[cfg(target_os = "linux")]
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicI32, AtomicU8, Ordering};
+
 #[cfg(target_os = "linux")]
 type RawDirent = libc::dirent64;
 #[cfg(target_os = "linux")]
@@ -12,27 +16,89 @@ unsafe fn getdents(fd: libc::c_int, buf: &mut [u8]) -> isize {
 }
 
 #[cfg(target_os = "freebsd")]
-type RawDirent = crate::sys::dirent;
+type RawDirent = crate::ffi::dirent;
 #[cfg(target_os = "freebsd")]
 #[inline]
-unsafe fn getdents(fd: libc::c_int, buf: &mut [u8]) -> isize {
-    crate::sys::getdirentries(
+unsafe fn getdents(fd: libc::c_int, buf: &mut [u8], ino64: bool) -> isize {
+    // We make the syscalls directly (instead of calling getdirentries()) so that the layout of the
+    // entries we get back depends only on the running kernel -- not on which version of FreeBSD
+    // this was built against, or which symbol version of getdirentries() it was linked with.
+    libc::syscall(
+        if ino64 {
+            crate::ffi::SYS_GETDIRENTRIES
+        } else {
+            crate::ffi::SYS_FREEBSD11_GETDIRENTRIES
+        },
         fd,
-        buf.as_mut_ptr() as *mut libc::c_char,
+        buf.as_mut_ptr(),
         buf.len(),
-        core::ptr::null_mut(),
+        core::ptr::null_mut::<libc::off_t>(),
     ) as isize
 }
 
-#[cfg(any(target_os = "macos", target_os = "ios"))]
+#[cfg(target_os = "freebsd")]
+pub fn has_ino64_dirents() -> bool {
+    use core::sync::atomic::{AtomicU8, Ordering};
+
+    // FreeBSD 12 switched to 64-bit inode numbers, which changed the layout of `struct dirent`
+    // (and added a new getdirentries() syscall that returns entries in the new layout). Trying to
+    // make a syscall that the kernel doesn't recognize will get the process killed with SIGSYS, so
+    // we have to check the kernel version to find out which one to use.
+
+    // 1=new layout, 0=old layout, other values=uninitialized
+    static HAS_INO64: AtomicU8 = AtomicU8::new(2);
+
+    match HAS_INO64.load(Ordering::Relaxed) {
+        1 => true,
+        0 => false,
+
+        _ => {
+            let mut osreldate = 0;
+            let mut oldlen = core::mem::size_of::<libc::c_int>();
+
+            // If sysctl() fails somehow (???), assume a modern kernel
+            let has_ino64 = unsafe {
+                libc::sysctl(
+                    [libc::CTL_KERN, libc::KERN_OSRELDATE].as_ptr(),
+                    2,
+                    &mut osreldate as *mut _ as *mut _,
+                    &mut oldlen,
+                    core::ptr::null(),
+                    0,
+                )
+            } != 0
+                || osreldate >= 1200031;
+
+            HAS_INO64.store(has_ino64 as u8, Ordering::Relaxed);
+            has_ino64
+        }
+    }
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "tvos",
+    target_os = "watchos"
+))]
 type RawDirent = libc::dirent;
-#[cfg(any(target_os = "macos", target_os = "ios"))]
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "tvos",
+    target_os = "watchos"
+))]
 #[inline]
 unsafe fn getdents(fd: libc::c_int, buf: &mut [u8]) -> isize {
     let mut offset = core::mem::MaybeUninit::<libc::off_t>::uninit();
 
-    libc::syscall(
-        crate::sys::SYS_GETDIRENTRIES64,
+    #[cfg(any(target_os = "tvos", target_os = "watchos"))]
+    use crate::ffi::syscall;
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    use libc::syscall;
+
+    syscall(
+        crate::ffi::SYS_GETDIRENTRIES64,
         fd,
         buf.as_mut_ptr(),
         buf.len(),
@@ -45,15 +111,15 @@ type RawDirent = libc::dirent;
 #[cfg(any(target_os = "netbsd", target_os = "solaris", target_os = "illumos"))]
 #[inline]
 unsafe fn getdents(fd: libc::c_int, buf: &mut [u8]) -> isize {
-    crate::sys::getdents(fd, buf.as_mut_ptr() as *mut _, buf.len()) as isize
+    crate::ffi::getdents(fd, buf.as_mut_ptr() as *mut _, buf.len()) as isize
 }
 
-fn parse_int_bytes<I: Iterator<Item = u8>>(it: I) -> Option<libc::c_int> {
+pub(crate) fn parse_int_bytes<I: Iterator<Item = u8>>(it: I) -> Option<libc::c_int> {
     let mut num: libc::c_int = 0;
     let mut seen_any = false;
 
     for ch in it {
-        if (b'0'..=b'9').contains(&ch) {
+        if ch.is_ascii_digit() {
             num = num
                 .checked_mul(10)?
                 .checked_add((ch - b'0') as libc::c_int)?;
@@ -70,6 +136,36 @@ fn parse_int_bytes<I: Iterator<Item = u8>>(it: I) -> Option<libc::c_int> {
     }
 }
 
+/// If opening or reading the directory failed with an error that suggests the OS won't let us read
+/// it at all (e.g. `getdents64()` being denied by a seccomp filter, or the macOS App Sandbox denying
+/// access to `/dev/fd`), this is set to that error code (and the
+/// directory won't be opened again). Otherwise, it's 0.
+static BLOCKED_ERRNO: AtomicI32 = AtomicI32::new(0);
+
+/// Get the error code stored in `BLOCKED_ERRNO`, if any.
+#[inline]
+pub fn blocked_errno() -> Option<libc::c_int> {
+    match BLOCKED_ERRNO.load(Ordering::Relaxed) {
+        0 => None,
+        eno => Some(eno),
+    }
+}
+
+const ORDER_UNKNOWN: u8 = 0;
+const ORDER_ASCENDING: u8 = 1;
+const ORDER_UNORDERED: u8 = 2;
+
+/// Whether the directory has been seen to list its entries in strictly ascending order (which
+/// `DirFdIter` relies on), or has been seen to list them out of order or with duplicates (e.g. on
+/// WSL 1), in which case it won't be opened again.
+static ENTRY_ORDER: AtomicU8 = AtomicU8::new(ORDER_UNKNOWN);
+
+/// Returns whether the directory has been seen to list its entries out of order.
+#[inline]
+pub fn entries_unordered() -> bool {
+    ENTRY_ORDER.load(Ordering::Relaxed) == ORDER_UNORDERED
+}
+
 #[repr(align(8))]
 struct DirFdIterBuf {
     data: [u8; core::mem::size_of::<RawDirent>()],
@@ -79,117 +175,349 @@ pub struct DirFdIter {
     minfd: libc::c_int,
     // This is ONLY < 0 if the iterator was exhausted during iteration and has now been closed.
     dirfd: libc::c_int,
+    // Whether `dirfd` should be closed when we're done with it (it isn't if it's cached)
+    owned: bool,
+    // Whether the kernel returns entries in the FreeBSD 12+ layout
+    #[cfg(target_os = "freebsd")]
+    ino64: bool,
     dirent_buf: DirFdIterBuf,
     dirent_nbytes: usize,
     dirent_offset: usize,
+    // The last file descriptor that was listed (whether or not it was returned), used to check
+    // that the entries are in ascending order
+    last_fd: libc::c_int,
+    counter: crate::counter::Counter,
 }
 
-impl DirFdIter {
-    #[inline]
-    pub fn open(minfd: libc::c_int) -> Option<Self> {
-        #[cfg(target_os = "linux")]
-        let dirfd = unsafe {
-            // Try /proc/self/fd on Linux.
-            // However, on WSL 1, getdents64() doesn't always return the entries in order, and also
-            // seems to skip some file descriptors. So skip it on WSL 1.
-
-            if crate::util::is_wsl_1() {
-                return None;
-            }
+impl core::fmt::Debug for DirFdIter {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let mut f = f.debug_struct("DirFdIter");
+        f.field("minfd", &self.minfd)
+            .field("dirfd", &self.dirfd)
+            .field("owned", &self.owned);
 
-            libc::open(
+        #[cfg(target_os = "freebsd")]
+        f.field("ino64", &self.ino64);
+
+        f.field("dirent_buf_size", &self.dirent_buf.data.len())
+            .field("dirent_nbytes", &self.dirent_nbytes)
+            .field("dirent_offset", &self.dirent_offset)
+            .field("last_fd", &self.last_fd)
+            .finish()
+    }
+}
+
+/// Open the directory that lists this process's file descriptors (e.g. `/proc/self/fd`), moving
+/// the file descriptor to `dirfd_minfd` or above if possible. Returns `None` if it isn't available.
+pub fn open_dir(dirfd_minfd: libc::c_int) -> Option<libc::c_int> {
+    // Reading the directory requires calling syscall() on these platforms
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "tvos",
+        target_os = "watchos"
+    ))]
+    if cfg!(feature = "libc-wrappers-only") {
+        return None;
+    }
+
+    if blocked_errno().is_some() || entries_unordered() {
+        // Don't bother; reading it will just fail again (or be useless)
+        return None;
+    }
+
+    #[cfg(target_os = "linux")]
+    let dirfd = unsafe {
+        // Try /proc/self/fd on Linux
+        match open_proc_self_fd_hardened() {
+            Ok(dirfd) => dirfd,
+            Err(()) => libc::open(
                 "/proc/self/fd\0".as_ptr() as *const libc::c_char,
                 libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
-            )
-        };
+            ),
+        }
+    };
 
-        #[cfg(target_os = "freebsd")]
-        let dirfd = {
-            // On FreeBSD platforms, /dev/fd is usually a static directory with only entries
-            // for 0, 1, and 2. This is obviously incorrect.
-            // However, it can also be a fdescfs filesystem, in which case it's correct.
-            // So we only trust /dev/fd if it's on a different device than /dev.
+    #[cfg(target_os = "freebsd")]
+    let dirfd = {
+        // On FreeBSD platforms, /dev/fd is usually a static directory with only entries
+        // for 0, 1, and 2. This is obviously incorrect.
+        // However, it can also be a fdescfs filesystem, in which case it's correct.
+        // So we only trust /dev/fd if it's on a different device than /dev.
 
-            let mut dev_stat = core::mem::MaybeUninit::uninit();
-            let mut devfd_stat = core::mem::MaybeUninit::uninit();
+        let mut dev_stat = core::mem::MaybeUninit::uninit();
+        let mut devfd_stat = core::mem::MaybeUninit::uninit();
 
-            unsafe {
-                let dirfd = libc::open(
-                    "/dev/fd\0".as_ptr() as *const _,
-                    libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
-                );
-
-                if dirfd >= 0
-                    && (libc::stat("/dev\0".as_ptr() as *const _, dev_stat.as_mut_ptr()) != 0
-                        || libc::fstat(dirfd, devfd_stat.as_mut_ptr()) != 0
-                        || dev_stat.assume_init().st_dev == devfd_stat.assume_init().st_dev)
-                {
-                    // We were able to open /dev/fd. However, one of the following happened:
-                    // 1. We weren't able to stat() /dev.
-                    // 2. We weren't able to fstat() dirfd (which is open to /dev/fd).
-                    // 3. /dev's device number is the same as /dev/fd's device number.
-                    //
-                    // Case (3) means that /dev/fd is almost definitely NOT an fdescfs, so we can't
-                    // trust it. Cases (1) and (2) mean that we can't tell, so we must
-                    // conservatively assume that it isn't an fdescfs.
-                    libc::close(dirfd);
-                    -1
-                } else {
-                    dirfd
-                }
+        unsafe {
+            let dirfd = libc::open(
+                "/dev/fd\0".as_ptr() as *const _,
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            );
+
+            if dirfd >= 0
+                && (libc::stat("/dev\0".as_ptr() as *const _, dev_stat.as_mut_ptr()) != 0
+                    || libc::fstat(dirfd, devfd_stat.as_mut_ptr()) != 0
+                    || dev_stat.assume_init().st_dev == devfd_stat.assume_init().st_dev)
+            {
+                // We were able to open /dev/fd. However, one of the following happened:
+                // 1. We weren't able to stat() /dev.
+                // 2. We weren't able to fstat() dirfd (which is open to /dev/fd).
+                // 3. /dev's device number is the same as /dev/fd's device number.
+                //
+                // Case (3) means that /dev/fd is almost definitely NOT an fdescfs, so we can't
+                // trust it. Cases (1) and (2) mean that we can't tell, so we must
+                // conservatively assume that it isn't an fdescfs.
+                libc::close(dirfd);
+                -1
+            } else {
+                dirfd
             }
-        };
+        }
+    };
+
+    #[cfg(target_os = "netbsd")]
+    let dirfd = unsafe {
+        // On NetBSD, /dev/fd is a static directory, but /proc/self/fd is correct
+
+        libc::open(
+            "/proc/self/fd\0".as_ptr() as *const libc::c_char,
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    };
+
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "tvos",
+        target_os = "watchos"
+    ))]
+    let dirfd = unsafe {
+        // On macOS, /dev/fd is correct
+
+        let fd = libc::open(
+            "/dev/fd\0".as_ptr() as *const libc::c_char,
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        );
 
-        #[cfg(target_os = "netbsd")]
-        let dirfd = unsafe {
-            // On NetBSD, /dev/fd is a static directory, but /proc/self/fd is correct
+        // /dev/fd always exists, so EPERM/EACCES means that a sandbox profile (usually the App
+        // Sandbox) is denying access to it. That won't change, so remember it.
+        if fd < 0 {
+            let eno = crate::util::errno();
+            if matches!(eno, libc::EPERM | libc::EACCES) {
+                BLOCKED_ERRNO.store(eno, Ordering::Relaxed);
+            }
+        }
+
+        fd
+    };
 
+    #[cfg(any(target_os = "solaris", target_os = "illumos"))]
+    let dirfd = unsafe {
+        // On Solaris/Illumos, both /dev/fd and /proc/self/fd should be correct
+        // So let's try /dev/fd, then /proc/self/fd if that fails
+
+        let fd = libc::open(
+            "/dev/fd\0".as_ptr() as *const libc::c_char,
+            libc::O_RDONLY | libc::O_CLOEXEC,
+        );
+
+        if fd < 0 {
             libc::open(
                 "/proc/self/fd\0".as_ptr() as *const libc::c_char,
-                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+                libc::O_RDONLY | libc::O_CLOEXEC,
             )
-        };
+        } else {
+            fd
+        }
+    };
 
-        #[cfg(any(target_os = "macos", target_os = "ios"))]
-        let dirfd = unsafe {
-            // On macOS, /dev/fd is correct
+    if dirfd >= 0 {
+        Some(relocate(dirfd, dirfd_minfd))
+    } else {
+        None
+    }
+}
 
-            libc::open(
-                "/dev/fd\0".as_ptr() as *const libc::c_char,
-                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
-            )
-        };
+/// Set to false once openat2() has failed in a way that indicates it isn't available
+#[cfg(target_os = "linux")]
+static MAY_HAVE_OPENAT2: AtomicBool = AtomicBool::new(true);
 
-        #[cfg(any(target_os = "solaris", target_os = "illumos"))]
-        let dirfd = unsafe {
-            // On Solaris/Illumos, both /dev/fd and /proc/self/fd should be correct
-            // So let's try /dev/fd, then /proc/self/fd if that fails
+#[cfg(target_os = "linux")]
+unsafe fn openat2(
+    dirfd: libc::c_int,
+    path: &[u8],
+    flags: libc::c_int,
+    resolve: u64,
+) -> libc::c_int {
+    debug_assert_eq!(path.last(), Some(&0));
+
+    let how = crate::ffi::open_how {
+        flags: flags as u64,
+        mode: 0,
+        resolve,
+    };
 
-            let fd = libc::open(
-                "/dev/fd\0".as_ptr() as *const libc::c_char,
-                libc::O_RDONLY | libc::O_CLOEXEC,
-            );
+    libc::syscall(
+        libc::SYS_openat2,
+        dirfd,
+        path.as_ptr() as *const libc::c_char,
+        &how as *const crate::ffi::open_how,
+        core::mem::size_of::<crate::ffi::open_how>(),
+    ) as libc::c_int
+}
 
-            if fd < 0 {
-                libc::open(
-                    "/proc/self/fd\0".as_ptr() as *const libc::c_char,
-                    libc::O_RDONLY | libc::O_CLOEXEC,
-                )
-            } else {
-                fd
+/// Open `/proc/self/fd` with `openat2()`, so that a bind mount or symlink planted somewhere along
+/// the path can't redirect us to a different directory.
+///
+/// Returns `Err(())` if `openat2()` isn't available (in which case a plain `open()` should be
+/// used), or `Ok(-1)` if opening the directory failed (possibly because it looks like it has been
+/// tampered with).
+#[cfg(target_os = "linux")]
+#[allow(clippy::unnecessary_cast)]
+unsafe fn open_proc_self_fd_hardened() -> Result<libc::c_int, ()> {
+    if !MAY_HAVE_OPENAT2.load(Ordering::Relaxed) {
+        return Err(());
+    }
+
+    // Getting to /proc necessarily crosses a mount point, so RESOLVE_NO_XDEV can't be used here.
+    // Instead, refuse to follow symlinks, and then check that what we got is actually a procfs.
+    let procfd = openat2(
+        libc::AT_FDCWD,
+        b"/proc\0",
+        libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        crate::ffi::RESOLVE_NO_SYMLINKS | crate::ffi::RESOLVE_NO_MAGICLINKS,
+    );
+
+    if procfd < 0 {
+        return match crate::util::errno() {
+            // Not supported by the kernel, blocked by a seccomp filter, or the kernel doesn't
+            // recognize the arguments
+            libc::ENOSYS | libc::EPERM | libc::E2BIG | libc::EINVAL => {
+                MAY_HAVE_OPENAT2.store(false, Ordering::Relaxed);
+                Err(())
             }
+            _ => Ok(-1),
         };
+    }
+
+    let mut st = core::mem::MaybeUninit::<libc::statfs>::uninit();
+    if libc::fstatfs(procfd, st.as_mut_ptr()) != 0
+        || st.assume_init().f_type as i64 != crate::ffi::PROC_SUPER_MAGIC
+    {
+        libc::close(procfd);
+        return Ok(-1);
+    }
+
+    // "self" is a symlink, but it's resolved by procfs itself (not something that can be
+    // planted). RESOLVE_NO_XDEV makes sure nothing has been mounted over self/fd.
+    let dirfd = openat2(
+        procfd,
+        b"self/fd\0",
+        libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        crate::ffi::RESOLVE_NO_XDEV | crate::ffi::RESOLVE_NO_MAGICLINKS,
+    );
+    libc::close(procfd);
+
+    Ok(dirfd)
+}
+
+#[inline]
+fn relocate(dirfd: libc::c_int, dirfd_minfd: libc::c_int) -> libc::c_int {
+    if dirfd >= dirfd_minfd {
+        return dirfd;
+    }
+
+    // Move the directory file descriptor up to `dirfd_minfd` or above. If that fails (e.g.
+    // because it's above the file descriptor limit), just keep using the original one.
+    let newfd = unsafe { libc::fcntl(dirfd, libc::F_DUPFD_CLOEXEC, dirfd_minfd) };
+
+    if newfd >= 0 {
+        unsafe {
+            libc::close(dirfd);
+        }
+        newfd
+    } else {
+        dirfd
+    }
+}
+
+impl DirFdIter {
+    #[inline]
+    pub fn open(
+        minfd: libc::c_int,
+        dirfd_minfd: libc::c_int,
+        counter: crate::counter::Counter,
+    ) -> Option<Self> {
+        open_dir(dirfd_minfd).map(|dirfd| Self::new(minfd, dirfd, true, counter))
+    }
+
+    /// Create an iterator that reads from `dirfd` (which was returned by `open_dir()` and is
+    /// owned by an `FdDirCache`), after rewinding it to the beginning. The directory file
+    /// descriptor will not be closed.
+    #[inline]
+    pub fn from_cached(
+        minfd: libc::c_int,
+        dirfd: libc::c_int,
+        counter: crate::counter::Counter,
+    ) -> Option<Self> {
+        if blocked_errno().is_some()
+            || entries_unordered()
+            || unsafe { libc::lseek(dirfd, 0, libc::SEEK_SET) } != 0
+        {
+            return None;
+        }
+
+        Some(Self::new(minfd, dirfd, false, counter))
+    }
+
+    #[inline]
+    fn new(
+        minfd: libc::c_int,
+        dirfd: libc::c_int,
+        owned: bool,
+        counter: crate::counter::Counter,
+    ) -> Self {
+        Self {
+            minfd,
+            dirfd,
+            owned,
+            #[cfg(target_os = "freebsd")]
+            ino64: has_ino64_dirents(),
+            dirent_buf: DirFdIterBuf {
+                data: [0; core::mem::size_of::<RawDirent>()],
+            },
+            dirent_nbytes: 0,
+            dirent_offset: 0,
+            last_fd: -1,
+            counter,
+        }
+    }
+
+    /// Check that `fd` (the next entry in the directory) comes after the previous one. If it
+    /// doesn't, the directory is marked as unordered (so it won't be used again) and `Err(())` is
+    /// returned, so the caller can fall back on other methods.
+    ///
+    /// Until the directory has been seen to list its entries in order, each batch of entries is
+    /// checked by `fill_buf()` before any of them are returned, so this should only happen if the
+    /// order changes later.
+    #[inline]
+    fn check_next(&mut self, fd: libc::c_int) -> Result<(), ()> {
+        if fd <= self.last_fd {
+            ENTRY_ORDER.store(ORDER_UNORDERED, Ordering::Relaxed);
+            return Err(());
+        }
+
+        self.last_fd = fd;
+        Ok(())
+    }
 
-        if dirfd >= 0 {
-            Some(Self {
-                minfd,
-                dirfd,
-                dirent_buf: DirFdIterBuf {
-                    data: [0; core::mem::size_of::<RawDirent>()],
-                },
-                dirent_nbytes: 0,
-                dirent_offset: 0,
-            })
+    /// Returns the directory file descriptor, if it's still open.
+    #[inline]
+    pub fn dirfd(&self) -> Option<libc::c_int> {
+        if self.dirfd >= 0 {
+            Some(self.dirfd)
         } else {
             None
         }
@@ -197,6 +525,23 @@ impl DirFdIter {
 
     #[inline]
     unsafe fn get_entry_info(&self, offset: usize) -> (Option<libc::c_int>, usize) {
+        #[cfg(target_os = "freebsd")]
+        if !self.ino64 {
+            #[allow(clippy::cast_ptr_alignment)]
+            let entry = &*(self.dirent_buf.data.as_ptr().add(offset)
+                as *const crate::ffi::freebsd11_dirent);
+
+            let fd = parse_int_bytes(
+                entry
+                    .d_name
+                    .iter()
+                    .take(entry.d_namlen as usize)
+                    .map(|c| *c as u8),
+            );
+
+            return (fd, entry.d_reclen as usize);
+        }
+
         #[allow(clippy::cast_ptr_alignment)] // We trust the kernel not to make us segfault
         let entry = &*(self.dirent_buf.data.as_ptr().add(offset) as *const RawDirent);
 
@@ -211,50 +556,118 @@ impl DirFdIter {
         (fd, entry.d_reclen as usize)
     }
 
+    /// Read the next batch of entries into the buffer, returning the number of bytes read (or a
+    /// negative value on error).
     #[inline]
-    pub fn next(&mut self) -> Result<Option<libc::c_int>, ()> {
-        if self.dirfd < 0 {
-            // Exhausted
-            return Ok(None);
+    fn read_entries(&mut self) -> isize {
+        self.counter.getdents();
+        #[cfg(target_os = "freebsd")]
+        return unsafe { getdents(self.dirfd, &mut self.dirent_buf.data, self.ino64) };
+        #[cfg(not(target_os = "freebsd"))]
+        return unsafe { getdents(self.dirfd, &mut self.dirent_buf.data) };
+    }
+
+    /// Check that the batch of entries in the buffer is in strictly ascending order, and that it
+    /// comes after the previous batch.
+    fn check_batch(&self) -> bool {
+        let mut last_fd = self.last_fd;
+        let mut offset = 0;
+
+        while offset < self.dirent_nbytes {
+            let (fd, reclen) = unsafe { self.get_entry_info(offset) };
+            offset += reclen;
+
+            if let Some(fd) = fd {
+                if fd <= last_fd {
+                    return false;
+                }
+                last_fd = fd;
+            }
         }
 
-        loop {
-            if self.dirent_offset >= self.dirent_nbytes {
-                let nbytes = unsafe { getdents(self.dirfd, &mut self.dirent_buf.data) };
-
-                match nbytes.cmp(&0) {
-                    // > 0 -> Found at least one entry
-                    core::cmp::Ordering::Greater => {
-                        self.dirent_nbytes = nbytes as usize;
-                        self.dirent_offset = 0;
-                    }
+        true
+    }
+
+    /// Read the next batch of entries into the buffer. Returns `Ok(false)` (after closing the
+    /// directory file descriptor, unless it's cached) on EOF.
+    ///
+    /// Until the directory has been seen to list all of its entries in order, each batch is
+    /// checked before any of its entries are returned. If the entries are out of order the first
+    /// time the directory is read, this is almost always caught in the first batch, so the caller
+    /// can fall back on other methods without having returned (and then missing) any.
+    #[inline]
+    fn fill_buf(&mut self) -> Result<bool, ()> {
+        let nbytes = self.read_entries();
+
+        match nbytes.cmp(&0) {
+            // > 0 -> Found at least one entry
+            core::cmp::Ordering::Greater => {
+                self.dirent_nbytes = nbytes as usize;
+                self.dirent_offset = 0;
+
+                if ENTRY_ORDER.load(Ordering::Relaxed) != ORDER_ASCENDING && !self.check_batch() {
+                    ENTRY_ORDER.store(ORDER_UNORDERED, Ordering::Relaxed);
+                    return Err(());
+                }
+
+                Ok(true)
+            }
+
+            // 0 -> EOF
+            core::cmp::Ordering::Equal => {
+                // If we got this far, every batch was in order
+                if ENTRY_ORDER.load(Ordering::Relaxed) == ORDER_UNKNOWN {
+                    ENTRY_ORDER.store(ORDER_ASCENDING, Ordering::Relaxed);
+                }
 
-                    // 0 -> EOF
-                    core::cmp::Ordering::Equal => {
-                        // Close the directory file descriptor and return None
-                        unsafe {
-                            libc::close(self.dirfd);
-                        }
-                        self.dirfd = -1;
-                        return Ok(None);
+                // Close the directory file descriptor
+                if self.owned {
+                    unsafe {
+                        libc::close(self.dirfd);
                     }
+                    self.counter.close();
+                }
+                self.dirfd = -1;
+                Ok(false)
+            }
 
-                    // < 0 -> Error
-                    _ => return Err(()),
+            // < 0 -> Error
+            _ => {
+                // EPERM/EACCES/ENOSYS mean that reading the directory is being denied outright
+                // (opening it succeeded, so this is usually a seccomp filter), so it will never
+                // work. Remember that so future iterators skip straight to the fallback methods.
+                // (The caller will close the directory file descriptor.)
+                let eno = crate::util::errno();
+                if matches!(eno, libc::EPERM | libc::EACCES | libc::ENOSYS) {
+                    BLOCKED_ERRNO.store(eno, Ordering::Relaxed);
                 }
+                Err(())
             }
+        }
+    }
 
-            // Note: We're assuming the OS will return the file descriptors in ascending order.
-            // This's probably the case, considering that the kernel probably stores them in that
-            // order.
+    #[inline]
+    pub fn next(&mut self) -> Result<Option<libc::c_int>, ()> {
+        if self.dirfd < 0 {
+            // Exhausted
+            return Ok(None);
+        }
+
+        loop {
+            if self.dirent_offset >= self.dirent_nbytes && !self.fill_buf()? {
+                return Ok(None);
+            }
 
             let (fd, reclen) = unsafe { self.get_entry_info(self.dirent_offset) };
 
             // Adjust the offset for next time
-            self.dirent_offset += reclen as usize;
+            self.dirent_offset += reclen;
 
             // Were we able to parse it?
             if let Some(fd) = fd {
+                // Callers rely on the file descriptors being returned in ascending order
+                self.check_next(fd)?;
+
                 // Only return it if 1) it's in the correct range and 2) it's not
                 // the directory file descriptor we're using
 
@@ -265,6 +678,45 @@ impl DirFdIter {
         }
     }
 
+    /// Equivalent to calling `f` on every file descriptor returned by `next()`, but loops over each
+    /// batch of entries directly.
+    ///
+    /// If an error occurs, `Err` is returned with the accumulated value so far (and the iterator is
+    /// left in the same state as if `next()` had failed).
+    pub fn fold<B, F>(&mut self, mut acc: B, mut f: F) -> Result<B, B>
+    where
+        F: FnMut(B, libc::c_int) -> B,
+    {
+        if self.dirfd < 0 {
+            // Exhausted
+            return Ok(acc);
+        }
+
+        loop {
+            while self.dirent_offset < self.dirent_nbytes {
+                let (fd, reclen) = unsafe { self.get_entry_info(self.dirent_offset) };
+                self.dirent_offset += reclen;
+
+                // Same checks as in next()
+                if let Some(fd) = fd {
+                    if self.check_next(fd).is_err() {
+                        return Err(acc);
+                    }
+
+                    if fd >= self.minfd && fd != self.dirfd {
+                        acc = f(acc, fd);
+                    }
+                }
+            }
+
+            match self.fill_buf() {
+                Ok(true) => (),
+                Ok(false) => return Ok(acc),
+                Err(()) => return Err(acc),
+            }
+        }
+    }
+
     #[inline]
     pub fn size_hint(&self) -> (usize, Option<usize>) {
         if self.dirfd < 0 {
@@ -282,14 +734,11 @@ impl DirFdIter {
             let (fd, reclen) = unsafe { self.get_entry_info(dirent_offset) };
 
             // Adjust the offset for next time
-            dirent_offset += reclen as usize;
+            dirent_offset += reclen;
 
             // Were we able to parse it?
             if let Some(fd) = fd {
-                // Sanity check
-                debug_assert!(fd >= self.minfd);
-
-                if fd != self.dirfd {
+                if fd >= self.minfd && fd != self.dirfd {
                     // We found one
                     low += 1;
                 }
@@ -304,16 +753,19 @@ impl Drop for DirFdIter {
     #[inline]
     fn drop(&mut self) {
         // Close the directory file descriptor if it's still open
-        if self.dirfd >= 0 {
+        if self.owned && self.dirfd >= 0 {
             unsafe {
                 libc::close(self.dirfd);
             }
+            self.counter.close();
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    extern crate std;
+
     use super::*;
 
     use core::fmt::Write;
@@ -365,4 +817,116 @@ mod tests {
         write!(&mut buf, "{}", libc::c_int::MAX as libc::c_uint + 1).unwrap();
         assert_eq!(parse_int_bytes(buf.iter_bytes()), None);
     }
+
+    /// Run `f` in a child process, so it can change the process-wide state without affecting
+    /// other tests that are running in parallel.
+    fn run_in_child(f: fn()) {
+        unsafe {
+            let pid = libc::fork();
+            assert!(pid >= 0);
+            if pid == 0 {
+                let res = std::panic::catch_unwind(f);
+                libc::_exit(if res.is_ok() { 0 } else { 1 });
+            }
+
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            assert!(libc::WIFEXITED(status));
+            assert_eq!(libc::WEXITSTATUS(status), 0);
+        }
+    }
+
+    #[test]
+    fn test_blocked_errno() {
+        run_in_child(|| {
+            BLOCKED_ERRNO.store(libc::EPERM, Ordering::Relaxed);
+            assert_eq!(blocked_errno(), Some(libc::EPERM));
+            assert!(DirFdIter::open(0, 0, Default::default()).is_none());
+
+            BLOCKED_ERRNO.store(0, Ordering::Relaxed);
+            assert_eq!(blocked_errno(), None);
+        });
+    }
+
+    #[test]
+    fn test_entry_order() {
+        run_in_child(|| {
+            ENTRY_ORDER.store(ORDER_UNKNOWN, Ordering::Relaxed);
+
+            let mut it = match DirFdIter::open(0, 0, Default::default()) {
+                Some(it) => it,
+                None => return,
+            };
+            // Reading through the directory checks the order
+            while it.next().unwrap().is_some() {}
+            assert_eq!(ENTRY_ORDER.load(Ordering::Relaxed), ORDER_ASCENDING);
+
+            let mut it = DirFdIter::open(0, 0, Default::default()).unwrap();
+            assert!(it.next().unwrap().is_some());
+
+            // Pretend that the next entry is listed out of order
+            it.last_fd = libc::c_int::MAX;
+            assert_eq!(it.next(), Err(()));
+            assert!(entries_unordered());
+            assert!(DirFdIter::open(0, 0, Default::default()).is_none());
+
+            // Before the order is known, an out-of-order batch is rejected before anything from
+            // it is returned
+            ENTRY_ORDER.store(ORDER_UNKNOWN, Ordering::Relaxed);
+            let mut it = DirFdIter::open(0, 0, Default::default()).unwrap();
+            it.last_fd = libc::c_int::MAX;
+            assert_eq!(it.next(), Err(()));
+            assert!(entries_unordered());
+        });
+    }
+
+    #[test]
+    fn test_dirfd_minfd() {
+        let mut rlim = unsafe { core::mem::zeroed() };
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) },
+            0
+        );
+        if rlim.rlim_cur <= 512 {
+            return;
+        }
+
+        if let Some(mut it) = DirFdIter::open(0, 512, Default::default()) {
+            assert!(it.dirfd >= 512);
+
+            while let Some(fd) = it.next().unwrap() {
+                assert_ne!(fd, it.dirfd);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_open_proc_self_fd_hardened() {
+        let dirfd = match unsafe { open_proc_self_fd_hardened() } {
+            Ok(dirfd) => dirfd,
+            // openat2() isn't available
+            Err(()) => return,
+        };
+        assert!(dirfd >= 0);
+
+        // It's the same directory as a plain open() would give
+        let mut st1 = core::mem::MaybeUninit::<libc::stat>::uninit();
+        let mut st2 = core::mem::MaybeUninit::<libc::stat>::uninit();
+        unsafe {
+            assert_eq!(libc::fstat(dirfd, st1.as_mut_ptr()), 0);
+            assert_eq!(
+                libc::stat(
+                    "/proc/self/fd\0".as_ptr() as *const libc::c_char,
+                    st2.as_mut_ptr()
+                ),
+                0
+            );
+            let (st1, st2) = (st1.assume_init(), st2.assume_init());
+            assert_eq!((st1.st_dev, st1.st_ino), (st2.st_dev, st2.st_ino));
+        }
+
+        let mut it = DirFdIter::new(0, dirfd, true, Default::default());
+        assert_eq!(it.next().unwrap(), Some(0));
+    }
 }
@@ -0,0 +1,172 @@
+#[cfg(feature = "std")]
+use std::io;
+
+#[cfg(feature = "std")]
+use super::{FdInfo, FdInfoIter};
+
+/// Write a minimal listing of the current process's open file descriptors to the file descriptor
+/// `outfd`.
+///
+/// This is intended for use in crash handlers (for example, in a `SIGSEGV` handler), so it is
+/// async-signal-safe (see ["Async-signal-safety"](./index.html#async-signal-safety)): it doesn't
+/// allocate memory, and it only calls `write()` and the functions that are used to iterate over
+/// the open file descriptors.
+///
+/// One line is written for each open file descriptor. Each line contains the file descriptor
+/// number, followed by ` cloexec` if the close-on-exec flag is set on it. For example:
+///
+/// ```text
+/// 0
+/// 1
+/// 2
+/// 3 cloexec
+/// ```
+///
+/// (Note that `outfd` itself will be included.)
+///
+/// Returns `true` if the entire listing was written successfully, or `false` if an error occurred
+/// while writing.
+pub fn dump_open_fds_raw(outfd: libc::c_int) -> bool {
+    // Buffer the output to reduce the number of write() calls
+    let mut buf = [0u8; 512];
+    let mut len = 0;
+
+    for info in super::iter_open_fds_info(0) {
+        // The longest possible line is "2147483647 cloexec\n" (19 bytes)
+        if buf.len() - len < 19 {
+            if !write_all(outfd, &buf[..len]) {
+                return false;
+            }
+            len = 0;
+        }
+
+        len += crate::util::format_int(info.fd, &mut buf[len..]);
+
+        if info.cloexec {
+            buf[len..len + 8].copy_from_slice(b" cloexec");
+            len += 8;
+        }
+
+        buf[len] = b'\n';
+        len += 1;
+    }
+
+    write_all(outfd, &buf[..len])
+}
+
+fn write_all(fd: libc::c_int, mut data: &[u8]) -> bool {
+    while !data.is_empty() {
+        let n = unsafe { libc::write(fd, data.as_ptr() as *const libc::c_void, data.len()) };
+
+        if n > 0 {
+            data = &data[n as usize..];
+        } else if n < 0 && crate::util::errno() == libc::EINTR {
+            continue;
+        } else {
+            return false;
+        }
+    }
+
+    true
+}
+
+#[cfg(feature = "std")]
+pub(super) fn kind_name(it: &FdInfoIter, info: &FdInfo) -> &'static str {
+    super::FdKind::detect(it, info.fd).name()
+}
+
+/// Write a human-readable table describing the current process's open file descriptors to `w`.
+///
+/// Each row lists a file descriptor, what kind of file it refers to, whether the close-on-exec
+/// flag is set on it, and (on platforms where it can be determined; see
+/// [`FdInfoIter::read_path()`](./struct.FdInfoIter.html)) the path it refers to. The exact format
+/// is not stable, and is only intended for debugging.
+///
+/// Note that if `w` itself writes to a file descriptor, that file descriptor will be included.
+///
+/// This is not async-signal-safe; see [`dump_open_fds_raw()`] for a version that is.
+#[cfg(feature = "std")]
+pub fn dump_open_fds<W: io::Write + ?Sized>(w: &mut W) -> io::Result<()> {
+    let mut it = super::iter_open_fds_info(0);
+
+    writeln!(w, "{:>4}  {:<8}  {:<7}  PATH", "FD", "KIND", "CLOEXEC")?;
+
+    while let Some(info) = it.next() {
+        let kind = kind_name(&it, &info);
+        let cloexec = if info.cloexec { "yes" } else { "no" };
+
+        write!(w, "{:>4}  {:<8}  {:<7}  ", info.fd, kind, cloexec)?;
+
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "solaris",
+            target_os = "illumos",
+        ))]
+        {
+            let mut buf = [0; libc::PATH_MAX as usize];
+            if let Some(path) = it.read_path(info.fd, &mut buf) {
+                w.write_all(path)?;
+                writeln!(w)?;
+                continue;
+            }
+        }
+
+        writeln!(w, "-")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_open_fds_raw() {
+        let mut fds = [-1; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        assert_eq!(
+            unsafe { libc::fcntl(fds[0], libc::F_SETFD, libc::FD_CLOEXEC) },
+            0
+        );
+
+        assert!(dump_open_fds_raw(fds[1]));
+        unsafe {
+            libc::close(fds[1]);
+        }
+
+        let mut buf = [0u8; 4096];
+        let mut len = 0;
+        loop {
+            let n = unsafe {
+                libc::read(
+                    fds[0],
+                    buf[len..].as_mut_ptr() as *mut libc::c_void,
+                    buf.len() - len,
+                )
+            };
+            assert!(n >= 0);
+            if n == 0 {
+                break;
+            }
+            len += n as usize;
+        }
+        unsafe {
+            libc::close(fds[0]);
+        }
+
+        let mut found = [false; 2];
+        for line in buf[..len].split(|&ch| ch == b'\n') {
+            let mut numbuf = [0; 10];
+            for (i, &fd) in fds.iter().enumerate() {
+                let n = crate::util::format_int(fd, &mut numbuf);
+                if line.starts_with(&numbuf[..n]) && matches!(line.get(n), None | Some(b' ')) {
+                    assert_eq!(&line[n..] == b" cloexec", i == 0);
+                    found[i] = true;
+                }
+            }
+        }
+        assert_eq!(found, [true; 2]);
+    }
+}
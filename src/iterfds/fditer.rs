@@ -6,21 +6,47 @@
 ///
 /// If this iterator is created with [`FdIterBuilder::possible()`](./struct.FdIterBuilder.html)
 /// set, or with one of the "possible" functions, then it may yield invalid file descriptors. This
-/// can be checked with [`Self::is_possible_iter()`].
+/// can be checked with [`Self::is_possible_iter()`]. (Iterators created with
+/// [`FdIterBuilder::iter_possible_from()`](./struct.FdIterBuilder.html) use the separate
+/// [`PossibleFdIter`] type instead.)
+///
+/// # Thread safety
+///
+/// `FdIter` is `Send` and `Sync`. It only holds plain data, plus (when it's reading a directory
+/// like `/proc/self/fd`) a directory file descriptor that it owns. The file descriptor table is
+/// shared by all of the threads in a process, so moving an iterator to another thread doesn't
+/// change what it yields. (Of course, if other threads are opening or closing file descriptors
+/// while it's running, it may miss some of them; see
+/// [`FdIterBuilder::threadsafe()`](./struct.FdIterBuilder.html#method.threadsafe).)
+///
+/// However, an `FdIter` should *not* be created before a `fork()` and then used in the child,
+/// since the directory file descriptor (including its position) would be shared with the parent.
+/// To set up iteration in one place and run it somewhere else (such as in the child after a
+/// `fork()`), use an [`FdIterPlan`](./struct.FdIterPlan.html), which doesn't open anything until
+/// it's started.
 pub struct FdIter {
-    #[cfg(any(
-        target_os = "linux",
-        target_os = "macos",
-        target_os = "ios",
-        target_os = "freebsd",
-        target_os = "netbsd",
-        target_os = "solaris",
-        target_os = "illumos",
+    #[cfg(all(
+        not(feature = "no-fs-backends"),
+        any(
+            target_os = "linux",
+            target_os = "macos",
+            all(
+                any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                feature = "ios-raw-syscalls"
+            ),
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "solaris",
+            target_os = "illumos",
+        )
     ))]
     pub(crate) dirfd_iter: Option<super::dirfd::DirFdIter>,
     pub(crate) curfd: libc::c_int,
+    /// A file descriptor that was returned by `peek()` but hasn't been consumed by `next()` yet
+    pub(crate) peeked: Option<libc::c_int>,
     pub(crate) possible: bool,
     pub(crate) maxfd: Option<libc::c_int>,
+    pub(crate) counter: crate::counter::Counter,
     /// If this is true, it essentially means "don't try the 'nfds' methods of finding the maximum
     /// open file descriptor."
     /// `close_open_fds()` passes this as true on some systems becaus the system has a working
@@ -30,133 +56,209 @@ pub struct FdIter {
     pub(crate) skip_nfds: bool,
 }
 
+/// Never loop over fewer than this many file descriptors when falling back on the file descriptor
+/// limit
+const MIN_FDLIMIT: libc::c_int = 1024;
+/// Never loop over more than this many file descriptors when falling back on the file descriptor
+/// limit (65536 is a LOT of file descriptors)
+const MAX_FDLIMIT: libc::c_int = 65536;
+
+/// Get the size of the file descriptor table (see `fd_table_size()`).
+pub(crate) fn fd_table_size() -> libc::c_int {
+    let mut rlim = core::mem::MaybeUninit::<libc::rlimit>::uninit();
+
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, rlim.as_mut_ptr()) } == 0 {
+        let cur = unsafe { rlim.assume_init() }.rlim_cur;
+
+        // Checking for RLIM_INFINITY explicitly is important because it may not fit in the return
+        // value of sysconf() (glibc returns -1, which would otherwise turn into the *minimum* in
+        // fdlimit_maxfd()).
+        if cur == libc::RLIM_INFINITY || cur > libc::c_int::MAX as libc::rlim_t {
+            libc::c_int::MAX
+        } else {
+            cur as libc::c_int
+        }
+    } else {
+        // This shouldn't happen, but fall back on sysconf() just in case
+        match unsafe { libc::sysconf(libc::_SC_OPEN_MAX) } {
+            // Indeterminate; assume it's unlimited
+            fdlimit if fdlimit < 0 || fdlimit > libc::c_int::MAX as libc::c_long => {
+                libc::c_int::MAX
+            }
+            fdlimit => fdlimit as libc::c_int,
+        }
+    }
+}
+
+/// Get the maximum file descriptor that a "maxfd loop" should check, based on the file descriptor
+/// limit. See `fallback_max_fd()`.
+pub(crate) fn fdlimit_maxfd() -> libc::c_int {
+    // Unlimited (or enormous) limits are clamped to MAX_FDLIMIT
+    fd_table_size().clamp(MIN_FDLIMIT, MAX_FDLIMIT) - 1
+}
+
 impl FdIter {
     fn get_maxfd_direct(&self) -> libc::c_int {
         // This function can return -1 if no file descriptors are open. Otherwise it should return
         // a nonnegative integer indicating the maximum file descriptor that might be open.
 
         #[cfg(target_os = "netbsd")]
-        unsafe {
-            // NetBSD allows us to get the maximum open file descriptor
-
-            *libc::__errno() = 0;
-            let maxfd = libc::fcntl(0, libc::F_MAXFD);
+        self.counter.fcntl(1);
+        #[cfg(target_os = "netbsd")]
+        if let Ok(maxfd) = crate::sys::fcntl_maxfd() {
+            // NetBSD allows us to get the maximum open file descriptor (or -1 if no file
+            // descriptors are open)
+            return maxfd;
+        }
 
-            if maxfd >= 0 {
-                return maxfd;
-            } else if maxfd == -1 && *libc::__errno() == 0 {
-                // fcntl(F_MAXFD) actually succeeded and returned -1, which means that no file
-                // descriptors are open.
-                return -1;
-            }
+        #[cfg(target_os = "macos")]
+        if let Some(maxfd) = Self::get_maxfd_pidinfo() {
+            // On macOS, if /dev/fd couldn't be read (for example, because the App Sandbox denied
+            // access to it), we can still get a list of the open file descriptors.
+            return maxfd;
         }
 
         #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
         if !self.skip_nfds {
             // On FreeBSD and OpenBSD, we can get the *number* of open file descriptors. From that,
             // we can use an is_fd_valid() loop to get the maximum open file descriptor.
-            if let Some(maxfd) = Self::get_nfds().and_then(Self::nfds_to_maxfd) {
+            if let Some(maxfd) =
+                Self::get_nfds().and_then(|nfds| Self::nfds_to_maxfd(nfds, self.counter))
+            {
                 return maxfd;
             }
         }
 
-        let fdlimit = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) };
+        fdlimit_maxfd()
+    }
 
-        // Clamp it at 65536 because that's a LOT of file descriptors
-        // Also don't trust values below 1024
+    #[cfg(target_os = "macos")]
+    fn get_maxfd_pidinfo() -> Option<libc::c_int> {
+        // proc_pidinfo(PROC_PIDLISTFDS) lists the process's open file descriptors in ascending
+        // order. We use a fixed-size buffer (this may be running after a fork()), and if the list
+        // fills it up we can't tell whether it was truncated, so we give up.
+        const MAX_ENTRIES: usize = 128;
 
-        fdlimit.max(1024).min(65536) as libc::c_int - 1
+        // The proc_pidinfo() call isn't made in deterministic mode
+        if cfg!(feature = "deterministic-syscalls") {
+            return None;
+        }
+
+        let mut buf = core::mem::MaybeUninit::<[libc::proc_fdinfo; MAX_ENTRIES]>::uninit();
+        let bufsize = core::mem::size_of::<[libc::proc_fdinfo; MAX_ENTRIES]>();
+
+        let size = unsafe {
+            libc::proc_pidinfo(
+                libc::getpid(),
+                libc::PROC_PIDLISTFDS,
+                0,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                bufsize as libc::c_int,
+            )
+        };
+
+        // 0 may indicate failure, so play it safe
+        if size <= 0 || size as usize >= bufsize {
+            return None;
+        }
+
+        let nfds = size as usize / core::mem::size_of::<libc::proc_fdinfo>();
+        let entries =
+            unsafe { core::slice::from_raw_parts(buf.as_ptr() as *const libc::proc_fdinfo, nfds) };
+
+        Some(entries.iter().map(|info| info.proc_fd).max().unwrap_or(-1))
     }
 
     #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
     #[inline]
-    fn get_nfds() -> Option<libc::c_int> {
+    pub(crate) fn get_nfds() -> Option<libc::c_int> {
+        // The sysctl() call isn't made in deterministic mode
         #[cfg(target_os = "freebsd")]
-        return {
-            let mib = [
-                libc::CTL_KERN,
-                libc::KERN_PROC,
-                crate::sys::KERN_PROC_NFDS,
-                0,
-            ];
-            let mut nfds: libc::c_int = 0;
-            let mut oldlen = core::mem::size_of::<libc::c_int>();
-
-            if unsafe {
-                libc::sysctl(
-                    mib.as_ptr(),
-                    mib.len() as libc::c_uint,
-                    &mut nfds as *mut libc::c_int as *mut libc::c_void,
-                    &mut oldlen,
-                    core::ptr::null(),
-                    0,
-                )
-            } == 0
-            {
-                Some(nfds)
-            } else {
-                None
-            }
-        };
+        if cfg!(feature = "deterministic-syscalls") {
+            return None;
+        }
+
+        #[cfg(target_os = "freebsd")]
+        return crate::sys::proc_nfds().ok();
 
         #[cfg(target_os = "openbsd")]
-        return Some(unsafe { crate::sys::getdtablecount() });
+        return Some(crate::sys::getdtablecount());
     }
 
     #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
     #[inline]
-    fn nfds_to_maxfd(nfds: libc::c_int) -> Option<libc::c_int> {
+    fn nfds_to_maxfd(
+        mut nfds: libc::c_int,
+        counter: crate::counter::Counter,
+    ) -> Option<libc::c_int> {
         // Given the number of open file descriptors, return the largest open file descriptor (or
         // None if it can't be reasonably determined).
 
-        if nfds == 0 {
-            // No open file descriptors -- nothing to do!
-            return Some(-1);
-        } else if nfds < 0 {
-            // Probably failure of the underlying function
-            return None;
-        } else if nfds >= 100 {
-            // We're probably better off just iterating through
-            return None;
-        }
+        // If the scan comes up short, we re-read the number of open file descriptors and try
+        // again (see below). This is the maximum number of scans we'll perform.
+        const MAX_ATTEMPTS: usize = 3;
+
+        for _ in 0..MAX_ATTEMPTS {
+            if nfds == 0 {
+                // No open file descriptors -- nothing to do!
+                return Some(-1);
+            } else if nfds < 0 {
+                // Probably failure of the underlying function
+                return None;
+            } else if nfds >= 100 {
+                // We're probably better off just iterating through
+                return None;
+            }
 
-        let mut nfds_found = 0;
+            let mut nfds_found = 0;
 
-        // We know the number of open file descriptors; let's use that to try to find the largest
-        // open file descriptor.
+            // We know the number of open file descriptors; let's use that to try to find the
+            // largest open file descriptor.
 
-        for fd in 0..(nfds * 2) {
-            if crate::util::is_fd_valid(fd) {
-                // Valid file descriptor
-                nfds_found += 1;
+            for fd in 0..(nfds * 2) {
+                counter.fcntl(1);
+                if crate::util::is_fd_valid(fd) {
+                    // Valid file descriptor
+                    nfds_found += 1;
 
-                if nfds_found >= nfds {
-                    // We've found all the open file descriptors.
-                    // We now know that the current `fd` is the largest open file descriptor
-                    return Some(fd);
+                    if nfds_found >= nfds {
+                        // We've found all the open file descriptors.
+                        // We now know that the current `fd` is the largest open file descriptor
+                        return Some(fd);
+                    }
                 }
             }
+
+            // We haven't found all of the open file descriptors yet, but it seems like we *should*
+            // have.
+            //
+            // This usually means one of two things:
+            //
+            // 1. The process opened a large number of file descriptors, then closed many of them.
+            //    However, it left several of the high-numbered file descriptors open. (For
+            //    example, consider the case where the open file descriptors are 0, 1, 2, 50, and
+            //    100. nfds=5, but the highest open file descriptor is actually 100!)
+            // 2. The 'nfds' method is vulnerable to a race condition: if a file descriptor is
+            //    closed after the number of open file descriptors has been obtained, but before
+            //    the fcntl() loop reaches that file descriptor, then the loop will never find all
+            //    of the open file descriptors because it will be stuck at n_fds_found = nfds-1.
+            //    If this happens, without this check the loop would essentially become an
+            //    infinite loop.
+            //    (For example, consider the case where the open file descriptors are 0, 1, 2, and
+            //    3. If file descriptor 3 is closed before the fd=3 iteration, then we will be
+            //    stuck at n_fds_found=3 and will never be able to find the 4th file descriptor.)
+            //
+            // We can tell these apart by re-reading the number of open file descriptors. If it
+            // hasn't changed, we're probably in case 1, and scanning again won't help. If it has
+            // changed, we may be in case 2, so we try again with the new count.
+
+            let new_nfds = Self::get_nfds()?;
+            if new_nfds == nfds {
+                break;
+            }
+            nfds = new_nfds;
         }
 
-        // We haven't found all of the open file descriptors yet, but it seems like we *should*
-        // have.
-        //
-        // This usually means one of two things:
-        //
-        // 1. The process opened a large number of file descriptors, then closed many of them.
-        //    However, it left several of the high-numbered file descriptors open. (For example,
-        //    consider the case where the open file descriptors are 0, 1, 2, 50, and 100. nfds=5,
-        //    but the highest open file descriptor is actually 100!)
-        // 2. The 'nfds' method is vulnerable to a race condition: if a file descriptor is closed
-        //    after the number of open file descriptors has been obtained, but before the fcntl()
-        //    loop reaches that file descriptor, then the loop will never find all of the open file
-        //    descriptors because it will be stuck at n_fds_found = nfds-1.
-        //    If this happens, without this check the loop would essentially become an infinite
-        //    loop.
-        //    (For example, consider the case where the open file descriptors are 0, 1, 2, and 3. If
-        //    file descriptor 3 is closed before the fd=3 iteration, then we will be stuck at
-        //    n_fds_found=3 and will never be able to find the 4th file descriptor.)
-        //
         // Error on the side of caution (case 2 is dangerous) and let the caller select another
         // method.
 
@@ -183,20 +285,184 @@ impl FdIter {
     pub fn is_possible_iter(&self) -> bool {
         self.possible
     }
+
+    /// Convert this iterator into one that will only yield valid file descriptors.
+    ///
+    /// If this iterator is a "possible" iterator (see [`Self::is_possible_iter()`]), then the
+    /// returned iterator will check each file descriptor (with `fcntl()`) as it is reached and skip
+    /// over invalid ones. Otherwise, this is a no-op.
+    ///
+    /// Any progress that this iterator has made is preserved.
+    #[inline]
+    pub fn validated(mut self) -> Self {
+        if self.possible {
+            // A peeked file descriptor hasn't been checked yet
+            if let Some(fd) = self.peeked {
+                self.counter.fcntl(1);
+                if !crate::util::is_fd_valid(fd) {
+                    self.peeked = None;
+                }
+            }
+        }
+
+        self.possible = false;
+        self
+    }
+
+    /// Convert this iterator into a [`PossibleFdIter`], which may yield invalid file descriptors.
+    ///
+    /// Any progress that this iterator has made is preserved. This is the inverse of
+    /// [`PossibleFdIter::validated()`].
+    #[inline]
+    pub fn into_possible(mut self) -> PossibleFdIter {
+        self.possible = true;
+        PossibleFdIter(self)
+    }
+
+    /// Return the next file descriptor that this iterator will yield, without consuming it.
+    ///
+    /// The file descriptor is fetched from the underlying source (e.g. the current batch of
+    /// directory entries, or the next file descriptor that passes the validity check) and buffered
+    /// until the next call to [`next()`](Iterator::next). This is similar to
+    /// [`core::iter::Peekable::peek()`], but it doesn't require wrapping the iterator (so methods
+    /// like [`Self::known_max_fd()`] remain available).
+    ///
+    /// Note that the file descriptor may be closed (or a new one may be opened) between the call
+    /// to `peek()` and the call to `next()`; the same caveats apply as for file descriptors
+    /// returned by `next()`.
+    #[inline]
+    pub fn peek(&mut self) -> Option<libc::c_int> {
+        if self.peeked.is_none() {
+            self.peeked = self.next_fd();
+        }
+
+        self.peeked
+    }
+
+    /// Write file descriptors from this iterator into `buf` until either `buf` is full or the
+    /// iterator is exhausted, and return how many were written.
+    ///
+    /// This is intended for code that can't allocate memory (such as `no_std` code, or code that
+    /// runs in a child process after a `fork()`) and needs to take a snapshot of the open file
+    /// descriptors. The file descriptors are written in ascending order.
+    ///
+    /// If this returns `buf.len()`, the output may have been truncated; [`Self::peek()`] will
+    /// return `Some` if there are more file descriptors left, and they can be collected with
+    /// another call to `collect_into_slice()`.
+    pub fn collect_into_slice(&mut self, buf: &mut [libc::c_int]) -> usize {
+        let mut n = 0;
+
+        for slot in buf.iter_mut() {
+            match self.next() {
+                Some(fd) => *slot = fd,
+                None => break,
+            }
+            n += 1;
+        }
+
+        n
+    }
+
+    /// Returns the maximum file descriptor that this iterator has determined might be open, if it
+    /// has computed one.
+    ///
+    /// This will return `None` if the iterator hasn't needed to compute it yet (for example, if
+    /// it is iterating using `/proc/self/fd` or `/dev/fd`). A return value of `Some(-1)` means
+    /// that the iterator determined that no file descriptors were open.
+    ///
+    /// Note that this value is only the upper bound that the iterator is using; on most platforms
+    /// it is derived from the file descriptor limit, not from the file descriptors that are
+    /// actually open.
+    #[inline]
+    pub fn known_max_fd(&self) -> Option<libc::c_int> {
+        self.maxfd
+    }
+
+    /// Returns the file descriptor that this iterator is using internally to read the list of open
+    /// file descriptors (for example, a file descriptor open to `/proc/self/fd`), if it has one
+    /// open.
+    ///
+    /// That file descriptor is open for as long as the iterator is reading the directory, but it's
+    /// never yielded by the iterator. This is useful for callers that do their own follow-up work
+    /// on the file descriptor table while iterating (for example, closing file descriptors
+    /// manually) and need to avoid touching it. It's closed once the iterator reaches the end of
+    /// the directory (or is dropped), at which point this returns `None`.
+    ///
+    /// It has the close-on-exec flag set, and it may be moved to a higher number with
+    /// [`FdIterBuilder::dirfd_minfd()`](crate::FdIterBuilder::dirfd_minfd).
+    #[inline]
+    pub fn helper_fd(&self) -> Option<libc::c_int> {
+        #[cfg(all(
+            not(feature = "no-fs-backends"),
+            any(
+                target_os = "linux",
+                target_os = "macos",
+                all(
+                    any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                    feature = "ios-raw-syscalls"
+                ),
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "solaris",
+                target_os = "illumos",
+            )
+        ))]
+        if let Some(dfd_iter) = self.dirfd_iter.as_ref() {
+            return dfd_iter.dirfd();
+        }
+
+        None
+    }
+
+    /// Get a short description of the method that this iterator is currently using (`"dirfd"`
+    /// if it is reading a directory like `/proc/self/fd`, or `"maxfd"` if it is looping up to a
+    /// maximum file descriptor).
+    pub(crate) fn backend_name(&self) -> &'static str {
+        #[cfg(all(
+            not(feature = "no-fs-backends"),
+            any(
+                target_os = "linux",
+                target_os = "macos",
+                all(
+                    any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                    feature = "ios-raw-syscalls"
+                ),
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "solaris",
+                target_os = "illumos",
+            )
+        ))]
+        if self.dirfd_iter.is_some() {
+            return "dirfd";
+        }
+
+        "maxfd"
+    }
 }
 
-impl Iterator for FdIter {
-    type Item = libc::c_int;
+impl FdIter {
+    #[inline]
+    fn is_fd_valid(&self, fd: libc::c_int) -> bool {
+        self.counter.fcntl(1);
+        crate::util::is_fd_valid(fd)
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        #[cfg(any(
-            target_os = "linux",
-            target_os = "macos",
-            target_os = "ios",
-            target_os = "freebsd",
-            target_os = "netbsd",
-            target_os = "solaris",
-            target_os = "illumos",
+    fn next_fd(&mut self) -> Option<libc::c_int> {
+        #[cfg(all(
+            not(feature = "no-fs-backends"),
+            any(
+                target_os = "linux",
+                target_os = "macos",
+                all(
+                    any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                    feature = "ios-raw-syscalls"
+                ),
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "solaris",
+                target_os = "illumos",
+            )
         ))]
         if let Some(dfd_iter) = self.dirfd_iter.as_mut() {
             // Try iterating using the directory file descriptor we opened
@@ -231,7 +497,7 @@ impl Iterator for FdIter {
 
             // If we weren't given the "possible" flag, we have to check that it's a valid file
             // descriptor first.
-            if self.possible || crate::util::is_fd_valid(fd) {
+            if self.possible || self.is_fd_valid(fd) {
                 return Some(fd);
             }
         }
@@ -240,15 +506,21 @@ impl Iterator for FdIter {
         None
     }
 
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        #[cfg(any(
-            target_os = "linux",
-            target_os = "macos",
-            target_os = "ios",
-            target_os = "freebsd",
-            target_os = "netbsd",
-            target_os = "solaris",
-            target_os = "illumos",
+    fn size_hint_unpeeked(&self) -> (usize, Option<usize>) {
+        #[cfg(all(
+            not(feature = "no-fs-backends"),
+            any(
+                target_os = "linux",
+                target_os = "macos",
+                all(
+                    any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                    feature = "ios-raw-syscalls"
+                ),
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "solaris",
+                target_os = "illumos",
+            )
         ))]
         if let Some(dfd_iter) = self.dirfd_iter.as_ref() {
             // Delegate to the directory file descriptor
@@ -272,6 +544,96 @@ impl Iterator for FdIter {
             (0, Some(libc::c_int::MAX as usize))
         }
     }
+}
+
+impl Iterator for FdIter {
+    type Item = libc::c_int;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.peeked.take() {
+            Some(fd) => Some(fd),
+            None => self.next_fd(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (low, high) = self.size_hint_unpeeked();
+
+        if self.peeked.is_some() {
+            (
+                low.saturating_add(1),
+                high.and_then(|high| high.checked_add(1)),
+            )
+        } else {
+            (low, high)
+        }
+    }
+
+    fn fold<B, F>(mut self, init: B, mut f: F) -> B
+    where
+        F: FnMut(B, Self::Item) -> B,
+    {
+        // This avoids going through next() (and re-checking which method is in use) for every
+        // file descriptor. Note that for_each() is implemented in terms of fold(), so it benefits
+        // too. (try_fold() can't be overridden on stable Rust.)
+
+        let mut acc = init;
+
+        if let Some(fd) = self.peeked.take() {
+            acc = f(acc, fd);
+        }
+
+        #[cfg(all(
+            not(feature = "no-fs-backends"),
+            any(
+                target_os = "linux",
+                target_os = "macos",
+                all(
+                    any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                    feature = "ios-raw-syscalls"
+                ),
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "solaris",
+                target_os = "illumos",
+            )
+        ))]
+        if let Some(dfd_iter) = self.dirfd_iter.as_mut() {
+            let curfd = &mut self.curfd;
+
+            match dfd_iter.fold(acc, |acc, fd| {
+                debug_assert!(fd >= *curfd);
+                // See next_fd()
+                *curfd = fd + 1;
+                f(acc, fd)
+            }) {
+                Ok(acc) => return acc,
+
+                // Fall back on a maxfd loop
+                Err(res) => {
+                    acc = res;
+                    self.dirfd_iter = None;
+                }
+            }
+        }
+
+        let maxfd = self.get_maxfd();
+
+        if self.possible {
+            for fd in self.curfd..=maxfd {
+                acc = f(acc, fd);
+            }
+        } else {
+            for fd in self.curfd..=maxfd {
+                if self.is_fd_valid(fd) {
+                    acc = f(acc, fd);
+                }
+            }
+        }
+
+        acc
+    }
 
     #[inline]
     fn min(mut self) -> Option<Self::Item> {
@@ -285,3 +647,145 @@ impl Iterator for FdIter {
 }
 
 impl core::iter::FusedIterator for FdIter {}
+
+// See "Thread safety" above; make sure this doesn't change by accident
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<FdIter>();
+    assert_send_sync::<PossibleFdIter>();
+};
+
+impl core::fmt::Debug for FdIter {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let mut f = f.debug_struct("FdIter");
+
+        f.field("backend", &self.backend_name());
+
+        #[cfg(all(
+            not(feature = "no-fs-backends"),
+            any(
+                target_os = "linux",
+                target_os = "macos",
+                all(
+                    any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                    feature = "ios-raw-syscalls"
+                ),
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "solaris",
+                target_os = "illumos",
+            )
+        ))]
+        if let Some(dfd_iter) = self.dirfd_iter.as_ref() {
+            f.field("dirfd_iter", dfd_iter);
+        }
+
+        f.field("curfd", &self.curfd)
+            .field("peeked", &self.peeked)
+            .field("possible", &self.possible)
+            .field("maxfd", &self.maxfd);
+
+        #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+        f.field("skip_nfds", &self.skip_nfds);
+
+        f.finish()
+    }
+}
+
+/// An iterator over the current process's file descriptors that may -- for efficiency -- also
+/// yield invalid file descriptors.
+///
+/// This is returned by [`FdIterBuilder::iter_possible_from()`](./struct.FdIterBuilder.html) and
+/// [`FdIter::into_possible()`]. It behaves like an [`FdIter`] created with the deprecated
+/// [`FdIterBuilder::possible()`](./struct.FdIterBuilder.html#method.possible) set, but since it's
+/// a separate type, it can't be accidentally passed to code that expects an `FdIter` (which only
+/// yields file descriptors that were valid when they were reached). See `FdIterBuilder::possible()`
+/// for the proper usage.
+///
+/// Use [`Self::validated()`] to convert it into an `FdIter` that checks each file descriptor.
+pub struct PossibleFdIter(FdIter);
+
+impl PossibleFdIter {
+    /// Convert this iterator into an [`FdIter`] that will only yield valid file descriptors (see
+    /// [`FdIter::validated()`]).
+    ///
+    /// Any progress that this iterator has made is preserved.
+    #[inline]
+    pub fn validated(self) -> FdIter {
+        self.0.validated()
+    }
+
+    /// Return the next file descriptor that this iterator will yield, without consuming it.
+    ///
+    /// See [`FdIter::peek()`].
+    #[inline]
+    pub fn peek(&mut self) -> Option<libc::c_int> {
+        self.0.peek()
+    }
+
+    /// Write file descriptors from this iterator into `buf` until either `buf` is full or the
+    /// iterator is exhausted, and return how many were written.
+    ///
+    /// See [`FdIter::collect_into_slice()`].
+    #[inline]
+    pub fn collect_into_slice(&mut self, buf: &mut [libc::c_int]) -> usize {
+        self.0.collect_into_slice(buf)
+    }
+
+    /// Returns the maximum file descriptor that this iterator has determined might be open, if it
+    /// has computed one.
+    ///
+    /// See [`FdIter::known_max_fd()`].
+    #[inline]
+    pub fn known_max_fd(&self) -> Option<libc::c_int> {
+        self.0.known_max_fd()
+    }
+
+    /// Returns the file descriptor that this iterator is using internally, if it has one open.
+    ///
+    /// See [`FdIter::helper_fd()`].
+    #[inline]
+    pub fn helper_fd(&self) -> Option<libc::c_int> {
+        self.0.helper_fd()
+    }
+}
+
+impl Iterator for PossibleFdIter {
+    type Item = libc::c_int;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+
+    #[inline]
+    fn fold<B, F>(self, init: B, f: F) -> B
+    where
+        F: FnMut(B, Self::Item) -> B,
+    {
+        self.0.fold(init, f)
+    }
+
+    #[inline]
+    fn min(self) -> Option<Self::Item> {
+        self.0.min()
+    }
+
+    #[inline]
+    fn max(self) -> Option<Self::Item> {
+        self.0.max()
+    }
+}
+
+impl core::iter::FusedIterator for PossibleFdIter {}
+
+impl core::fmt::Debug for PossibleFdIter {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_tuple("PossibleFdIter").field(&self.0).finish()
+    }
+}
@@ -0,0 +1,159 @@
+use core::fmt;
+
+/// The maximum number of file descriptors that a [`FdViolations`] records.
+pub const MAX_RECORDED_VIOLATIONS: usize = 16;
+
+/// A list of file descriptors that failed a check (such as [`assert_only_open_fds()`] or
+/// [`verify_all_cloexec()`]).
+///
+/// So that checks can be performed without allocating memory, only the lowest
+/// [`MAX_RECORDED_VIOLATIONS`] file descriptors are recorded; [`Self::count()`] gives the total
+/// number.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct FdViolations {
+    fds: [libc::c_int; MAX_RECORDED_VIOLATIONS],
+    nrecorded: usize,
+    count: usize,
+}
+
+impl FdViolations {
+    #[inline]
+    fn new() -> Self {
+        Self {
+            fds: [0; MAX_RECORDED_VIOLATIONS],
+            nrecorded: 0,
+            count: 0,
+        }
+    }
+
+    /// Add a file descriptor. They must be added in ascending order.
+    #[inline]
+    fn push(&mut self, fd: libc::c_int) {
+        if self.nrecorded < MAX_RECORDED_VIOLATIONS {
+            self.fds[self.nrecorded] = fd;
+            self.nrecorded += 1;
+        }
+        self.count += 1;
+    }
+
+    #[inline]
+    fn into_result(self) -> Result<(), Self> {
+        if self.count == 0 {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Get the file descriptors that were recorded, in ascending order.
+    ///
+    /// If [`Self::is_truncated()`] returns `true`, this only contains the first
+    /// [`MAX_RECORDED_VIOLATIONS`] of them.
+    #[inline]
+    pub fn fds(&self) -> &[libc::c_int] {
+        &self.fds[..self.nrecorded]
+    }
+
+    /// Get the total number of file descriptors that failed the check.
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Check whether some of the file descriptors that failed the check were not recorded (see
+    /// [`Self::fds()`]).
+    #[inline]
+    pub fn is_truncated(&self) -> bool {
+        self.count > self.nrecorded
+    }
+}
+
+impl fmt::Debug for FdViolations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FdViolations")
+            .field("fds", &self.fds())
+            .field("count", &self.count)
+            .finish()
+    }
+}
+
+impl fmt::Display for FdViolations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("File descriptors ")?;
+
+        for (i, fd) in self.fds().iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", fd)?;
+        }
+
+        if self.is_truncated() {
+            write!(f, " (and {} more)", self.count - self.nrecorded)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FdViolations {}
+
+/// Check that the only open file descriptors are the ones listed in `expected` (which does not
+/// have to be sorted).
+///
+/// This is intended for programs that need to start with a known file descriptor table, such as
+/// setuid programs and sandbox entry points; for example, `assert_only_open_fds(&[0, 1, 2])` at
+/// the beginning of `main()` fails if the program was launched with any unexpected inherited file
+/// descriptors. (Use [`CloseFdsBuilder::closefrom()`](./struct.CloseFdsBuilder.html#method.closefrom)
+/// to close them instead.)
+///
+/// If any other file descriptors are open, they are returned as an error. File descriptors in
+/// `expected` that are *not* open are not considered violations.
+///
+/// This does not allocate memory. See the warnings for [`FdIterBuilder`](./struct.FdIterBuilder.html)
+/// regarding multithreaded programs.
+pub fn assert_only_open_fds(expected: &[libc::c_int]) -> Result<(), FdViolations> {
+    let mut violations = FdViolations::new();
+
+    super::iter_open_fds(0).for_each(|fd| {
+        if !expected.contains(&fd) {
+            violations.push(fd);
+        }
+    });
+
+    violations.into_result()
+}
+
+/// Check that every open file descriptor starting at `minfd` (except the ones in `keep_fds`, which
+/// does not have to be sorted) has the close-on-exec flag set.
+///
+/// In other words, this checks that no file descriptors (other than the ones that are expected)
+/// will be inherited if the current process calls `exec()`. This can be used to assert that
+/// property in tests, or after calling
+/// [`CloseFdsBuilder::cloexecfrom()`](./struct.CloseFdsBuilder.html#method.cloexecfrom).
+///
+/// If any file descriptors are missing the close-on-exec flag, they are returned as an error. (A
+/// file descriptor that is closed between being found and having its flags checked is ignored.)
+///
+/// This does not allocate memory. See the warnings for [`FdIterBuilder`](./struct.FdIterBuilder.html)
+/// regarding multithreaded programs.
+pub fn verify_all_cloexec(
+    minfd: libc::c_int,
+    keep_fds: &[libc::c_int],
+) -> Result<(), FdViolations> {
+    let mut violations = FdViolations::new();
+
+    super::iter_open_fds(minfd).for_each(|fd| {
+        if keep_fds.contains(&fd) {
+            return;
+        }
+
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags >= 0 && flags & libc::FD_CLOEXEC == 0 {
+            violations.push(fd);
+        }
+    });
+
+    violations.into_result()
+}
@@ -0,0 +1,797 @@
+use super::FdIter;
+
+/// Information about an open file descriptor, as yielded by an [`FdInfoIter`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub struct FdInfo {
+    /// The file descriptor number.
+    pub fd: libc::c_int,
+    /// Whether the close-on-exec flag is set on the file descriptor.
+    pub cloexec: bool,
+    /// The file status flags of the open file description, as returned by `fcntl(F_GETFL)`.
+    ///
+    /// This includes the access mode (which can be extracted by masking with `libc::O_ACCMODE`)
+    /// and flags like `O_NONBLOCK` and `O_APPEND` (and `O_PATH` on Linux).
+    pub status_flags: libc::c_int,
+}
+
+/// Details about an open file descriptor, as read from `/proc/self/fdinfo` by
+/// [`FdInfoIter::read_proc_fdinfo()`].
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub struct ProcFdInfo {
+    /// The current file offset.
+    pub pos: u64,
+    /// The file status flags (as in [`FdInfo::status_flags`]) combined with the close-on-exec
+    /// flag (reported as `O_CLOEXEC`).
+    pub flags: libc::c_int,
+    /// The ID of the mount that the file is on (see `/proc/self/mountinfo`). This is only
+    /// reported on Linux 3.15+.
+    pub mnt_id: Option<libc::c_int>,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl ProcFdInfo {
+    pub(super) fn parse(data: &[u8]) -> Option<Self> {
+        let mut pos = None;
+        let mut flags = None;
+        let mut mnt_id = None;
+
+        // Only look at complete lines, in case the data was truncated
+        let end = data.iter().rposition(|&ch| ch == b'\n')?;
+
+        for line in data[..end].split(|&ch| ch == b'\n') {
+            let sep = match line.iter().position(|&ch| ch == b':') {
+                Some(sep) => sep,
+                None => continue,
+            };
+
+            let (key, value) = (&line[..sep], trim_ascii_start(&line[sep + 1..]));
+
+            match key {
+                b"pos" => pos = parse_u64(value, 10),
+                b"flags" => flags = parse_u64(value, 8).map(|f| f as libc::c_int),
+                b"mnt_id" => mnt_id = parse_u64(value, 10).map(|m| m as libc::c_int),
+                _ => (),
+            }
+        }
+
+        Some(Self {
+            pos: pos?,
+            flags: flags?,
+            mnt_id,
+        })
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn trim_ascii_start(mut s: &[u8]) -> &[u8] {
+    while let Some((&ch, rest)) = s.split_first() {
+        if !ch.is_ascii_whitespace() {
+            break;
+        }
+        s = rest;
+    }
+    s
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn parse_u64(s: &[u8], radix: u32) -> Option<u64> {
+    if s.is_empty() {
+        return None;
+    }
+
+    s.iter().try_fold(0u64, |num, &ch| {
+        let digit = (ch as char).to_digit(radix)?;
+        num.checked_mul(radix as u64)?.checked_add(digit as u64)
+    })
+}
+
+/// Details about a socket file descriptor, as returned by [`FdInfoIter::read_socket_info()`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub struct SocketInfo {
+    /// The socket's address family (for example, `libc::AF_INET`).
+    pub domain: libc::c_int,
+    /// The socket's type (for example, `libc::SOCK_STREAM`).
+    pub sock_type: libc::c_int,
+    /// The socket's protocol (for example, `libc::IPPROTO_TCP`).
+    ///
+    /// This is only available on some platforms (Linux, Android, FreeBSD, and OpenBSD).
+    pub protocol: Option<libc::c_int>,
+    /// The address that the socket is bound to, if it's an IPv4 or IPv6 socket.
+    #[cfg(feature = "std")]
+    pub local_addr: Option<std::net::SocketAddr>,
+    /// The address of the socket's peer, if it's a connected IPv4 or IPv6 socket.
+    #[cfg(feature = "std")]
+    pub peer_addr: Option<std::net::SocketAddr>,
+}
+
+#[cfg(feature = "std")]
+fn sockaddr_to_std(addr: &libc::sockaddr_storage) -> Option<std::net::SocketAddr> {
+    use std::net::{SocketAddrV4, SocketAddrV6};
+
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+            Some(
+                SocketAddrV4::new(
+                    u32::from_be(addr.sin_addr.s_addr).into(),
+                    u16::from_be(addr.sin_port),
+                )
+                .into(),
+            )
+        }
+
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+            Some(
+                SocketAddrV6::new(
+                    addr.sin6_addr.s6_addr.into(),
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                )
+                .into(),
+            )
+        }
+
+        _ => None,
+    }
+}
+
+/// A special kind of file descriptor provided by Linux, as identified by
+/// [`FdInfoIter::read_special_kind()`].
+///
+/// Most of these are "anonymous inode" file descriptors, which don't correspond to a file.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum SpecialFdKind {
+    /// An epoll instance (see `epoll_create(2)`).
+    Epoll,
+    /// An eventfd (see `eventfd(2)`).
+    EventFd,
+    /// A signalfd (see `signalfd(2)`).
+    SignalFd,
+    /// A timerfd (see `timerfd_create(2)`).
+    TimerFd,
+    /// An inotify instance (see `inotify_init(2)`).
+    Inotify,
+    /// A file created by `memfd_create(2)`.
+    Memfd,
+    /// A process file descriptor (see `pidfd_open(2)`).
+    PidFd,
+    /// An io_uring instance (see `io_uring_setup(2)`).
+    IoUring,
+    /// Some other kind of anonymous inode file descriptor.
+    OtherAnonInode,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl SpecialFdKind {
+    pub(super) fn from_link(link: &[u8]) -> Option<Self> {
+        if let Some(name) = link.strip_prefix(b"anon_inode:") {
+            Some(match name {
+                b"[eventpoll]" => Self::Epoll,
+                b"[eventfd]" => Self::EventFd,
+                b"[signalfd]" => Self::SignalFd,
+                b"[timerfd]" => Self::TimerFd,
+                b"inotify" => Self::Inotify,
+                b"[pidfd]" => Self::PidFd,
+                b"[io_uring]" => Self::IoUring,
+                _ => Self::OtherAnonInode,
+            })
+        } else if link.starts_with(b"/memfd:") {
+            Some(Self::Memfd)
+        } else if link.starts_with(b"pidfd:") {
+            // Linux 6.9+ (pidfs)
+            Some(Self::PidFd)
+        } else {
+            None
+        }
+    }
+}
+
+/// An iterator over information on the current process's open file descriptors.
+///
+/// This can be created with [`FdIterBuilder::iter_info_from()`](./struct.FdIterBuilder.html) or
+/// [`iter_open_fds_info()`](./fn.iter_open_fds_info.html). The same warnings that apply to
+/// [`FdIter`] apply here.
+///
+/// Unlike an `FdIter`, this never yields invalid file descriptors: the information is collected
+/// with the same `fcntl()` call that is used to check whether each file descriptor is valid.
+pub struct FdInfoIter {
+    it: FdIter,
+}
+
+impl FdInfoIter {
+    #[inline]
+    pub(crate) fn new(mut it: FdIter) -> Self {
+        // We check each file descriptor's validity ourselves
+        it.possible = true;
+        Self { it }
+    }
+
+    /// Read the path that the given file descriptor refers to into `buf`.
+    ///
+    /// This uses the symbolic links in `/proc/self/fd` (Linux) or `/proc/self/path` (Solaris and
+    /// Illumos), so the results are subject to the caveats of those interfaces (for example,
+    /// sockets and pipes will have "paths" like `socket:[1234]`, and paths to files that have
+    /// been deleted may have ` (deleted)` appended on Linux). If this iterator has a directory
+    /// file descriptor open to `/proc/self/fd`, it will be used to look up the link.
+    ///
+    /// `fd` is usually a file descriptor that was just yielded by this iterator. On success, the
+    /// part of `buf` that the path was written into is returned. `None` is returned if the path
+    /// could not be read, or if it doesn't fit in `buf`.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "solaris",
+        target_os = "illumos",
+    ))]
+    pub fn read_path<'b>(&self, fd: libc::c_int, buf: &'b mut [u8]) -> Option<&'b [u8]> {
+        let len = self.readlink_fd(fd, buf)?;
+
+        // If the path filled the whole buffer, it may have been truncated
+        if len >= buf.len() {
+            None
+        } else {
+            Some(&buf[..len])
+        }
+    }
+
+    /// Read the link for the given file descriptor into `buf`, returning the number of bytes
+    /// read. The contents may be truncated.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "solaris",
+        target_os = "illumos",
+    ))]
+    fn readlink_fd(&self, fd: libc::c_int, buf: &mut [u8]) -> Option<usize> {
+        let mut pathbuf = [0; 40];
+
+        #[cfg(all(target_os = "linux", not(feature = "no-fs-backends")))]
+        let (dirfd, path) = match self.it.dirfd_iter.as_ref().and_then(|d| d.dirfd()) {
+            Some(dirfd) => (dirfd, crate::util::format_fd_path(b"", fd, &mut pathbuf)),
+            None => (
+                libc::AT_FDCWD,
+                crate::util::format_fd_path(b"/proc/self/fd/", fd, &mut pathbuf),
+            ),
+        };
+
+        #[cfg(any(
+            target_os = "android",
+            all(target_os = "linux", feature = "no-fs-backends")
+        ))]
+        let (dirfd, path) = (
+            libc::AT_FDCWD,
+            crate::util::format_fd_path(b"/proc/self/fd/", fd, &mut pathbuf),
+        );
+
+        #[cfg(any(target_os = "solaris", target_os = "illumos"))]
+        let (dirfd, path) = (
+            libc::AT_FDCWD,
+            crate::util::format_fd_path(b"/proc/self/path/", fd, &mut pathbuf),
+        );
+
+        let len = unsafe {
+            libc::readlinkat(
+                dirfd,
+                path.as_ptr() as *const libc::c_char,
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        };
+
+        if len < 0 {
+            None
+        } else {
+            Some(len as usize)
+        }
+    }
+
+    /// Identify the given file descriptor if it's one of the special kinds of file descriptors
+    /// that Linux provides (such as an epoll instance or an eventfd).
+    ///
+    /// This works by examining the link in `/proc/self/fd` (see [`Self::read_path()`]). `None` is
+    /// returned if the file descriptor isn't one of these special kinds (for example, if it's a
+    /// regular file or a socket), or if the link couldn't be read.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn read_special_kind(&self, fd: libc::c_int) -> Option<SpecialFdKind> {
+        // We only need to look at the beginning of the link
+        let mut buf = [0; 32];
+        let len = self.readlink_fd(fd, &mut buf)?;
+        SpecialFdKind::from_link(&buf[..len])
+    }
+
+    /// Check whether the given file descriptor refers to a file (or directory) that has been
+    /// deleted.
+    ///
+    /// These are a common reason for disk space not being freed after files are deleted. This
+    /// checks whether the file has a link count of 0. On Linux, it also checks for the
+    /// ` (deleted)` suffix that is added to the link in `/proc/self/fd` (if the link can be read),
+    /// which avoids an `fstat()` call for most files that haven't been deleted.
+    ///
+    /// `None` is returned if this can't be determined (for example, because the file descriptor
+    /// was closed).
+    pub fn is_deleted(&self, fd: libc::c_int) -> Option<bool> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            let mut buf = [0; libc::PATH_MAX as usize];
+
+            if let Some(path) = self.read_path(fd, &mut buf) {
+                // Only files and directories have paths starting with a "/"; other "paths" look
+                // like "socket:[1234]" or "anon_inode:[eventfd]".
+                if !path.starts_with(b"/") || !path.ends_with(b" (deleted)") {
+                    return Some(false);
+                }
+                // The suffix alone isn't conclusive; the file's real name might end with
+                // " (deleted)". So we also check the link count below.
+            }
+        }
+
+        let mut st = core::mem::MaybeUninit::uninit();
+        if unsafe { libc::fstat(fd, st.as_mut_ptr()) } != 0 {
+            return None;
+        }
+        let st = unsafe { st.assume_init() };
+
+        // st_mode isn't always a mode_t (e.g. on 32-bit Android)
+        #[allow(clippy::unnecessary_cast)]
+        let fmt = st.st_mode as libc::mode_t & libc::S_IFMT;
+
+        Some(matches!(fmt, libc::S_IFREG | libc::S_IFDIR) && st.st_nlink == 0)
+    }
+
+    /// Get details about the given file descriptor if it refers to a socket.
+    ///
+    /// `None` is returned if `fd` is not a socket (or if the information could not be retrieved).
+    pub fn read_socket_info(&self, fd: libc::c_int) -> Option<SocketInfo> {
+        fn getsockopt_int(fd: libc::c_int, opt: libc::c_int) -> Option<libc::c_int> {
+            let mut val: libc::c_int = 0;
+            let mut len = core::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+            if unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    opt,
+                    &mut val as *mut _ as *mut libc::c_void,
+                    &mut len,
+                )
+            } == 0
+            {
+                Some(val)
+            } else {
+                None
+            }
+        }
+
+        // This fails with ENOTSOCK if it's not a socket
+        let sock_type = getsockopt_int(fd, libc::SO_TYPE)?;
+
+        let mut local_addr = unsafe { core::mem::zeroed::<libc::sockaddr_storage>() };
+        let mut len = core::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let have_local_addr =
+            unsafe { libc::getsockname(fd, &mut local_addr as *mut _ as *mut _, &mut len) } == 0;
+
+        // Ask for the domain directly where that's supported, and only fall back on the address
+        // family reported by getsockname() elsewhere
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+        let domain = getsockopt_int(fd, libc::SO_DOMAIN);
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+        let domain = None;
+
+        let domain = match domain {
+            Some(domain) => domain,
+            None if have_local_addr => local_addr.ss_family as libc::c_int,
+            None => return None,
+        };
+
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "openbsd",
+        ))]
+        let protocol = getsockopt_int(fd, libc::SO_PROTOCOL);
+        #[cfg(not(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "openbsd",
+        )))]
+        let protocol = None;
+
+        #[cfg(feature = "std")]
+        let peer_addr = {
+            let mut peer_addr = unsafe { core::mem::zeroed::<libc::sockaddr_storage>() };
+            let mut len = core::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+            if unsafe { libc::getpeername(fd, &mut peer_addr as *mut _ as *mut _, &mut len) } == 0 {
+                sockaddr_to_std(&peer_addr)
+            } else {
+                None
+            }
+        };
+
+        Some(SocketInfo {
+            domain,
+            sock_type,
+            protocol,
+            #[cfg(feature = "std")]
+            local_addr: if have_local_addr {
+                sockaddr_to_std(&local_addr)
+            } else {
+                None
+            },
+            #[cfg(feature = "std")]
+            peer_addr,
+        })
+    }
+
+    /// Read details about the given file descriptor from `/proc/self/fdinfo`.
+    ///
+    /// This requires opening and reading a file for each file descriptor, so it's significantly
+    /// more expensive than the information collected in [`FdInfo`]. `None` is returned if the
+    /// information could not be read (for example, because `/proc` isn't mounted or the file
+    /// descriptor was closed).
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn read_proc_fdinfo(&self, fd: libc::c_int) -> Option<ProcFdInfo> {
+        let mut pathbuf = [0; 40];
+        let path = crate::util::format_fd_path(b"/proc/self/fdinfo/", fd, &mut pathbuf);
+
+        let file = unsafe {
+            libc::open(
+                path.as_ptr() as *const libc::c_char,
+                libc::O_RDONLY | libc::O_CLOEXEC,
+            )
+        };
+        if file < 0 {
+            return None;
+        }
+
+        // The fields we're interested in come first, so we don't need to read the whole file
+        // (which may be quite long for e.g. epoll file descriptors)
+        let mut buf = [0; 256];
+        let n = unsafe { libc::read(file, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        unsafe {
+            libc::close(file);
+        }
+
+        if n <= 0 {
+            return None;
+        }
+
+        ProcFdInfo::parse(&buf[..n as usize])
+    }
+}
+
+impl Iterator for FdInfoIter {
+    type Item = FdInfo;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for fd in self.it.by_ref() {
+            // This doubles as the check for whether the file descriptor is valid
+            let fd_flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+            if fd_flags < 0 {
+                continue;
+            }
+
+            // This can only fail if the file descriptor was closed in the meantime
+            let status_flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if status_flags < 0 {
+                continue;
+            }
+
+            return Some(FdInfo {
+                fd,
+                cloexec: fd_flags & libc::FD_CLOEXEC != 0,
+                status_flags,
+            });
+        }
+
+        None
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.it.size_hint().1)
+    }
+}
+
+impl core::iter::FusedIterator for FdInfoIter {}
+
+#[cfg(test)]
+mod tests {
+    use crate::FdIterBuilder;
+
+    #[test]
+    fn test_info_cloexec() {
+        let fd1 = unsafe { libc::open("/\0".as_ptr() as *const libc::c_char, libc::O_RDONLY) };
+        assert!(fd1 >= 0);
+        let fd2 = unsafe {
+            libc::open(
+                "/\0".as_ptr() as *const libc::c_char,
+                libc::O_RDONLY | libc::O_CLOEXEC,
+            )
+        };
+        assert!(fd2 >= 0);
+
+        for &fs in [true, false].iter() {
+            let mut found = [false; 2];
+
+            for info in FdIterBuilder::new().allow_filesystem(fs).iter_info_from(0) {
+                if info.fd == fd1 {
+                    assert!(!info.cloexec);
+                    found[0] = true;
+                } else if info.fd == fd2 {
+                    assert!(info.cloexec);
+                    found[1] = true;
+                }
+            }
+
+            assert_eq!(found, [true; 2]);
+        }
+
+        unsafe {
+            libc::close(fd1);
+            libc::close(fd2);
+        }
+    }
+
+    #[test]
+    fn test_info_status_flags() {
+        let fd1 = unsafe { libc::open("/\0".as_ptr() as *const libc::c_char, libc::O_RDONLY) };
+        assert!(fd1 >= 0);
+        let fd2 = unsafe {
+            libc::open(
+                "/\0".as_ptr() as *const libc::c_char,
+                libc::O_RDONLY | libc::O_NONBLOCK,
+            )
+        };
+        assert!(fd2 >= 0);
+
+        let mut found = [false; 2];
+
+        for info in FdIterBuilder::new().iter_info_from(0) {
+            if info.fd == fd1 {
+                assert_eq!(info.status_flags & libc::O_ACCMODE, libc::O_RDONLY);
+                assert_eq!(info.status_flags & libc::O_NONBLOCK, 0);
+                found[0] = true;
+            } else if info.fd == fd2 {
+                assert_eq!(info.status_flags & libc::O_ACCMODE, libc::O_RDONLY);
+                assert_ne!(info.status_flags & libc::O_NONBLOCK, 0);
+                found[1] = true;
+            }
+        }
+
+        assert_eq!(found, [true; 2]);
+
+        unsafe {
+            libc::close(fd1);
+            libc::close(fd2);
+        }
+    }
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "solaris",
+        target_os = "illumos",
+    ))]
+    #[test]
+    fn test_info_read_path() {
+        let fd = unsafe { libc::open("/\0".as_ptr() as *const libc::c_char, libc::O_RDONLY) };
+        assert!(fd >= 0);
+
+        for &fs in [true, false].iter() {
+            let mut it = FdIterBuilder::new().allow_filesystem(fs).iter_info_from(fd);
+            assert_eq!(it.next().map(|info| info.fd), Some(fd));
+
+            let mut buf = [0; 10];
+            assert_eq!(it.read_path(fd, &mut buf), Some(&b"/"[..]));
+            // Too small
+            assert_eq!(it.read_path(fd, &mut buf[..1]), None);
+        }
+
+        unsafe {
+            libc::close(fd);
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_parse_proc_fdinfo() {
+        use super::ProcFdInfo;
+
+        assert_eq!(
+            ProcFdInfo::parse(b"pos:\t12\nflags:\t02004002\nmnt_id:\t25\nino:\t1234\n"),
+            Some(ProcFdInfo {
+                pos: 12,
+                flags: 0o2004002,
+                mnt_id: Some(25),
+            })
+        );
+        assert_eq!(
+            ProcFdInfo::parse(b"pos:\t0\nflags:\t0100000\n"),
+            Some(ProcFdInfo {
+                pos: 0,
+                flags: 0o100000,
+                mnt_id: None,
+            })
+        );
+
+        // Incomplete lines are ignored
+        assert_eq!(ProcFdInfo::parse(b"pos:\t0\nflags:\t01"), None);
+        assert_eq!(ProcFdInfo::parse(b"pos:\t0\n"), None);
+        assert_eq!(ProcFdInfo::parse(b"pos:\tx\nflags:\t0\n"), None);
+        assert_eq!(ProcFdInfo::parse(b"pos:\t0\nflags:\t8\n"), None);
+        assert_eq!(ProcFdInfo::parse(b""), None);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_read_proc_fdinfo() {
+        let fd = unsafe {
+            libc::open(
+                "/\0".as_ptr() as *const libc::c_char,
+                libc::O_RDONLY | libc::O_CLOEXEC,
+            )
+        };
+        assert!(fd >= 0);
+
+        let it = FdIterBuilder::new().iter_info_from(fd);
+        if let Some(info) = it.read_proc_fdinfo(fd) {
+            assert_eq!(info.pos, 0);
+            assert_eq!(info.flags & libc::O_ACCMODE, libc::O_RDONLY);
+            assert_ne!(info.flags & libc::O_CLOEXEC, 0);
+        }
+
+        unsafe {
+            libc::close(fd);
+        }
+    }
+
+    #[test]
+    fn test_read_socket_info() {
+        let mut fds = [-1; 2];
+        assert_eq!(
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) },
+            0
+        );
+
+        let it = FdIterBuilder::new().iter_info_from(0);
+
+        let info = it.read_socket_info(fds[0]).unwrap();
+        assert_eq!(info.domain, libc::AF_UNIX);
+        assert_eq!(info.sock_type, libc::SOCK_STREAM);
+        assert!(matches!(info.protocol, None | Some(0)));
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+
+        // Unbound sockets
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+        assert!(fd >= 0);
+        let info = it.read_socket_info(fd).unwrap();
+        assert_eq!(info.domain, libc::AF_INET);
+        assert_eq!(info.sock_type, libc::SOCK_DGRAM);
+        unsafe {
+            libc::close(fd);
+        }
+
+        let fd = unsafe { libc::open("/\0".as_ptr() as *const libc::c_char, libc::O_RDONLY) };
+        assert!(fd >= 0);
+        assert_eq!(it.read_socket_info(fd), None);
+        unsafe {
+            libc::close(fd);
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_special_kind_from_link() {
+        use super::SpecialFdKind;
+
+        for &(link, kind) in [
+            (&b"anon_inode:[eventpoll]"[..], Some(SpecialFdKind::Epoll)),
+            (b"anon_inode:[eventfd]", Some(SpecialFdKind::EventFd)),
+            (b"anon_inode:[signalfd]", Some(SpecialFdKind::SignalFd)),
+            (b"anon_inode:[timerfd]", Some(SpecialFdKind::TimerFd)),
+            (b"anon_inode:inotify", Some(SpecialFdKind::Inotify)),
+            (b"anon_inode:[pidfd]", Some(SpecialFdKind::PidFd)),
+            (b"pidfd:[1234]", Some(SpecialFdKind::PidFd)),
+            (b"anon_inode:[io_uring]", Some(SpecialFdKind::IoUring)),
+            (
+                b"anon_inode:[userfaultfd]",
+                Some(SpecialFdKind::OtherAnonInode),
+            ),
+            (b"/memfd:name (deleted)", Some(SpecialFdKind::Memfd)),
+            (b"/", None),
+            (b"socket:[1234]", None),
+            (b"pipe:[1234]", None),
+            (b"", None),
+        ]
+        .iter()
+        {
+            assert_eq!(SpecialFdKind::from_link(link), kind, "{:?}", link);
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_read_special_kind() {
+        use super::SpecialFdKind;
+
+        let epfd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        assert!(epfd >= 0);
+        let evfd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        assert!(evfd >= 0);
+        let fd = unsafe { libc::open("/\0".as_ptr() as *const libc::c_char, libc::O_RDONLY) };
+        assert!(fd >= 0);
+
+        for &fs in [true, false].iter() {
+            let it = FdIterBuilder::new().allow_filesystem(fs).iter_info_from(0);
+
+            // /proc may not be mounted
+            if it.read_special_kind(epfd).is_some() {
+                assert_eq!(it.read_special_kind(epfd), Some(SpecialFdKind::Epoll));
+                assert_eq!(it.read_special_kind(evfd), Some(SpecialFdKind::EventFd));
+                assert_eq!(it.read_special_kind(fd), None);
+            }
+        }
+
+        unsafe {
+            libc::close(epfd);
+            libc::close(evfd);
+            libc::close(fd);
+        }
+    }
+
+    #[test]
+    fn test_is_deleted() {
+        let mut path = *b"/tmp/close_fds_test_XXXXXX\0";
+        let fd = unsafe { libc::mkstemp(path.as_mut_ptr() as *mut libc::c_char) };
+        assert!(fd >= 0);
+
+        let it = FdIterBuilder::new().iter_info_from(0);
+
+        assert_eq!(it.is_deleted(fd), Some(false));
+        assert_eq!(
+            unsafe { libc::unlink(path.as_ptr() as *const libc::c_char) },
+            0
+        );
+        assert_eq!(it.is_deleted(fd), Some(true));
+
+        // A file whose name just happens to end with " (deleted)"
+        let mut path2 = [0; 64];
+        path2[..path.len() - 1].copy_from_slice(&path[..path.len() - 1]);
+        path2[path.len() - 1..path.len() + 9].copy_from_slice(b" (deleted)");
+        let fd2 = unsafe {
+            libc::open(
+                path2.as_ptr() as *const libc::c_char,
+                libc::O_RDWR | libc::O_CREAT | libc::O_EXCL | libc::O_CLOEXEC,
+                0o600,
+            )
+        };
+        assert!(fd2 >= 0);
+
+        assert_eq!(it.is_deleted(fd2), Some(false));
+        assert_eq!(
+            unsafe { libc::unlink(path2.as_ptr() as *const libc::c_char) },
+            0
+        );
+        assert_eq!(it.is_deleted(fd2), Some(true));
+
+        unsafe {
+            libc::close(fd);
+            libc::close(fd2);
+        }
+    }
+}
@@ -1,16 +1,60 @@
+#[cfg(feature = "std")]
+mod audit;
+mod cache;
+mod dump;
 mod fditer;
-pub use fditer::FdIter;
-
-#[cfg(any(
-    target_os = "linux",
-    target_os = "macos",
-    target_os = "ios",
-    target_os = "freebsd",
-    target_os = "netbsd",
-    target_os = "solaris",
-    target_os = "illumos",
+mod hygiene;
+mod info;
+#[cfg(feature = "std")]
+mod monitor;
+#[cfg(feature = "std")]
+mod pidfds;
+#[cfg(feature = "std")]
+mod snapshot;
+mod usage;
+#[cfg(feature = "std")]
+pub use audit::{FdAuditPolicy, FdAuditViolation, FdKind};
+pub use cache::FdDirCache;
+#[cfg(feature = "std")]
+pub use dump::dump_open_fds;
+pub use dump::dump_open_fds_raw;
+pub use fditer::{FdIter, PossibleFdIter};
+pub use hygiene::{
+    assert_only_open_fds, verify_all_cloexec, FdViolations, MAX_RECORDED_VIOLATIONS,
+};
+pub use info::{FdInfo, FdInfoIter, SocketInfo};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use info::{ProcFdInfo, SpecialFdKind};
+#[cfg(feature = "std")]
+pub use monitor::{FdMonitor, FdMonitorBuilder, FdMonitorEvent};
+#[cfg(feature = "std")]
+pub use pidfds::count_pid_fds;
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+pub use pidfds::{
+    audit_processes, find_fd_holders, find_fd_holders_by_inode, list_pid_fds, PidFdEntry,
+    ProcessFds,
+};
+#[cfg(feature = "std")]
+pub use snapshot::{assert_no_new_fds, expect_fds_closed, FdGuard, FdSnapshot};
+pub(crate) use usage::lower_fd_limit;
+pub use usage::{fd_usage, is_fd_pressure, raise_fd_limit, FdLimitError, FdUsage};
+
+#[cfg(all(
+    not(feature = "no-fs-backends"),
+    any(
+        target_os = "linux",
+        target_os = "macos",
+        all(
+            any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+            feature = "ios-raw-syscalls"
+        ),
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "solaris",
+        target_os = "illumos",
+    )
 ))]
-mod dirfd;
+pub(crate) mod dirfd;
 
 /// A "builder" to construct an [`FdIter`] with custom parameters.
 ///
@@ -42,16 +86,59 @@ pub struct FdIterBuilder {
     possible: bool,
     #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
     skip_nfds: bool,
-    #[cfg(any(
-        target_os = "linux",
-        target_os = "macos",
-        target_os = "ios",
-        target_os = "freebsd",
-        target_os = "netbsd",
-        target_os = "solaris",
-        target_os = "illumos",
+    #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+    threadsafe_auto: bool,
+    #[cfg(all(
+        not(feature = "no-fs-backends"),
+        any(
+            target_os = "linux",
+            target_os = "macos",
+            all(
+                any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                feature = "ios-raw-syscalls"
+            ),
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "solaris",
+            target_os = "illumos",
+        )
     ))]
     dirfd: bool,
+    #[cfg(all(
+        not(feature = "no-fs-backends"),
+        any(
+            target_os = "linux",
+            target_os = "macos",
+            all(
+                any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                feature = "ios-raw-syscalls"
+            ),
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "solaris",
+            target_os = "illumos",
+        )
+    ))]
+    dirfd_minfd: libc::c_int,
+    /// A directory file descriptor owned by an `FdDirCache` (or -1). This is only set on
+    /// temporary copies of builders that can't outlive the cache.
+    #[cfg(all(
+        not(feature = "no-fs-backends"),
+        any(
+            target_os = "linux",
+            target_os = "macos",
+            all(
+                any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                feature = "ios-raw-syscalls"
+            ),
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "solaris",
+            target_os = "illumos",
+        )
+    ))]
+    cached_dirfd: libc::c_int,
+    counter: crate::counter::Counter,
 }
 
 impl FdIterBuilder {
@@ -62,16 +149,57 @@ impl FdIterBuilder {
             possible: false,
             #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
             skip_nfds: false,
-            #[cfg(any(
-                target_os = "linux",
-                target_os = "macos",
-                target_os = "ios",
-                target_os = "freebsd",
-                target_os = "netbsd",
-                target_os = "solaris",
-                target_os = "illumos",
+            #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+            threadsafe_auto: false,
+            #[cfg(all(
+                not(feature = "no-fs-backends"),
+                any(
+                    target_os = "linux",
+                    target_os = "macos",
+                    all(
+                        any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                        feature = "ios-raw-syscalls"
+                    ),
+                    target_os = "freebsd",
+                    target_os = "netbsd",
+                    target_os = "solaris",
+                    target_os = "illumos",
+                )
             ))]
             dirfd: true,
+            #[cfg(all(
+                not(feature = "no-fs-backends"),
+                any(
+                    target_os = "linux",
+                    target_os = "macos",
+                    all(
+                        any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                        feature = "ios-raw-syscalls"
+                    ),
+                    target_os = "freebsd",
+                    target_os = "netbsd",
+                    target_os = "solaris",
+                    target_os = "illumos",
+                )
+            ))]
+            dirfd_minfd: 0,
+            #[cfg(all(
+                not(feature = "no-fs-backends"),
+                any(
+                    target_os = "linux",
+                    target_os = "macos",
+                    all(
+                        any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                        feature = "ios-raw-syscalls"
+                    ),
+                    target_os = "freebsd",
+                    target_os = "netbsd",
+                    target_os = "solaris",
+                    target_os = "illumos",
+                )
+            ))]
+            cached_dirfd: -1,
+            counter: crate::counter::Counter(None),
         }
     }
 
@@ -85,8 +213,19 @@ impl FdIterBuilder {
     ///
     /// You should only use this flag if you immediately perform an operation on each file
     /// descriptor that implicitly checks if the file descriptor is valid.
+    ///
+    /// This is deprecated because the resulting iterator is a plain `FdIter`, so nothing stops it
+    /// from being passed to code that expects valid file descriptors. Use
+    /// [`Self::iter_possible_from()`] instead, which returns a separate [`PossibleFdIter`] type.
+    #[deprecated(note = "use `iter_possible_from()`, which returns a `PossibleFdIter`")]
     #[inline]
     pub fn possible(&mut self, possible: bool) -> &mut Self {
+        self.set_possible(possible)
+    }
+
+    // Like possible(), for internal code that never lets the `FdIter` escape
+    #[inline]
+    pub(crate) fn set_possible(&mut self, possible: bool) -> &mut Self {
         self.possible = possible;
         self
     }
@@ -107,6 +246,32 @@ impl FdIterBuilder {
         #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
         {
             self.skip_nfds = threadsafe;
+            self.threadsafe_auto = false;
+        }
+        self
+    }
+
+    /// Make the returned `FdIter` behave reliably in multithreaded programs (as with
+    /// [`Self::threadsafe()`]) only if the current process actually has more than one thread when
+    /// [`Self::iter_from()`] is called.
+    ///
+    /// This allows single-threaded programs (and child processes after a `fork()`, which only have
+    /// one thread) to get the best performance, without giving up reliability in multithreaded
+    /// programs. If the number of threads can't be determined, the process is assumed to be
+    /// multithreaded.
+    ///
+    /// Note that if a single-threaded program creates a thread *while* the returned `FdIter` is
+    /// being consumed, the issues described in [`Self::threadsafe()`] may still occur.
+    ///
+    /// Calling [`Self::threadsafe()`] afterward overrides this.
+    ///
+    /// Currently, this will only have an effect on the same platforms as [`Self::threadsafe()`]
+    /// (i.e. OpenBSD and FreeBSD).
+    #[inline]
+    pub fn threadsafe_auto(&mut self) -> &mut Self {
+        #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+        {
+            self.threadsafe_auto = true;
         }
         self
     }
@@ -121,17 +286,26 @@ impl FdIterBuilder {
     /// It may be desirable to set this to `false` e.g. if `chroot()`ing into an environment where
     /// untrusted code may be able to replace `/proc` or `/dev`. However, on some platforms (such
     /// as Linux<5.9 and macOS) setting this to `false` may significantly decrease performance.
+    ///
+    /// If the `no-fs-backends` feature is enabled, this has no effect (the filesystem is never
+    /// examined).
     #[allow(unused_variables)]
     #[inline]
     pub fn allow_filesystem(&mut self, fs: bool) -> &mut Self {
-        #[cfg(any(
-            target_os = "linux",
-            target_os = "macos",
-            target_os = "ios",
-            target_os = "freebsd",
-            target_os = "netbsd",
-            target_os = "solaris",
-            target_os = "illumos",
+        #[cfg(all(
+            not(feature = "no-fs-backends"),
+            any(
+                target_os = "linux",
+                target_os = "macos",
+                all(
+                    any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                    feature = "ios-raw-syscalls"
+                ),
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "solaris",
+                target_os = "illumos",
+            )
         ))]
         {
             self.dirfd = fs;
@@ -139,6 +313,123 @@ impl FdIterBuilder {
         self
     }
 
+    /// Set the lowest file descriptor number that may be used for the directory file descriptor
+    /// opened internally (default is `0`).
+    ///
+    /// If [`Self::allow_filesystem()`] is enabled, the returned `FdIter` may open a file
+    /// descriptor to a directory such as `/proc/self/fd` or `/dev/fd`. By default, this takes the
+    /// lowest available file descriptor number, which may be inconvenient if the caller is about to
+    /// `dup2()` other file descriptors into place (for example, in a child process before an
+    /// `exec()`). If this is set, the directory file descriptor will be moved to `dirfd_minfd` or
+    /// above (using `fcntl(F_DUPFD_CLOEXEC)`) right after it is opened.
+    ///
+    /// This is done on a best-effort basis: if the directory file descriptor can't be moved (for
+    /// example, because `dirfd_minfd` is above the file descriptor limit), it will be left where
+    /// it is.
+    #[allow(unused_variables)]
+    #[inline]
+    pub fn dirfd_minfd(&mut self, dirfd_minfd: libc::c_int) -> &mut Self {
+        #[cfg(all(
+            not(feature = "no-fs-backends"),
+            any(
+                target_os = "linux",
+                target_os = "macos",
+                all(
+                    any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                    feature = "ios-raw-syscalls"
+                ),
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "solaris",
+                target_os = "illumos",
+            )
+        ))]
+        {
+            self.dirfd_minfd = dirfd_minfd;
+        }
+        self
+    }
+
+    /// Count the system calls that the returned iterators make in `counter` (default is `None`).
+    ///
+    /// This covers the `fcntl()` calls made to check whether file descriptors are open, the calls
+    /// made to read the entries of a directory like `/proc/self/fd`, and the `close()` call that
+    /// closes that directory. See [`SyscallCounter`](crate::SyscallCounter) for more information.
+    #[inline]
+    pub fn syscall_counter(
+        &mut self,
+        counter: Option<&'static crate::SyscallCounter>,
+    ) -> &mut Self {
+        self.counter = crate::counter::Counter(counter);
+        self
+    }
+
+    /// Create a [`PossibleFdIter`] that iterates over the open file descriptors starting at
+    /// `minfd`, but may -- for efficiency -- also yield invalid file descriptors.
+    ///
+    /// This ignores the [`Self::possible()`] setting.
+    #[inline]
+    pub fn iter_possible_from(&self, minfd: libc::c_int) -> PossibleFdIter {
+        self.iter_from(minfd).into_possible()
+    }
+
+    /// Create an [`FdIterPlan`] that will iterate over the open file descriptors starting at
+    /// `minfd` with this builder's settings once it's started.
+    ///
+    /// Unlike an `FdIter`, this doesn't open any file descriptors, so it can be created in one
+    /// thread (or before a `fork()`) and started in another (or in the child).
+    #[inline]
+    pub fn plan(&self, minfd: libc::c_int) -> FdIterPlan {
+        FdIterPlan {
+            builder: self.clone(),
+            minfd,
+        }
+    }
+
+    /// Identical to [`Self::iter_from()`], but uses the directory file descriptor cached in
+    /// `cache` (opening it if necessary) instead of opening a new one.
+    ///
+    /// See [`FdDirCache`] for more information. The returned iterator borrows `cache`, so the
+    /// cached file descriptor can't be closed while it's in use.
+    #[inline]
+    pub fn iter_from_cached<'c>(
+        &self,
+        cache: &'c mut FdDirCache,
+        minfd: libc::c_int,
+    ) -> impl core::iter::FusedIterator<Item = libc::c_int> + core::fmt::Debug + 'c {
+        self.with_cache(cache).iter_from(minfd)
+    }
+
+    /// Create a copy of this builder that uses the directory file descriptor cached in `cache`.
+    ///
+    /// The returned builder (and any iterators created from it) must not outlive `cache`.
+    #[allow(unused_variables)]
+    pub(crate) fn with_cache(&self, cache: &mut FdDirCache) -> Self {
+        #[allow(unused_mut)]
+        let mut builder = self.clone();
+
+        #[cfg(all(
+            not(feature = "no-fs-backends"),
+            any(
+                target_os = "linux",
+                target_os = "macos",
+                all(
+                    any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                    feature = "ios-raw-syscalls"
+                ),
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "solaris",
+                target_os = "illumos",
+            )
+        ))]
+        if builder.dirfd {
+            builder.cached_dirfd = cache.get(builder.dirfd_minfd);
+        }
+
+        builder
+    }
+
     /// Create an `FdIter` that iterates over the open file descriptors starting at `minfd`.
     pub fn iter_from(&self, mut minfd: libc::c_int) -> FdIter {
         if minfd < 0 {
@@ -147,28 +438,51 @@ impl FdIterBuilder {
 
         FdIter {
             curfd: minfd,
+            peeked: None,
             possible: self.possible,
             maxfd: None,
+            counter: self.counter,
             #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
-            skip_nfds: self.skip_nfds,
-            #[cfg(any(
-                target_os = "linux",
-                target_os = "macos",
-                target_os = "ios",
-                target_os = "freebsd",
-                target_os = "netbsd",
-                target_os = "solaris",
-                target_os = "illumos",
+            skip_nfds: self.skip_nfds
+                || (self.threadsafe_auto && crate::util::is_multithreaded().unwrap_or(true)),
+            #[cfg(all(
+                not(feature = "no-fs-backends"),
+                any(
+                    target_os = "linux",
+                    target_os = "macos",
+                    all(
+                        any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                        feature = "ios-raw-syscalls"
+                    ),
+                    target_os = "freebsd",
+                    target_os = "netbsd",
+                    target_os = "solaris",
+                    target_os = "illumos",
+                )
             ))]
-            dirfd_iter: if self.dirfd {
-                dirfd::DirFdIter::open(minfd)
-            } else {
+            dirfd_iter: if !self.dirfd {
                 None
+            } else if self.cached_dirfd >= 0 {
+                dirfd::DirFdIter::from_cached(minfd, self.cached_dirfd, self.counter)
+            } else {
+                dirfd::DirFdIter::open(minfd, self.dirfd_minfd, self.counter)
             },
         }
     }
 }
 
+impl FdIterBuilder {
+    /// Create an [`FdInfoIter`] that iterates over information on the open file descriptors
+    /// starting at `minfd`.
+    ///
+    /// The [`Self::possible()`] setting is ignored, since an `FdInfoIter` never yields invalid
+    /// file descriptors.
+    #[inline]
+    pub fn iter_info_from(&self, minfd: libc::c_int) -> FdInfoIter {
+        FdInfoIter::new(self.iter_from(minfd))
+    }
+}
+
 impl Default for FdIterBuilder {
     #[inline]
     fn default() -> Self {
@@ -176,6 +490,34 @@ impl Default for FdIterBuilder {
     }
 }
 
+/// A "plan" for iterating over file descriptors, created with [`FdIterBuilder::plan()`].
+///
+/// This records the builder's settings and `minfd`, but doesn't open anything (such as the
+/// directory file descriptor that an [`FdIter`] may use) until [`Self::start()`] is called. As a
+/// result, it's safe to create a plan in one thread and start it in another, or to create it
+/// before a `fork()` and start it in the child (where `start()` can be called, since it's
+/// async-signal-safe). This is the recommended way to move iteration between threads or
+/// processes; see [`FdIter`]'s "Thread safety" section.
+#[derive(Clone, Debug)]
+pub struct FdIterPlan {
+    builder: FdIterBuilder,
+    minfd: libc::c_int,
+}
+
+impl FdIterPlan {
+    /// Get the minimum file descriptor that this plan will iterate from.
+    #[inline]
+    pub fn minfd(&self) -> libc::c_int {
+        self.minfd
+    }
+
+    /// Start iterating. This may be called multiple times; each call creates a new `FdIter`.
+    #[inline]
+    pub fn start(&self) -> FdIter {
+        self.builder.iter_from(self.minfd)
+    }
+}
+
 /// Iterate over all open file descriptors for the current process, starting at `minfd`. The file
 /// descriptors are guaranteed to be returned in ascending order.
 ///
@@ -198,36 +540,162 @@ pub fn iter_open_fds_threadsafe(minfd: libc::c_int) -> FdIter {
     FdIterBuilder::new().threadsafe(true).iter_from(minfd)
 }
 
+/// Iterate over information on all open file descriptors for the current process, starting at
+/// `minfd`. The file descriptors are guaranteed to be returned in ascending order.
+///
+/// This is equivalent to `FdIterBuilder::new().iter_info_from(minfd)`.
+///
+/// See the warnings for [`FdIterBuilder`].
+#[inline]
+pub fn iter_open_fds_info(minfd: libc::c_int) -> FdInfoIter {
+    FdIterBuilder::new().iter_info_from(minfd)
+}
+
 /// Identical to `iter_open_fds()`, but may -- for efficiency -- yield invalid file descriptors.
 ///
 /// This is equivalent to `FdIterBuilder::new().possible(true).iter_from(minfd)`.
 ///
-/// See [`FdIterBuilder::possible()`] for more information.
+/// This is deprecated for the same reasons as [`FdIterBuilder::possible()`]; use
+/// `FdIterBuilder::new().iter_possible_from(minfd)` (which returns a [`PossibleFdIter`]) instead.
+#[deprecated(note = "use `FdIterBuilder::iter_possible_from()`, which returns a `PossibleFdIter`")]
 #[inline]
 pub fn iter_possible_fds(minfd: libc::c_int) -> FdIter {
-    FdIterBuilder::new().possible(true).iter_from(minfd)
+    FdIterBuilder::new().set_possible(true).iter_from(minfd)
 }
 
 /// Identical to `iter_open_fds_threadsafe()`, but may -- for efficiency -- yield invalid file
 /// descriptors.
 ///
-/// This is equivalent to `FdIterBuilder::new().possible(true).threadafe(true).iter_from(minfd)`.
+/// This is equivalent to `FdIterBuilder::new().possible(true).threadsafe(true).iter_from(minfd)`.
 ///
-/// See [`FdIterBuilder::possible()`] and [`FdIterBuilder::threadsafe()`] for more information.
+/// This is deprecated for the same reasons as [`FdIterBuilder::possible()`]; use
+/// `FdIterBuilder::new().threadsafe(true).iter_possible_from(minfd)` (which returns a
+/// [`PossibleFdIter`]) instead.
+#[deprecated(note = "use `FdIterBuilder::iter_possible_from()`, which returns a `PossibleFdIter`")]
 #[inline]
 pub fn iter_possible_fds_threadsafe(minfd: libc::c_int) -> FdIter {
     FdIterBuilder::new()
-        .possible(true)
+        .set_possible(true)
         .threadsafe(true)
         .iter_from(minfd)
 }
 
+/// Get the upper bound that an [`FdIter`] will use if it has to fall back on checking every
+/// possible file descriptor (a "maxfd loop").
+///
+/// Iterators always try to enumerate the open file descriptors exactly first (for example, by
+/// reading `/proc/self/fd` or `/dev/fd`, or by using `fcntl(F_MAXFD)` on NetBSD). This bound is
+/// only used if that isn't possible.
+///
+/// It is derived from the soft `RLIMIT_NOFILE` limit, clamped to the range 1024-65536: if the
+/// limit is `RLIM_INFINITY` or larger than 65536, 65535 is returned. This means that file
+/// descriptors above the bound may still be open (for example, if the limit was lowered after they
+/// were opened). When closing file descriptors, this crate uses `closefrom()` or `close_range()`
+/// (where available) to close any file descriptors above the bound; elsewhere, it keeps checking
+/// every possible file descriptor up to the unclamped limit (see [`fd_table_size()`]).
+///
+/// [`FdIter::known_max_fd()`] can be used to check the bound that a particular iterator actually
+/// used.
+#[inline]
+pub fn fallback_max_fd() -> libc::c_int {
+    fditer::fdlimit_maxfd()
+}
+
+/// Get the size of the current process's file descriptor table (like `getdtablesize()`).
+///
+/// This is the soft `RLIMIT_NOFILE` limit: new file descriptors can only be opened below it. (The
+/// hard limit only bounds how far the soft limit can be raised.) If the limit is `RLIM_INFINITY`
+/// or doesn't fit in a `c_int`, `c_int::MAX` is returned. If `getrlimit()` fails,
+/// `sysconf(_SC_OPEN_MAX)` is used instead.
+///
+/// This is the value that [`fallback_max_fd()`] is derived from (before clamping), so it can be
+/// used to size things like `poll()` arrays consistently with this crate. Note that, as explained
+/// there, file descriptors at or above this value may still be open if the limit was lowered after
+/// they were opened.
+#[inline]
+pub fn fd_table_size() -> libc::c_int {
+    fditer::fd_table_size()
+}
+
+/// If this crate has stopped reading the open file descriptors from a directory (such as
+/// `/proc/self/fd` or `/dev/fd`) because the OS denied access to its entries, get the error code
+/// that reading it failed with.
+///
+/// Under strict seccomp profiles, opening `/proc/self/fd` may succeed but `getdents64()` may then
+/// fail with `EPERM`. When reading the directory fails with `EPERM`, `EACCES`, or `ENOSYS`, the
+/// [`FdIter`] that hit the error closes the directory file descriptor and falls back on the other
+/// methods of finding open file descriptors (see [`fallback_max_fd()`]). The error is then
+/// recorded, and later iterators in this process skip the directory entirely instead of retrying
+/// the blocked system call every time. (Other errors are assumed to be transient, and only affect
+/// the iterator that encountered them.)
+///
+/// On macOS, the App Sandbox may instead deny opening `/dev/fd` in the first place (with `EPERM`
+/// or `EACCES`); that error is recorded the same way. Iterators then use
+/// `proc_pidinfo(PROC_PIDLISTFDS)` to find the maximum open file descriptor (if not too many are
+/// open), so they only loop up to that instead of up to [`fallback_max_fd()`].
+///
+/// This always returns `None` if the directory was never read, on platforms where file
+/// descriptors are not found this way, and if the `no-fs-backends` feature is enabled.
+#[inline]
+pub fn dirfd_backend_error() -> Option<libc::c_int> {
+    cfg_if::cfg_if! {
+        if #[cfg(all(
+            not(feature = "no-fs-backends"),
+            any(
+                target_os = "linux",
+                target_os = "macos",
+                all(
+                    any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                    feature = "ios-raw-syscalls"
+                ),
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "solaris",
+                target_os = "illumos",
+            )
+        ))] {
+            dirfd::blocked_errno()
+        } else {
+            None
+        }
+    }
+}
+
 #[inline]
 pub(crate) fn probe() {
-    // Check if we're on WSL 1 on Linux (since that prevents us from using /proc/self/fd)
-    // Otherwise, there's not much we can check in advance
-    #[cfg(target_os = "linux")]
-    crate::util::is_wsl_1();
+    // Check whether the directory lists file descriptors in order (it doesn't on WSL 1, for
+    // example); this happens the first time it's read through
+    #[cfg(all(
+        not(feature = "no-fs-backends"),
+        any(
+            target_os = "linux",
+            target_os = "macos",
+            all(
+                any(target_os = "ios", target_os = "tvos", target_os = "watchos"),
+                feature = "ios-raw-syscalls"
+            ),
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "solaris",
+            target_os = "illumos",
+        )
+    ))]
+    {
+        if let Some(mut it) = dirfd::DirFdIter::open(0, 0, Default::default()) {
+            while let Ok(Some(_)) = it.next() {}
+        }
+        if dirfd::entries_unordered() {
+            log_debug!(
+                "The file descriptor directory lists entries out of order; it will not be used"
+            );
+        }
+    }
+
+    // On FreeBSD, check which getdirentries() syscall the kernel supports
+    #[cfg(all(target_os = "freebsd", not(feature = "no-fs-backends")))]
+    if !dirfd::has_ino64_dirents() {
+        log_debug!("Using the pre-FreeBSD 12 getdirentries() syscall");
+    }
 }
 
 #[cfg(test)]
@@ -257,6 +725,7 @@ mod tests {
         let fds = open_files();
         test_size_hint_generic(FdIterBuilder::new().threadsafe(false).iter_from(0));
         test_size_hint_generic(FdIterBuilder::new().threadsafe(true).iter_from(0));
+        test_size_hint_generic(FdIterBuilder::new().threadsafe_auto().iter_from(0));
         unsafe {
             close_files(&fds);
         }
@@ -266,13 +735,13 @@ mod tests {
     fn test_size_hint_possible() {
         test_size_hint_generic(
             FdIterBuilder::new()
-                .possible(true)
+                .set_possible(true)
                 .threadsafe(false)
                 .iter_from(0),
         );
         test_size_hint_generic(
             FdIterBuilder::new()
-                .possible(true)
+                .set_possible(true)
                 .threadsafe(true)
                 .iter_from(0),
         );
@@ -280,13 +749,13 @@ mod tests {
         let fds = open_files();
         test_size_hint_generic(
             FdIterBuilder::new()
-                .possible(true)
+                .set_possible(true)
                 .threadsafe(false)
                 .iter_from(0),
         );
         test_size_hint_generic(
             FdIterBuilder::new()
-                .possible(true)
+                .set_possible(true)
                 .threadsafe(true)
                 .iter_from(0),
         );
@@ -355,6 +824,7 @@ mod tests {
         let fds = open_files();
         test_fused_generic(FdIterBuilder::new().threadsafe(false).iter_from(0));
         test_fused_generic(FdIterBuilder::new().threadsafe(true).iter_from(0));
+        test_fused_generic(FdIterBuilder::new().threadsafe_auto().iter_from(0));
         unsafe {
             close_files(&fds);
         }
@@ -364,13 +834,13 @@ mod tests {
     fn test_fused_possible() {
         test_fused_generic(
             FdIterBuilder::new()
-                .possible(true)
+                .set_possible(true)
                 .threadsafe(false)
                 .iter_from(0),
         );
         test_fused_generic(
             FdIterBuilder::new()
-                .possible(true)
+                .set_possible(true)
                 .threadsafe(true)
                 .iter_from(0),
         );
@@ -378,13 +848,13 @@ mod tests {
         let fds = open_files();
         test_fused_generic(
             FdIterBuilder::new()
-                .possible(true)
+                .set_possible(true)
                 .threadsafe(false)
                 .iter_from(0),
         );
         test_fused_generic(
             FdIterBuilder::new()
-                .possible(true)
+                .set_possible(true)
                 .threadsafe(true)
                 .iter_from(0),
         );
@@ -399,4 +869,258 @@ mod tests {
         fditer.by_ref().count();
         assert_eq!(fditer.next(), None);
     }
+
+    #[test]
+    fn test_validated() {
+        let fds = open_files();
+        unsafe {
+            close_files(&fds[5..]);
+        }
+
+        for &fs in [true, false].iter() {
+            let mut fditer = FdIterBuilder::new()
+                .set_possible(true)
+                .allow_filesystem(fs)
+                .iter_from(fds[0]);
+            assert_eq!(fditer.next(), Some(fds[0]));
+
+            let fditer = fditer.validated();
+            assert!(!fditer.is_possible_iter());
+
+            // Other tests may be opening/closing file descriptors concurrently, so we can only
+            // check that the ones we know about were yielded, and that the closed ones were not
+            // (unless another test has since reused them).
+            let mut found = [false; 4];
+            for fd in fditer {
+                if let Some(i) = fds[1..5].iter().position(|&x| x == fd) {
+                    found[i] = true;
+                } else if fds[5..].contains(&fd) {
+                    assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0);
+                }
+            }
+            assert_eq!(found, [true; 4]);
+        }
+
+        unsafe {
+            close_files(&fds[..5]);
+        }
+    }
+
+    #[test]
+    fn test_peek() {
+        let fds = open_files();
+
+        for &fs in [true, false].iter() {
+            for &possible in [true, false].iter() {
+                let mut fditer = FdIterBuilder::new()
+                    .set_possible(possible)
+                    .allow_filesystem(fs)
+                    .iter_from(fds[0]);
+
+                assert_eq!(fditer.peek(), Some(fds[0]));
+                assert_eq!(fditer.peek(), Some(fds[0]));
+                assert!(fditer.size_hint().0 >= if possible { 1 } else { 0 });
+                assert_eq!(fditer.next(), Some(fds[0]));
+
+                // Other tests may be opening/closing file descriptors concurrently, so we can only
+                // check that peek() agrees with next()
+                loop {
+                    let fd = fditer.peek();
+                    assert_eq!(fditer.next(), fd);
+                    if fd.is_none() {
+                        break;
+                    }
+                }
+                assert_eq!(fditer.peek(), None);
+            }
+        }
+
+        unsafe {
+            close_files(&fds);
+        }
+    }
+
+    #[test]
+    fn test_collect_into_slice() {
+        let fds = open_files();
+        unsafe {
+            close_files(&fds[5..]);
+        }
+
+        for &fs in [true, false].iter() {
+            let mut fditer = FdIterBuilder::new().allow_filesystem(fs).iter_from(fds[0]);
+            assert_eq!(fditer.collect_into_slice(&mut []), 0);
+
+            // Collect in small chunks to exercise truncation. Other tests may be opening/closing
+            // file descriptors concurrently, so we can only check the ones we know about.
+            let mut found = [false; 5];
+            let mut prev = -1;
+            loop {
+                let mut buf = [-1; 2];
+                let n = fditer.collect_into_slice(&mut buf);
+                assert!(n <= 2);
+
+                for &fd in buf[..n].iter() {
+                    assert!(fd > prev);
+                    prev = fd;
+                    if let Some(i) = fds[..5].iter().position(|&x| x == fd) {
+                        found[i] = true;
+                    }
+                }
+
+                if n < 2 {
+                    assert_eq!(fditer.peek(), None);
+                    break;
+                }
+            }
+            assert_eq!(found, [true; 5]);
+            assert!(prev >= fds[4]);
+        }
+
+        unsafe {
+            close_files(&fds[..5]);
+        }
+    }
+
+    #[test]
+    fn test_fold() {
+        let fds = open_files();
+        unsafe {
+            close_files(&fds[5..]);
+        }
+
+        for &fs in [true, false].iter() {
+            for &possible in [true, false].iter() {
+                let mut builder = FdIterBuilder::new();
+                builder.set_possible(possible).allow_filesystem(fs);
+
+                // fold() should yield the file descriptors in ascending order, starting with the
+                // peeked one (if any)
+                let mut fditer = builder.iter_from(fds[0]);
+                assert_eq!(fditer.peek(), Some(fds[0]));
+
+                let mut found = [false; 5];
+                let last = fditer.fold(-1, |prev, fd| {
+                    assert!(fd > prev);
+                    if let Some(i) = fds[..5].iter().position(|&x| x == fd) {
+                        found[i] = true;
+                    }
+                    fd
+                });
+                assert_eq!(found, [true; 5]);
+                assert!(last >= fds[4]);
+
+                // Other tests may be opening/closing file descriptors concurrently, so we can only
+                // check the ones we know about
+                let mut fditer = builder.iter_from(fds[0]);
+                fditer.next();
+                let mut count = 0;
+                fditer.for_each(|fd| {
+                    if fds[1..5].contains(&fd) {
+                        count += 1;
+                    }
+                });
+                assert_eq!(count, 4);
+            }
+        }
+
+        unsafe {
+            close_files(&fds[..5]);
+        }
+    }
+
+    #[test]
+    fn test_peek_validated() {
+        // Not used by any other tests
+        const FD: libc::c_int = 950;
+        assert!(!crate::util::is_fd_valid(FD));
+
+        let mut fditer = FdIterBuilder::new()
+            .set_possible(true)
+            .allow_filesystem(false)
+            .iter_from(FD);
+        // A "possible" iterator yields it even though it isn't open
+        assert_eq!(fditer.peek(), Some(FD));
+
+        // Once the iterator is validated, the peeked file descriptor is rechecked
+        let mut fditer = fditer.validated();
+        assert_ne!(fditer.peek(), Some(FD));
+        assert_ne!(fditer.next(), Some(FD));
+    }
+
+    #[test]
+    fn test_known_max_fd() {
+        let mut fditer = FdIterBuilder::new().allow_filesystem(false).iter_from(0);
+        assert_eq!(fditer.known_max_fd(), None);
+
+        let last = fditer.by_ref().last().unwrap();
+        assert!(fditer.known_max_fd().unwrap() >= last);
+    }
+
+    #[test]
+    fn test_fallback_max_fd() {
+        let maxfd = fallback_max_fd();
+        assert!((1023..=65535).contains(&maxfd), "{}", maxfd);
+
+        #[cfg(not(any(target_os = "netbsd", target_os = "freebsd", target_os = "openbsd")))]
+        {
+            let mut fditer = FdIterBuilder::new()
+                .allow_filesystem(false)
+                .set_possible(true)
+                .iter_from(0);
+            fditer.by_ref().for_each(drop);
+            assert_eq!(fditer.known_max_fd(), Some(maxfd));
+        }
+    }
+
+    #[test]
+    fn test_fd_table_size() {
+        let size = fd_table_size();
+        assert!(size > 0);
+        assert_eq!(fallback_max_fd(), size.clamp(1024, 65536) - 1);
+
+        let mut rlim = unsafe { core::mem::zeroed::<libc::rlimit>() };
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) },
+            0
+        );
+        if rlim.rlim_cur != libc::RLIM_INFINITY && rlim.rlim_cur <= libc::c_int::MAX as libc::rlim_t
+        {
+            assert_eq!(size as libc::rlim_t, rlim.rlim_cur);
+        } else {
+            assert_eq!(size, libc::c_int::MAX);
+        }
+    }
+
+    #[test]
+    fn test_possible_fd_iter() {
+        let fds = open_files();
+        unsafe {
+            close_files(&fds[5..]);
+        }
+
+        let mut it = FdIterBuilder::new()
+            .allow_filesystem(false)
+            .iter_possible_from(fds[0]);
+        assert_eq!(it.peek(), Some(fds[0]));
+        assert_eq!(it.next(), Some(fds[0]));
+        assert!(it.known_max_fd().is_some());
+
+        let mut it = it.validated();
+        assert!(!it.is_possible_iter());
+        for &fd in fds[1..5].iter() {
+            assert!(it.any(|fd2| fd2 == fd));
+        }
+
+        let mut it = FdIterBuilder::new()
+            .allow_filesystem(false)
+            .iter_from(fds[0])
+            .into_possible();
+        assert_eq!(it.next(), Some(fds[0]));
+        assert!(it.by_ref().take(10).any(|fd| fd == fds[5]));
+
+        unsafe {
+            close_files(&fds[..5]);
+        }
+    }
 }
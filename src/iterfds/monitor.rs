@@ -0,0 +1,248 @@
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// An event reported by an [`FdMonitor`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum FdMonitorEvent {
+    /// The number of open file descriptors rose above the threshold set with
+    /// [`FdMonitorBuilder::threshold()`].
+    ///
+    /// This is only reported when the count *crosses* the threshold; it will not be reported again
+    /// until the count drops back to (or below) the threshold and then exceeds it again.
+    ThresholdExceeded {
+        /// The number of open file descriptors.
+        count: usize,
+    },
+    /// The number of open file descriptors increased in each of the last `samples` checks, where
+    /// `samples` is the value set with [`FdMonitorBuilder::growth_samples()`].
+    ///
+    /// After this is reported, it will not be reported again until the count has increased in
+    /// `samples` more consecutive checks.
+    Growing {
+        /// The number of open file descriptors.
+        count: usize,
+    },
+}
+
+/// A "builder" to configure and start an [`FdMonitor`].
+///
+/// # Example
+///
+/// ```
+/// # use close_fds::FdMonitorBuilder;
+/// # use std::time::Duration;
+/// let monitor = FdMonitorBuilder::new()
+///     .interval(Duration::from_secs(30))
+///     .threshold(1000)
+///     .growth_samples(10)
+///     .spawn(|event| eprintln!("possible file descriptor leak: {:?}", event))
+///     .unwrap();
+///
+/// // ...
+///
+/// monitor.stop();
+/// ```
+#[derive(Clone, Debug)]
+pub struct FdMonitorBuilder {
+    interval: Duration,
+    threshold: Option<usize>,
+    growth_samples: Option<usize>,
+}
+
+impl FdMonitorBuilder {
+    /// Create a new builder.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            threshold: None,
+            growth_samples: None,
+        }
+    }
+
+    /// Set how often the open file descriptors will be counted (default is 10 seconds).
+    #[inline]
+    pub fn interval(&mut self, interval: Duration) -> &mut Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Report [`FdMonitorEvent::ThresholdExceeded`] when the number of open file descriptors rises
+    /// above `threshold` (default is to not check a threshold).
+    #[inline]
+    pub fn threshold(&mut self, threshold: usize) -> &mut Self {
+        self.threshold = Some(threshold);
+        self
+    }
+
+    /// Report [`FdMonitorEvent::Growing`] when the number of open file descriptors has increased in
+    /// `samples` consecutive checks (default is to not check for growth).
+    ///
+    /// `samples` is clamped to a minimum of 1.
+    #[inline]
+    pub fn growth_samples(&mut self, samples: usize) -> &mut Self {
+        self.growth_samples = Some(samples.max(1));
+        self
+    }
+
+    /// Spawn a thread that periodically counts the open file descriptors and calls `callback`
+    /// (from that thread) whenever an [`FdMonitorEvent`] occurs.
+    ///
+    /// The first check is performed immediately. The thread runs until the returned
+    /// [`FdMonitor`] is stopped or dropped.
+    pub fn spawn<F>(&self, mut callback: F) -> io::Result<FdMonitor>
+    where
+        F: FnMut(FdMonitorEvent) + Send + 'static,
+    {
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        let config = self.clone();
+
+        let thread = thread::Builder::new()
+            .name("fd-monitor".into())
+            .spawn(move || {
+                let mut state = MonitorState::default();
+
+                loop {
+                    state.update(&config, super::usage::count_open_fds(), &mut callback);
+
+                    match stop_rx.recv_timeout(config.interval) {
+                        Err(mpsc::RecvTimeoutError::Timeout) => (),
+                        _ => break,
+                    }
+                }
+            })?;
+
+        Ok(FdMonitor {
+            stop_tx: Some(stop_tx),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Default for FdMonitorBuilder {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Default)]
+struct MonitorState {
+    last_count: Option<usize>,
+    exceeded: bool,
+    increases: usize,
+}
+
+impl MonitorState {
+    fn update<F: FnMut(FdMonitorEvent)>(
+        &mut self,
+        config: &FdMonitorBuilder,
+        count: usize,
+        callback: &mut F,
+    ) {
+        if let Some(threshold) = config.threshold {
+            let exceeded = count > threshold;
+            if exceeded && !self.exceeded {
+                callback(FdMonitorEvent::ThresholdExceeded { count });
+            }
+            self.exceeded = exceeded;
+        }
+
+        if let Some(samples) = config.growth_samples {
+            match self.last_count {
+                Some(last_count) if count > last_count => self.increases += 1,
+                _ => self.increases = 0,
+            }
+
+            if self.increases >= samples {
+                callback(FdMonitorEvent::Growing { count });
+                self.increases = 0;
+            }
+        }
+
+        self.last_count = Some(count);
+    }
+}
+
+/// A background thread that watches for file descriptor leaks.
+///
+/// This is created with [`FdMonitorBuilder::spawn()`]. The thread is stopped when this is dropped
+/// (or when [`Self::stop()`] is called).
+#[derive(Debug)]
+pub struct FdMonitor {
+    stop_tx: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl FdMonitor {
+    /// Stop the monitor thread and wait for it to exit.
+    ///
+    /// If the callback panicked, the panic is propagated.
+    pub fn stop(mut self) {
+        if let Err(e) = self.shutdown() {
+            std::panic::resume_unwind(e);
+        }
+    }
+
+    fn shutdown(&mut self) -> thread::Result<()> {
+        // Dropping the sender wakes up the thread
+        drop(self.stop_tx.take());
+
+        match self.thread.take() {
+            Some(thread) => thread.join(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for FdMonitor {
+    #[inline]
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn run(config: &FdMonitorBuilder, counts: &[usize]) -> Vec<FdMonitorEvent> {
+        let mut state = MonitorState::default();
+        let mut events = Vec::new();
+        for &count in counts {
+            state.update(config, count, &mut |event| events.push(event));
+        }
+        events
+    }
+
+    #[test]
+    fn test_threshold() {
+        assert_eq!(
+            run(FdMonitorBuilder::new().threshold(5), &[3, 5, 6, 7, 5, 8]),
+            [
+                FdMonitorEvent::ThresholdExceeded { count: 6 },
+                FdMonitorEvent::ThresholdExceeded { count: 8 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_growth() {
+        assert_eq!(
+            run(
+                FdMonitorBuilder::new().growth_samples(2),
+                &[3, 4, 4, 5, 6, 7, 8, 2, 3]
+            ),
+            [
+                FdMonitorEvent::Growing { count: 6 },
+                FdMonitorEvent::Growing { count: 8 },
+            ]
+        );
+
+        assert_eq!(run(&FdMonitorBuilder::new(), &[3, 4, 5, 6]), []);
+    }
+}
@@ -0,0 +1,434 @@
+use std::io;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::{ffi::OsString, os::unix::ffi::OsStrExt, vec::Vec};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use super::{ProcFdInfo, SpecialFdKind};
+
+/// Count the file descriptors that are open in the process with the given PID.
+///
+/// This is intended for monitoring other processes (for example, a supervisor tracking the number
+/// of file descriptors used by each of its children), so it tries to avoid enumerating every file
+/// descriptor:
+///
+/// - On Linux and Android, the entries in `/proc/<pid>/fd` are counted. (On Linux 6.2+, the count
+///   is taken directly from the size of that directory.)
+/// - On Solaris, Illumos, and NetBSD (if `/proc` is mounted), the entries in `/proc/<pid>/fd` are
+///   counted.
+/// - On macOS, `proc_pidinfo(PROC_PIDLISTFDS)` is used.
+/// - On FreeBSD, the `kern.proc.filedesc` sysctl is used.
+///
+/// If `pid` is the current process's PID, the file descriptors are counted the same way as with
+/// [`iter_open_fds_threadsafe()`](./fn.iter_open_fds_threadsafe.html), which works on every
+/// platform. Otherwise, an error with kind `Unsupported` is returned on other platforms.
+///
+/// Counting file descriptors in another process usually requires the same permissions as sending
+/// it a signal (or more). The result is inherently racy; the process may open or close file
+/// descriptors at any time.
+pub fn count_pid_fds(pid: libc::pid_t) -> io::Result<usize> {
+    if pid == unsafe { libc::getpid() } {
+        return Ok(super::usage::count_open_fds());
+    }
+
+    count_other_pid_fds(pid)
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "netbsd",
+))]
+fn count_other_pid_fds(pid: libc::pid_t) -> io::Result<usize> {
+    let path = std::format!("/proc/{}/fd", pid);
+
+    // On Linux 6.2+, the "size" of this directory is the number of open file descriptors. (On
+    // older kernels, it's 0, and an empty directory has a size of 0 anyway.)
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let size = std::fs::metadata(&path)?.len();
+        if size > 0 {
+            return Ok(size as usize);
+        }
+    }
+
+    let mut count = 0;
+    for entry in std::fs::read_dir(&path)? {
+        entry?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(target_os = "macos")]
+fn count_other_pid_fds(pid: libc::pid_t) -> io::Result<usize> {
+    use std::vec::Vec;
+
+    // Passing a NULL buffer returns an estimate of the size of the buffer that will be needed
+    let size =
+        unsafe { libc::proc_pidinfo(pid, libc::PROC_PIDLISTFDS, 0, core::ptr::null_mut(), 0) };
+    if size <= 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Leave some room in case more file descriptors get opened
+    let mut buf: Vec<libc::proc_fdinfo> =
+        Vec::with_capacity(size as usize / libc::PROC_PIDLISTFD_SIZE as usize + 32);
+
+    let size = unsafe {
+        libc::proc_pidinfo(
+            pid,
+            libc::PROC_PIDLISTFDS,
+            0,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            (buf.capacity() * libc::PROC_PIDLISTFD_SIZE as usize) as libc::c_int,
+        )
+    };
+    if size <= 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(size as usize / libc::PROC_PIDLISTFD_SIZE as usize)
+}
+
+#[cfg(target_os = "freebsd")]
+fn count_other_pid_fds(pid: libc::pid_t) -> io::Result<usize> {
+    use std::vec::Vec;
+
+    let mib = [
+        libc::CTL_KERN,
+        libc::KERN_PROC,
+        libc::KERN_PROC_FILEDESC,
+        pid as libc::c_int,
+    ];
+
+    let mut buf = Vec::<u8>::new();
+
+    loop {
+        let mut len = 0;
+        if unsafe {
+            libc::sysctl(
+                mib.as_ptr(),
+                mib.len() as libc::c_uint,
+                core::ptr::null_mut(),
+                &mut len,
+                core::ptr::null(),
+                0,
+            )
+        } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Leave some room in case more file descriptors get opened
+        len += len / 8;
+        buf.resize(len, 0);
+
+        if unsafe {
+            libc::sysctl(
+                mib.as_ptr(),
+                mib.len() as libc::c_uint,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+                core::ptr::null(),
+                0,
+            )
+        } < 0
+        {
+            if crate::util::errno() == libc::ENOMEM {
+                // Still not enough room; try again
+                continue;
+            }
+            return Err(io::Error::last_os_error());
+        }
+
+        buf.truncate(len);
+        break;
+    }
+
+    // The buffer contains a series of variable-length `struct kinfo_file`s. Each one starts with
+    // `kf_structsize`, followed by `kf_type` and `kf_fd`. Entries for the current directory, root
+    // directory, etc. have negative `kf_fd`s.
+    const INT_SIZE: usize = core::mem::size_of::<libc::c_int>();
+    const FD_OFFSET: usize = 2 * INT_SIZE;
+
+    let read_int = |offset: usize| {
+        let mut bytes = [0; INT_SIZE];
+        bytes.copy_from_slice(&buf[offset..offset + INT_SIZE]);
+        libc::c_int::from_ne_bytes(bytes)
+    };
+
+    let mut count = 0;
+    let mut offset = 0;
+    while offset + FD_OFFSET + INT_SIZE <= buf.len() {
+        let structsize = read_int(offset);
+        if structsize <= 0 {
+            break;
+        }
+
+        if read_int(offset + FD_OFFSET) >= 0 {
+            count += 1;
+        }
+        offset += structsize as usize;
+    }
+
+    Ok(count)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "netbsd",
+    target_os = "macos",
+    target_os = "freebsd",
+)))]
+fn count_other_pid_fds(_pid: libc::pid_t) -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "counting another process's file descriptors is not supported on this platform",
+    ))
+}
+
+/// Information about a file descriptor that is open in some process, as returned by
+/// [`list_pid_fds()`] and [`audit_processes()`].
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub struct PidFdEntry {
+    /// The file descriptor number (in the process it belongs to).
+    pub fd: libc::c_int,
+    /// The target of the `/proc/<pid>/fd/<fd>` symbolic link.
+    ///
+    /// For files and directories, this is the path; for other file descriptors, it looks like
+    /// `socket:[1234]` or `anon_inode:[eventfd]`.
+    pub target: OsString,
+    /// The contents of `/proc/<pid>/fdinfo/<fd>`, if they could be read.
+    pub fdinfo: Option<ProcFdInfo>,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl PidFdEntry {
+    /// Whether the close-on-exec flag is set on the file descriptor (`None` if this is unknown
+    /// because [`Self::fdinfo`] could not be read).
+    #[inline]
+    pub fn cloexec(&self) -> Option<bool> {
+        self.fdinfo
+            .map(|info| info.flags & libc::O_CLOEXEC == libc::O_CLOEXEC)
+    }
+
+    /// Whether the file descriptor refers to a socket.
+    #[inline]
+    pub fn is_socket(&self) -> bool {
+        self.target.as_bytes().starts_with(b"socket:")
+    }
+
+    /// Get the kind of "special" file descriptor that this is (see [`SpecialFdKind`]).
+    #[inline]
+    pub fn special_kind(&self) -> Option<SpecialFdKind> {
+        SpecialFdKind::from_link(self.target.as_bytes())
+    }
+}
+
+/// List the file descriptors that are open in the process with the given PID, along with
+/// information on each of them (see [`PidFdEntry`]).
+///
+/// This reads `/proc/<pid>/fd` and `/proc/<pid>/fdinfo`. File descriptors that are closed while
+/// the list is being built are omitted. The entries are sorted by file descriptor number.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn list_pid_fds(pid: libc::pid_t) -> io::Result<Vec<PidFdEntry>> {
+    let mut entries = Vec::new();
+
+    for dirent in std::fs::read_dir(std::format!("/proc/{}/fd", pid))? {
+        let dirent = dirent?;
+
+        let fd = match dirent
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<libc::c_int>().ok())
+        {
+            Some(fd) => fd,
+            None => continue,
+        };
+
+        let target = match std::fs::read_link(dirent.path()) {
+            Ok(target) => target.into_os_string(),
+            // It was closed
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        let fdinfo = std::fs::read(std::format!("/proc/{}/fdinfo/{}", pid, fd))
+            .ok()
+            .and_then(|data| ProcFdInfo::parse(&data));
+
+        entries.push(PidFdEntry { fd, target, fdinfo });
+    }
+
+    entries.sort_unstable_by_key(|entry| entry.fd);
+    Ok(entries)
+}
+
+/// The file descriptors that are open in a single process, as reported by [`audit_processes()`].
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct ProcessFds {
+    /// The process's PID.
+    pub pid: libc::pid_t,
+    /// The process's open file descriptors, or the error that occurred while listing them (most
+    /// commonly, `PermissionDenied`).
+    pub fds: io::Result<Vec<PidFdEntry>>,
+}
+
+/// List the open file descriptors of every process visible in `/proc`.
+///
+/// This is intended for auditing; for example, finding processes that have sockets open without
+/// the close-on-exec flag set:
+///
+/// ```
+/// # #[cfg(any(target_os = "linux", target_os = "android"))]
+/// for process in close_fds::audit_processes().unwrap() {
+///     if let Ok(fds) = process.fds {
+///         for entry in fds {
+///             if entry.is_socket() && entry.cloexec() == Some(false) {
+///                 println!("{}: {} is an inheritable socket", process.pid, entry.fd);
+///             }
+///         }
+///     }
+/// }
+/// ```
+///
+/// Processes that exit while the list is being built are omitted. If the file descriptors of a
+/// particular process can't be listed (usually because the caller doesn't have permission), the
+/// error is reported in [`ProcessFds::fds`]. An error is only returned if `/proc` itself can't be
+/// read.
+///
+/// The results are sorted by PID. Note that this is a snapshot of a constantly changing system;
+/// processes may start, exit, and open or close file descriptors at any time.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn audit_processes() -> io::Result<Vec<ProcessFds>> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("audit_processes").entered();
+    #[cfg(feature = "tracing")]
+    let start = std::time::Instant::now();
+
+    let mut processes = Vec::new();
+
+    for dirent in std::fs::read_dir("/proc")? {
+        let dirent = dirent?;
+
+        let pid = match dirent
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<libc::pid_t>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+
+        match list_pid_fds(pid) {
+            // It exited
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            fds => {
+                if let Err(e) = &fds {
+                    log_debug!("Unable to list file descriptors of process {}: {}", pid, e);
+                }
+                processes.push(ProcessFds { pid, fds })
+            }
+        }
+    }
+
+    processes.sort_unstable_by_key(|process| process.pid);
+
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        processes = processes.len(),
+        fds = processes
+            .iter()
+            .filter_map(|process| process.fds.as_ref().ok())
+            .map(|fds| fds.len())
+            .sum::<usize>(),
+        elapsed_us = start.elapsed().as_micros() as u64,
+        "audited processes"
+    );
+
+    Ok(processes)
+}
+
+/// Find the processes that have the file at the given path open, returning `(pid, fd)` pairs.
+///
+/// This is equivalent to calling [`find_fd_holders_by_inode()`] with the device and inode numbers
+/// of `path` (symbolic links are followed). See that function for details.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn find_fd_holders<P: AsRef<std::path::Path>>(
+    path: P,
+) -> io::Result<Vec<(libc::pid_t, libc::c_int)>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let meta = std::fs::metadata(path)?;
+    find_fd_holders_by_inode(meta.dev(), meta.ino())
+}
+
+/// Find the processes that have the file with the given device and inode numbers open, returning
+/// `(pid, fd)` pairs.
+///
+/// Unlike [`find_fd_holders()`], this can find files that have been deleted but are still open
+/// (which continue to take up disk space). Only file descriptors referring to the file itself are
+/// found; for example, processes whose current directory is inside a mount, or that have files
+/// mapped into memory, are not reported.
+///
+/// Every file descriptor of every process visible in `/proc` is checked. Processes whose file
+/// descriptors can't be examined (usually because the caller doesn't have permission) are
+/// skipped. The results are sorted by PID and then by file descriptor.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn find_fd_holders_by_inode(dev: u64, ino: u64) -> io::Result<Vec<(libc::pid_t, libc::c_int)>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut holders = Vec::new();
+
+    for dirent in std::fs::read_dir("/proc")? {
+        let dirent = dirent?;
+
+        let pid = match dirent
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<libc::pid_t>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+
+        let fd_dir = match std::fs::read_dir(dirent.path().join("fd")) {
+            Ok(fd_dir) => fd_dir,
+            Err(e) => {
+                log_debug!("Unable to list file descriptors of process {}: {}", pid, e);
+                continue;
+            }
+        };
+
+        for fd_dirent in fd_dir.flatten() {
+            let fd = match fd_dirent
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<libc::c_int>().ok())
+            {
+                Some(fd) => fd,
+                None => continue,
+            };
+
+            // stat()ing the "symbolic link" gives us information on the open file itself (even if
+            // it's been deleted)
+            if let Ok(meta) = std::fs::metadata(fd_dirent.path()) {
+                if meta.dev() == dev && meta.ino() == ino {
+                    holders.push((pid, fd));
+                }
+            }
+        }
+    }
+
+    holders.sort_unstable();
+    Ok(holders)
+}
@@ -0,0 +1,306 @@
+use std::boxed::Box;
+use std::string::String;
+use std::vec::Vec;
+
+/// A record of the file descriptors that were open at a particular point in time.
+///
+/// This can be used to find file descriptors that were opened (and not closed) after the
+/// snapshot was taken. See also [`FdGuard`].
+///
+/// Note that a snapshot only records file descriptor *numbers*. If a file descriptor is closed and
+/// another file is opened with the same number, that will not be detected.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct FdSnapshot {
+    fds: Vec<libc::c_int>,
+}
+
+impl FdSnapshot {
+    /// Take a snapshot of the currently open file descriptors.
+    ///
+    /// In a multithreaded program, other threads may be opening and closing file descriptors
+    /// while (and after) the snapshot is taken, which may lead to confusing results.
+    pub fn take() -> Self {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("FdSnapshot::take").entered();
+        #[cfg(feature = "tracing")]
+        let start = std::time::Instant::now();
+
+        let mut fditer = super::iter_open_fds(0);
+        let fds: Vec<libc::c_int> = fditer.by_ref().collect();
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            method = fditer.backend_name(),
+            fds = fds.len(),
+            elapsed_us = start.elapsed().as_micros() as u64,
+            "took file descriptor snapshot"
+        );
+
+        log_debug!(
+            "Found {} open file descriptors (method: {})",
+            fds.len(),
+            fditer.backend_name()
+        );
+
+        Self { fds }
+    }
+
+    /// Get the file descriptors that were open when this snapshot was taken, in ascending order.
+    #[inline]
+    pub fn fds(&self) -> &[libc::c_int] {
+        &self.fds
+    }
+
+    /// Check whether the given file descriptor was open when this snapshot was taken.
+    #[inline]
+    pub fn contains(&self, fd: libc::c_int) -> bool {
+        self.fds.binary_search(&fd).is_ok()
+    }
+
+    /// Get the file descriptors that are currently open, but were not open when this snapshot was
+    /// taken, in ascending order.
+    pub fn new_fds(&self) -> Vec<libc::c_int> {
+        super::iter_open_fds(0)
+            .filter(|&fd| !self.contains(fd))
+            .collect()
+    }
+
+    /// Get the file descriptors that were open when this snapshot was taken, but are not open
+    /// anymore, in ascending order.
+    pub fn closed_fds(&self) -> Vec<libc::c_int> {
+        self.fds
+            .iter()
+            .cloned()
+            .filter(|&fd| !crate::util::is_fd_valid(fd))
+            .collect()
+    }
+}
+
+type LeakCallback = Box<dyn FnMut(&[libc::c_int]) + Send>;
+
+enum LeakAction {
+    Panic,
+    Callback(LeakCallback),
+}
+
+/// A guard that checks for leaked file descriptors when it goes out of scope.
+///
+/// When an `FdGuard` is created, it takes a [`FdSnapshot`] of the open file descriptors. When it
+/// is dropped, it checks whether any new file descriptors have been opened since then (and not
+/// closed). By default, it panics if any were found; [`Self::with_callback()`] can be used to
+/// report them differently, and [`Self::finish()`] can be used to check for them without
+/// dropping the guard.
+///
+/// The same caveats that apply to [`FdSnapshot`] apply here; in particular, in a multithreaded
+/// program, file descriptors opened by other threads will be reported as leaks.
+///
+/// # Example
+///
+/// ```
+/// # use close_fds::FdGuard;
+/// let guard = FdGuard::new();
+///
+/// let f = std::fs::File::open("/").unwrap();
+/// drop(f);
+///
+/// // Panics if `f` wasn't closed
+/// drop(guard);
+/// ```
+pub struct FdGuard {
+    snapshot: FdSnapshot,
+    action: Option<LeakAction>,
+}
+
+impl FdGuard {
+    /// Create a new guard that panics if any file descriptors were leaked when it is dropped.
+    ///
+    /// (If the thread is already panicking when the guard is dropped, it will not panic again.)
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            snapshot: FdSnapshot::take(),
+            action: Some(LeakAction::Panic),
+        }
+    }
+
+    /// Create a new guard that calls `callback` with a list of the leaked file descriptors (if
+    /// there are any) when it is dropped.
+    #[inline]
+    pub fn with_callback<F: FnMut(&[libc::c_int]) + Send + 'static>(callback: F) -> Self {
+        Self {
+            snapshot: FdSnapshot::take(),
+            action: Some(LeakAction::Callback(Box::new(callback))),
+        }
+    }
+
+    /// Get the snapshot that was taken when this guard was created.
+    #[inline]
+    pub fn snapshot(&self) -> &FdSnapshot {
+        &self.snapshot
+    }
+
+    /// Get the file descriptors that have been opened since this guard was created (and not
+    /// closed), in ascending order.
+    #[inline]
+    pub fn leaked_fds(&self) -> Vec<libc::c_int> {
+        self.snapshot.new_fds()
+    }
+
+    /// Consume this guard without panicking or calling the callback, and return the file
+    /// descriptors that have been opened since it was created (and not closed), in ascending
+    /// order.
+    #[inline]
+    pub fn finish(mut self) -> Vec<libc::c_int> {
+        self.action = None;
+        self.leaked_fds()
+    }
+}
+
+impl Default for FdGuard {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for FdGuard {
+    fn drop(&mut self) {
+        let action = match self.action.as_mut() {
+            Some(action) => action,
+            None => return,
+        };
+
+        let leaked = self.snapshot.new_fds();
+        if leaked.is_empty() {
+            return;
+        }
+
+        match action {
+            LeakAction::Panic => {
+                if !std::thread::panicking() {
+                    panic!("leaked file descriptors:\n{}", describe_fds(&leaked));
+                }
+            }
+            LeakAction::Callback(callback) => callback(&leaked),
+        }
+    }
+}
+
+impl core::fmt::Debug for FdGuard {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("FdGuard")
+            .field("snapshot", &self.snapshot)
+            .finish()
+    }
+}
+
+/// Assert that no file descriptors have been opened since `snapshot` was taken (and not closed).
+///
+/// If any have, this panics with a message listing each of them, along with what kind of file it
+/// refers to and (where possible) its path. This is intended for use in tests.
+///
+/// The same caveats that apply to [`FdSnapshot`] apply here; in particular, in a multithreaded
+/// program (such as a test suite run with multiple threads), file descriptors opened by other
+/// threads will be reported as leaks.
+///
+/// # Example
+///
+/// ```
+/// # use close_fds::{assert_no_new_fds, FdSnapshot};
+/// let snapshot = FdSnapshot::take();
+///
+/// let f = std::fs::File::open("/").unwrap();
+/// drop(f);
+///
+/// assert_no_new_fds(&snapshot);
+/// ```
+#[track_caller]
+pub fn assert_no_new_fds(snapshot: &FdSnapshot) {
+    let leaked = snapshot.new_fds();
+    if !leaked.is_empty() {
+        panic!("leaked file descriptors:\n{}", describe_fds(&leaked));
+    }
+}
+
+/// Assert that all of the given file descriptors are closed.
+///
+/// If any of them are open, this panics with a message listing each of them, along with what kind
+/// of file it refers to and (where possible) its path. This is intended for use in tests.
+///
+/// Note that if a file descriptor is closed and another file is opened with the same number, it
+/// will be reported as open.
+#[track_caller]
+pub fn expect_fds_closed(fds: &[libc::c_int]) {
+    let mut open: Vec<libc::c_int> = fds
+        .iter()
+        .cloned()
+        .filter(|&fd| crate::util::is_fd_valid(fd))
+        .collect();
+
+    if !open.is_empty() {
+        open.sort_unstable();
+        open.dedup();
+        panic!(
+            "file descriptors expected to be closed are open:\n{}",
+            describe_fds(&open)
+        );
+    }
+}
+
+/// Build a description of each of the given file descriptors (which must be sorted in ascending
+/// order), one per line.
+fn describe_fds(fds: &[libc::c_int]) -> String {
+    use core::fmt::Write;
+
+    let mut res = String::new();
+    let mut remaining = fds;
+
+    let mut it = super::iter_open_fds_info(fds.first().cloned().unwrap_or(0));
+    while let Some(info) = it.next() {
+        // Skip the file descriptors that were closed before we got to them
+        while let Some(&fd) = remaining.first() {
+            if fd >= info.fd {
+                break;
+            }
+            let _ = writeln!(res, "  {} (closed)", fd);
+            remaining = &remaining[1..];
+        }
+
+        match remaining.first() {
+            Some(&fd) if fd == info.fd => remaining = &remaining[1..],
+            Some(_) => continue,
+            None => break,
+        }
+
+        let _ = write!(
+            res,
+            "  {} ({}{})",
+            info.fd,
+            super::dump::kind_name(&it, &info),
+            if info.cloexec { ", cloexec" } else { "" },
+        );
+
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "solaris",
+            target_os = "illumos",
+        ))]
+        {
+            let mut buf = [0; libc::PATH_MAX as usize];
+            if let Some(path) = it.read_path(info.fd, &mut buf) {
+                let _ = write!(res, ": {}", String::from_utf8_lossy(path));
+            }
+        }
+
+        res.push('\n');
+    }
+
+    for fd in remaining {
+        let _ = writeln!(res, "  {} (closed)", fd);
+    }
+
+    // Remove the trailing newline
+    res.pop();
+    res
+}
@@ -0,0 +1,290 @@
+/// The current process's file descriptor usage, as returned by [`fd_usage()`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub struct FdUsage {
+    /// The number of open file descriptors.
+    pub open: usize,
+    /// The soft `RLIMIT_NOFILE` limit, or `None` if it's unlimited (or couldn't be determined).
+    pub soft_limit: Option<u64>,
+    /// The hard `RLIMIT_NOFILE` limit, or `None` if it's unlimited (or couldn't be determined).
+    pub hard_limit: Option<u64>,
+}
+
+impl FdUsage {
+    /// Check whether the number of open file descriptors is at least `threshold_pct` percent of
+    /// the soft limit.
+    ///
+    /// This always returns `false` if the soft limit is unlimited (or unknown).
+    pub fn is_above(&self, threshold_pct: u8) -> bool {
+        match self.soft_limit {
+            Some(limit) => (self.open as u128) * 100 >= (limit as u128) * (threshold_pct as u128),
+            None => false,
+        }
+    }
+}
+
+/// Get the current process's file descriptor usage: the number of open file descriptors, and the
+/// `RLIMIT_NOFILE` limits.
+///
+/// This is intended for servers that want to shed load (or log a warning) before opening a file
+/// descriptor fails with `EMFILE`. The open file descriptors are counted as cheaply as possible
+/// (for example, using the `kern.proc.nfds` sysctl on FreeBSD or `getdtablecount()` on OpenBSD);
+/// otherwise, they're counted the same way as with
+/// [`iter_open_fds_threadsafe()`](./fn.iter_open_fds_threadsafe.html), so this is safe to call
+/// from multithreaded programs.
+///
+/// The result is inherently racy; other threads may open or close file descriptors at any time.
+pub fn fd_usage() -> FdUsage {
+    let mut rlim = core::mem::MaybeUninit::<libc::rlimit>::uninit();
+
+    let (soft_limit, hard_limit) =
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, rlim.as_mut_ptr()) } == 0 {
+            let rlim = unsafe { rlim.assume_init() };
+            (rlim_to_opt(rlim.rlim_cur), rlim_to_opt(rlim.rlim_max))
+        } else {
+            (None, None)
+        };
+
+    FdUsage {
+        open: count_open_fds(),
+        soft_limit,
+        hard_limit,
+    }
+}
+
+/// Check whether the current process has at least `threshold_pct` percent of its soft
+/// `RLIMIT_NOFILE` limit's worth of file descriptors open.
+///
+/// This is equivalent to `fd_usage().is_above(threshold_pct)`. See [`fd_usage()`] and
+/// [`FdUsage::is_above()`] for more information.
+#[inline]
+pub fn is_fd_pressure(threshold_pct: u8) -> bool {
+    fd_usage().is_above(threshold_pct)
+}
+
+/// Convert an `rlim_t` to the representation used by this module (`None` means unlimited).
+#[inline]
+fn rlim_to_opt(lim: libc::rlim_t) -> Option<u64> {
+    if lim == libc::RLIM_INFINITY {
+        None
+    } else {
+        #[allow(clippy::unnecessary_cast)]
+        Some(lim as u64)
+    }
+}
+
+/// The inverse of `rlim_to_opt()` (values that don't fit are treated as unlimited).
+#[allow(clippy::unnecessary_cast)]
+#[inline]
+fn opt_to_rlim(lim: Option<u64>) -> libc::rlim_t {
+    match lim {
+        Some(lim) if lim < libc::RLIM_INFINITY as u64 => lim as libc::rlim_t,
+        _ => libc::RLIM_INFINITY,
+    }
+}
+
+/// Raise the soft `RLIMIT_NOFILE` limit to the hard limit (or to `cap`, if that's lower).
+///
+/// Many systems set a low default soft limit (often 1024) for compatibility with programs that use
+/// `select()`, but a much higher hard limit; servers that need many file descriptors usually want
+/// to raise the soft limit at startup. The soft limit is never lowered: if it's already at least
+/// the target, nothing is changed. (Passing a `cap` of `None` means "no cap".)
+///
+/// On macOS, iOS, tvOS, and watchOS, the soft limit can't be raised above the
+/// `kern.maxfilesperproc` sysctl (even if the hard limit is higher or unlimited), so the target is
+/// also capped to that.
+///
+/// On success, the old and new soft limits are returned (`None` means unlimited).
+///
+/// Note that raising the limit above 1024 can break code that uses `select()`, which can't handle
+/// file descriptors numbered 1024 or higher on most systems.
+pub fn raise_fd_limit(cap: Option<u64>) -> Result<(Option<u64>, Option<u64>), FdLimitError> {
+    let mut rlim = core::mem::MaybeUninit::<libc::rlimit>::uninit();
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, rlim.as_mut_ptr()) } != 0 {
+        return Err(FdLimitError::Get(crate::util::errno()));
+    }
+    let mut rlim = unsafe { rlim.assume_init() };
+
+    let old = rlim_to_opt(rlim.rlim_cur);
+
+    let target = match (rlim_to_opt(rlim.rlim_max), cap) {
+        (Some(hard), Some(cap)) => Some(hard.min(cap)),
+        (hard, None) => hard,
+        (None, cap) => cap,
+    };
+
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "tvos",
+        target_os = "watchos"
+    ))]
+    let target = match max_files_per_proc() {
+        Some(maxfiles) => Some(target.map_or(maxfiles, |target| target.min(maxfiles))),
+        None => target,
+    };
+
+    // Never lower the limit
+    match (old, target) {
+        (None, _) => return Ok((old, old)),
+        (Some(old), Some(new)) if new <= old => return Ok((Some(old), Some(old))),
+        _ => (),
+    }
+
+    rlim.rlim_cur = opt_to_rlim(target);
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlim) } != 0 {
+        return Err(FdLimitError::Set(crate::util::errno()));
+    }
+
+    Ok((old, target))
+}
+
+/// Lower the soft `RLIMIT_NOFILE` limit to `limit` (if it's higher), and the hard limit too if
+/// `lower_hard` is true. (See `CloseFdsBuilder::closefrom_and_limit()`.)
+///
+/// This is async-signal-safe.
+pub(crate) fn lower_fd_limit(limit: u64, lower_hard: bool) -> Result<(), FdLimitError> {
+    let mut rlim = core::mem::MaybeUninit::<libc::rlimit>::uninit();
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, rlim.as_mut_ptr()) } != 0 {
+        return Err(FdLimitError::Get(crate::util::errno()));
+    }
+    let mut rlim = unsafe { rlim.assume_init() };
+
+    let limit = opt_to_rlim(Some(limit));
+    let lower = |cur: &mut libc::rlim_t| {
+        // RLIM_INFINITY is the largest value on every platform we support, but check anyway
+        if *cur == libc::RLIM_INFINITY || *cur > limit {
+            *cur = limit;
+        }
+    };
+
+    lower(&mut rlim.rlim_cur);
+    if lower_hard {
+        lower(&mut rlim.rlim_max);
+    }
+
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlim) } != 0 {
+        return Err(FdLimitError::Set(crate::util::errno()));
+    }
+
+    Ok(())
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "tvos",
+    target_os = "watchos"
+))]
+fn max_files_per_proc() -> Option<u64> {
+    let mut maxfiles: libc::c_int = 0;
+    let mut oldlen = core::mem::size_of::<libc::c_int>();
+
+    if unsafe {
+        libc::sysctlbyname(
+            "kern.maxfilesperproc\0".as_ptr() as *const libc::c_char,
+            &mut maxfiles as *mut libc::c_int as *mut libc::c_void,
+            &mut oldlen,
+            core::ptr::null_mut(),
+            0,
+        )
+    } == 0
+        && maxfiles > 0
+    {
+        Some(maxfiles as u64)
+    } else {
+        None
+    }
+}
+
+/// An error from adjusting the `RLIMIT_NOFILE` limit (see [`raise_fd_limit()`] and
+/// [`CloseFdsBuilder::closefrom_and_limit()`](./struct.CloseFdsBuilder.html#method.closefrom_and_limit)).
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum FdLimitError {
+    /// `getrlimit()` failed with the given error code.
+    Get(libc::c_int),
+    /// `setrlimit()` failed with the given error code.
+    Set(libc::c_int),
+}
+
+impl core::fmt::Display for FdLimitError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            Self::Get(eno) => write!(f, "getrlimit() failed with error code {}", eno),
+            Self::Set(eno) => write!(f, "setrlimit() failed with error code {}", eno),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FdLimitError {}
+
+/// Count the file descriptors that are open in the current process.
+pub(super) fn count_open_fds() -> usize {
+    // On FreeBSD and OpenBSD, we can get the count directly
+    #[cfg(any(target_os = "freebsd", target_os = "openbsd"))]
+    if let Some(nfds) = super::FdIter::get_nfds() {
+        return nfds as usize;
+    }
+
+    // This may be called from multithreaded programs
+    super::FdIterBuilder::new()
+        .threadsafe(true)
+        .iter_from(0)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_above() {
+        let usage = FdUsage {
+            open: 80,
+            soft_limit: Some(100),
+            hard_limit: None,
+        };
+        assert!(usage.is_above(0));
+        assert!(usage.is_above(80));
+        assert!(!usage.is_above(81));
+
+        let usage = FdUsage {
+            open: 1 << 20,
+            soft_limit: None,
+            hard_limit: None,
+        };
+        assert!(!usage.is_above(0));
+        assert!(!usage.is_above(100));
+    }
+
+    #[test]
+    fn test_rlim_conversion() {
+        assert_eq!(rlim_to_opt(libc::RLIM_INFINITY), None);
+        assert_eq!(rlim_to_opt(1024), Some(1024));
+        assert_eq!(opt_to_rlim(None), libc::RLIM_INFINITY);
+        assert_eq!(opt_to_rlim(Some(1024)), 1024);
+        assert_eq!(opt_to_rlim(Some(u64::MAX)), libc::RLIM_INFINITY);
+    }
+
+    #[test]
+    fn test_fd_usage() {
+        let fd = unsafe { libc::open("/\0".as_ptr() as *const libc::c_char, libc::O_RDONLY) };
+        assert!(fd >= 0);
+
+        let usage = fd_usage();
+        assert!(usage.open >= 1);
+        if let (Some(soft), Some(hard)) = (usage.soft_limit, usage.hard_limit) {
+            assert!(soft <= hard);
+        }
+        if let Some(soft) = usage.soft_limit {
+            assert!(soft > 0);
+            assert!(usage.open as u64 <= soft);
+        }
+
+        unsafe {
+            libc::close(fd);
+        }
+    }
+}
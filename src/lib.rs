@@ -43,12 +43,9 @@
 //!
 //! unsafe {
 //!     cmd.pre_exec(move || {
-//!         // On macOS/iOS, just set them as close-on-exec (some sources indicate closing them
-//!         // directly may cause problems)
-//!         #[cfg(any(target_os = "macos", target_os = "ios"))]
-//!         close_fds::set_fds_cloexec(3, &keep_fds);
-//!         #[cfg(not(any(target_os = "macos", target_os = "ios")))]
-//!         close_fds::close_open_fds(3, &keep_fds);
+//!         // Close them (or, on macOS/iOS/tvOS/watchOS, just set them as close-on-exec, since
+//!         // some sources indicate closing them directly may cause problems)
+//!         close_fds::close_or_cloexec_open_fds(3, &keep_fds);
 //!
 //!         Ok(())
 //!     });
@@ -69,6 +66,86 @@
 //! taken. The documentation of each helper function describes how the same task could be performed
 //! using one of the builders.
 //!
+//! # Cargo features
+//!
+//! This crate is `no_std` by default. The following optional features are available:
+//!
+//! - `std`: Enables APIs that require the standard library, such as
+//!   [`CloseFdsBuilder::pre_exec_fn()`], [`dump_open_fds()`], and [`FdGuard`].
+//! - `async-process`: Enables [`CloseFdsBuilder::async_process_command()`], which creates
+//!   `async_process::Command`s (used by `smol`) that close file descriptors in the child. (Implies
+//!   `std`.)
+//! - `tokio`: Enables `TokioCommandExt`, which integrates with `tokio::process::Command`. (Implies
+//!   `std`.)
+//! - `no-fs-backends`: Removes the code that iterates over file descriptors by reading
+//!   `/proc/self/fd` or `/dev/fd`. Only system calls that don't touch the filesystem (such as
+//!   `close_range()`, `closefrom()`, and `fcntl()`) will be used, as if
+//!   [`FdIterBuilder::allow_filesystem(false)`](FdIterBuilder::allow_filesystem) had been
+//!   specified everywhere. This may decrease performance on some platforms.
+//! - `deterministic-syscalls`: Restricts the crate to a fixed set of system calls, for programs
+//!   that run under a seccomp filter (or a similar allowlist). See [Deterministic system
+//!   calls](#deterministic-system-calls) below. (Implies `no-fs-backends`.)
+//! - `libc-wrappers-only`: Never calls `syscall()` directly; only the libc wrapper functions are
+//!   used. This is intended for environments where raw system calls confuse tooling (such as
+//!   Valgrind or sanitizers) or aren't allowed by the platform. Fast paths that require raw system
+//!   calls are skipped: `close_range()` is never used on Linux, Android, or FreeBSD, and file
+//!   descriptors are not found by reading `/proc/self/fd` or `/dev/fd` on Linux, FreeBSD, or
+//!   macOS (where reading the directory requires raw `getdents64()`/`getdirentries()` calls).
+//!   As with `deterministic-syscalls`, this can be slower, and on Linux and Android file
+//!   descriptors above the file descriptor limit may be missed.
+//! - `ios-raw-syscalls`: On iOS, tvOS, and watchOS, find open file descriptors by reading
+//!   `/dev/fd` (as on macOS). Reading the directory requires calling `syscall()`, which is a
+//!   private API on these platforms and may get an app rejected from the App Store, so by default
+//!   only public APIs are used on them (open file descriptors are found by checking each possible
+//!   file descriptor with `fcntl()`). This has no effect on other platforms, or if
+//!   `no-fs-backends` or `libc-wrappers-only` is enabled.
+//! - `log`: Emits [`log`](https://docs.rs/log) records (with the target `close_fds`) describing
+//!   which kernel features were detected by [`probe_features()`], which method was used to
+//!   iterate over file descriptors in APIs like [`FdSnapshot::take()`], and processes that
+//!   couldn't be examined by the auditing APIs. Records are never emitted from functions that may
+//!   be called after `fork()` (such as [`CloseFdsBuilder::closefrom()`] or [`FdIter`]), since
+//!   logging is not async-signal-safe.
+//! - `tracing`: Wraps the parent side of the process spawning helpers (such as
+//!   [`spawn_with_closed_fds()`]) and some of the `std`-only enumeration APIs (such as
+//!   [`FdSnapshot::take()`]) in [`tracing`](https://docs.rs/tracing) spans, and emits events
+//!   describing the strategy that was chosen, how many file descriptors were examined, and how
+//!   long it took. As with `log`, nothing is emitted from code that may run after `fork()`.
+//! - `serde`: Implements `Serialize` and `Deserialize` for [`KeepFdsBuf`], so lists of file
+//!   descriptors to keep can be loaded from configuration files.
+//! - `test-util`: Enables [`MockFdTable`], a simulated file descriptor table that builders can be
+//!   run against, for unit-testing keep-list logic without forking or closing real file
+//!   descriptors. With `std` also enabled, it adds fixtures for tests that work with real file
+//!   descriptors: [`open_dummy_fds()`], [`open_fd_at()`], and [`run_in_child()`]. (Intended for
+//!   `[dev-dependencies]`.)
+//!
+//! # Deterministic system calls
+//!
+//! By default, this crate opportunistically tries newer system calls (such as `close_range()`)
+//! and falls back on older ones if they fail, and it queries the OS (e.g. with `sysctl()`) to
+//! decide which approach to use. Under a seccomp filter, that can result in unexpected `EPERM`
+//! errors or the process being killed with `SIGSYS` if the filter doesn't allow the newer calls.
+//!
+//! With the `deterministic-syscalls` feature enabled, none of that probing takes place. Closing
+//! file descriptors, setting them as close-on-exec, and iterating over them will only ever use:
+//!
+//! - `close()`
+//! - `fcntl()` with `F_GETFD` or `F_SETFD` (plus `F_MAXFD` on NetBSD)
+//! - `getrlimit(RLIMIT_NOFILE)` (which glibc and musl implement with `prlimit64()` on Linux), and
+//!   `sysconf(_SC_OPEN_MAX)` if that fails
+//! - `closefrom()` on FreeBSD, NetBSD, OpenBSD, and DragonFly BSD
+//! - `getdtablecount()` on OpenBSD
+//!
+//! Note that this means file descriptors are always found by checking every possible file
+//! descriptor up to the file descriptor limit, which can be noticeably slower if the limit is high
+//! (when closing file descriptors, this check isn't clamped to 65536 on platforms without
+//! `closefrom()`). On some platforms, it also means that file descriptors above the limit (for
+//! example, if it was lowered after they were opened) may be missed. Other APIs (such
+//! as [`FdInfo`], [`dump_open_fds()`], and the process spawning helpers) are not covered by this
+//! and may make other system calls.
+//!
+//! On Linux and Android, [`required_syscalls()`] returns the list of system calls that may be
+//! made (in either mode), so it can be fed into a seccomp filter.
+//!
 //! # Async-signal-safety
 //!
 //! ## Background
@@ -81,8 +158,9 @@
 //!
 //! ## Async-signal-safety in this crate
 //!
-//! **TL;DR**: The functions in this crate are async-signal-safe on Linux, macOS/iOS, the BSDs, and
-//! Solaris/Illumos. They *should* also be async-signal-safe on other \*nix-like OSes.
+//! **TL;DR**: The functions in this crate are async-signal-safe on Linux, macOS/iOS (and
+//! tvOS/watchOS), the BSDs, and Solaris/Illumos. They *should* also be async-signal-safe on other
+//! \*nix-like OSes.
 //!
 //! Since the functions in this crate are most useful in the child process after a `fork()`, this
 //! crate tries to make all of them async-signal-safe. However, many of the optimizations that this
@@ -93,11 +171,17 @@
 //! to the ones required by POSIX):
 //!
 //! - `closefrom()` on the BSDs
-//! - The `close_range()` syscall on Linux and FreeBSD
+//! - The `close_range()` syscall on Linux, Android, and FreeBSD
+//! - `__system_property_get()` on Android (used to check the API level before trying
+//!   `close_range()`)
 //! - `sysctl()` on FreeBSD
 //! - `getdtablecount()` on OpenBSD
-//! - `getdirentries()`/`getdents()` (whichever is available) on Linux, NetBSD, FreeBSD, macOS/iOS,
-//!   and Solaris/Illumos
+//! - `getdirentries()`/`getdents()` (whichever is available) on Linux, NetBSD, FreeBSD,
+//!   macOS/iOS/tvOS/watchOS, and Solaris/Illumos
+//! - The `openat2()` syscall and `fstatfs()` on Linux (used to make sure that `/proc/self/fd` is
+//!   really part of a procfs)
+//! - `proc_pidinfo()` on macOS (used to find the maximum open file descriptor if `/dev/fd` can't
+//!   be opened)
 //! - `sysconf(_SC_OPEN_MAX)` on all OSes
 //!
 //! All of these except for `sysconf()` are implemented as system calls (or thin wrappers around
@@ -111,13 +195,26 @@
 
 #![no_std]
 
+#[cfg(feature = "std")]
+extern crate std;
+
+#[macro_use]
+mod macros;
+
 mod closefds;
+mod counter;
+mod ffi;
 mod iterfds;
-mod sys;
+pub mod sys;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod syscalls;
 mod util;
 
 pub use closefds::*;
+pub use counter::{SyscallCounter, SyscallCounts};
 pub use iterfds::*;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use syscalls::{required_syscalls, Syscall};
 
 /// Probe for the presence of kernel features that allow performance boosts.
 ///
@@ -127,8 +224,9 @@ pub use iterfds::*;
 /// allow the child processes to slightly optimize the syscalls they make.
 ///
 /// Calling this function more than once will generally not re-probe (except perhaps when called
-/// from multiple threads). In addition, it is currently a no-op on all platforms except FreeBSD
-/// and Linux (though that may change).
+/// from multiple threads). In addition, it is currently a no-op on all platforms except FreeBSD,
+/// Linux, and Android (though that may change), and it does nothing if the
+/// `deterministic-syscalls` feature is enabled.
 ///
 /// Note that no benchmarks have been conducted, and the performance boost may turn out to be
 /// negligible.
@@ -137,9 +235,16 @@ pub fn probe_features() {
     probe_features_imp();
 }
 
-#[cfg_attr(not(any(target_os = "linux", target_os = "freebsd")), inline)]
+#[cfg_attr(
+    not(any(target_os = "linux", target_os = "android", target_os = "freebsd")),
+    inline
+)]
 fn probe_features_imp() {
-    if cfg!(any(target_os = "linux", target_os = "freebsd")) {
+    if cfg!(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd"
+    )) {
         // To avoid unnecessary syscalls, do nothing if the function is called multiple times
         use core::sync::atomic::{AtomicBool, Ordering};
         static PROBED: AtomicBool = AtomicBool::new(false);
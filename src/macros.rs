@@ -0,0 +1,38 @@
+// Logging macros that compile to nothing if the `log` feature is disabled. These must ONLY be
+// used in code that is never called after fork() or from a signal handler (loggers allocate
+// memory, take locks, and write to file descriptors that may have been closed).
+
+// Not every platform has something to log
+#![allow(unused_macros)]
+
+#[cfg(feature = "log")]
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        ::log::debug!(target: "close_fds", $($arg)*)
+    };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+
+#[cfg(feature = "log")]
+macro_rules! log_warn {
+    ($($arg:tt)*) => {
+        ::log::warn!(target: "close_fds", $($arg)*)
+    };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! log_warn {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
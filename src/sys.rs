@@ -1,64 +1,112 @@
-#[cfg(target_os = "linux")]
-pub const CLOSE_RANGE_CLOEXEC: libc::c_uint = 1 << 2;
+//! Thin wrappers around the low-level platform primitives that this crate uses internally.
+//!
+//! These are intended for advanced users who need direct access to a primitive (for example, to
+//! build their own [`FdCloser`](crate::FdCloser)) without having to declare the functions and
+//! constants themselves. Most programs should use the higher-level APIs instead.
+//!
+//! Each item is only available on the platforms where the underlying primitive exists. Functions
+//! that can fail return the `errno` value as the error. Like the rest of this crate, they don't
+//! allocate memory and are async-signal-safe.
 
-#[cfg(target_os = "freebsd")]
-pub const SYS_CLOSE_RANGE: libc::c_int = 575;
-#[cfg(target_os = "freebsd")]
-pub const KERN_PROC_NFDS: libc::c_int = 43;
+/// The flag that makes `close_range()` set the close-on-exec flag on the file descriptors instead
+/// of closing them (Linux 5.11+, FreeBSD 13.1+).
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub const CLOSE_RANGE_CLOEXEC: libc::c_uint = 1 << 2;
 
-#[cfg(any(target_os = "macos", target_os = "ios"))]
-pub const SYS_GETDIRENTRIES64: libc::c_int = 344;
+/// The flag that makes `close_range()` unshare the file descriptor table before closing the file
+/// descriptors (Linux 5.9+), as with `unshare(CLONE_FILES)`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub const CLOSE_RANGE_UNSHARE: libc::c_uint = 1 << 1;
 
+/// The number of the `close_range()` system call on FreeBSD (12.2+).
 #[cfg(target_os = "freebsd")]
-#[repr(C)]
-pub struct dirent {
-    pub d_fileno: libc::ino_t,
-    pub d_off: libc::off_t,
-    pub d_reclen: u16,
-    pub d_type: u8,
-    d_pad0: u8,
-    pub d_namlen: u16,
-    d_pad1: u16,
-    pub d_name: [libc::c_char; 256],
-}
+pub const SYS_CLOSE_RANGE: libc::c_int = 575;
 
+/// The `kern.proc.nfds` sysctl (the last component of the MIB), which reports the number of open
+/// file descriptors in the current process on FreeBSD. See [`proc_nfds()`].
 #[cfg(target_os = "freebsd")]
-extern "C" {
-    pub fn closefrom(lowfd: libc::c_int);
+pub const KERN_PROC_NFDS: libc::c_int = 43;
 
-    pub fn getdirentries(
-        fd: libc::c_int,
-        buf: *mut libc::c_char,
-        nbytes: libc::size_t,
-        basep: *mut libc::off_t,
-    ) -> libc::ssize_t;
+/// Close all open file descriptors starting at `minfd` with the system's `closefrom()`.
+///
+/// On FreeBSD, this never fails. On OpenBSD, NetBSD, and DragonFly BSD, `EBADF` is returned if
+/// no file descriptors at or above `minfd` were open (or if `minfd` is negative).
+///
+/// # Safety
+///
+/// See [`CloseFdsBuilder::closefrom()`](crate::CloseFdsBuilder::closefrom).
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+))]
+#[inline]
+pub unsafe fn closefrom(minfd: libc::c_int) -> Result<(), libc::c_int> {
+    cfg_if::cfg_if! {
+        if #[cfg(target_os = "freebsd")] {
+            crate::ffi::closefrom(minfd);
+            Ok(())
+        } else {
+            if crate::ffi::closefrom(minfd) == 0 {
+                Ok(())
+            } else {
+                Err(crate::util::errno())
+            }
+        }
+    }
 }
 
+/// Get the number of open file descriptors in the current process with OpenBSD's
+/// `getdtablecount()`.
 #[cfg(target_os = "openbsd")]
-extern "C" {
-    pub fn getdtablecount() -> libc::c_int;
+#[inline]
+pub fn getdtablecount() -> libc::c_int {
+    unsafe { crate::ffi::getdtablecount() }
 }
 
-#[cfg(any(target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))]
-extern "C" {
-    pub fn closefrom(fd: libc::c_int) -> libc::c_int;
+/// Get the number of open file descriptors in the current process from the `kern.proc.nfds`
+/// sysctl on FreeBSD.
+#[cfg(target_os = "freebsd")]
+pub fn proc_nfds() -> Result<libc::c_int, libc::c_int> {
+    let mib = [libc::CTL_KERN, libc::KERN_PROC, KERN_PROC_NFDS, 0];
+    let mut nfds: libc::c_int = 0;
+    let mut oldlen = core::mem::size_of::<libc::c_int>();
+
+    if unsafe {
+        libc::sysctl(
+            mib.as_ptr(),
+            mib.len() as libc::c_uint,
+            &mut nfds as *mut libc::c_int as *mut libc::c_void,
+            &mut oldlen,
+            core::ptr::null(),
+            0,
+        )
+    } == 0
+    {
+        Ok(nfds)
+    } else {
+        Err(crate::util::errno())
+    }
 }
 
+/// Get the highest open file descriptor in the current process with `fcntl(F_MAXFD)` on NetBSD.
+///
+/// Returns `Ok(-1)` if no file descriptors are open.
 #[cfg(target_os = "netbsd")]
-extern "C" {
-    #[link_name = "__getdents30"]
-    pub fn getdents(
-        fildes: libc::c_int,
-        buf: *mut libc::c_char,
-        nbyte: libc::size_t,
-    ) -> libc::c_int;
-}
+pub fn fcntl_maxfd() -> Result<libc::c_int, libc::c_int> {
+    unsafe {
+        *libc::__errno() = 0;
+        let maxfd = libc::fcntl(0, libc::F_MAXFD);
 
-#[cfg(any(target_os = "solaris", target_os = "illumos"))]
-extern "C" {
-    pub fn getdents(
-        fildes: libc::c_int,
-        buf: *mut libc::dirent,
-        nbyte: libc::size_t,
-    ) -> libc::c_int;
+        if maxfd >= 0 {
+            Ok(maxfd)
+        } else {
+            // fcntl(F_MAXFD) returns -1 without setting errno if no file descriptors are open
+            match *libc::__errno() {
+                0 => Ok(-1),
+                eno => Err(eno),
+            }
+        }
+    }
 }
@@ -0,0 +1,178 @@
+/// A system call that may be made by this crate (see [`required_syscalls()`]).
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Syscall {
+    name: &'static str,
+    number: libc::c_long,
+}
+
+impl Syscall {
+    /// Get the name of this system call (e.g. `"close_range"`).
+    ///
+    /// This is the name used in `<sys/syscall.h>` (without the `SYS_`/`__NR_` prefix), which is
+    /// also what tools like libseccomp accept.
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Get the number of this system call on the architecture that this crate was compiled for.
+    #[inline]
+    pub fn number(&self) -> libc::c_long {
+        self.number
+    }
+}
+
+macro_rules! syscall {
+    ($name:ident, $num:ident) => {
+        Syscall {
+            name: stringify!($name),
+            number: libc::$num,
+        }
+    };
+}
+
+/// Made regardless of configuration
+static BASE_SYSCALLS: &[Syscall] = &[
+    syscall!(close, SYS_close),
+    syscall!(fcntl, SYS_fcntl),
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "arm",
+        target_arch = "mips",
+        target_arch = "powerpc",
+        target_arch = "sparc",
+        target_arch = "m68k",
+        target_arch = "hexagon",
+    ))]
+    syscall!(fcntl64, SYS_fcntl64),
+    // getrlimit() is implemented with this by glibc and musl
+    syscall!(prlimit64, SYS_prlimit64),
+    #[cfg(all(
+        target_os = "android",
+        any(
+            target_arch = "aarch64",
+            target_arch = "x86_64",
+            target_arch = "riscv64"
+        )
+    ))]
+    syscall!(getrlimit, SYS_getrlimit),
+    #[cfg(all(target_os = "android", any(target_arch = "arm", target_arch = "x86")))]
+    syscall!(ugetrlimit, SYS_ugetrlimit),
+];
+
+/// Only made if the `deterministic-syscalls` and `libc-wrappers-only` features are disabled
+#[cfg(not(any(feature = "deterministic-syscalls", feature = "libc-wrappers-only")))]
+static PROBED_SYSCALLS: &[Syscall] = &[syscall!(close_range, SYS_close_range)];
+#[cfg(any(feature = "deterministic-syscalls", feature = "libc-wrappers-only"))]
+static PROBED_SYSCALLS: &[Syscall] = &[];
+
+/// Only made if the `no-fs-backends` and `libc-wrappers-only` features are disabled (used to read
+/// `/proc/self/fd`)
+#[cfg(all(
+    target_os = "linux",
+    not(any(feature = "no-fs-backends", feature = "libc-wrappers-only"))
+))]
+static FS_SYSCALLS: &[Syscall] = &[
+    syscall!(openat, SYS_openat),
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "s390x",
+        target_arch = "sparc",
+        target_arch = "sparc64",
+        target_arch = "m68k",
+        target_arch = "hexagon",
+    ))]
+    syscall!(open, SYS_open),
+    syscall!(getdents64, SYS_getdents64),
+    // Used to open /proc/self/fd safely (if available)
+    syscall!(openat2, SYS_openat2),
+    syscall!(fstatfs, SYS_fstatfs),
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "arm",
+        target_arch = "mips",
+        target_arch = "powerpc",
+        target_arch = "sparc",
+        target_arch = "m68k",
+        target_arch = "hexagon",
+    ))]
+    syscall!(fstatfs64, SYS_fstatfs64),
+];
+#[cfg(not(all(
+    target_os = "linux",
+    not(any(feature = "no-fs-backends", feature = "libc-wrappers-only"))
+)))]
+static FS_SYSCALLS: &[Syscall] = &[];
+
+/// Get the system calls that the functions in this crate which close file descriptors, set them as
+/// close-on-exec, or iterate over them may make.
+///
+/// This is intended for applications that generate seccomp filters programmatically: if all of
+/// the returned system calls are allowed, those functions will never be denied a system call. The
+/// list depends on the features this crate was compiled with; for example, if the
+/// `deterministic-syscalls` or `libc-wrappers-only` feature is enabled, `close_range()` is not
+/// included. (See the crate-level documentation.)
+///
+/// Notes:
+///
+/// - The list may include system calls that are never actually made in practice. For example,
+///   `open()` and `openat()` are both included (where available), since which one `libc::open()`
+///   uses depends on the libc version.
+/// - For functions in the system libc (such as `getrlimit()`), only the system call(s) used by
+///   current versions of glibc, musl, and bionic are listed.
+/// - Other APIs (such as [`FdInfo`](crate::FdInfo) and the process spawning helpers) are not
+///   covered by this and may make other system calls.
+///
+/// This is only available on Linux and Android.
+pub fn required_syscalls() -> impl Iterator<Item = Syscall> + Clone {
+    BASE_SYSCALLS
+        .iter()
+        .chain(PROBED_SYSCALLS)
+        .chain(FS_SYSCALLS)
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required_syscalls() {
+        let syscalls = required_syscalls();
+
+        let close = syscalls.clone().find(|s| s.name() == "close").unwrap();
+        assert_eq!(close.number(), libc::SYS_close);
+
+        assert_eq!(
+            syscalls.clone().any(|s| s.name() == "close_range"),
+            !cfg!(any(
+                feature = "deterministic-syscalls",
+                feature = "libc-wrappers-only"
+            ))
+        );
+        assert_eq!(
+            syscalls.clone().any(|s| s.name() == "getdents64"),
+            cfg!(all(
+                target_os = "linux",
+                not(any(
+                    feature = "no-fs-backends",
+                    feature = "libc-wrappers-only"
+                ))
+            ))
+        );
+
+        // No duplicates
+        for (i, a) in syscalls.clone().enumerate() {
+            for b in syscalls.clone().skip(i + 1) {
+                assert_ne!(a.name(), b.name());
+                assert_ne!(a.number(), b.number());
+            }
+        }
+    }
+}
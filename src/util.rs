@@ -76,49 +76,192 @@ pub fn check_should_keep(keep_fds: &mut &[libc::c_int], fd: libc::c_int, fds_sor
     }
 }
 
-#[cfg(target_os = "linux")]
-#[inline]
-pub fn is_wsl_1() -> bool {
+/// Returns whether the current process has more than one thread, or `None` if that can't be
+/// determined (or if the `deterministic-syscalls` feature is enabled).
+#[cfg(target_os = "freebsd")]
+pub fn is_multithreaded() -> Option<bool> {
+    if cfg!(feature = "deterministic-syscalls") {
+        return None;
+    }
+
+    let mib = [
+        libc::CTL_KERN,
+        libc::KERN_PROC,
+        libc::KERN_PROC_PID,
+        unsafe { libc::getpid() },
+    ];
+    let mut kinfo = core::mem::MaybeUninit::<libc::kinfo_proc>::uninit();
+    let mut oldlen = core::mem::size_of::<libc::kinfo_proc>();
+
+    if unsafe {
+        libc::sysctl(
+            mib.as_ptr(),
+            mib.len() as libc::c_uint,
+            kinfo.as_mut_ptr() as *mut libc::c_void,
+            &mut oldlen,
+            core::ptr::null(),
+            0,
+        )
+    } == 0
+        && oldlen == core::mem::size_of::<libc::kinfo_proc>()
+    {
+        Some(unsafe { kinfo.assume_init() }.ki_numthreads > 1)
+    } else {
+        None
+    }
+}
+
+/// Returns whether the current process has more than one thread, or `None` if that can't be
+/// determined (or if the `deterministic-syscalls` feature is enabled).
+#[cfg(target_os = "openbsd")]
+pub fn is_multithreaded() -> Option<bool> {
+    // With KERN_PROC_SHOW_THREADS, we get one entry for the process itself and one for each
+    // thread. So a single-threaded process has 2 entries, and we only need room for 3 to tell if
+    // there are more threads.
+    const MAX_ENTRIES: usize = 3;
+
+    if cfg!(feature = "deterministic-syscalls") {
+        return None;
+    }
+
+    let mib = [
+        libc::CTL_KERN,
+        libc::KERN_PROC,
+        libc::KERN_PROC_PID | libc::KERN_PROC_SHOW_THREADS,
+        unsafe { libc::getpid() },
+        core::mem::size_of::<libc::kinfo_proc>() as libc::c_int,
+        MAX_ENTRIES as libc::c_int,
+    ];
+    let mut kinfo = core::mem::MaybeUninit::<[libc::kinfo_proc; MAX_ENTRIES]>::uninit();
+    let mut oldlen = core::mem::size_of::<[libc::kinfo_proc; MAX_ENTRIES]>();
+
+    if unsafe {
+        libc::sysctl(
+            mib.as_ptr(),
+            mib.len() as libc::c_uint,
+            kinfo.as_mut_ptr() as *mut libc::c_void,
+            &mut oldlen,
+            core::ptr::null_mut(),
+            0,
+        )
+    } == 0
+    {
+        Some(oldlen / core::mem::size_of::<libc::kinfo_proc>() > 2)
+    } else if errno() == libc::ENOMEM {
+        // More entries than we had room for
+        Some(true)
+    } else {
+        None
+    }
+}
+
+/// Returns whether it's safe to try making the `close_range()` syscall on Android.
+#[cfg(target_os = "android")]
+pub fn android_close_range_allowed() -> bool {
     use core::sync::atomic::{AtomicU8, Ordering};
 
-    // 0=Not running on WSL 1
-    // 1=Running on WSL 1
-    // >1=Uninitialized
-    static IS_WSL1: AtomicU8 = AtomicU8::new(2);
+    // Android apps run under a seccomp filter, and making a syscall that isn't on its allowlist
+    // gets the process killed with SIGSYS. close_range() is only known to be allowed starting with
+    // API level 34 (when bionic added a wrapper for it), so we check the API level before trying
+    // it.
+
+    // 1=allowed, 0=not allowed, other values=uninitialized
+    static ALLOWED: AtomicU8 = AtomicU8::new(2);
 
-    match IS_WSL1.load(Ordering::Relaxed) {
-        // Already initialized; return the result
+    match ALLOWED.load(Ordering::Relaxed) {
         1 => true,
         0 => false,
 
         _ => {
-            let mut uname = unsafe { core::mem::zeroed() };
-            unsafe {
-                libc::uname(&mut uname);
-            }
+            let mut buf = [0 as libc::c_char; libc::PROP_VALUE_MAX as usize];
 
-            let uname_release_len = uname
-                .release
-                .iter()
-                .position(|c| *c == 0)
-                .unwrap_or(uname.release.len());
-
-            // uname.release is an array of `libc::c_char`s. `libc::c_char` may be either a u8 or
-            // an i8, so unfortunately we have to use unsafe operations to get a reference as a
-            // &[u8].
-            let uname_release = unsafe {
-                core::slice::from_raw_parts(uname.release.as_ptr() as *const u8, uname_release_len)
+            let len = unsafe {
+                libc::__system_property_get(
+                    "ro.build.version.sdk\0".as_ptr() as *const libc::c_char,
+                    buf.as_mut_ptr(),
+                )
             };
 
-            // It seems that on WSL 1 the kernel "release name" ends with "-Microsoft", and on WSL
-            // 2 the release name ends with "-microsoft-standard". So we look for "Microsoft" at
-            // the end to mean WSL 1.
-            let is_wsl1 = uname_release.ends_with(b"Microsoft");
+            let api_level = buf[..core::cmp::max(len, 0) as usize].iter().try_fold(
+                0 as libc::c_int,
+                |num, &ch| {
+                    #[allow(clippy::unnecessary_cast)]
+                    let ch = ch as u8;
+                    if ch.is_ascii_digit() {
+                        num.checked_mul(10)?.checked_add((ch - b'0') as libc::c_int)
+                    } else {
+                        None
+                    }
+                },
+            );
+
+            // If we couldn't get the API level, err on the side of caution
+            let allowed = len > 0 && matches!(api_level, Some(level) if level >= 34);
+
+            ALLOWED.store(allowed as u8, Ordering::Relaxed);
+            allowed
+        }
+    }
+}
+
+/// Write `prefix`, followed by the decimal representation of `fd` and a NUL byte, into `buf`.
+/// Returns the part of `buf` that was written to (including the NUL byte).
+///
+/// `fd` must not be negative, and `prefix` must be no longer than 28 bytes.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "solaris",
+    target_os = "illumos",
+))]
+pub fn format_fd_path<'a>(prefix: &[u8], fd: libc::c_int, buf: &'a mut [u8; 40]) -> &'a [u8] {
+    debug_assert!(fd >= 0);
+
+    buf[..prefix.len()].copy_from_slice(prefix);
+    let end = prefix.len() + format_int(fd, &mut buf[prefix.len()..]);
+    buf[end] = 0;
+
+    &buf[..end + 1]
+}
 
-            // Store the result
-            IS_WSL1.store(is_wsl1 as u8, Ordering::Relaxed);
+/// Write the decimal representation of the nonnegative integer `n` to the start of `buf`,
+/// returning the number of bytes written.
+pub fn format_int(n: libc::c_int, buf: &mut [u8]) -> usize {
+    debug_assert!(n >= 0);
+
+    let mut ndigits = 1;
+    let mut m = n / 10;
+    while m > 0 {
+        ndigits += 1;
+        m /= 10;
+    }
 
-            is_wsl1
+    let mut m = n;
+    for ch in buf[..ndigits].iter_mut().rev() {
+        *ch = b'0' + (m % 10) as u8;
+        m /= 10;
+    }
+
+    ndigits
+}
+
+#[inline]
+pub fn errno() -> libc::c_int {
+    unsafe {
+        cfg_if::cfg_if! {
+            if #[cfg(any(target_os = "linux", target_os = "dragonfly"))] {
+                *libc::__errno_location()
+            } else if #[cfg(any(
+                target_os = "android",
+                target_os = "netbsd",
+                target_os = "openbsd",
+            ))] {
+                *libc::__errno()
+            } else if #[cfg(any(target_os = "solaris", target_os = "illumos"))] {
+                *libc::___errno()
+            } else {
+                *libc::__error()
+            }
         }
     }
 }
@@ -128,48 +271,88 @@ pub fn is_fd_valid(fd: libc::c_int) -> bool {
     unsafe { libc::fcntl(fd, libc::F_GETFD) >= 0 }
 }
 
-#[cfg(any(target_os = "linux", target_os = "freebsd"))]
-pub fn apply_range<F: FnMut(libc::c_int, libc::c_int) -> Result<(), ()>>(
+pub fn apply_range<
+    I: IntoIterator<Item = libc::c_int>,
+    F: FnMut(libc::c_int, libc::c_int) -> Result<(), ()>,
+>(
     minfd: libc::c_int,
-    mut keep_fds: &[libc::c_int],
+    keep_fds: I,
     mut func: F,
 ) -> Result<(), ()> {
-    // Skip over any elements of keep_fds that are less than minfd
-    if let Some(index) = keep_fds.iter().position(|&fd| fd >= minfd) {
-        keep_fds = &keep_fds[index..];
-    } else {
-        // keep_fds is empty (or would be when all elements < minfd are removed)
-        return func(minfd, libc::c_int::MAX);
-    }
+    // `keep_fds` must yield the file descriptors in ascending order.
+
+    // The lowest file descriptor that hasn't been covered yet
+    let mut low = minfd;
+
+    for fd in keep_fds {
+        if fd < low {
+            // Either fd < minfd, or this is a duplicate of the previous file descriptor
+            continue;
+        } else if fd > low {
+            // Apply it to the "gap" before this file descriptor
+            func(low, fd - 1)?;
+        }
 
-    if keep_fds[0] > minfd {
-        func(minfd, keep_fds[0] - 1)?;
+        if fd == libc::c_int::MAX {
+            // Nothing left
+            return Ok(());
+        }
+        low = fd + 1;
     }
 
-    for i in 0..(keep_fds.len() - 1) {
-        // Safety: i will only ever be in the range [0, keep_fds.len() - 2].
-        // So i and i + 1 will always be valid indices in keep_fds.
-        let low = unsafe { *keep_fds.get_unchecked(i) };
-        let high = unsafe { *keep_fds.get_unchecked(i + 1) };
+    func(low, libc::c_int::MAX)
+}
 
-        debug_assert!(high >= low);
+/// Set the close-on-exec flag on the given file descriptor.
+///
+/// Returns `Ok(true)` if the flag was set, `Ok(false)` if nothing was done (either the file
+/// descriptor isn't open or it already had the flag set), or `Err(())` if the file descriptor
+/// is open but setting the flag failed.
+pub fn set_cloexec(fd: libc::c_int) -> Result<bool, ()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
 
-        if high - low >= 2 {
-            func(low + 1, high - 1)?;
+    if flags >= 0 && (flags & libc::FD_CLOEXEC) != libc::FD_CLOEXEC {
+        // fcntl(F_GETFD) succeeded, and it did *not* return the FD_CLOEXEC flag
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+            return Err(());
         }
+        Ok(true)
+    } else {
+        Ok(false)
     }
-
-    func(keep_fds[keep_fds.len() - 1] + 1, libc::c_int::MAX)
 }
 
-pub fn set_cloexec(fd: libc::c_int) {
+/// Clear the close-on-exec flag on the given file descriptor.
+///
+/// Returns `Ok(true)` if the flag was cleared, `Ok(false)` if nothing was done (either the file
+/// descriptor isn't open or it didn't have the flag set), or `Err(())` if the file descriptor is
+/// open but clearing the flag failed.
+pub fn clear_cloexec(fd: libc::c_int) -> Result<bool, ()> {
     let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
 
-    if flags >= 0 && (flags & libc::FD_CLOEXEC) != libc::FD_CLOEXEC {
-        // fcntl(F_GETFD) succeeded, and it did *not* return the FD_CLOEXEC flag
-        unsafe {
-            libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC);
+    if flags >= 0 && (flags & libc::FD_CLOEXEC) == libc::FD_CLOEXEC {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) } < 0 {
+            return Err(());
         }
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+/// Set the close-on-exec flag on the given file descriptor without checking its current flags
+/// first (any other file descriptor flags are cleared).
+///
+/// Returns `Ok(true)` if the flag was set (whether or not it was already set), `Ok(false)` if the
+/// file descriptor isn't open, or `Err(())` if the file descriptor is open but setting the flag
+/// failed.
+pub fn set_cloexec_blind(fd: libc::c_int) -> Result<bool, ()> {
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } >= 0 {
+        Ok(true)
+    } else if errno() == libc::EBADF {
+        Ok(false)
+    } else {
+        Err(())
     }
 }
 
@@ -188,6 +371,38 @@ mod tests {
         }
     }
 
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "solaris",
+        target_os = "illumos",
+    ))]
+    #[test]
+    fn test_format_fd_path() {
+        let mut buf = [0xff; 40];
+        assert_eq!(format_fd_path(b"", 0, &mut buf), b"0\0");
+        assert_eq!(format_fd_path(b"", 7, &mut buf), b"7\0");
+        assert_eq!(
+            format_fd_path(b"/proc/self/fd/", 10, &mut buf),
+            b"/proc/self/fd/10\0"
+        );
+        assert_eq!(
+            format_fd_path(b"/proc/self/fdinfo/", libc::c_int::MAX, &mut buf),
+            b"/proc/self/fdinfo/2147483647\0"
+        );
+    }
+
+    #[test]
+    fn test_format_int() {
+        let mut buf = [0; 10];
+        assert_eq!(format_int(0, &mut buf), 1);
+        assert_eq!(&buf[..1], b"0");
+        assert_eq!(format_int(1234, &mut buf), 4);
+        assert_eq!(&buf[..4], b"1234");
+        assert_eq!(format_int(libc::c_int::MAX, &mut buf), 10);
+        assert_eq!(&buf[..10], b"2147483647");
+    }
+
     #[test]
     fn test_inspect_keep_fds() {
         assert_eq!(inspect_keep_fds(&[]), (libc::c_int::MIN, true));
@@ -199,6 +414,12 @@ mod tests {
         assert_eq!(inspect_keep_fds(&[0, 1, 2, 5, 7]), (7, true));
         assert_eq!(inspect_keep_fds(&[0, 1, 2, 7, 5]), (7, false));
 
+        // Duplicates don't affect sortedness
+        assert_eq!(inspect_keep_fds(&[0, 0]), (0, true));
+        assert_eq!(inspect_keep_fds(&[0, 1, 1, 2, 5, 5, 5]), (5, true));
+        assert_eq!(inspect_keep_fds(&[1, 1, 0]), (1, false));
+        assert_eq!(inspect_keep_fds(&[5, 3, 5]), (5, false));
+
         assert_eq!(inspect_keep_fds(&[-1]), (-1, true));
         assert_eq!(
             inspect_keep_fds(&[libc::c_int::MIN]),
@@ -231,6 +452,19 @@ mod tests {
         assert_eq!(keep_fds, &[8, 10]);
     }
 
+    #[test]
+    fn test_check_should_keep_sorted_duplicates() {
+        let mut keep_fds: &[libc::c_int] = &[1, 1, 5, 5, 5, 8];
+
+        assert!(!check_should_keep(&mut keep_fds, 0, true));
+        assert!(check_should_keep(&mut keep_fds, 1, true));
+        assert!(!check_should_keep(&mut keep_fds, 2, true));
+        assert!(check_should_keep(&mut keep_fds, 5, true));
+        assert!(!check_should_keep(&mut keep_fds, 6, true));
+        assert!(check_should_keep(&mut keep_fds, 8, true));
+        assert!(!check_should_keep(&mut keep_fds, 9, true));
+    }
+
     #[test]
     fn test_check_should_keep_not_sorted() {
         let mut keep_fds: &[libc::c_int] = &[0, 1, 5, 8, 10];
@@ -289,6 +523,17 @@ mod tests {
         );
         assert_eq!(minfd, 3);
 
+        // Duplicates are skipped over
+        keep_fds = &[2, 2, 3, 3, 4, 6, 6];
+        minfd = 3;
+        assert_eq!(simplify_keep_fds(keep_fds, true, &mut minfd), &[6, 6]);
+        assert_eq!(minfd, 5);
+
+        keep_fds = &[3, 3, 3];
+        minfd = 3;
+        assert_eq!(simplify_keep_fds(keep_fds, true, &mut minfd), &[]);
+        assert_eq!(minfd, 4);
+
         // And if fds_sorted=false, no simplification can be performed
 
         keep_fds = &[2, 1, 0];
@@ -305,7 +550,6 @@ mod tests {
         assert_eq!(minfd, 3);
     }
 
-    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
     #[test]
     fn test_apply_range() {
         macro_rules! check_ok {
@@ -313,7 +557,7 @@ mod tests {
                 let mut ranges = [(0, 0); 100];
                 let mut len = 0;
 
-                apply_range($minfd, &[$($keep_fds),*], |low, high| {
+                apply_range($minfd, [$($keep_fds),*].iter().cloned(), |low, high| {
                     *ranges.get_mut(len).unwrap() = (low, high);
                     len += 1;
                     Ok(())
@@ -338,11 +582,26 @@ mod tests {
             [(3, 4), (7, 8), (11, 19), (21, 22), (24, libc::c_int::MAX)],
         );
 
+        check_ok!(3, [5, 5, 6, 9, 9], [(3, 4), (7, 8), (10, libc::c_int::MAX)]);
+        check_ok!(3, [0, 0, 3, 3, 4], [(5, libc::c_int::MAX)]);
+        check_ok!(3, [2, 2, 5, 5], [(3, 4), (6, libc::c_int::MAX)]);
+        check_ok!(3, [5, 5, 5, 5], [(3, 4), (6, libc::c_int::MAX)]);
+        check_ok!(
+            3,
+            [libc::c_int::MAX, libc::c_int::MAX],
+            [(3, libc::c_int::MAX - 1)]
+        );
+        check_ok!(
+            3,
+            [4, libc::c_int::MAX],
+            [(3, 3), (5, libc::c_int::MAX - 1)]
+        );
+
         macro_rules! check_err {
             ($minfd:expr, [$($keep_fds:expr),* $(,)?], $call:expr $(,)?) => {{
                 let mut call = None;
 
-                apply_range($minfd, &[$($keep_fds),*], |low, high| {
+                apply_range($minfd, [$($keep_fds),*].iter().cloned(), |low, high| {
                     assert!(call.is_none());
                     call = Some((low, high));
                     Err(())
@@ -378,8 +637,8 @@ mod tests {
     #[test]
     fn test_set_cloexec() {
         // No panic on errors like this
-        set_cloexec(-1);
-        set_cloexec(libc::c_int::MAX);
+        assert_eq!(set_cloexec(-1), Ok(false));
+        assert_eq!(set_cloexec(libc::c_int::MAX), Ok(false));
 
         fn is_cloexec(fd: libc::c_int) -> bool {
             let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
@@ -389,8 +648,22 @@ mod tests {
 
         with_fd(|fd| {
             assert!(!is_cloexec(fd));
-            set_cloexec(fd);
+            assert_eq!(set_cloexec(fd), Ok(true));
             assert!(is_cloexec(fd));
+            assert_eq!(set_cloexec(fd), Ok(false));
+            assert!(is_cloexec(fd));
+        });
+    }
+
+    #[test]
+    fn test_set_cloexec_blind() {
+        assert_eq!(set_cloexec_blind(-1), Ok(false));
+        assert_eq!(set_cloexec_blind(libc::c_int::MAX), Ok(false));
+
+        with_fd(|fd| {
+            assert_eq!(set_cloexec_blind(fd), Ok(true));
+            assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } & libc::FD_CLOEXEC != 0);
+            assert_eq!(set_cloexec_blind(fd), Ok(true));
         });
     }
 }
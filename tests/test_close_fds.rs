@@ -55,21 +55,26 @@ fn run_basic_test(
     ),
     builder: close_fds::CloseFdsBuilder,
 ) {
-    let f1 = std::fs::File::open("/").unwrap();
-    let f2 = std::fs::File::open("/").unwrap();
-    let f3 = std::fs::File::open("/").unwrap();
+    // Use raw file descriptors; the callbacks may close them, and dropping a `File` whose file
+    // descriptor has already been closed is an IO safety violation.
+    let fd1 = std::fs::File::open("/").unwrap().into_raw_fd();
+    let fd2 = std::fs::File::open("/").unwrap().into_raw_fd();
+    let fd3 = std::fs::File::open("/").unwrap().into_raw_fd();
 
-    let fd1 = f1.as_raw_fd();
-    let fd2 = f2.as_raw_fd();
-    let fd3 = f3.as_raw_fd();
-
-    drop(f3);
+    unsafe {
+        libc::close(fd3);
+    }
 
     assert!(is_fd_open(fd1));
     assert!(is_fd_open(fd2));
     assert!(!is_fd_open(fd3));
 
     callback(fd1, fd2, fd3, builder.clone());
+
+    unsafe {
+        libc::close(fd1);
+        libc::close(fd2);
+    }
 }
 
 fn iter_open_fds_test(
@@ -147,9 +152,8 @@ fn iter_possible_fds_test(
         let mut builder = close_fds::FdIterBuilder::new();
         builder.allow_filesystem(fs);
         builder.threadsafe(threadsafe);
-        builder.possible(true);
 
-        fds = builder.iter_from(-1).collect();
+        fds = builder.iter_possible_from(-1).collect();
         check_sorted(&fds);
         assert!(!fds.contains(&-1));
         assert!(fds.contains(&0));
@@ -158,11 +162,11 @@ fn iter_possible_fds_test(
         // Don't check for fd3 not being present because it might legitimately be
         // returned by iter_possible_fds()
 
-        assert_eq!(builder.iter_from(-1).min(), Some(0));
-        assert_eq!(builder.iter_from(-1).max().as_ref(), fds.last());
-        assert_eq!(builder.iter_from(-1).count(), fds.len());
+        assert_eq!(builder.iter_possible_from(-1).min(), Some(0));
+        assert_eq!(builder.iter_possible_from(-1).max().as_ref(), fds.last());
+        assert_eq!(builder.iter_possible_from(-1).count(), fds.len());
 
-        fds = builder.iter_from(0).collect();
+        fds = builder.iter_possible_from(0).collect();
         check_sorted(&fds);
         assert!(fds.contains(&0));
         assert!(fds.contains(&fd1));
@@ -170,19 +174,19 @@ fn iter_possible_fds_test(
 
         // Test handling of minfd
 
-        fds = builder.iter_from(fd1).collect();
+        fds = builder.iter_possible_from(fd1).collect();
         check_sorted(&fds);
         assert!(!fds.contains(&0));
         assert!(fds.contains(&fd1));
         assert!(fds.contains(&fd2));
 
-        fds = builder.iter_from(fd2).collect();
+        fds = builder.iter_possible_from(fd2).collect();
         check_sorted(&fds);
         assert!(!fds.contains(&0));
         assert!(!fds.contains(&fd1));
         assert!(fds.contains(&fd2));
 
-        fds = builder.iter_from(fd3).collect();
+        fds = builder.iter_possible_from(fd3).collect();
         check_sorted(&fds);
         assert!(!fds.contains(&0));
         assert!(!fds.contains(&fd1));
@@ -283,8 +287,109 @@ fn close_fds_keep3_test(
     assert!(!fds.contains(&fd3));
 }
 
+fn close_fds_also_keep_test(
+    fd1: libc::c_int,
+    fd2: libc::c_int,
+    fd3: libc::c_int,
+    builder: close_fds::CloseFdsBuilder,
+) {
+    let mut fds: Vec<_> = close_fds::iter_open_fds(fd1).collect();
+    check_sorted(&fds);
+    assert!(fds.contains(&fd1));
+    assert!(fds.contains(&fd2));
+    assert!(!fds.contains(&fd3));
+
+    let keep1 = [fd3];
+    let keep2 = [fd2];
+    unsafe {
+        builder
+            .clone()
+            .keep_fds(&[0])
+            .also_keep(&keep1)
+            .unwrap()
+            .also_keep(&keep2)
+            .unwrap()
+            .closefrom(fd1);
+    }
+
+    fds = close_fds::iter_open_fds(fd1).collect();
+    check_sorted(&fds);
+    assert!(!fds.contains(&fd1));
+    assert!(fds.contains(&fd2));
+    assert!(!fds.contains(&fd3));
+}
+
+fn close_fds_juggle_test(
+    fd1: libc::c_int,
+    fd2: libc::c_int,
+    fd3: libc::c_int,
+    builder: close_fds::CloseFdsBuilder,
+) {
+    // Not a new open() because that would reuse fd3
+    let fd4 = unsafe { libc::fcntl(fd2, libc::F_DUPFD, fd3 + 1) };
+    assert!(fd4 > fd3);
+
+    // Make sure the flags and the open file descriptions are preserved
+    set_fd_cloexec(fd2, true);
+    set_fd_cloexec(fd4, false);
+    let ino2 = std::fs::metadata(format!("/proc/self/fd/{}", fd2)).ok();
+
+    unsafe {
+        builder
+            .clone()
+            .juggle_keep_fds(true)
+            .keep_fds(&[fd4, fd3, fd2, 0])
+            .closefrom(fd1);
+    }
+
+    let fds: Vec<_> = close_fds::iter_open_fds(fd1).collect();
+    check_sorted(&fds);
+    assert!(!fds.contains(&fd1));
+    assert!(fds.contains(&fd2));
+    assert!(!fds.contains(&fd3));
+    assert!(fds.contains(&fd4));
+
+    assert_eq!(is_fd_cloexec(fd2), Some(true));
+    assert_eq!(is_fd_cloexec(fd4), Some(false));
+    if let Some(ino2) = ino2 {
+        let new_ino2 = std::fs::metadata(format!("/proc/self/fd/{}", fd2)).unwrap();
+        assert_eq!(ino2.ino(), new_ino2.ino());
+    }
+
+    unsafe {
+        libc::close(fd4);
+    }
+}
+
+fn cloexec_blind_test(
+    fd1: libc::c_int,
+    fd2: libc::c_int,
+    fd3: libc::c_int,
+    builder: close_fds::CloseFdsBuilder,
+) {
+    set_fd_cloexec(fd1, false);
+    set_fd_cloexec(fd2, false);
+
+    // Use an unsorted list so the flags are set one at a time even on Linux 5.11+
+    for _ in 0..2 {
+        let stats = builder
+            .clone()
+            .blind_cloexec(true)
+            .keep_fds(&[fd2, 0])
+            .cloexecfrom_stats(fd1);
+
+        assert_eq!(is_fd_cloexec(fd1), Some(true));
+        assert_eq!(is_fd_cloexec(fd2), Some(false));
+        assert_eq!(is_fd_cloexec(fd3), None);
+
+        // The flag is "set" on fd1 every time (other tests may be opening and closing file
+        // descriptors at the same time, so this is only a lower bound)
+        assert!(stats.changed >= 1);
+    }
+}
+
 fn large_open_fds_test(
-    mangle_keep_fds: fn(&mut [libc::c_int]),
+    mangle_keep_fds: fn(&mut Vec<libc::c_int>),
     builder: close_fds::CloseFdsBuilder,
 ) {
     let mut openfds = Vec::new();
@@ -335,11 +440,16 @@ fn large_open_fds_test(
         assert_eq!(is_fd_cloexec(*fd), Some(false));
     }
     // Make them all close-on-exec
-    builder.cloexecfrom(lowfd);
+    let stats = builder.cloexecfrom_stats(lowfd);
     // Now make sure they're all close-on-exec
     for fd in openfds.iter() {
         assert_eq!(is_fd_cloexec(*fd), Some(true));
     }
+    // And check that the statistics are sane. Other tests may be opening and closing file
+    // descriptors at the same time, so only our own file descriptors can be counted on.
+    if !stats.bulk {
+        assert!(stats.changed >= openfds.len());
+    }
 
     // Set them all as non-close-on-exec again
     for fd in openfds.iter() {
@@ -440,9 +550,33 @@ fn run_tests() {
             run_basic_test(close_fds_keep1_test, builder.clone());
             run_basic_test(close_fds_keep2_test, builder.clone());
             run_basic_test(close_fds_keep3_test, builder.clone());
+            run_basic_test(close_fds_also_keep_test, builder.clone());
+            run_basic_test(cloexec_blind_test, builder.clone());
+            run_basic_test(close_fds_juggle_test, builder.clone());
+            run_basic_test(
+                close_fds_keep2_test,
+                builder.clone().close_descending(true).clone(),
+            );
 
             large_open_fds_test(|keep_fds| keep_fds.sort_unstable(), builder.clone());
             large_open_fds_test(|_keep_fds| (), builder.clone());
+            large_open_fds_test(
+                |_keep_fds| (),
+                builder.clone().juggle_keep_fds(true).clone(),
+            );
+            large_open_fds_test(
+                |_keep_fds| (),
+                builder.clone().close_descending(true).clone(),
+            );
+            // Sorted, but with every file descriptor duplicated
+            large_open_fds_test(
+                |keep_fds| {
+                    let dups = keep_fds.clone();
+                    keep_fds.extend(dups);
+                    keep_fds.sort_unstable();
+                },
+                builder.clone(),
+            );
             large_open_fds_test(
                 |keep_fds| {
                     keep_fds.sort_unstable_by(|a, b| ((a + b) % 5).cmp(&3));
@@ -549,3 +683,1821 @@ fn run_no_fds_tests() {
         },
     }
 }
+
+// This requires close_range() (except on NetBSD), which isn't used in deterministic mode or in
+// libc-wrappers-only mode
+#[cfg(any(
+    all(
+        not(any(feature = "deterministic-syscalls", feature = "libc-wrappers-only")),
+        any(target_os = "linux", target_os = "android", target_os = "freebsd"),
+    ),
+    target_os = "netbsd",
+))]
+#[test]
+fn run_above_limit_tests() {
+    // Check that file descriptors above the maximum file descriptor that a "maxfd loop" would
+    // check (because the file descriptor limit was lowered after they were opened) still get
+    // closed. Like above, this has to be done in a separate process.
+
+    run_in_child(|| unsafe {
+        let fd = libc::open("/\0".as_ptr() as *const libc::c_char, libc::O_RDONLY);
+        if fd < 0 {
+            return 1;
+        }
+        for &newfd in [2000, 2002, 3000].iter() {
+            if libc::dup2(fd, newfd) != newfd {
+                return 1;
+            }
+        }
+
+        let limit = libc::rlimit {
+            rlim_cur: 1024,
+            rlim_max: 1024,
+        };
+        if libc::setrlimit(libc::RLIMIT_NOFILE, &limit) < 0 {
+            return 2;
+        }
+
+        // Don't use /proc/self/fd or /dev/fd, and keep a file descriptor above the limit so
+        // the loop never gets past the end of the keep list
+        close_fds::CloseFdsBuilder::new()
+            .allow_filesystem(false)
+            .keep_fds(&[2002, fd])
+            .closefrom(3);
+
+        if libc::fcntl(2000, libc::F_GETFD) >= 0 || libc::fcntl(3000, libc::F_GETFD) >= 0 {
+            return 3;
+        }
+        if libc::fcntl(fd, libc::F_GETFD) < 0 || libc::fcntl(2002, libc::F_GETFD) < 0 {
+            return 4;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_above_clamp_tests() {
+    // Check that file descriptors above the clamped maximum file descriptor that a "maxfd loop"
+    // checks (65535) still get closed, even without closefrom() or close_range().
+
+    fn check(keep_high: bool) -> libc::c_int {
+        unsafe {
+            let mut limit = core::mem::MaybeUninit::uninit();
+            if libc::getrlimit(libc::RLIMIT_NOFILE, limit.as_mut_ptr()) < 0 {
+                return 1;
+            }
+            let mut limit = limit.assume_init();
+            limit.rlim_cur = 70000;
+            if limit.rlim_max != libc::RLIM_INFINITY && limit.rlim_max < 70000 {
+                limit.rlim_max = 70000;
+            }
+            if libc::setrlimit(libc::RLIMIT_NOFILE, &limit) < 0 {
+                // We can't raise the limit that far; nothing to test
+                return 0;
+            }
+
+            let fd = libc::open("/\0".as_ptr() as *const libc::c_char, libc::O_RDONLY);
+            if fd < 0 {
+                return 1;
+            }
+            for &newfd in [66000, 68000, 69999].iter() {
+                if libc::dup2(fd, newfd) != newfd {
+                    return 1;
+                }
+            }
+
+            // Make close_range() fail (as on older kernels)
+            #[cfg(target_os = "linux")]
+            if !block_close_range() {
+                return 2;
+            }
+
+            // Don't use /proc/self/fd or /dev/fd
+            let keep_fds = [fd, 68000];
+            close_fds::CloseFdsBuilder::new()
+                .allow_filesystem(false)
+                .keep_fds(if keep_high { &keep_fds } else { &keep_fds[..1] })
+                .closefrom(3);
+
+            if libc::fcntl(66000, libc::F_GETFD) >= 0 || libc::fcntl(69999, libc::F_GETFD) >= 0 {
+                return 3;
+            }
+            if libc::fcntl(fd, libc::F_GETFD) < 0
+                || (libc::fcntl(68000, libc::F_GETFD) >= 0) != keep_high
+            {
+                return 4;
+            }
+
+            0
+        }
+    }
+
+    // With a file descriptor to keep above the clamped maximum, and without one (in which case
+    // everything after the last one to keep is closed in one go)
+    run_in_child(|| check(true));
+    run_in_child(|| check(false));
+}
+
+#[test]
+fn run_atfork_tests() {
+    // The handlers affect every fork() in the process, so they have to be installed in a separate
+    // process (which then forks again to check them).
+
+    fn fork_and_check(check: fn() -> bool) -> bool {
+        match unsafe { libc::fork() } {
+            0 => unsafe { libc::_exit(if check() { 0 } else { 1 }) },
+            ret if ret < 0 => false,
+            pid => {
+                let mut stat = 0;
+                if unsafe { libc::waitpid(pid, &mut stat, 0) } != pid {
+                    return false;
+                }
+                libc::WIFEXITED(stat) && libc::WEXITSTATUS(stat) == 0
+            }
+        }
+    }
+
+    run_in_child(|| unsafe {
+        // Two non-close-on-exec file descriptors
+        let fd1 = libc::dup2(0, 700);
+        let fd2 = libc::dup2(0, 701);
+        if fd1 != 700 || fd2 != 701 {
+            return 1;
+        }
+
+        if close_fds::install_atfork_closer(3, &[701]).is_err() {
+            return 2;
+        }
+
+        if !fork_and_check(|| is_fd_cloexec(700) == Some(true) && is_fd_cloexec(701) == Some(false))
+        {
+            return 3;
+        }
+
+        // The parent is unaffected
+        if is_fd_cloexec(700) != Some(false) {
+            return 4;
+        }
+
+        close_fds::uninstall_atfork_closer();
+        if !fork_and_check(|| {
+            is_fd_cloexec(700) == Some(false) && is_fd_cloexec(701) == Some(false)
+        }) {
+            return 5;
+        }
+
+        // Reinstalling replaces the old configuration
+        if close_fds::install_atfork_closer(3, &[700]).is_err() {
+            return 6;
+        }
+        if !fork_and_check(|| is_fd_cloexec(700) == Some(false) && is_fd_cloexec(701) == Some(true))
+        {
+            return 7;
+        }
+
+        let keep_fds: Vec<libc::c_int> =
+            (0..=close_fds::MAX_ATFORK_KEEP_FDS as libc::c_int).collect();
+        if close_fds::install_atfork_closer(3, &keep_fds)
+            != Err(close_fds::AtforkCloserError::TooManyKeepFds(
+                close_fds::MAX_ATFORK_KEEP_FDS as libc::c_int,
+            ))
+        {
+            return 8;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_clofork_tests() {
+    // The handlers affect every fork() in the process, so they have to be registered in a separate
+    // process (which then forks again to check them)
+    run_in_child(|| unsafe {
+        unsafe fn fork_and_check(check: fn() -> bool) -> bool {
+            match libc::fork() {
+                0 => libc::_exit(if check() { 0 } else { 1 }),
+                ret if ret < 0 => false,
+                pid => {
+                    let mut stat = 0;
+                    libc::waitpid(pid, &mut stat, 0) == pid
+                        && libc::WIFEXITED(stat)
+                        && libc::WEXITSTATUS(stat) == 0
+                }
+            }
+        }
+
+        for fd in 700..703 {
+            if libc::dup2(0, fd) != fd {
+                return 1;
+            }
+        }
+
+        if close_fds::set_clofork(700).is_err() || close_fds::set_clofork(702).is_err() {
+            return 2;
+        }
+        if !close_fds::is_clofork(700) || close_fds::is_clofork(701) {
+            return 3;
+        }
+
+        if !fork_and_check(|| {
+            // The registry is cleared in the child
+            !is_fd_open(700) && is_fd_open(701) && !is_fd_open(702) && !close_fds::is_clofork(700)
+        }) {
+            return 4;
+        }
+        // The parent is unaffected
+        if !(700..703).all(is_fd_open) || !close_fds::is_clofork(702) {
+            return 5;
+        }
+
+        if !close_fds::unset_clofork(702) || close_fds::unset_clofork(702) {
+            return 6;
+        }
+        if !fork_and_check(|| !is_fd_open(700) && is_fd_open(701) && is_fd_open(702)) {
+            return 7;
+        }
+
+        // Explicitly (e.g. after vfork())
+        if !fork_and_check(|| {
+            libc::dup2(0, 700);
+            close_fds::run_clofork();
+            true
+        }) {
+            return 8;
+        }
+        close_fds::run_clofork();
+        if is_fd_open(700) || !is_fd_open(701) || !close_fds::is_clofork(700) {
+            return 9;
+        }
+        close_fds::unset_clofork(700);
+
+        if close_fds::set_clofork(-1) != Err(close_fds::CloforkError::BadFd(-1)) {
+            return 10;
+        }
+        for fd in 0..close_fds::MAX_CLOFORK_FDS as libc::c_int {
+            if close_fds::set_clofork(800 + fd).is_err() {
+                return 11;
+            }
+        }
+        if close_fds::set_clofork(799) != Err(close_fds::CloforkError::Full(799)) {
+            return 12;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_stabilize_cloexec_tests() {
+    run_in_child(|| unsafe {
+        for &blind in [false, true].iter() {
+            for fd in 100..104 {
+                if libc::dup2(0, fd) != fd {
+                    return 1;
+                }
+            }
+
+            let stats = close_fds::CloseFdsBuilder::new()
+                .blind_cloexec(blind)
+                .stabilize(3)
+                .cloexecfrom_stats(100);
+            if !(100..104).all(|fd| is_fd_cloexec(fd) == Some(true)) || stats.failed != 0 {
+                return 2;
+            }
+            // The second pass doesn't find anything (unless it was done in bulk, in which case
+            // there's no second pass)
+            if stats.passes != if stats.bulk { 1 } else { 2 } {
+                return 3;
+            }
+        }
+
+        let stats = close_fds::CloseFdsBuilder::new()
+            .stabilize(0)
+            .cloexecfrom_stats(100);
+        if stats.passes != 1 {
+            return 4;
+        }
+
+        for fd in 100..104 {
+            set_fd_cloexec(fd, false);
+        }
+        let stats = close_fds::set_fds_cloexec_stats(100, &[101]);
+        if (!stats.bulk && stats.changed != 3) || is_fd_cloexec(101) != Some(false) {
+            return 5;
+        }
+        let stats = close_fds::set_fds_cloexec_threadsafe_stats(100, &[101]);
+        if stats.changed != 0 || stats.failed != 0 {
+            return 6;
+        }
+
+        // The cached version makes the same passes (make sure the flag isn't set in bulk, so that
+        // there's more than one)
+        #[cfg(target_os = "linux")]
+        if !block_close_range() {
+            return 7;
+        }
+        for fd in 100..104 {
+            set_fd_cloexec(fd, false);
+        }
+        let mut cache = close_fds::FdDirCache::new();
+        let stats = close_fds::CloseFdsBuilder::new()
+            .stabilize(3)
+            .cloexecfrom_cached(&mut cache, 100);
+        if !(100..104).all(|fd| is_fd_cloexec(fd) == Some(true))
+            || stats.passes != if stats.bulk { 1 } else { 2 }
+        {
+            return 8;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_observer_tests() {
+    use close_fds::FdDecision;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    // Bit (fd - 100) is set for each file descriptor in 100..104 that was acted on / kept
+    static ACTED: AtomicU32 = AtomicU32::new(0);
+    static KEPT: AtomicU32 = AtomicU32::new(0);
+
+    fn mark(mask: &AtomicU32, low: libc::c_int, high: libc::c_int) {
+        for fd in core::cmp::max(low, 100)..=core::cmp::min(high, 103) {
+            mask.fetch_or(1 << (fd - 100), Ordering::SeqCst);
+        }
+    }
+
+    fn observe(decision: FdDecision) {
+        match decision {
+            FdDecision::Kept(fd) => mark(&KEPT, fd, fd),
+            FdDecision::Closed(fd) | FdDecision::Cloexec(fd) => mark(&ACTED, fd, fd),
+            FdDecision::ClosedRange { low, high } | FdDecision::CloexecRange { low, high } => {
+                mark(&ACTED, low, high)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    run_in_child(|| unsafe {
+        let keep_fds = [101];
+
+        for &descending in [false, true].iter() {
+            for fd in 100..104 {
+                if libc::dup2(0, fd) != fd {
+                    return 1;
+                }
+            }
+
+            ACTED.store(0, Ordering::SeqCst);
+            KEPT.store(0, Ordering::SeqCst);
+            close_fds::CloseFdsBuilder::new()
+                .keep_fds(&keep_fds)
+                .close_descending(descending)
+                .observer(Some(observe))
+                .closefrom(100);
+            if ACTED.load(Ordering::SeqCst) != 0b1101
+                || (100..104).any(|fd| is_fd_open(fd) != (fd == 101))
+            {
+                return 2;
+            }
+            // When closing in descending order, every file descriptor is visited individually
+            if descending && KEPT.load(Ordering::SeqCst) != 0b0010 {
+                return 3;
+            }
+        }
+
+        for fd in 100..104 {
+            if libc::dup2(0, fd) != fd {
+                return 4;
+            }
+        }
+        ACTED.store(0, Ordering::SeqCst);
+        close_fds::CloseFdsBuilder::new()
+            .keep_fds(&keep_fds)
+            .observer(Some(observe))
+            .cloexecfrom(100);
+        if ACTED.load(Ordering::SeqCst) != 0b1101 || is_fd_cloexec(101) != Some(false) {
+            return 5;
+        }
+
+        // Nothing is reported without an observer
+        ACTED.store(0, Ordering::SeqCst);
+        close_fds::CloseFdsBuilder::new().closefrom(100);
+        if ACTED.load(Ordering::SeqCst) != 0 {
+            return 6;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_redirect_stdio_tests() {
+    use close_fds::StdioSource;
+
+    run_in_child(|| unsafe {
+        let mut p1 = [0; 2];
+        let mut p2 = [0; 2];
+        if libc::pipe(p1.as_mut_ptr()) < 0 || libc::pipe(p2.as_mut_ptr()) < 0 {
+            return 1;
+        }
+        if libc::dup2(p1[1], 1) != 1 || libc::dup2(p2[1], 2) != 2 {
+            return 2;
+        }
+        libc::close(p1[1]);
+        libc::close(p2[1]);
+
+        let read_pipe = |fd| {
+            let mut buf = [0u8; 8];
+            let n = libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+            if n < 0 {
+                Vec::new()
+            } else {
+                buf[..n as usize].to_vec()
+            }
+        };
+
+        // Swap stdout and stderr, and replace stdin with /dev/null
+        set_fd_cloexec(2, true);
+        if close_fds::redirect_stdio(StdioSource::Null, StdioSource::Fd(2), StdioSource::Fd(1))
+            .is_err()
+        {
+            return 3;
+        }
+        if (0..3).any(|fd| is_fd_cloexec(fd) != Some(false)) {
+            return 4;
+        }
+        libc::write(1, b"x".as_ptr() as *const libc::c_void, 1);
+        libc::write(2, b"y".as_ptr() as *const libc::c_void, 1);
+        if read_pipe(p2[0]) != b"x" || read_pipe(p1[0]) != b"y" {
+            return 5;
+        }
+        let mut buf = [0u8; 1];
+        if libc::read(0, buf.as_mut_ptr() as *mut libc::c_void, 1) != 0 {
+            return 6;
+        }
+
+        // Redirecting to itself clears the close-on-exec flag
+        set_fd_cloexec(1, true);
+        if close_fds::redirect_stdio(
+            StdioSource::Inherit,
+            StdioSource::Fd(1),
+            StdioSource::Inherit,
+        )
+        .is_err()
+            || is_fd_cloexec(1) != Some(false)
+        {
+            return 7;
+        }
+
+        // A bad source changes nothing
+        set_fd_cloexec(1, true);
+        if close_fds::redirect_stdio(StdioSource::Inherit, StdioSource::Null, StdioSource::Fd(-1))
+            != Err(libc::EBADF)
+            || close_fds::redirect_stdio(
+                StdioSource::Fd(0),
+                StdioSource::Fd(150),
+                StdioSource::Inherit,
+            ) != Err(libc::EBADF)
+            || is_fd_cloexec(1) != Some(true)
+        {
+            return 8;
+        }
+
+        // A closed standard file descriptor can be reopened to /dev/null
+        libc::close(0);
+        if close_fds::redirect_stdio(
+            StdioSource::Null,
+            StdioSource::Inherit,
+            StdioSource::Inherit,
+        )
+        .is_err()
+            || !is_fd_open(0)
+        {
+            return 9;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_compact_tests() {
+    run_in_child(|| unsafe {
+        let mut pipefds = [0; 2];
+        if libc::pipe(pipefds.as_mut_ptr()) < 0 {
+            return 1;
+        }
+        if libc::dup2(pipefds[0], 800) != 800 || libc::dup2(pipefds[1], 801) != 801 {
+            return 1;
+        }
+        libc::close(pipefds[0]);
+        libc::close(pipefds[1]);
+        set_fd_cloexec(800, true);
+
+        // Errors are reported before anything is changed
+        if close_fds::compact_fds(3, &[800, 801, 800]).err()
+            != Some(close_fds::CompactFdsError::Duplicate(800))
+            || close_fds::compact_fds(3, &[800, 799]).err()
+                != Some(close_fds::CompactFdsError::BadFd(799))
+            || close_fds::compact_fds(3, &[-1]).err() != Some(close_fds::CompactFdsError::BadFd(-1))
+        {
+            return 2;
+        }
+        if !is_fd_open(800) || !is_fd_open(801) {
+            return 3;
+        }
+
+        let mapping: Vec<(libc::c_int, libc::c_int)> =
+            match close_fds::compact_fds(3, &[801, 0, 800]) {
+                Ok(mapping) => mapping.collect(),
+                Err(_) => return 4,
+            };
+        if mapping != [(801, 3), (0, 4), (800, 5)] {
+            return 5;
+        }
+
+        // Everything else was closed
+        if close_fds::iter_open_fds(3).collect::<Vec<_>>() != [3, 4, 5] || !is_fd_open(0) {
+            return 6;
+        }
+
+        // The file descriptors ended up in the right places, without the close-on-exec flag
+        let accmode = |fd| libc::fcntl(fd, libc::F_GETFL) & libc::O_ACCMODE;
+        if accmode(3) != libc::O_WRONLY || accmode(5) != libc::O_RDONLY {
+            return 7;
+        }
+        if (3..=5).any(|fd| is_fd_cloexec(fd) != Some(false)) {
+            return 8;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn test_fditer_debug() {
+    let mut fditer = close_fds::FdIterBuilder::new()
+        .allow_filesystem(false)
+        .iter_from(0);
+    let debug = format!("{:?}", fditer);
+    assert!(debug.starts_with("FdIter {"), "{}", debug);
+    assert!(debug.contains("backend: \"maxfd\""), "{}", debug);
+    assert!(debug.contains("maxfd: None"), "{}", debug);
+
+    assert_eq!(fditer.next(), Some(0));
+    let debug = format!("{:?}", fditer);
+    assert!(debug.contains("curfd: 1"), "{}", debug);
+    assert!(debug.contains("maxfd: Some("), "{}", debug);
+
+    #[cfg(all(
+        target_os = "linux",
+        not(any(feature = "no-fs-backends", feature = "libc-wrappers-only"))
+    ))]
+    {
+        let mut fditer = close_fds::FdIterBuilder::new().iter_from(0);
+        assert_eq!(fditer.next(), Some(0));
+        let debug = format!("{:?}", fditer);
+        assert!(debug.contains("backend: \"dirfd\""), "{}", debug);
+        assert!(debug.contains("dirfd_iter: DirFdIter {"), "{}", debug);
+        assert!(debug.contains("dirent_nbytes: "), "{}", debug);
+    }
+}
+
+#[test]
+fn test_fditer_helper_fd() {
+    // The dirfd iterator opens (and closes) a file descriptor of its own, which could race with
+    // other tests if this ran in the test runner process
+    run_in_child(|| {
+        let fditer = close_fds::FdIterBuilder::new()
+            .allow_filesystem(false)
+            .iter_from(0);
+        if fditer.helper_fd().is_some() {
+            return 1;
+        }
+
+        #[cfg(all(
+            target_os = "linux",
+            not(any(feature = "no-fs-backends", feature = "libc-wrappers-only"))
+        ))]
+        {
+            let mut fditer = close_fds::FdIterBuilder::new()
+                .dirfd_minfd(700)
+                .iter_from(0);
+            if fditer.next() != Some(0) {
+                return 2;
+            }
+
+            let helper_fd = match fditer.helper_fd() {
+                Some(fd) if fd >= 700 => fd,
+                _ => return 3,
+            };
+            if is_fd_cloexec(helper_fd) != Some(true) {
+                return 4;
+            }
+
+            // It's never yielded, and it's closed at the end
+            if fditer.by_ref().any(|fd| fd == helper_fd) {
+                return 5;
+            }
+            if fditer.helper_fd().is_some() {
+                return 6;
+            }
+        }
+
+        0
+    });
+}
+
+#[test]
+fn test_keep_fds_from_env() {
+    let name = std::ffi::CString::new("CLOSE_FDS_TEST_KEEP").unwrap();
+    let mut buf = close_fds::KeepFdsBuf::<8>::new();
+
+    std::env::remove_var("CLOSE_FDS_TEST_KEEP");
+    assert_eq!(buf.extend_from_env(&name), Ok(false));
+
+    std::env::set_var("CLOSE_FDS_TEST_KEEP", "3,7-9");
+    assert_eq!(buf.extend_from_env(&name), Ok(true));
+    assert_eq!(buf.as_slice(), [3, 7, 8, 9]);
+
+    std::env::set_var("CLOSE_FDS_TEST_KEEP", "3;4");
+    assert_eq!(
+        buf.extend_from_env(&name),
+        Err(close_fds::KeepFdsSpecError::Invalid)
+    );
+
+    std::env::remove_var("CLOSE_FDS_TEST_KEEP");
+    assert_eq!(close_fds::KEEP_FDS_ENV.to_bytes(), b"CLOSE_FDS_KEEP");
+}
+
+#[cfg(all(feature = "log", feature = "std"))]
+#[test]
+fn test_log_records() {
+    use std::sync::Mutex;
+
+    static RECORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct Logger;
+
+    impl log::Log for Logger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            if record.target() == "close_fds" {
+                RECORDS.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    log::set_logger(&Logger).unwrap();
+    log::set_max_level(log::LevelFilter::Debug);
+
+    close_fds::FdSnapshot::take();
+
+    assert!(RECORDS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|record| record.starts_with("Found ") && record.contains(" open file descriptors")));
+}
+
+/// Install a seccomp filter that makes `close_range()` fail with `ENOSYS` in the current process
+/// (which should be a child created for the test), so that the fallbacks used on older kernels
+/// get tested. Returns `false` if the filter couldn't be installed.
+#[cfg(target_os = "linux")]
+unsafe fn block_close_range() -> bool {
+    let filter = [
+        libc::sock_filter {
+            code: (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16,
+            jt: 0,
+            jf: 0,
+            k: 0,
+        },
+        libc::sock_filter {
+            code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+            jt: 0,
+            jf: 1,
+            k: libc::SYS_close_range as u32,
+        },
+        libc::sock_filter {
+            code: (libc::BPF_RET | libc::BPF_K) as u16,
+            jt: 0,
+            jf: 0,
+            k: libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32,
+        },
+        libc::sock_filter {
+            code: (libc::BPF_RET | libc::BPF_K) as u16,
+            jt: 0,
+            jf: 0,
+            k: libc::SECCOMP_RET_ALLOW,
+        },
+    ];
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr() as *mut _,
+    };
+    libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == 0
+        && libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &prog) == 0
+}
+
+fn run_in_child(check: fn() -> libc::c_int) {
+    match unsafe { libc::fork() } {
+        0 => unsafe { libc::_exit(check()) },
+        ret if ret < 0 => panic!("Error fork()ing: {}", std::io::Error::last_os_error()),
+        pid => unsafe {
+            let mut stat = 0;
+
+            if libc::waitpid(pid, &mut stat, 0) < 0 {
+                panic!(
+                    "Error wait()ing for child: {}",
+                    std::io::Error::last_os_error()
+                )
+            }
+
+            assert!(libc::WIFEXITED(stat), "Process did not exit normally");
+            assert_eq!(
+                libc::WEXITSTATUS(stat),
+                0,
+                "Process exited with non-zero value"
+            );
+        },
+    }
+}
+
+#[test]
+fn run_assert_only_open_fds_tests() {
+    run_in_child(|| unsafe {
+        close_fds::close_open_fds(3, &[]);
+
+        if close_fds::assert_only_open_fds(&[0, 1, 2]).is_err()
+            || close_fds::assert_only_open_fds(&[2, 1, 0, 10]).is_err()
+        {
+            return 1;
+        }
+
+        match close_fds::assert_only_open_fds(&[0, 2]) {
+            Err(v) if v.fds() == [1] && v.count() == 1 && !v.is_truncated() => (),
+            _ => return 2,
+        }
+
+        for fd in 100..120 {
+            if libc::dup2(0, fd) != fd {
+                return 3;
+            }
+        }
+
+        match close_fds::assert_only_open_fds(&[0, 1, 2, 105]) {
+            Err(v)
+                if v.fds().len() == close_fds::MAX_RECORDED_VIOLATIONS
+                    && v.fds()[..6] == [100, 101, 102, 103, 104, 106]
+                    && v.count() == 19
+                    && v.is_truncated() =>
+            {
+                if v.to_string()
+                    != "File descriptors 100, 101, 102, 103, 104, 106, 107, 108, 109, 110, 111, \
+                        112, 113, 114, 115, 116 (and 3 more)"
+                {
+                    return 5;
+                }
+            }
+            _ => return 4,
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_verify_all_cloexec_tests() {
+    run_in_child(|| {
+        let fd1 = unsafe { libc::dup2(0, 600) };
+        let fd2 = unsafe { libc::dup2(0, 601) };
+        if fd1 != 600 || fd2 != 601 {
+            return 1;
+        }
+
+        close_fds::CloseFdsBuilder::new().cloexecfrom(3);
+        if close_fds::verify_all_cloexec(3, &[]).is_err() {
+            return 2;
+        }
+
+        set_fd_cloexec(fd1, false);
+        set_fd_cloexec(fd2, false);
+        match close_fds::verify_all_cloexec(3, &[fd2]) {
+            Err(v) if v.fds() == [fd1] && v.count() == 1 => (),
+            _ => return 3,
+        }
+        match close_fds::verify_all_cloexec(fd2, &[]) {
+            Err(v) if v.fds() == [fd2] => (),
+            _ => return 4,
+        }
+        if close_fds::verify_all_cloexec(3, &[fd1, fd2]).is_err() {
+            return 5;
+        }
+
+        0
+    });
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn run_close_new_fds_since_tests() {
+    run_in_child(|| unsafe {
+        for fd in 100..102 {
+            if libc::dup2(0, fd) != fd {
+                return 1;
+            }
+        }
+
+        let snapshot = close_fds::FdSnapshot::take();
+
+        for fd in 102..105 {
+            if libc::dup2(0, fd) != fd {
+                return 2;
+            }
+        }
+        // Closed and reopened since the snapshot
+        libc::close(101);
+        if libc::dup2(0, 101) != 101 {
+            return 3;
+        }
+
+        if close_fds::CloseFdsBuilder::new()
+            .keep_fds(&[103])
+            .close_new_fds_since(&snapshot, 3)
+            .is_err()
+        {
+            return 7;
+        }
+
+        if !is_fd_open(100) || !is_fd_open(101) {
+            return 4;
+        }
+        if is_fd_open(102) || !is_fd_open(103) || is_fd_open(104) {
+            return 5;
+        }
+        if !(0..3).all(is_fd_open) {
+            return 6;
+        }
+
+        // There's no room for the snapshot, so nothing is closed
+        if libc::dup2(0, 104) != 104 {
+            return 8;
+        }
+        let mut builder = close_fds::CloseFdsBuilder::new();
+        for _ in 0..4 {
+            builder.also_keep(&[]).unwrap();
+        }
+        if builder.close_new_fds_since(&snapshot, 3) != Err(close_fds::TooManyKeepLists)
+            || !is_fd_open(104)
+        {
+            return 9;
+        }
+
+        0
+    });
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn run_receive_listen_fds_tests() {
+    use std::os::unix::io::IntoRawFd;
+
+    run_in_child(|| unsafe {
+        for &fd in [3, 4, 100].iter() {
+            if libc::dup2(0, fd) != fd {
+                return 1;
+            }
+            set_fd_cloexec(fd, false);
+        }
+
+        // Malformed; nothing is closed
+        std::env::set_var("LISTEN_PID", libc::getpid().to_string());
+        std::env::set_var("LISTEN_FDS", "x");
+        if close_fds::receive_listen_fds(
+            &close_fds::CloseFdsBuilder::new(),
+            close_fds::FdAction::Close,
+        )
+        .is_ok()
+            || std::env::var_os("LISTEN_PID").is_some()
+            || !is_fd_open(100)
+        {
+            return 2;
+        }
+
+        std::env::set_var("LISTEN_PID", libc::getpid().to_string());
+        std::env::set_var("LISTEN_FDS", "2");
+        std::env::set_var("LISTEN_FDNAMES", "a:b");
+        let fds: Vec<libc::c_int> = match close_fds::receive_listen_fds(
+            &close_fds::CloseFdsBuilder::new(),
+            close_fds::FdAction::Close,
+        ) {
+            Ok(fds) => fds.into_iter().map(|fd| fd.into_raw_fd()).collect(),
+            Err(_) => return 3,
+        };
+        if fds != [3, 4] || is_fd_cloexec(3) != Some(true) || is_fd_cloexec(4) != Some(true) {
+            return 4;
+        }
+        if is_fd_open(100) {
+            return 5;
+        }
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"].iter() {
+            if std::env::var_os(name).is_some() {
+                return 6;
+            }
+        }
+
+        // For a different process; the file descriptors are marked close-on-exec
+        std::env::set_var("LISTEN_PID", "1");
+        std::env::set_var("LISTEN_FDS", "2");
+        set_fd_cloexec(4, false);
+        match close_fds::receive_listen_fds(
+            close_fds::CloseFdsBuilder::new().keep_fds(&[3]),
+            close_fds::FdAction::Cloexec,
+        ) {
+            Ok(fds) if fds.is_empty() => (),
+            _ => return 7,
+        }
+        if is_fd_cloexec(4) != Some(true) || std::env::var_os("LISTEN_PID").is_some() {
+            return 8;
+        }
+
+        // A passed file descriptor isn't open
+        libc::close(4);
+        std::env::set_var("LISTEN_PID", libc::getpid().to_string());
+        std::env::set_var("LISTEN_FDS", "2");
+        match close_fds::receive_listen_fds(
+            &close_fds::CloseFdsBuilder::new(),
+            close_fds::FdAction::Close,
+        ) {
+            Err(e) if e.raw_os_error() == Some(libc::EBADF) => (),
+            _ => return 9,
+        }
+        if !is_fd_open(3) {
+            return 10;
+        }
+
+        0
+    });
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn run_harden_setuid_startup_tests() {
+    run_in_child(|| unsafe {
+        for fd in [100, 101, 102] {
+            if libc::dup2(0, fd) != fd {
+                return 1;
+            }
+        }
+        set_fd_cloexec(101, true);
+        libc::close(1);
+
+        let report = match close_fds::harden_setuid_startup(&[102, 101, 110], true) {
+            Ok(report) => report,
+            Err(_) => return 2,
+        };
+
+        if report.reopened_std_fds != [1] || report.kept_fds != [101, 102] {
+            return 3;
+        }
+        if !report.closed_fds.iter().any(|v| v.fd == 100)
+            || report.closed_fds.iter().any(|v| v.fd < 3 || v.fd == 101)
+        {
+            return 4;
+        }
+
+        if is_fd_cloexec(100).is_some()
+            || is_fd_cloexec(1).is_none()
+            || is_fd_cloexec(101) != Some(false)
+            || is_fd_cloexec(102) != Some(false)
+        {
+            return 5;
+        }
+        if close_fds::assert_only_open_fds(&[0, 1, 2, 101, 102]).is_err() {
+            return 6;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_raise_fd_limit_tests() {
+    run_in_child(|| unsafe {
+        let mut rlim = std::mem::zeroed::<libc::rlimit>();
+        if libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) != 0 {
+            return 1;
+        }
+        let hard = if rlim.rlim_max == libc::RLIM_INFINITY {
+            None
+        } else {
+            Some(rlim.rlim_max as u64)
+        };
+        if matches!(hard, Some(hard) if hard < 512) {
+            // Not enough room to test
+            return 0;
+        }
+
+        rlim.rlim_cur = 256;
+        if libc::setrlimit(libc::RLIMIT_NOFILE, &rlim) != 0 {
+            return 1;
+        }
+
+        if close_fds::raise_fd_limit(Some(512)) != Ok((Some(256), Some(512))) {
+            return 2;
+        }
+        // Never lowered
+        if close_fds::raise_fd_limit(Some(300)) != Ok((Some(512), Some(512))) {
+            return 3;
+        }
+        if close_fds::fd_usage().soft_limit != Some(512) {
+            return 4;
+        }
+
+        match close_fds::raise_fd_limit(None) {
+            Ok((Some(512), new)) if cfg!(target_vendor = "apple") || new == hard => (),
+            _ => return 5,
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_closefrom_and_limit_tests() {
+    run_in_child(|| unsafe {
+        if libc::dup2(0, 150) != 150 {
+            return 1;
+        }
+
+        let old_hard = close_fds::fd_usage().hard_limit;
+
+        if close_fds::CloseFdsBuilder::new()
+            .keep_fds(&[150])
+            .closefrom_and_limit(3, 2, false)
+            .is_err()
+        {
+            return 2;
+        }
+
+        let usage = close_fds::fd_usage();
+        if usage.soft_limit != Some(5) || usage.hard_limit != old_hard {
+            return 3;
+        }
+
+        for &expected in [3, 4].iter() {
+            if libc::open("/\0".as_ptr() as *const libc::c_char, libc::O_RDONLY) != expected {
+                return 4;
+            }
+        }
+        if libc::open("/\0".as_ptr() as *const libc::c_char, libc::O_RDONLY) >= 0
+            || std::io::Error::last_os_error().raw_os_error() != Some(libc::EMFILE)
+        {
+            return 5;
+        }
+        // Still usable
+        if is_fd_cloexec(150).is_none() {
+            return 6;
+        }
+
+        if close_fds::CloseFdsBuilder::new()
+            .keep_fds(&[150])
+            .closefrom_and_limit(3, 1, true)
+            .is_err()
+        {
+            return 7;
+        }
+        let usage = close_fds::fd_usage();
+        if usage.soft_limit != Some(4) || usage.hard_limit != Some(4) || is_fd_cloexec(3).is_some()
+        {
+            return 8;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_closefrom_and_cloexec_kept_tests() {
+    run_in_child(|| unsafe {
+        for fd in 100..105 {
+            if libc::dup2(0, fd) != fd {
+                return 1;
+            }
+            set_fd_cloexec(fd, false);
+        }
+
+        close_fds::CloseFdsBuilder::new()
+            .keep_fds(&[102, 104])
+            .also_keep(&[1, 101])
+            .unwrap()
+            .closefrom_and_cloexec_kept(101);
+
+        // Below minfd, so left alone
+        if is_fd_cloexec(100) != Some(false) {
+            return 2;
+        }
+        if is_fd_cloexec(101) != Some(true)
+            || is_fd_cloexec(102) != Some(true)
+            || is_fd_cloexec(103).is_some()
+            || is_fd_cloexec(104) != Some(true)
+        {
+            return 3;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_make_kept_inheritable_tests() {
+    run_in_child(|| unsafe {
+        for &close in [true, false].iter() {
+            for fd in 100..105 {
+                if libc::dup2(0, fd) != fd {
+                    return 1;
+                }
+                set_fd_cloexec(fd, true);
+            }
+
+            let mut builder = close_fds::CloseFdsBuilder::new();
+            builder
+                .keep_fds(&[100, 102])
+                .also_keep(&[104, 110])
+                .unwrap()
+                .make_kept_inheritable(true);
+            if close {
+                builder.closefrom(101);
+            } else {
+                builder.cloexecfrom(101);
+            }
+
+            // Below minfd, so left alone
+            if is_fd_cloexec(100) != Some(true) {
+                return 2;
+            }
+            if is_fd_cloexec(102) != Some(false) || is_fd_cloexec(104) != Some(false) {
+                return 3;
+            }
+            let expected = if close { None } else { Some(true) };
+            if is_fd_cloexec(101) != expected || is_fd_cloexec(103) != expected {
+                return 4;
+            }
+        }
+
+        // closefrom_and_cloexec_kept() ignores it
+        close_fds::CloseFdsBuilder::new()
+            .keep_fds(&[102])
+            .make_kept_inheritable(true)
+            .closefrom_and_cloexec_kept(101);
+        if is_fd_cloexec(102) != Some(true) {
+            return 5;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_close_fds_from_env_tests() {
+    run_in_child(|| unsafe {
+        for fd in 100..106 {
+            if libc::dup2(0, fd) != fd {
+                return 1;
+            }
+        }
+
+        let name = std::ffi::CStr::from_bytes_with_nul(b"CLOSE_FDS_TEST_ENV_KEEP\0").unwrap();
+        if close_fds::close_fds_from_env(name, 101) != Ok(false) || !(100..106).all(is_fd_open) {
+            return 2;
+        }
+
+        let mut keep_fds = close_fds::KeepFdsBuf::<4>::new();
+        keep_fds.extend_from_spec(b"1,102-103,105").unwrap();
+        let mut entry = [0u8; 64];
+        let entry = match keep_fds.format_env(name, &mut entry) {
+            Some(entry) => entry,
+            None => return 3,
+        };
+        if entry.to_bytes() != b"CLOSE_FDS_TEST_ENV_KEEP=1,102-103,105"
+            || libc::putenv(entry.as_ptr() as *mut _) != 0
+        {
+            return 4;
+        }
+
+        if close_fds::close_fds_from_env(name, 101) != Ok(true) {
+            return 5;
+        }
+        if !is_fd_open(100)
+            || is_fd_open(101)
+            || !is_fd_open(102)
+            || !is_fd_open(103)
+            || is_fd_open(104)
+            || !is_fd_open(105)
+        {
+            return 6;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn test_recommended_action() {
+    assert_eq!(
+        close_fds::recommended_action(),
+        if cfg!(target_vendor = "apple") {
+            close_fds::FdAction::Cloexec
+        } else {
+            close_fds::FdAction::Close
+        }
+    );
+
+    run_in_child(|| unsafe {
+        if libc::dup2(0, 120) != 120 {
+            return 1;
+        }
+        set_fd_cloexec(120, false);
+
+        close_fds::FdAction::Cloexec.apply(&close_fds::CloseFdsBuilder::new(), 120);
+        if is_fd_cloexec(120) != Some(true) {
+            return 2;
+        }
+
+        close_fds::FdAction::Close.apply(&close_fds::CloseFdsBuilder::new(), 120);
+        if is_fd_cloexec(120).is_some() {
+            return 3;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_close_or_cloexec_open_fds_tests() {
+    run_in_child(|| unsafe {
+        for fd in [110, 111] {
+            if libc::dup2(0, fd) != fd {
+                return 1;
+            }
+            set_fd_cloexec(fd, false);
+        }
+
+        close_fds::close_or_cloexec_open_fds(110, &[111]);
+
+        let expected = if cfg!(target_vendor = "apple") {
+            Some(true)
+        } else {
+            None
+        };
+        if is_fd_cloexec(110) != expected || is_fd_cloexec(111) != Some(false) {
+            return 2;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_closefrom_with_token_tests() {
+    run_in_child(|| {
+        let token = unsafe { close_fds::PreExecToken::post_fork() };
+
+        for fd in [100, 101] {
+            if unsafe { libc::dup2(0, fd) } != fd {
+                return 1;
+            }
+        }
+
+        close_fds::CloseFdsBuilder::new()
+            .keep_fds(&[101])
+            .closefrom_with(token, 100);
+
+        if is_fd_cloexec(100).is_some() || is_fd_cloexec(101).is_none() {
+            return 2;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_fd_dir_cache_tests() {
+    run_in_child(|| {
+        fn check_open(cache: &mut close_fds::FdDirCache) -> bool {
+            let mut fds = [-1; 3];
+            let mut n = 0;
+            for fd in close_fds::FdIterBuilder::new().iter_from_cached(cache, 120) {
+                if fd < 130 && n < fds.len() {
+                    fds[n] = fd;
+                    n += 1;
+                }
+            }
+            fds[..n] == [120, 121]
+        }
+
+        for fd in [120, 121] {
+            if unsafe { libc::dup2(0, fd) } != fd {
+                return 1;
+            }
+            set_fd_cloexec(fd, false);
+        }
+
+        let mut cache = close_fds::FdDirCache::new();
+        for _ in 0..2 {
+            if !check_open(&mut cache) {
+                return 2;
+            }
+        }
+
+        close_fds::CloseFdsBuilder::new()
+            .keep_fds(&[121])
+            .cloexecfrom_cached(&mut cache, 120);
+        if is_fd_cloexec(120) != Some(true) || is_fd_cloexec(121) != Some(false) {
+            return 3;
+        }
+
+        // The cache should notice that it's in a different process, and not interfere with the
+        // parent's copy
+        match unsafe { libc::fork() } {
+            0 => unsafe { libc::_exit(if check_open(&mut cache) { 0 } else { 1 }) },
+            pid if pid > 0 => {
+                let mut stat = 0;
+                if unsafe { libc::waitpid(pid, &mut stat, 0) } != pid
+                    || !libc::WIFEXITED(stat)
+                    || libc::WEXITSTATUS(stat) != 0
+                {
+                    return 4;
+                }
+            }
+            _ => return 5,
+        }
+
+        if !check_open(&mut cache) {
+            return 6;
+        }
+
+        cache.invalidate();
+        if cache.as_raw_fd().is_some() {
+            return 7;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_close_stdio_only_tests() {
+    run_in_child(|| unsafe {
+        for fd in [3, 130, 131] {
+            if libc::dup2(0, fd) != fd {
+                return 1;
+            }
+        }
+
+        close_fds::close_open_fds(0, &[0, 1, 2]);
+
+        if is_fd_cloexec(0).is_none() || is_fd_cloexec(1).is_none() || is_fd_cloexec(2).is_none() {
+            return 2;
+        }
+        if is_fd_cloexec(3).is_some()
+            || is_fd_cloexec(130).is_some()
+            || is_fd_cloexec(131).is_some()
+        {
+            return 3;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn test_fditer_send() {
+    run_in_child(|| {
+        let fds: Vec<_> = close_fds::iter_open_fds(0).collect();
+        if !fds.contains(&0) {
+            return 1;
+        }
+
+        let fditer = close_fds::iter_open_fds(0);
+        match std::thread::spawn(move || fditer.collect::<Vec<_>>()).join() {
+            Ok(fds) if fds.contains(&0) => 0,
+            _ => 2,
+        }
+    });
+}
+
+#[test]
+fn run_fditer_plan_tests() {
+    run_in_child(|| {
+        let plan = close_fds::FdIterBuilder::new().plan(140);
+        if plan.minfd() != 140 {
+            return 1;
+        }
+
+        if unsafe { libc::dup2(0, 140) } != 140 {
+            return 2;
+        }
+
+        // Started after the file descriptor was opened, so it's included
+        match unsafe { libc::fork() } {
+            0 => {
+                let mut it = plan.start();
+                let ok = it.next() == Some(140) && it.all(|fd| fd > 140);
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+            }
+            pid if pid > 0 => {
+                let mut stat = 0;
+                if unsafe { libc::waitpid(pid, &mut stat, 0) } != pid
+                    || !libc::WIFEXITED(stat)
+                    || libc::WEXITSTATUS(stat) != 0
+                {
+                    return 3;
+                }
+            }
+            _ => return 4,
+        }
+
+        if plan.start().next() != Some(140) {
+            return 5;
+        }
+
+        0
+    });
+}
+
+#[cfg(all(
+    target_os = "linux",
+    not(any(feature = "no-fs-backends", feature = "libc-wrappers-only"))
+))]
+#[test]
+fn run_shared_fd_table_tests() {
+    use close_fds::SharedFdTablePolicy;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static WARNED: AtomicBool = AtomicBool::new(false);
+
+    run_in_child(|| unsafe {
+        // This may be None if some processes can't be compared (e.g. ones owned by other users)
+        if close_fds::is_fd_table_shared() == Some(true) {
+            return 1;
+        }
+
+        // Like fork(), but the child shares our file descriptor table
+        let pid = libc::syscall(
+            libc::SYS_clone,
+            libc::CLONE_FILES | libc::SIGCHLD,
+            0usize,
+            0usize,
+            0usize,
+            0usize,
+        ) as libc::pid_t;
+        if pid == 0 {
+            loop {
+                libc::pause();
+            }
+        } else if pid < 0 {
+            return 2;
+        }
+
+        let ret = (|| {
+            match close_fds::is_fd_table_shared() {
+                Some(true) => (),
+                // kcmp() isn't available
+                None => return 0,
+                Some(false) => return 3,
+            }
+
+            let mut builder = close_fds::CloseFdsBuilder::new();
+
+            if libc::dup2(0, 100) != 100 {
+                return 4;
+            }
+            builder
+                .shared_fd_table(SharedFdTablePolicy::Refuse)
+                .closefrom(3);
+            if !is_fd_open(100) {
+                return 5;
+            }
+
+            builder
+                .shared_fd_table(SharedFdTablePolicy::Warn(|| {
+                    WARNED.store(true, Ordering::SeqCst)
+                }))
+                .closefrom(3);
+            if is_fd_open(100) || !WARNED.load(Ordering::SeqCst) {
+                return 6;
+            }
+
+            if libc::dup2(0, 100) != 100 {
+                return 7;
+            }
+            builder
+                .shared_fd_table(SharedFdTablePolicy::Unshare)
+                .closefrom(3);
+            if is_fd_open(100) || close_fds::is_fd_table_shared() == Some(true) {
+                return 8;
+            }
+
+            0
+        })();
+
+        libc::kill(pid, libc::SIGKILL);
+        libc::waitpid(pid, core::ptr::null_mut(), 0);
+
+        ret
+    });
+}
+
+#[test]
+fn run_custom_closer_tests() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static RANGES: AtomicUsize = AtomicUsize::new(0);
+    static LISTED: AtomicUsize = AtomicUsize::new(0);
+
+    // Supports everything (but only looks at the first 1024 file descriptors)
+    struct RangeCloser;
+
+    impl close_fds::FdCloser for RangeCloser {
+        unsafe fn close_range(
+            &self,
+            low: libc::c_int,
+            high: libc::c_int,
+        ) -> Result<(), libc::c_int> {
+            RANGES.fetch_add(1, Ordering::SeqCst);
+            for fd in low..=high.min(1023) {
+                libc::close(fd);
+            }
+            Ok(())
+        }
+
+        fn cloexec_range(&self, low: libc::c_int, high: libc::c_int) -> Result<(), libc::c_int> {
+            RANGES.fetch_add(1, Ordering::SeqCst);
+            for fd in low..=high.min(1023) {
+                set_fd_cloexec(fd, true);
+            }
+            Ok(())
+        }
+    }
+
+    // Can only list file descriptors
+    struct ListCloser;
+
+    impl close_fds::FdCloser for ListCloser {
+        fn for_each_fd(
+            &self,
+            minfd: libc::c_int,
+            f: &mut dyn FnMut(libc::c_int) -> bool,
+        ) -> Result<(), libc::c_int> {
+            for fd in minfd..1024 {
+                if is_fd_open(fd) {
+                    LISTED.fetch_add(1, Ordering::SeqCst);
+                    if !f(fd) {
+                        break;
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    run_in_child(|| unsafe {
+        for fd in 100..105 {
+            if libc::dup2(0, fd) != fd {
+                return 1;
+            }
+            set_fd_cloexec(fd, false);
+        }
+
+        close_fds::CloseFdsBuilder::new()
+            .keep_fds(&[101, 103])
+            .closer(Some(&RangeCloser))
+            .cloexecfrom(3);
+        // [3, 100], [102, 102], and [104, MAX]
+        if RANGES.load(Ordering::SeqCst) != 3 {
+            return 2;
+        }
+        if is_fd_cloexec(100) != Some(true)
+            || is_fd_cloexec(101) != Some(false)
+            || is_fd_cloexec(102) != Some(true)
+            || is_fd_cloexec(103) != Some(false)
+            || is_fd_cloexec(104) != Some(true)
+        {
+            return 3;
+        }
+
+        close_fds::CloseFdsBuilder::new()
+            .keep_fds(&[103, 101])
+            .closer(Some(&ListCloser))
+            .closefrom(100);
+        if LISTED.load(Ordering::SeqCst) != 5 {
+            return 4;
+        }
+        if is_fd_open(100) || !is_fd_open(101) || is_fd_open(102) || !is_fd_open(103) {
+            return 5;
+        }
+        if is_fd_open(104) {
+            return 6;
+        }
+
+        RANGES.store(0, Ordering::SeqCst);
+        close_fds::CloseFdsBuilder::new()
+            .keep_fds(&[101])
+            .closer(Some(&RangeCloser))
+            .closefrom(3);
+        if RANGES.load(Ordering::SeqCst) != 2 || !is_fd_open(101) || is_fd_open(103) {
+            return 7;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_close_range_tests() {
+    use close_fds::{close_range, CloseRangeError, CloseRangeFlag};
+
+    run_in_child(|| unsafe {
+        for fd in 100..=103 {
+            libc::dup2(0, fd);
+        }
+
+        if close_range(102, 101, &[]) != Err(CloseRangeError::Os(libc::EINVAL)) {
+            return 1;
+        }
+        if !is_fd_open(101) || !is_fd_open(102) {
+            return 2;
+        }
+
+        match close_range(100, 101, &[CloseRangeFlag::Cloexec]) {
+            Ok(()) => {
+                if is_fd_cloexec(100) != Some(true)
+                    || is_fd_cloexec(101) != Some(true)
+                    || is_fd_cloexec(102) != Some(false)
+                {
+                    return 3;
+                }
+            }
+            Err(CloseRangeError::Unsupported) => (),
+            Err(_) => return 4,
+        }
+
+        match close_range(101, 102, &[]) {
+            Ok(()) => {
+                if !is_fd_open(100) || is_fd_open(101) || is_fd_open(102) || !is_fd_open(103) {
+                    return 5;
+                }
+            }
+            Err(CloseRangeError::Unsupported) => {
+                if !is_fd_open(101) || !is_fd_open(102) {
+                    return 6;
+                }
+            }
+            Err(_) => return 7,
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_closefrom_tests() {
+    run_in_child(|| unsafe {
+        for fd in 100..=103 {
+            libc::dup2(0, fd);
+        }
+
+        close_fds::closefrom(102);
+        if !is_fd_open(100) || !is_fd_open(101) || is_fd_open(102) || is_fd_open(103) {
+            return 1;
+        }
+
+        close_fds::closefrom(3);
+        if close_fds::iter_open_fds(0).collect::<Vec<libc::c_int>>() != [0, 1, 2] {
+            return 2;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_syscall_counter_tests() {
+    use close_fds::SyscallCounter;
+
+    static COUNTER: SyscallCounter = SyscallCounter::new();
+
+    run_in_child(|| unsafe {
+        for fd in 100..=102 {
+            libc::dup2(0, fd);
+        }
+
+        // Iterating without the filesystem has to check each file descriptor with fcntl()
+        let nfds = close_fds::FdIterBuilder::new()
+            .allow_filesystem(false)
+            .syscall_counter(Some(&COUNTER))
+            .iter_from(100)
+            .count();
+        let counts = COUNTER.reset();
+        if nfds != 3 || counts.fcntl < 3 || counts.close != 0 || counts.total() != counts.fcntl {
+            return 1;
+        }
+
+        close_fds::CloseFdsBuilder::new()
+            .keep_fds(&[100])
+            .allow_filesystem(false)
+            .syscall_counter(Some(&COUNTER))
+            .cloexecfrom(100);
+        let counts = COUNTER.reset();
+        // Either close_range(CLOSE_RANGE_CLOEXEC) was used, or each file descriptor was checked
+        if counts.close_range == 0 && counts.fcntl < 3 {
+            return 2;
+        }
+
+        close_fds::CloseFdsBuilder::new()
+            .syscall_counter(Some(&COUNTER))
+            .closefrom(3);
+        let counts = COUNTER.reset();
+        if counts.close_range == 0 && counts.closefrom == 0 && counts.close < 3 {
+            return 3;
+        }
+        if is_fd_open(100) || is_fd_open(101) || is_fd_open(102) {
+            return 4;
+        }
+
+        // Nothing is counted without a counter
+        libc::dup2(0, 100);
+        close_fds::closefrom(3);
+        if COUNTER.get() != Default::default() {
+            return 5;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_selftest_tests() {
+    use close_fds::SelftestOutcome;
+
+    let report = close_fds::selftest().unwrap();
+    assert_eq!(report.close_fds, SelftestOutcome::Works);
+    assert_eq!(report.cloexec_fds, SelftestOutcome::Works);
+    assert_eq!(report.maxfd_iter, SelftestOutcome::Works);
+    assert_ne!(report.dirfd_iter, SelftestOutcome::Broken);
+    assert_ne!(report.close_range, SelftestOutcome::Broken);
+    assert_ne!(report.close_range_cloexec, SelftestOutcome::Broken);
+    assert_ne!(report.closefrom, SelftestOutcome::Broken);
+    assert!(report.is_ok());
+
+    // The checks run in a child process, so this process's file descriptors are untouched
+    run_in_child(|| unsafe {
+        libc::dup2(0, 100);
+        if close_fds::selftest().map(|r| r.is_ok()) != Ok(true) {
+            return 1;
+        }
+        if !is_fd_open(100) || is_fd_cloexec(100) != Some(false) {
+            return 2;
+        }
+
+        0
+    });
+}
+
+#[test]
+fn run_juggle_no_free_slots_tests() {
+    // There are no free slots near minfd, so juggling would leave the copies far above it (and
+    // the normal method should be used instead)
+    run_in_child(|| unsafe {
+        for fd in 100..300 {
+            if libc::dup2(0, fd) != fd {
+                return 1;
+            }
+        }
+        set_fd_cloexec(299, true);
+
+        close_fds::CloseFdsBuilder::new()
+            .juggle_keep_fds(true)
+            .keep_fds(&[0, 1, 2, 299])
+            .closefrom(100);
+
+        if close_fds::iter_open_fds(100).collect::<Vec<_>>() != [299] {
+            return 2;
+        }
+        if is_fd_cloexec(299) != Some(true) {
+            return 3;
+        }
+
+        0
+    });
+}
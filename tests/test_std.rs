@@ -0,0 +1,1028 @@
+#![cfg(feature = "std")]
+
+use std::io::Read;
+use std::os::unix::prelude::*;
+use std::process::Command;
+
+fn pipe() -> (std::fs::File, std::fs::File) {
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    unsafe {
+        (
+            std::fs::File::from_raw_fd(fds[0]),
+            std::fs::File::from_raw_fd(fds[1]),
+        )
+    }
+}
+
+#[test]
+fn test_pre_exec_fn_with_mappings() {
+    let (mut r1, w1) = pipe();
+    let (mut r2, w2) = pipe();
+
+    let mut cmd = Command::new("sh");
+    // Move the two pipes' write ends to 7 and 8. Either of those may already be open (possibly as
+    // the other mapping's parent_fd), and the mappings have to be applied without clobbering it.
+    cmd.args(["-c", "echo a >&7; echo b >&8"]);
+
+    let f = close_fds::CloseFdsBuilder::new()
+        .pre_exec_fn_with_mappings(
+            3,
+            &[
+                close_fds::FdMapping {
+                    parent_fd: w1.as_raw_fd(),
+                    child_fd: 7,
+                },
+                close_fds::FdMapping {
+                    parent_fd: w2.as_raw_fd(),
+                    child_fd: 8,
+                },
+            ],
+        )
+        .unwrap();
+    unsafe {
+        cmd.pre_exec(f);
+    }
+
+    assert!(cmd.status().unwrap().success());
+    drop(w1);
+    drop(w2);
+
+    let mut s = String::new();
+    r1.read_to_string(&mut s).unwrap();
+    assert_eq!(s, "a\n");
+    s.clear();
+    r2.read_to_string(&mut s).unwrap();
+    assert_eq!(s, "b\n");
+}
+
+#[test]
+fn test_pre_exec_fn_with_mappings_exec_error() {
+    // The temporary file descriptors used to apply the mappings must not clobber the pipe that
+    // the standard library uses to report exec() errors (which is usually opened right above the
+    // highest file descriptor being mapped).
+    let pipes: Vec<_> = (0..4).map(|_| pipe()).collect();
+    let parent_fd = pipes[3].1.as_raw_fd();
+
+    let mappings: Vec<_> = (3..7)
+        .map(|child_fd| close_fds::FdMapping {
+            parent_fd,
+            child_fd,
+        })
+        .collect();
+
+    let mut cmd = Command::new("/nonexistent/close_fds_test");
+    let f = close_fds::CloseFdsBuilder::new()
+        .pre_exec_fn_with_mappings(3, &mappings)
+        .unwrap();
+    unsafe {
+        cmd.pre_exec(f);
+    }
+
+    assert_eq!(
+        cmd.spawn().unwrap_err().kind(),
+        std::io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn test_pre_exec_fn_with_mappings_collision() {
+    let builder = close_fds::CloseFdsBuilder::new();
+
+    assert!(builder
+        .pre_exec_fn_with_mappings(
+            3,
+            &[
+                close_fds::FdMapping {
+                    parent_fd: 0,
+                    child_fd: 5,
+                },
+                close_fds::FdMapping {
+                    parent_fd: 1,
+                    child_fd: 5,
+                },
+            ],
+        )
+        .is_err());
+
+    assert!(builder
+        .pre_exec_fn_with_mappings(
+            3,
+            &[close_fds::FdMapping {
+                parent_fd: -1,
+                child_fd: 5,
+            }],
+        )
+        .is_err());
+}
+
+#[test]
+fn test_read_socket_info_addrs() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+    let it = close_fds::iter_open_fds_info(0);
+
+    let info = it.read_socket_info(listener.as_raw_fd()).unwrap();
+    assert_eq!(info.domain, libc::AF_INET);
+    assert_eq!(info.sock_type, libc::SOCK_STREAM);
+    assert_eq!(info.local_addr, Some(listener.local_addr().unwrap()));
+    assert_eq!(info.peer_addr, None);
+
+    let info = it.read_socket_info(client.as_raw_fd()).unwrap();
+    assert_eq!(info.local_addr, Some(client.local_addr().unwrap()));
+    assert_eq!(info.peer_addr, Some(listener.local_addr().unwrap()));
+    if let Some(protocol) = info.protocol {
+        assert_eq!(protocol, libc::IPPROTO_TCP);
+    }
+}
+
+#[test]
+fn test_dump_open_fds() {
+    let f = std::fs::File::open("/").unwrap();
+    let fd = f.as_raw_fd();
+
+    let mut out = Vec::new();
+    close_fds::dump_open_fds(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+
+    let mut lines = out.lines();
+    assert!(lines.next().unwrap().trim_start().starts_with("FD"));
+
+    let line = lines
+        .find(|line| line.split_whitespace().next() == Some(&fd.to_string()))
+        .unwrap();
+    let fields: Vec<_> = line.split_whitespace().collect();
+    assert_eq!(fields[1], "dir");
+    assert_eq!(fields[2], "yes");
+    // The path may not be available (for example, if /proc isn't mounted on Linux)
+    assert!(fields[3] == "/" || fields[3] == "-", "{:?}", fields[3]);
+}
+
+#[test]
+fn test_fd_audit_policy() {
+    use close_fds::FdKind;
+
+    let f = std::fs::File::open("/").unwrap();
+    let fd = f.as_raw_fd();
+
+    let mut policy = close_fds::FdAuditPolicy::new();
+    policy.allow_kinds(&[FdKind::File, FdKind::Fifo, FdKind::Socket]);
+
+    let violations = policy.audit();
+    let violation = violations.iter().find(|v| v.fd == fd).unwrap();
+    assert_eq!(violation.kind, FdKind::Dir);
+    assert_eq!(violation.kind.name(), "dir");
+    assert!(violation.cloexec);
+    // The path may not be available (for example, if /proc isn't mounted on Linux)
+    if let Some(path) = violation.path.as_ref() {
+        assert_eq!(path, std::path::Path::new("/"));
+    }
+    assert!(violations
+        .iter()
+        .all(|v| ![FdKind::File, FdKind::Fifo, FdKind::Socket].contains(&v.kind)));
+
+    assert!(!policy.allow_fds(&[fd]).audit().iter().any(|v| v.fd == fd));
+
+    policy = close_fds::FdAuditPolicy::new();
+    assert!(!policy
+        .allow_kinds(&[FdKind::Dir])
+        .audit()
+        .iter()
+        .any(|v| v.fd == fd));
+}
+
+fn send_fds(sock: libc::c_int, fds: &[libc::c_int]) {
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+
+    let datalen = std::mem::size_of_val(fds) as libc::c_uint;
+    let mut cmsgbuf = vec![0u64; unsafe { libc::CMSG_SPACE(datalen) } as usize / 8 + 1];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsgbuf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(datalen) } as _;
+
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(datalen) as _;
+        std::ptr::copy_nonoverlapping(
+            fds.as_ptr(),
+            libc::CMSG_DATA(cmsg) as *mut libc::c_int,
+            fds.len(),
+        );
+
+        assert_eq!(libc::sendmsg(sock, &msg, 0), 1);
+    }
+}
+
+#[test]
+fn test_drain_scm_rights() {
+    for &sotype in [libc::SOCK_STREAM, libc::SOCK_DGRAM].iter() {
+        let mut socks = [0; 2];
+        assert_eq!(
+            unsafe { libc::socketpair(libc::AF_UNIX, sotype, 0, socks.as_mut_ptr()) },
+            0
+        );
+
+        let (r, w) = pipe();
+        send_fds(socks[0], &[w.as_raw_fd()]);
+        send_fds(socks[0], &[w.as_raw_fd(), w.as_raw_fd()]);
+        drop(w);
+
+        assert_eq!(close_fds::drain_scm_rights(socks[1]).unwrap(), 3);
+        assert_eq!(close_fds::drain_scm_rights(socks[1]).unwrap(), 0);
+
+        // All of the write ends were closed, so reading should hit EOF
+        let mut buf = [0; 1];
+        assert_eq!((&r).read(&mut buf).unwrap(), 0);
+
+        unsafe {
+            libc::close(socks[0]);
+            libc::close(socks[1]);
+        }
+    }
+
+    let (r, _w) = pipe();
+    assert_eq!(
+        close_fds::drain_scm_rights(r.as_raw_fd())
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOTSOCK)
+    );
+}
+
+#[test]
+fn test_fd_snapshot() {
+    let f1 = std::fs::File::open("/").unwrap();
+    let snapshot = close_fds::FdSnapshot::take();
+    assert!(snapshot.contains(f1.as_raw_fd()));
+
+    let f2 = std::fs::File::open("/").unwrap();
+    assert!(!snapshot.contains(f2.as_raw_fd()));
+    assert!(snapshot.new_fds().contains(&f2.as_raw_fd()));
+
+    // Use a high file descriptor so it won't be reused by other tests running concurrently
+    let fd = unsafe { libc::dup2(f1.as_raw_fd(), 900) };
+    assert_eq!(fd, 900);
+    let snapshot = close_fds::FdSnapshot::take();
+    assert!(!snapshot.closed_fds().contains(&900));
+    unsafe {
+        libc::close(fd);
+    }
+    assert_eq!(snapshot.closed_fds(), [900]);
+}
+
+#[test]
+fn test_fd_guard() {
+    use std::sync::{Arc, Mutex};
+
+    let leaked = Arc::new(Mutex::new(Vec::new()));
+    let leaked2 = leaked.clone();
+    let guard = close_fds::FdGuard::with_callback(move |fds| leaked2.lock().unwrap().extend(fds));
+
+    let f = std::fs::File::open("/").unwrap();
+    assert!(guard.leaked_fds().contains(&f.as_raw_fd()));
+    drop(guard);
+    assert!(leaked.lock().unwrap().contains(&f.as_raw_fd()));
+
+    let guard = close_fds::FdGuard::new();
+    let f2 = std::fs::File::open("/").unwrap();
+    assert!(guard.finish().contains(&f2.as_raw_fd()));
+}
+
+#[test]
+fn test_fd_guard_panic() {
+    let res = std::panic::catch_unwind(|| {
+        let _guard = close_fds::FdGuard::new();
+        std::mem::forget(std::fs::File::open("/").unwrap());
+    });
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_assert_no_new_fds() {
+    let snapshot = close_fds::FdSnapshot::take();
+    let f = std::fs::File::open("/").unwrap();
+    let fd = f.as_raw_fd();
+
+    let msg = std::panic::catch_unwind(|| close_fds::assert_no_new_fds(&snapshot))
+        .unwrap_err()
+        .downcast::<String>()
+        .unwrap();
+    assert!(msg.starts_with("leaked file descriptors:\n"), "{}", msg);
+    assert!(
+        msg.lines()
+            .any(|line| line.starts_with(&format!("  {} (dir, cloexec)", fd))),
+        "{}",
+        msg
+    );
+    #[cfg(target_os = "linux")]
+    assert!(
+        msg.lines()
+            .any(|line| line == format!("  {} (dir, cloexec): /", fd)),
+        "{}",
+        msg
+    );
+
+    drop(f);
+}
+
+#[test]
+fn test_expect_fds_closed() {
+    let f = std::fs::File::open("/").unwrap();
+    let fd = f.as_raw_fd();
+
+    let msg = std::panic::catch_unwind(|| close_fds::expect_fds_closed(&[fd, fd]))
+        .unwrap_err()
+        .downcast::<String>()
+        .unwrap();
+    assert_eq!(msg.lines().count(), 2, "{}", msg);
+    assert!(
+        msg.lines()
+            .nth(1)
+            .unwrap()
+            .starts_with(&format!("  {} (dir", fd)),
+        "{}",
+        msg
+    );
+
+    drop(f);
+    close_fds::expect_fds_closed(&[]);
+}
+
+#[test]
+fn test_fd_monitor() {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let monitor = close_fds::FdMonitorBuilder::new()
+        .interval(std::time::Duration::from_millis(10))
+        .threshold(0)
+        .spawn(move |event| {
+            let _ = tx.send(event);
+        })
+        .unwrap();
+
+    match rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap() {
+        close_fds::FdMonitorEvent::ThresholdExceeded { count } => assert!(count > 0),
+        event => panic!("unexpected event {:?}", event),
+    }
+
+    monitor.stop();
+}
+
+fn wait_for_exit(pid: libc::pid_t) -> libc::c_int {
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFEXITED(status));
+    libc::WEXITSTATUS(status)
+}
+
+fn sh_argv(script: &[u8]) -> [std::ffi::CString; 3] {
+    [
+        std::ffi::CString::new("sh").unwrap(),
+        std::ffi::CString::new("-c").unwrap(),
+        std::ffi::CString::new(script).unwrap(),
+    ]
+}
+
+type SpawnFn = fn(
+    &std::ffi::CStr,
+    &[&std::ffi::CStr],
+    Option<&[&std::ffi::CStr]>,
+    libc::c_int,
+    &close_fds::CloseFdsBuilder,
+) -> std::io::Result<libc::pid_t>;
+
+#[test]
+fn test_spawn_with_closed_fds() {
+    test_spawn_generic(close_fds::spawn_with_closed_fds);
+    test_spawn_generic(close_fds::spawn_with_closed_fds_vfork);
+    test_spawn_generic(close_fds::posix_spawn_with_closed_fds);
+}
+
+fn test_spawn_generic(spawn: SpawnFn) {
+    use std::ffi::{CStr, CString};
+
+    let sh = CString::new("/bin/sh").unwrap();
+    let builder = close_fds::CloseFdsBuilder::new();
+
+    let argv = sh_argv(b"exit 3");
+    let argv: Vec<&CStr> = argv.iter().map(|s| s.as_c_str()).collect();
+    let pid = spawn(&sh, &argv, None, 3, &builder).unwrap();
+    assert_eq!(wait_for_exit(pid), 3);
+
+    let argv = sh_argv(b"[ \"$FOO\" = bar ]");
+    let argv: Vec<&CStr> = argv.iter().map(|s| s.as_c_str()).collect();
+    let envp = [CString::new("FOO=bar").unwrap()];
+    let envp: Vec<&CStr> = envp.iter().map(|s| s.as_c_str()).collect();
+    let pid = spawn(&sh, &argv, Some(&envp), 3, &builder).unwrap();
+    assert_eq!(wait_for_exit(pid), 0);
+
+    let nonexistent = CString::new("/nonexistent").unwrap();
+    assert_eq!(
+        spawn(&nonexistent, &[&nonexistent], None, 3, &builder)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOENT)
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_spawn_with_listen_fds() {
+    use std::ffi::{CStr, CString};
+    use std::io::Read;
+
+    // Use a high file descriptor so it won't be reused by other tests running concurrently
+    let f = std::fs::File::open("/").unwrap();
+    assert_eq!(unsafe { libc::dup2(f.as_raw_fd(), 910) }, 910);
+    drop(f);
+
+    let (mut r1, w1) = pipe();
+    let (mut r2, w2) = pipe();
+
+    let sh = CString::new("/bin/sh").unwrap();
+    let argv = sh_argv(
+        b"[ \"$LISTEN_FDS\" = 3 ] && [ \"$LISTEN_PID\" = $$ ] && [ -z \"${LISTEN_FDNAMES+x}\" ] \
+          && [ \"$FOO\" = bar ] && [ ! -e /proc/self/fd/910 ] \
+          && echo a >&3 && echo b >&4 && echo c >&5",
+    );
+    let argv: Vec<&CStr> = argv.iter().map(|s| s.as_c_str()).collect();
+    let envp = [
+        CString::new("FOO=bar").unwrap(),
+        CString::new("LISTEN_PID=1").unwrap(),
+        CString::new("LISTEN_FDNAMES=stale").unwrap(),
+    ];
+    let envp: Vec<&CStr> = envp.iter().map(|s| s.as_c_str()).collect();
+
+    let pid = close_fds::spawn_with_listen_fds(
+        &sh,
+        &argv,
+        Some(&envp),
+        &[w2.as_raw_fd(), w1.as_raw_fd(), w2.as_raw_fd()],
+        &close_fds::CloseFdsBuilder::new(),
+    )
+    .unwrap();
+    drop(w1);
+    drop(w2);
+    unsafe {
+        libc::close(910);
+    }
+    assert_eq!(wait_for_exit(pid), 0);
+
+    let mut buf = String::new();
+    r1.read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "b\n");
+    buf.clear();
+    r2.read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "a\nc\n");
+
+    assert_eq!(
+        close_fds::spawn_with_listen_fds(
+            &sh,
+            &argv,
+            None,
+            &[-1],
+            &close_fds::CloseFdsBuilder::new()
+        )
+        .unwrap_err()
+        .raw_os_error(),
+        Some(libc::EBADF)
+    );
+
+    let nonexistent = CString::new("/nonexistent").unwrap();
+    assert_eq!(
+        close_fds::spawn_with_listen_fds(
+            &nonexistent,
+            &[&nonexistent],
+            None,
+            &[0],
+            &close_fds::CloseFdsBuilder::new()
+        )
+        .unwrap_err()
+        .raw_os_error(),
+        Some(libc::ENOENT)
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_spawn_daemon_with_closed_fds() {
+    use std::ffi::{CStr, CString};
+    use std::io::BufRead;
+
+    // Use high file descriptors so they won't be reused by other tests running concurrently
+    let (r, w) = pipe();
+    assert_eq!(unsafe { libc::dup2(w.as_raw_fd(), 903) }, 903);
+    drop(w);
+    let f = std::fs::File::open("/").unwrap();
+    assert_eq!(unsafe { libc::dup2(f.as_raw_fd(), 904) }, 904);
+    drop(f);
+
+    let sh = CString::new("/bin/sh").unwrap();
+    let argv =
+        sh_argv(b"[ ! -e /proc/self/fd/904 ] || exit 1; echo $$ >/proc/self/fd/903; exec sleep 10");
+    let argv: Vec<&CStr> = argv.iter().map(|s| s.as_c_str()).collect();
+
+    let pid = close_fds::spawn_daemon_with_closed_fds(
+        &sh,
+        &argv,
+        None,
+        3,
+        close_fds::CloseFdsBuilder::new().keep_fds(&[903]),
+    )
+    .unwrap();
+    unsafe {
+        libc::close(903);
+        libc::close(904);
+    }
+
+    let mut line = String::new();
+    std::io::BufReader::new(r).read_line(&mut line).unwrap();
+    assert_eq!(line.trim().parse::<libc::pid_t>().unwrap(), pid);
+
+    // It's in a new session, but it isn't the session leader
+    let sid = unsafe { libc::getsid(pid) };
+    assert!(sid > 0);
+    assert_ne!(sid, pid);
+    assert_ne!(sid, unsafe { libc::getsid(0) });
+
+    // It's not our child
+    assert_eq!(
+        unsafe { libc::waitpid(pid, std::ptr::null_mut(), libc::WNOHANG) },
+        -1
+    );
+    assert_eq!(
+        std::io::Error::last_os_error().raw_os_error(),
+        Some(libc::ECHILD)
+    );
+
+    unsafe {
+        libc::kill(pid, libc::SIGKILL);
+    }
+
+    let nonexistent = CString::new("/nonexistent").unwrap();
+    assert_eq!(
+        close_fds::spawn_daemon_with_closed_fds(
+            &nonexistent,
+            &[&nonexistent],
+            None,
+            3,
+            &close_fds::CloseFdsBuilder::new()
+        )
+        .unwrap_err()
+        .raw_os_error(),
+        Some(libc::ENOENT)
+    );
+}
+
+#[test]
+fn test_spawn_make_kept_inheritable() {
+    for &spawn in [
+        close_fds::spawn_with_closed_fds as SpawnFn,
+        close_fds::spawn_with_closed_fds_vfork,
+        close_fds::spawn_daemon_with_closed_fds,
+    ]
+    .iter()
+    {
+        use std::ffi::{CStr, CString};
+
+        let sh = CString::new("/bin/sh").unwrap();
+        let argv = sh_argv(b"sleep 10");
+        let argv: Vec<&CStr> = argv.iter().map(|s| s.as_c_str()).collect();
+
+        let mut builder = close_fds::CloseFdsBuilder::new();
+        builder.make_kept_inheritable(true);
+
+        // The spawn function should return as soon as the program has been executed, not when it
+        // exits (i.e. the pipe used to report errors must not be inherited)
+        let start = std::time::Instant::now();
+        let pid = spawn(&sh, &argv, None, 3, &builder).unwrap();
+        let elapsed = start.elapsed();
+
+        unsafe {
+            libc::kill(pid, libc::SIGKILL);
+            // The daemon isn't our child, so this fails for it
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+        }
+
+        assert!(elapsed < std::time::Duration::from_secs(5));
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_spawn_with_closed_fds_closes() {
+    // Use a high file descriptor so it won't be reused by other tests running concurrently
+    let f = std::fs::File::open("/").unwrap();
+    let fd = unsafe { libc::dup2(f.as_raw_fd(), 901) };
+    assert_eq!(fd, 901);
+    drop(f);
+
+    test_spawn_closes_generic(close_fds::spawn_with_closed_fds);
+    test_spawn_closes_generic(close_fds::spawn_with_closed_fds_vfork);
+    test_spawn_closes_generic(close_fds::posix_spawn_with_closed_fds);
+
+    unsafe {
+        libc::close(fd);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn test_spawn_closes_generic(spawn: SpawnFn) {
+    use std::ffi::{CStr, CString};
+
+    let sh = CString::new("/bin/sh").unwrap();
+
+    let argv = sh_argv(b"[ ! -e /proc/self/fd/901 ]");
+    let argv: Vec<&CStr> = argv.iter().map(|s| s.as_c_str()).collect();
+    let pid = spawn(&sh, &argv, None, 3, &close_fds::CloseFdsBuilder::new()).unwrap();
+    assert_eq!(wait_for_exit(pid), 0);
+
+    let argv = sh_argv(b"[ -e /proc/self/fd/901 ]");
+    let argv: Vec<&CStr> = argv.iter().map(|s| s.as_c_str()).collect();
+    let pid = spawn(
+        &sh,
+        &argv,
+        None,
+        3,
+        close_fds::CloseFdsBuilder::new().keep_fds(&[901]),
+    )
+    .unwrap();
+    assert_eq!(wait_for_exit(pid), 0);
+}
+
+#[test]
+fn test_pre_exec_pipeline() {
+    let (mut r, w) = pipe();
+    let wfd = w.as_raw_fd();
+
+    let write_step = move |data: &'static [u8]| {
+        move || {
+            if unsafe { libc::write(wfd, data.as_ptr() as *const libc::c_void, data.len()) } < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    };
+
+    let mut cmd = Command::new("sh");
+    // This would fail if the closing step ran first (or not at all)
+    cmd.args(["-c", &format!("! true >&{}", wfd)]);
+
+    let f = close_fds::PreExecPipeline::new()
+        .close_fds(3, &close_fds::CloseFdsBuilder::new())
+        .step(write_step(b"1"))
+        .step(write_step(b"2"))
+        .build();
+    unsafe {
+        cmd.pre_exec(f);
+    }
+
+    assert!(cmd.status().unwrap().success());
+    drop(w);
+
+    let mut s = String::new();
+    r.read_to_string(&mut s).unwrap();
+    assert_eq!(s, "12");
+}
+
+#[test]
+fn test_pre_exec_pipeline_redirect_stdio() {
+    use close_fds::StdioSource;
+
+    let (mut r, w) = pipe();
+
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", "read x || echo eof; echo err >&2"]);
+
+    let f = close_fds::PreExecPipeline::new()
+        .close_fds(3, &close_fds::CloseFdsBuilder::new())
+        .redirect_stdio(
+            StdioSource::Null,
+            StdioSource::Fd(w.as_raw_fd()),
+            StdioSource::Fd(w.as_raw_fd()),
+        )
+        .build();
+    unsafe {
+        cmd.pre_exec(f);
+    }
+
+    assert!(cmd.status().unwrap().success());
+    drop(w);
+
+    let mut s = String::new();
+    r.read_to_string(&mut s).unwrap();
+    assert_eq!(s, "eof\nerr\n");
+}
+
+#[test]
+fn test_collect_keep_fds() {
+    let f1 = std::fs::File::open("/").unwrap();
+    let f2 = std::fs::File::open("/").unwrap();
+    let (lo, hi) = if f1.as_raw_fd() < f2.as_raw_fd() {
+        (f1.as_raw_fd(), f2.as_raw_fd())
+    } else {
+        (f2.as_raw_fd(), f1.as_raw_fd())
+    };
+
+    let mut buf = [0; 4];
+    assert_eq!(
+        close_fds::collect_keep_fds([&f2, &f1, &f2].iter().copied(), &mut buf),
+        Some(&[lo, hi][..])
+    );
+    assert_eq!(
+        close_fds::collect_keep_fds(vec![&f1], &mut buf),
+        Some(&[f1.as_raw_fd()][..])
+    );
+    assert_eq!(
+        close_fds::collect_keep_fds(std::iter::empty::<&std::fs::File>(), &mut buf),
+        Some(&[][..])
+    );
+
+    let mut buf = [0; 1];
+    assert_eq!(close_fds::collect_keep_fds(&[f1, f2], &mut buf), None);
+}
+
+#[test]
+fn test_count_pid_fds() {
+    assert!(close_fds::count_pid_fds(unsafe { libc::getpid() }).unwrap() >= 3);
+
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+    {
+        let mut child = Command::new("sleep")
+            .arg("10")
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        let pid = child.id() as libc::pid_t;
+
+        // Other tests may be leaking non-close-on-exec file descriptors to it
+        assert!(close_fds::count_pid_fds(pid).unwrap() >= 3);
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        close_fds::count_pid_fds(pid).unwrap_err();
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_audit_processes() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let pid = unsafe { libc::getpid() };
+
+    // An inheritable socket, and a close-on-exec file
+    let (sock, _peer) = std::os::unix::net::UnixStream::pair().unwrap();
+    let sockfd = unsafe { libc::dup(sock.as_raw_fd()) };
+    assert!(sockfd >= 0);
+    let f = std::fs::File::open("/").unwrap();
+
+    let fds = close_fds::list_pid_fds(pid).unwrap();
+    assert!(fds.windows(2).all(|w| w[0].fd < w[1].fd));
+
+    let entry = fds.iter().find(|entry| entry.fd == sockfd).unwrap();
+    assert!(entry.is_socket());
+    assert_eq!(entry.cloexec(), Some(false));
+    assert_eq!(entry.special_kind(), None);
+
+    let entry = fds.iter().find(|entry| entry.fd == f.as_raw_fd()).unwrap();
+    assert!(!entry.is_socket());
+    assert_eq!(entry.cloexec(), Some(true));
+    assert_eq!(entry.target.as_bytes(), b"/");
+
+    let processes = close_fds::audit_processes().unwrap();
+    assert!(processes.windows(2).all(|w| w[0].pid < w[1].pid));
+    let process = processes.iter().find(|process| process.pid == pid).unwrap();
+    assert!(process
+        .fds
+        .as_ref()
+        .unwrap()
+        .iter()
+        .any(|entry| entry.fd == sockfd && entry.is_socket()));
+
+    unsafe {
+        libc::close(sockfd);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_find_fd_holders() {
+    use std::os::unix::fs::MetadataExt;
+
+    let pid = unsafe { libc::getpid() };
+
+    // Other tests may be fork()ing, so only look at this process
+    let find_own = |holders: Vec<(libc::pid_t, libc::c_int)>| {
+        holders
+            .into_iter()
+            .filter(|&(p, _)| p == pid)
+            .collect::<Vec<_>>()
+    };
+
+    let path = std::env::temp_dir().join(format!("close_fds_test_holders_{}", pid));
+    let f = std::fs::File::create(&path).unwrap();
+    let meta = f.metadata().unwrap();
+
+    assert_eq!(
+        find_own(close_fds::find_fd_holders(&path).unwrap()),
+        [(pid, f.as_raw_fd())]
+    );
+
+    // Deleted (but still open) files can be found by inode
+    std::fs::remove_file(&path).unwrap();
+    close_fds::find_fd_holders(&path).unwrap_err();
+    assert_eq!(
+        find_own(close_fds::find_fd_holders_by_inode(meta.dev(), meta.ino()).unwrap()),
+        [(pid, f.as_raw_fd())]
+    );
+
+    drop(f);
+    assert_eq!(
+        find_own(close_fds::find_fd_holders_by_inode(meta.dev(), meta.ino()).unwrap()),
+        []
+    );
+}
+
+#[test]
+fn test_sanitize_and_reexec() {
+    // The re-execution has to happen in a separate process, so run this test binary again (with
+    // only test_sanitize_and_reexec_child() enabled)
+    let status = Command::new(std::env::current_exe().unwrap())
+        .args([
+            "--exact",
+            "test_sanitize_and_reexec_child",
+            "--test-threads=1",
+            "--nocapture",
+        ])
+        .env("CLOSE_FDS_TEST_REEXEC_CHILD", "1")
+        .env_remove("CLOSE_FDS_TEST_REEXEC_MARKER")
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn test_sanitize_and_reexec_child() {
+    if std::env::var_os("CLOSE_FDS_TEST_REEXEC_CHILD").is_none() {
+        return;
+    }
+
+    let marker = std::ffi::OsStr::new("CLOSE_FDS_TEST_REEXEC_MARKER");
+    let is_fd_open = |fd| unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0;
+
+    if std::env::var_os(marker).is_some() {
+        // This is the re-executed process; file descriptors 905 and 906 should have been closed
+        // (though 907 was kept), and stdin should have been reopened
+        assert!(!is_fd_open(905));
+        assert!(!is_fd_open(906));
+        assert!(is_fd_open(907));
+        assert!(is_fd_open(0));
+
+        unsafe {
+            close_fds::sanitize_and_reexec(
+                marker,
+                close_fds::CloseFdsBuilder::new().keep_fds(&[907]),
+            )
+            .unwrap();
+        }
+        assert!(std::env::var_os(marker).is_none());
+        assert!(is_fd_open(907));
+        return;
+    }
+
+    let f = std::fs::File::open("/").unwrap();
+    unsafe {
+        assert_eq!(libc::dup2(f.as_raw_fd(), 905), 905);
+        assert_eq!(libc::dup2(f.as_raw_fd(), 907), 907);
+        // Close-on-exec, but still closed before re-executing
+        assert_eq!(libc::fcntl(f.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 906), 906);
+        libc::close(0);
+    }
+
+    let err = unsafe {
+        close_fds::sanitize_and_reexec(marker, close_fds::CloseFdsBuilder::new().keep_fds(&[907]))
+    }
+    .unwrap_err();
+    panic!("sanitize_and_reexec() failed: {}", err);
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_tracing_events() {
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata};
+
+    #[derive(Default)]
+    struct Collector {
+        spans: Mutex<Vec<&'static str>>,
+        events: Mutex<Vec<String>>,
+    }
+
+    struct MessageVisitor<'a>(&'a mut String);
+
+    impl Visit for MessageVisitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                *self.0 = format!("{:?}", value);
+            }
+        }
+    }
+
+    struct Subscriber(Arc<Collector>);
+
+    impl tracing::Subscriber for Subscriber {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &Attributes) -> Id {
+            let mut spans = self.0.spans.lock().unwrap();
+            spans.push(attrs.metadata().name());
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _span: &Id, _values: &Record) {}
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event) {
+            let mut message = String::new();
+            event.record(&mut MessageVisitor(&mut message));
+            self.0.events.lock().unwrap().push(message);
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    let collector = Arc::new(Collector::default());
+
+    tracing::subscriber::with_default(Subscriber(collector.clone()), || {
+        close_fds::FdSnapshot::take();
+
+        let cstr = |s: &'static [u8]| std::ffi::CStr::from_bytes_with_nul(s).unwrap();
+        let pid = close_fds::spawn_with_closed_fds(
+            cstr(b"/bin/sh\0"),
+            &[cstr(b"sh\0"), cstr(b"-c\0"), cstr(b"exit 0\0")],
+            None,
+            3,
+            &close_fds::CloseFdsBuilder::new(),
+        )
+        .unwrap();
+        assert_eq!(unsafe { libc::waitpid(pid, std::ptr::null_mut(), 0) }, pid);
+    });
+
+    let spans = collector.spans.lock().unwrap();
+    assert!(spans.contains(&"FdSnapshot::take"), "{:?}", spans);
+    assert!(spans.contains(&"spawn_with_closed_fds"), "{:?}", spans);
+
+    let events = collector.events.lock().unwrap();
+    for message in [
+        "took file descriptor snapshot",
+        "prepared to spawn child process",
+        "child process finished closing file descriptors",
+    ]
+    .iter()
+    {
+        assert!(events.iter().any(|e| e == message), "{:?}", events);
+    }
+}
+
+#[cfg(feature = "async-process")]
+#[test]
+fn test_async_process_command() {
+    let (_r, w) = pipe();
+
+    let mut cmd = Command::new("sh");
+    // This fails if the write end of the pipe was inherited
+    cmd.args(["-c", &format!("! true >&{}", w.as_raw_fd())]);
+
+    let mut child = close_fds::CloseFdsBuilder::new()
+        .async_process_command(cmd, 3)
+        .spawn()
+        .unwrap();
+
+    let status = loop {
+        if let Some(status) = child.try_status().unwrap() {
+            break status;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    };
+    assert!(status.success());
+}